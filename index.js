@@ -1,5 +1,9 @@
 import './style.scss';
 
+// keep a reference to the app handle so that it (and its listeners)
+// isn't freed once it is no longer reachable from JS
+let app;
+
 (async () => {
-  (await import('./pkg')).main();
+  app = (await import('./pkg')).main();
 })();
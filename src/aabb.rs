@@ -0,0 +1,70 @@
+use crate::math::{Point, Vec3};
+
+/// Axis-aligned bounding box
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+
+/// Pairs of indices into `Aabb::corners` that form the 12 edges of a box
+pub const AABB_EDGES: [(usize, usize); 12] = [
+    // bottom face
+    (0, 1),
+    (1, 3),
+    (3, 2),
+    (2, 0),
+    // top face
+    (4, 5),
+    (5, 7),
+    (7, 6),
+    (6, 4),
+    // verticals
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+impl Aabb {
+    pub fn new(min: Point, max: Point) -> Self {
+        Aabb { min, max }
+    }
+
+    /// The smallest box containing both boxes
+    pub fn union(a: &Aabb, b: &Aabb) -> Aabb {
+        Aabb {
+            min: Point(
+                a.min.x().min(b.min.x()),
+                a.min.y().min(b.min.y()),
+                a.min.z().min(b.min.z()),
+            ),
+            max: Point(
+                a.max.x().max(b.max.x()),
+                a.max.y().max(b.max.y()),
+                a.max.z().max(b.max.z()),
+            ),
+        }
+    }
+
+    pub fn center(&self) -> Point {
+        (&self.min + &self.max) / 2.
+    }
+
+    /// Distance from the center to any corner
+    pub fn bounding_radius(&self) -> f64 {
+        Vec3::distance(&self.min, &self.max) / 2.
+    }
+
+    /// Corner `i` takes its x from max when bit 0 is set, y when bit 1 is set, and z when bit 2 is set
+    pub fn corners(&self) -> [Point; 8] {
+        let (min, max) = (&self.min, &self.max);
+        [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            Point(
+                if i & 1 == 0 { min.x() } else { max.x() },
+                if i & 2 == 0 { min.y() } else { max.y() },
+                if i & 4 == 0 { min.z() } else { max.z() },
+            )
+        })
+    }
+}
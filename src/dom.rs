@@ -1,13 +1,14 @@
 use crate::{
     dom,
-    state::{self, State},
-    STATE,
+    glsl::{self, HitResult},
+    state::{self, Axis, State, ViewPreset},
 };
-use std::sync::MutexGuard;
-use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use std::any::Any;
+use std::sync::{Arc, Mutex, MutexGuard};
+use wasm_bindgen::{prelude::Closure, Clamped, JsCast, JsValue};
 use web_sys::{
-    Element, Event, HtmlAnchorElement, HtmlButtonElement, HtmlDivElement, KeyboardEvent,
-    MouseEvent, WheelEvent,
+    CanvasRenderingContext2d, Element, Event, EventTarget, HtmlAnchorElement, HtmlButtonElement,
+    HtmlCanvasElement, HtmlDivElement, ImageData, KeyboardEvent, MouseEvent, Storage, WheelEvent,
 };
 
 pub const MAX_CANVAS_SIZE: u32 = 1280;
@@ -31,252 +32,865 @@ pub fn canvas() -> web_sys::HtmlCanvasElement {
         .unwrap()
 }
 
-pub fn handle_wheel(e: WheelEvent) {
+/// The scene configured in the canvas's `data-scene` attribute, if any:
+/// either a scene preset name or base64-encoded scene JSON
+pub fn scene_attribute(canvas: &HtmlCanvasElement) -> Option<String> {
+    canvas.get_attribute("data-scene")
+}
+
+/// Whether the canvas has a `data-restore-state` attribute, which restores any state
+/// saved to localStorage on startup
+pub fn should_restore_state(canvas: &HtmlCanvasElement) -> bool {
+    canvas.has_attribute("data-restore-state")
+}
+
+/// The page's localStorage, which may be unavailable (such as in some private browsing modes)
+pub fn local_storage() -> Result<Storage, JsValue> {
+    window()
+        .local_storage()?
+        .ok_or_else(|| "localStorage is unavailable".into())
+}
+
+/// Decodes base64-encoded UTF-8 text
+pub fn decode_base64(encoded: &str) -> Result<String, JsValue> {
+    let binary = window().atob(encoded.trim())?;
+    Ok(binary_string_to_utf8(&binary)?)
+}
+
+/// `atob` returns a "binary string" with one char per decoded byte,
+/// so multi-byte characters still need to be decoded from UTF-8
+fn binary_string_to_utf8(binary: &str) -> Result<String, String> {
+    let bytes = binary
+        .chars()
+        .map(|char| u8::try_from(char).map_err(|_| format!("Invalid binary string: {char:?}")))
+        .collect::<Result<Vec<u8>, String>>()?;
+    String::from_utf8(bytes).map_err(|error| format!("Decoded base64 isn't UTF-8: {error}"))
+}
+
+pub fn canvas_by_id(id: &str) -> Option<HtmlCanvasElement> {
+    document()
+        .get_element_by_id(id)
+        .and_then(|element| element.dyn_into::<HtmlCanvasElement>().ok())
+}
+
+/// Page UI elements (buttons, modal, etc.) are optional, since
+/// embedded ray tracers may not have any UI around them
+fn optional_element<T: JsCast>(selector: &str) -> Option<T> {
+    document()
+        .query_selector(selector)
+        .ok()
+        .flatten()
+        .and_then(|element| element.dyn_into::<T>().ok())
+}
+
+/// Keeps the renderer from going idle (and wakes it up if it already has)
+fn register_input(state: &mut MutexGuard<State>) {
+    let now = window().performance().unwrap().now();
+    state.register_input(now);
+}
+
+pub fn handle_wheel(state: &Arc<Mutex<State>>, e: WheelEvent) {
     // can take a mutex guard here, because it will never be called while render loop is running
-    let mut state = (*STATE).lock().unwrap();
+    let mut state = state.lock().unwrap();
+    register_input(&mut state);
+    if state.camera_paused {
+        return;
+    }
     let adjustment = 1. + 0.03 * e.delta_y().signum();
     let new_value = state.camera_field_of_view * adjustment;
     state.set_fov(new_value);
 }
 
-pub fn handle_reset() {
+pub fn handle_reset(state: &Arc<Mutex<State>>) {
     // can take a mutex guard here, because it will never be called while render loop is running
-    let mut state = (*STATE).lock().unwrap();
+    let mut state = state.lock().unwrap();
+    // callbacks registered from JS are not part of the scene, so they survive a reset
+    let on_frame = state.on_frame.take();
+    let has_keyboard_focus = state.has_keyboard_focus;
     *state = State::default();
+    state.on_frame = on_frame;
+    state.has_keyboard_focus = has_keyboard_focus;
+    // GPU buffers may have been sized for a different accumulation frame count
+    state.should_update_accumulation_buffers = true;
 }
 
-pub fn handle_keydown(e: KeyboardEvent) {
+pub fn handle_keydown(state: &Arc<Mutex<State>>, e: KeyboardEvent) {
     // can take a mutex guard here, because it will never be called while render loop is running
-    let mut state = (*STATE).lock().unwrap();
+    let mut state = state.lock().unwrap();
+    // key listeners are shared by every ray tracer on the page,
+    // so only the one that was interacted with last responds (or wakes up)
+    if !state.has_keyboard_focus {
+        return;
+    }
+    register_input(&mut state);
+    // x/y/z only lock an axis in the middle of a drag, and are left alone otherwise
+    if let Some(axis) = Axis::from_key(&e.key()).filter(|_| state.sphere_drag.is_some()) {
+        state.axis_lock = Some(axis);
+        return;
+    }
+    // numpad views follow Blender: 1 = front, 3 = right, 7 = top (ctrl for the opposite side)
+    let view_preset = match (e.code().as_str(), e.ctrl_key()) {
+        ("Numpad1", false) => Some(ViewPreset::Front),
+        ("Numpad1", true) => Some(ViewPreset::Back),
+        ("Numpad3", false) => Some(ViewPreset::Right),
+        ("Numpad3", true) => Some(ViewPreset::Left),
+        ("Numpad7", false) => Some(ViewPreset::Top),
+        ("Numpad7", true) => Some(ViewPreset::Bottom),
+        ("Numpad5", _) => Some(ViewPreset::Iso),
+        _ => None,
+    };
+    if let Some(view_preset) = view_preset {
+        // an empty scene has nothing to look at, so there's nothing to do
+        let _ = state.set_view_preset(view_preset);
+        return;
+    }
     match e.key().as_str() {
         "w" | "W" => state.keydown_map.w = true,
         "a" | "A" => state.keydown_map.a = true,
         "s" | "S" => state.keydown_map.s = true,
         "d" | "D" => state.keydown_map.d = true,
+        "q" | "Q" => state.keydown_map.q = true,
+        "e" | "E" => state.keydown_map.e = true,
         " " => state.keydown_map.space = true,
         "Shift" => state.keydown_map.shift = true,
+        "m" | "M" => state.clear_measurement(),
+        "p" | "P" => state.toggle_profiler(),
+        "h" | "H" => {
+            let ui_hidden = !state.ui_hidden;
+            set_ui_hidden(&mut state, ui_hidden);
+        }
         "Escape" => show_pause_screen(&mut state),
         _ => {}
     }
 }
 
 pub fn hide_pause_screen(state: &mut MutexGuard<State>) {
-    let backdrop = document()
-        .query_selector("#backdrop")
-        .unwrap()
-        .unwrap()
-        .dyn_into::<HtmlDivElement>()
-        .unwrap();
-    backdrop.class_list().add_1("hide").unwrap();
-    state.is_paused = false;
+    if let Some(backdrop) = optional_element::<HtmlDivElement>("#backdrop") {
+        backdrop.class_list().add_1("hide").unwrap();
+    }
+    state.set_paused(false);
 }
 
 pub fn show_pause_screen(state: &mut MutexGuard<State>) {
-    let backdrop = document()
-        .query_selector("#backdrop")
-        .unwrap()
-        .unwrap()
-        .dyn_into::<HtmlDivElement>()
-        .unwrap();
-    backdrop.class_list().remove_1("hide").unwrap();
-    state.is_paused = true;
+    if let Some(backdrop) = optional_element::<HtmlDivElement>("#backdrop") {
+        backdrop.class_list().remove_1("hide").unwrap();
+    }
+    state.set_paused(true);
+}
+
+/// Hides or shows the page's UI chrome. This is purely cosmetic: rendering is unaffected
+pub fn set_ui_hidden(state: &mut MutexGuard<State>, ui_hidden: bool) {
+    state.ui_hidden = ui_hidden;
+    let elements = match document().query_selector_all("#fps, button") {
+        Ok(elements) => elements,
+        Err(_) => return,
+    };
+    for i in 0..elements.length() {
+        if let Some(element) = elements
+            .get(i)
+            .and_then(|node| node.dyn_into::<Element>().ok())
+        {
+            element
+                .class_list()
+                .toggle_with_force("hide", ui_hidden)
+                .unwrap();
+        }
+    }
 }
 
-pub fn handle_resize() {
+pub fn handle_resize(state: &Arc<Mutex<State>>) {
     // can take a mutex guard here, because it will never be called while render loop is running
-    let mut state = (*STATE).lock().unwrap();
+    let mut state = state.lock().unwrap();
+    state.should_update_canvas_rect = true;
+    // a fixed resolution is only scaled to fit the window by CSS
+    if state.fixed_resolution.is_some() {
+        return;
+    }
     state.should_update_to_match_window_size = true;
 }
 
-pub fn handle_keyup(e: KeyboardEvent) {
+pub fn handle_scroll(state: &Arc<Mutex<State>>) {
+    // can take a mutex guard here, because it will never be called while render loop is running
+    state.lock().unwrap().should_update_canvas_rect = true;
+}
+
+pub fn handle_keyup(state: &Arc<Mutex<State>>, e: KeyboardEvent) {
     // can take a mutex guard here, because it will never be called while render loop is running
-    let mut state = (*STATE).lock().unwrap();
+    let mut state = state.lock().unwrap();
+    if !state.has_keyboard_focus {
+        return;
+    }
+    register_input(&mut state);
+    if Axis::from_key(&e.key()).is_some_and(|axis| state.axis_lock == Some(axis)) {
+        state.axis_lock = None;
+        return;
+    }
     match e.key().as_str() {
         "w" | "W" => state.keydown_map.w = false,
         "a" | "A" => state.keydown_map.a = false,
         "s" | "S" => state.keydown_map.s = false,
         "d" | "D" => state.keydown_map.d = false,
+        "q" | "Q" => state.keydown_map.q = false,
+        "e" | "E" => state.keydown_map.e = false,
         "Shift" => state.keydown_map.shift = false,
         " " => state.keydown_map.space = false,
         _ => {}
     }
 }
 
-pub fn handle_mouse_move(e: MouseEvent) {
-    let mut state = (*STATE).lock().unwrap();
-    // camera should move slower when more "zoomed in"
-    let dx = (e.movement_x() as f64) * state.look_sensitivity * state.camera_field_of_view;
-    let dy = -(e.movement_y() as f64) * state.look_sensitivity * state.camera_field_of_view;
-    let yaw = state.yaw + dx;
-    let pitch = state.pitch + dy;
-    state.set_camera_angles(yaw, pitch);
+pub fn handle_mouse_move(state: &Arc<Mutex<State>>, canvas: &HtmlCanvasElement, e: MouseEvent) {
+    let mut state = state.lock().unwrap();
+    register_input(&mut state);
+    // without pointer lock, dragging a sphere moves it under the cursor instead of looking around
+    let is_looking = state.sphere_drag.is_none() || is_pointer_locked(canvas);
+    if is_looking && !state.camera_paused {
+        // camera should move slower when more "zoomed in"
+        let dx = (e.movement_x() as f64) * state.look_sensitivity * state.camera_field_of_view;
+        let dy = -(e.movement_y() as f64) * state.look_sensitivity * state.camera_field_of_view;
+        let yaw = state.yaw + dx;
+        let pitch = state.pitch + dy;
+        state.set_camera_angles(yaw, pitch);
+    }
+    if state.sphere_drag.is_some() {
+        let (s, t) = viewport_coordinates(&state, canvas, &e);
+        if let Err(error) = state.update_sphere_drag(s, t) {
+            log::warn!("{error}");
+            state.end_sphere_drag();
+        }
+    }
     state::update_cursor_position_in_world(&mut state);
 }
 
+/// Grabs whichever sphere is under the cursor (or crosshair), to be dragged until the
+/// mouse button is released. Holding x, y, or z while dragging locks movement to that axis
+pub fn handle_mouse_down(state: &Arc<Mutex<State>>, canvas: &HtmlCanvasElement, e: MouseEvent) {
+    // alt + click is for measuring
+    if e.button() != 0 || e.alt_key() {
+        return;
+    }
+    let mut state = state.lock().unwrap();
+    let (s, t) = viewport_coordinates(&state, canvas, &e);
+    if let HitResult::Hit { data } = glsl::get_hit_at(&state, s, t) {
+        // rect lights can't be dragged (they aren't spheres)
+        if let Err(error) = state.start_sphere_drag(data.uuid, data.hit_point) {
+            log::warn!("{error}");
+        }
+    }
+}
+
+/// Lets go of any dragged sphere (listened for on the window, since the cursor may
+/// have left the canvas by the time the button is released)
+pub fn handle_mouse_up(state: &Arc<Mutex<State>>) {
+    let mut state = state.lock().unwrap();
+    if state.end_sphere_drag() {
+        // the click that follows shouldn't also enter pointer lock
+        state.should_ignore_next_click = true;
+    }
+}
+
+fn is_pointer_locked(canvas: &HtmlCanvasElement) -> bool {
+    let canvas_as_element: &Element = canvas.as_ref();
+    document().pointer_lock_element().as_ref() == Some(canvas_as_element)
+}
+
+/// Converts a mouse event's position into viewport coordinates (0->1, from the bottom-left)
+fn viewport_coordinates(state: &State, canvas: &HtmlCanvasElement, e: &MouseEvent) -> (f64, f64) {
+    // while pointer lock is enabled, the cursor is always at the center of the canvas
+    if is_pointer_locked(canvas) {
+        (0.5, 0.5)
+    } else {
+        let width = canvas.client_width().max(1) as f64;
+        let height = canvas.client_height().max(1) as f64;
+        state.canvas_to_viewport(
+            e.offset_x() as f64 / width,
+            1. - e.offset_y() as f64 / height,
+        )
+    }
+}
+
+/// Flies the camera to whichever object was double-clicked
+pub fn handle_double_click(state: &Arc<Mutex<State>>, canvas: &HtmlCanvasElement, e: MouseEvent) {
+    let mut state = state.lock().unwrap();
+    register_input(&mut state);
+    let (s, t) = viewport_coordinates(&state, canvas, &e);
+    if let HitResult::Hit { data } = glsl::get_hit_at(&state, s, t) {
+        let now = window().performance().unwrap().now();
+        // rect lights can't be focused on (they aren't spheres)
+        if let Err(error) = state.focus_on_object(data.uuid, now) {
+            log::warn!("{error}");
+        }
+    }
+}
+
+/// Alt + click places measurement points. Otherwise, clicking enters pointer lock
+pub fn handle_canvas_click(state: &Arc<Mutex<State>>, canvas: &HtmlCanvasElement, e: MouseEvent) {
+    {
+        let mut state = state.lock().unwrap();
+        register_input(&mut state);
+        if std::mem::take(&mut state.should_ignore_next_click) {
+            return;
+        }
+    }
+    if !e.alt_key() {
+        let element: &Element = canvas.as_ref();
+        element.request_pointer_lock();
+        return;
+    }
+
+    let mut state = state.lock().unwrap();
+    let (s, t) = viewport_coordinates(&state, canvas, &e);
+    if let HitResult::Hit { data } = glsl::get_hit_at(&state, s, t) {
+        state.add_measurement_point(data.hit_point);
+    }
+}
+
 /// Waits until immediately after rendering on the next frame to save the image
 /// so that the canvas isn't blank
-pub fn handle_save_image(_: MouseEvent) {
+pub fn handle_save_image(state: &Arc<Mutex<State>>) {
     // can take a mutex guard here, because it will never be called while render loop is running
-    let mut state = (*STATE).lock().unwrap();
+    let mut state = state.lock().unwrap();
     state.should_render = true;
     state.should_save = true;
 }
 
 /// if user has requested to save, save immediately after rendering
-pub fn save_image(state: &mut MutexGuard<State>) {
+pub fn save_image(state: &mut MutexGuard<State>, canvas: &HtmlCanvasElement) {
     if state.should_save {
         state.should_save = false;
-        let data_url = canvas()
-            .to_data_url()
-            .unwrap()
-            .replace("image/png", "image/octet-stream");
-        let a = dom::document()
-            .create_element("a")
-            .unwrap()
-            .dyn_into::<HtmlAnchorElement>()
-            .unwrap();
-
-        a.set_href(&data_url);
-        a.set_download("canvas.png");
-        a.click();
+        download_canvas(canvas, "canvas.png").unwrap();
     }
 }
 
+/// Downloads whatever is currently drawn on the canvas as a png
+fn download_canvas(canvas: &HtmlCanvasElement, file_name: &str) -> Result<(), JsValue> {
+    let data_url = canvas
+        .to_data_url()?
+        .replace("image/png", "image/octet-stream");
+    let a = dom::document()
+        .create_element("a")?
+        .dyn_into::<HtmlAnchorElement>()?;
+
+    a.set_href(&data_url);
+    a.set_download(file_name);
+    a.click();
+    Ok(())
+}
+
+/// Encodes RGBA pixels (top row first) as a png and downloads it
+pub fn download_pixels(
+    pixels: &mut [u8],
+    width: u32,
+    height: u32,
+    file_name: &str,
+) -> Result<(), JsValue> {
+    download_canvas(&pixels_to_canvas(pixels, width, height)?, file_name)
+}
+
+/// Encodes RGBA pixels (top row first) as a png data URL
+pub fn pixels_to_data_url(pixels: &mut [u8], width: u32, height: u32) -> Result<String, JsValue> {
+    pixels_to_canvas(pixels, width, height)?.to_data_url()
+}
+
+/// Draws RGBA pixels (top row first) onto a new (detached) canvas
+fn pixels_to_canvas(
+    pixels: &mut [u8],
+    width: u32,
+    height: u32,
+) -> Result<HtmlCanvasElement, JsValue> {
+    let canvas = document()
+        .create_element("canvas")?
+        .dyn_into::<HtmlCanvasElement>()?;
+    canvas.set_width(width);
+    canvas.set_height(height);
+    let context = canvas
+        .get_context("2d")?
+        .ok_or("Couldn't get a 2d context to encode the image with")?
+        .dyn_into::<CanvasRenderingContext2d>()?;
+    let image_data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(pixels), width, height)?;
+    context.put_image_data(&image_data, 0., 0.)?;
+    Ok(canvas)
+}
+
 pub fn update_fps_indicator(now: f64, state: &mut MutexGuard<State>) {
-    let fps_indicator = dom::document()
-        .query_selector("#fps")
-        .unwrap()
-        .unwrap()
-        .dyn_into::<web_sys::HtmlParagraphElement>()
-        .unwrap();
+    let fps_indicator = match optional_element::<web_sys::HtmlParagraphElement>("#fps") {
+        Some(fps_indicator) => fps_indicator,
+        None => return,
+    };
 
     if now - state.prev_fps_update_time > 250. {
         state.prev_fps_update_time = now;
-        let average_fps: f64 = state.prev_fps.iter().sum::<f64>() / (state.prev_fps.len() as f64);
+        let average_fps = state.average_fps();
         fps_indicator.set_text_content(Some(&format!("{:.2} fps", average_fps)))
     }
 }
 
-pub fn add_listeners() -> Result<(), JsValue> {
+pub fn create_crosshair() -> Result<HtmlDivElement, JsValue> {
+    let crosshair = document()
+        .create_element("div")?
+        .dyn_into::<HtmlDivElement>()?;
+    crosshair.class_list().add_2("crosshair", "hide")?;
+    document().body().unwrap().append_child(&crosshair)?;
+    Ok(crosshair)
+}
+
+pub fn create_profiler() -> Result<HtmlDivElement, JsValue> {
+    let profiler = document()
+        .create_element("div")?
+        .dyn_into::<HtmlDivElement>()?;
+    profiler.class_list().add_2("profiler", "hide")?;
+    document().body().unwrap().append_child(&profiler)?;
+    Ok(profiler)
+}
+
+/// Keeps the profiler overlay in the top corner of the canvas, showing the latest stats
+/// Where the canvas sits on the page. Reading this forces a layout,
+/// so it's only measured again after a resize or scroll (see `should_update_canvas_rect`)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CanvasRect {
+    pub left: f64,
+    pub top: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl CanvasRect {
+    pub fn measure(canvas: &HtmlCanvasElement) -> Self {
+        let rect = canvas.get_bounding_client_rect();
+        CanvasRect {
+            left: rect.left(),
+            top: rect.top(),
+            width: rect.width(),
+            height: rect.height(),
+        }
+    }
+}
+
+pub fn update_profiler(state: &MutexGuard<State>, profiler: &HtmlDivElement, rect: &CanvasRect) {
+    if !state.show_profiler || state.ui_hidden {
+        profiler.class_list().add_1("hide").unwrap();
+        return;
+    }
+    profiler.class_list().remove_1("hide").unwrap();
+    let stats = state.render_stats();
+    profiler.set_inner_text(&format!(
+        "{:.1} fps\n{:.2} ms\nrender count: {}\nsamples: {}\nspheres: {}",
+        state.average_fps(),
+        stats.last_frame_time,
+        state.render_count,
+        stats.accumulated_samples,
+        state.world_spheres.len(),
+    ));
+
+    let style = profiler.style();
+    style
+        .set_property("left", &format!("{}px", rect.left))
+        .unwrap();
+    style
+        .set_property("top", &format!("{}px", rect.top))
+        .unwrap();
+}
+
+/// Shows an error message on top of the page. Failures are ignored, since this is
+/// used from the panic hook, where there's no way left to report them
+pub fn show_error_overlay(message: &str) {
+    let document = match web_sys::window().and_then(|window| window.document()) {
+        Some(document) => document,
+        None => return,
+    };
+    let (overlay, body) = match (document.create_element("div"), document.body()) {
+        (Ok(overlay), Some(body)) => (overlay, body),
+        _ => return,
+    };
+    overlay.set_class_name("error-overlay");
+    overlay.set_text_content(Some(message));
+    let _ = body.append_child(&overlay);
+}
+
+/// Keeps the crosshair centered on the canvas (which may move or resize at any time)
+pub fn update_crosshair(state: &MutexGuard<State>, crosshair: &HtmlDivElement, rect: &CanvasRect) {
+    if !state.crosshair_enabled {
+        crosshair.class_list().add_1("hide").unwrap();
+        return;
+    }
+    crosshair.class_list().remove_1("hide").unwrap();
+    // show which axis a drag is locked to
+    let axis_lock = state
+        .sphere_drag
+        .as_ref()
+        .and(state.axis_lock)
+        .map(|axis| axis.name());
+    crosshair.set_text_content(axis_lock);
+
+    let center_x = rect.left + rect.width / 2.;
+    let center_y = rect.top + rect.height / 2.;
+    let style = crosshair.style();
+    style
+        .set_property("left", &format!("{center_x}px"))
+        .unwrap();
+    style.set_property("top", &format!("{center_y}px")).unwrap();
+    style
+        .set_property("width", &format!("{}px", state.crosshair_size))
+        .unwrap();
+    style
+        .set_property("height", &format!("{}px", state.crosshair_size))
+        .unwrap();
+}
+
+/// A line drawn between two measured points, labelled with the distance between them
+pub struct MeasurementOverlay {
+    line: HtmlDivElement,
+    label: HtmlDivElement,
+}
+
+impl MeasurementOverlay {
+    pub fn create() -> Result<Self, JsValue> {
+        let line = document()
+            .create_element("div")?
+            .dyn_into::<HtmlDivElement>()?;
+        line.class_list().add_2("measurement-line", "hide")?;
+        let label = document()
+            .create_element("div")?
+            .dyn_into::<HtmlDivElement>()?;
+        label.class_list().add_2("measurement-label", "hide")?;
+        let body = document().body().unwrap();
+        body.append_child(&line)?;
+        body.append_child(&label)?;
+        Ok(MeasurementOverlay { line, label })
+    }
+
+    pub fn remove(&self) {
+        self.line.remove();
+        self.label.remove();
+    }
+
+    fn hide(&self) {
+        self.line.class_list().add_1("hide").unwrap();
+        self.label.class_list().add_1("hide").unwrap();
+    }
+}
+
+/// Keeps the measurement line over the measured points as the camera moves
+pub fn update_measurement_overlay(
+    state: &MutexGuard<State>,
+    overlay: &MeasurementOverlay,
+    rect: &CanvasRect,
+) {
+    let (start, end, distance) = match (
+        &state.measurement_start,
+        &state.measurement_end,
+        state.measured_distance(),
+    ) {
+        (Some(start), Some(end), Some(distance)) => (start, end, distance),
+        _ => return overlay.hide(),
+    };
+    let (start, end) = match (
+        state.project_to_viewport(start),
+        state.project_to_viewport(end),
+    ) {
+        (Some(start), Some(end)) => (start, end),
+        // can't draw a line to a point behind the camera
+        _ => return overlay.hide(),
+    };
+
+    let to_screen = |(s, t): (f64, f64)| {
+        let (s, t) = state.viewport_to_canvas(s, t);
+        (
+            rect.left + s * rect.width,
+            rect.top + (1. - t) * rect.height,
+        )
+    };
+    let (start_x, start_y) = to_screen(start);
+    let (end_x, end_y) = to_screen(end);
+    let length = (end_x - start_x).hypot(end_y - start_y);
+    let angle = (end_y - start_y).atan2(end_x - start_x);
+
+    overlay.line.class_list().remove_1("hide").unwrap();
+    let style = overlay.line.style();
+    style.set_property("left", &format!("{start_x}px")).unwrap();
+    style.set_property("top", &format!("{start_y}px")).unwrap();
+    style.set_property("width", &format!("{length}px")).unwrap();
+    style
+        .set_property("transform", &format!("rotate({angle}rad)"))
+        .unwrap();
+
+    overlay.label.class_list().remove_1("hide").unwrap();
+    overlay.label.set_inner_text(&format!("{distance:.2}"));
+    let style = overlay.label.style();
+    style
+        .set_property("left", &format!("{}px", (start_x + end_x) / 2.))
+        .unwrap();
+    style
+        .set_property("top", &format!("{}px", (start_y + end_y) / 2.))
+        .unwrap();
+}
+
+/// Calls the user's frame callback, if there is one.
+/// Exceptions are logged rather than allowed to stop the render loop.
+pub fn call_on_frame(on_frame: &js_sys::Function, fps: f64, render_count: u32) {
+    let frame_info = js_sys::Object::new();
+    let result = js_sys::Reflect::set(&frame_info, &"fps".into(), &fps.into())
+        .and_then(|_| {
+            js_sys::Reflect::set(&frame_info, &"renderCount".into(), &render_count.into())
+        })
+        .and_then(|_| on_frame.call1(&JsValue::NULL, &frame_info));
+    if let Err(error) = result {
+        log::error!("Error thrown by frame callback: {error:?}");
+    }
+}
+
+/// An event listener that is removed from its target when dropped
+pub struct Listener {
+    target: EventTarget,
+    event: &'static str,
+    callback: js_sys::Function,
+    // keeps the Rust closure alive for as long as the listener is registered
+    _closure: Box<dyn Any>,
+}
+
+impl Listener {
+    pub fn add<T: ?Sized + 'static>(
+        target: &EventTarget,
+        event: &'static str,
+        closure: Closure<T>,
+    ) -> Result<Self, JsValue> {
+        let callback: js_sys::Function =
+            closure.as_ref().unchecked_ref::<js_sys::Function>().clone();
+        target.add_event_listener_with_callback(event, &callback)?;
+        Ok(Listener {
+            target: target.clone(),
+            event,
+            callback,
+            _closure: Box::new(closure),
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        let _ = self
+            .target
+            .remove_event_listener_with_callback(self.event, &self.callback);
+    }
+}
+
+pub fn add_listeners(
+    state: &Arc<Mutex<State>>,
+    canvas: &HtmlCanvasElement,
+) -> Result<Vec<Listener>, JsValue> {
     // GET ELEMENTS
     let window = dom::window();
     let document = dom::document();
-    let canvas = document
-        .query_selector("canvas")?
-        .unwrap()
-        .dyn_into::<web_sys::HtmlCanvasElement>()?;
+    let enable_button = optional_element::<HtmlButtonElement>("#enable");
+    let cancel_button = optional_element::<HtmlButtonElement>("#cancel");
+    let save_image_button = optional_element::<HtmlButtonElement>("#save-image");
+    let reset_button = optional_element::<HtmlButtonElement>("#reset");
+    let backdrop = optional_element::<HtmlDivElement>("#backdrop");
 
-    let enable_button = document
-        .query_selector("#enable")?
-        .unwrap()
-        .dyn_into::<HtmlButtonElement>()?;
+    // ADD LISTENERS
+    // listeners are added (rather than assigned with `set_on*`) so that
+    // several ray tracers on the same page don't overwrite each other's handlers.
+    // they are all removed again once the returned list is dropped
+    let mut listeners = Vec::new();
+
+    let handle_wheel = {
+        let state = state.clone();
+        Closure::wrap(Box::new(move |e| dom::handle_wheel(&state, e)) as Box<dyn FnMut(WheelEvent)>)
+    };
+    listeners.push(Listener::add(canvas, "wheel", handle_wheel)?);
 
-    let cancel_button = document
-        .query_selector("#cancel")?
-        .unwrap()
-        .dyn_into::<HtmlButtonElement>()?;
+    let handle_resize = {
+        let state = state.clone();
+        Closure::wrap(Box::new(move || dom::handle_resize(&state)) as Box<dyn FnMut()>)
+    };
+    listeners.push(Listener::add(&window, "resize", handle_resize)?);
 
-    let save_image_button = document
-        .query_selector("#save-image")?
-        .unwrap()
-        .dyn_into::<HtmlButtonElement>()?;
+    let handle_scroll = {
+        let state = state.clone();
+        Closure::wrap(Box::new(move || dom::handle_scroll(&state)) as Box<dyn FnMut()>)
+    };
+    listeners.push(Listener::add(&window, "scroll", handle_scroll)?);
+
+    if let Some(reset_button) = reset_button {
+        let handle_reset = {
+            let state = state.clone();
+            Closure::wrap(Box::new(move || dom::handle_reset(&state)) as Box<dyn FnMut()>)
+        };
+        listeners.push(Listener::add(&reset_button, "click", handle_reset)?);
+    }
 
-    let reset_button = document
-        .query_selector("#reset")?
-        .unwrap()
-        .dyn_into::<HtmlButtonElement>()?;
+    if let Some(save_image_button) = save_image_button {
+        let handle_save_image = {
+            let state = state.clone();
+            Closure::wrap(Box::new(move || dom::handle_save_image(&state)) as Box<dyn FnMut()>)
+        };
+        listeners.push(Listener::add(
+            &save_image_button,
+            "click",
+            handle_save_image,
+        )?);
+    }
 
-    let backdrop = document
-        .query_selector("#backdrop")?
-        .unwrap()
-        .dyn_into::<HtmlDivElement>()?;
+    let handle_keydown = {
+        let state = state.clone();
+        Closure::wrap(
+            Box::new(move |e| dom::handle_keydown(&state, e)) as Box<dyn FnMut(KeyboardEvent)>
+        )
+    };
+    listeners.push(Listener::add(&window, "keydown", handle_keydown)?);
 
-    let state = (*STATE).lock().unwrap();
-    canvas.set_width(state.width);
-    canvas.set_height(state.height);
-    drop(state);
+    let handle_keyup = {
+        let state = state.clone();
+        Closure::wrap(
+            Box::new(move |e| dom::handle_keyup(&state, e)) as Box<dyn FnMut(KeyboardEvent)>
+        )
+    };
+    listeners.push(Listener::add(&window, "keyup", handle_keyup)?);
 
-    // ADD LISTENERS
-    // not planning on removing any of these listeners for the
-    // duration of the program, so using `forget()` here is fine for now
-    let handle_wheel = Closure::wrap(Box::new(dom::handle_wheel) as Box<dyn FnMut(WheelEvent)>);
-    window.set_onwheel(Some(handle_wheel.as_ref().unchecked_ref()));
-    handle_wheel.forget();
-
-    let handle_resize = Closure::wrap(Box::new(dom::handle_resize) as Box<dyn FnMut()>);
-    window.set_onresize(Some(handle_resize.as_ref().unchecked_ref()));
-    handle_resize.forget();
-
-    let handle_reset = Closure::wrap(Box::new(dom::handle_reset) as Box<dyn FnMut()>);
-    reset_button.set_onclick(Some(handle_reset.as_ref().unchecked_ref()));
-    handle_reset.forget();
-
-    let handle_save_image =
-        Closure::wrap(Box::new(dom::handle_save_image) as Box<dyn FnMut(MouseEvent)>);
-    save_image_button.set_onclick(Some(handle_save_image.as_ref().unchecked_ref()));
-    handle_save_image.forget();
-
-    let handle_keydown =
-        Closure::wrap(Box::new(dom::handle_keydown) as Box<dyn FnMut(KeyboardEvent)>);
-    window.set_onkeydown(Some(handle_keydown.as_ref().unchecked_ref()));
-    handle_keydown.forget();
-
-    let handle_keyup = Closure::wrap(Box::new(dom::handle_keyup) as Box<dyn FnMut(KeyboardEvent)>);
-    window.set_onkeyup(Some(handle_keyup.as_ref().unchecked_ref()));
-    handle_keyup.forget();
-
-    let handle_enable_button_click = {
+    // embedded canvases without a pause modal can be entered by clicking on them directly
+    let handle_canvas_click = {
+        let state = state.clone();
         let canvas = canvas.clone();
-        Closure::wrap(Box::new(move |_| {
-            let element: &Element = canvas.as_ref();
-            element.request_pointer_lock();
-        }) as Box<dyn FnMut(MouseEvent)>)
+        Closure::wrap(
+            Box::new(move |e| dom::handle_canvas_click(&state, &canvas, e))
+                as Box<dyn FnMut(MouseEvent)>,
+        )
     };
-    enable_button.set_onclick(Some(handle_enable_button_click.as_ref().unchecked_ref()));
-    handle_enable_button_click.forget();
+    listeners.push(Listener::add(canvas, "click", handle_canvas_click)?);
+    if let Some(enable_button) = enable_button {
+        listeners.push(Listener::add(
+            &enable_button,
+            "click",
+            create_request_pointer_lock_handler(canvas),
+        )?);
+    }
 
-    let handle_cancel_button_click = {
-        let backdrop = backdrop;
-        let state = STATE.clone();
-        Closure::wrap(Box::new(move |_| {
-            backdrop.class_list().add_1("hide").unwrap();
-            (*state).lock().unwrap().is_paused = false;
-        }) as Box<dyn FnMut(MouseEvent)>)
-    };
-    cancel_button.set_onclick(Some(handle_cancel_button_click.as_ref().unchecked_ref()));
-    handle_cancel_button_click.forget();
+    if let Some(cancel_button) = cancel_button {
+        let handle_cancel_button_click = {
+            let backdrop = backdrop;
+            let state = state.clone();
+            Closure::wrap(Box::new(move || {
+                if let Some(backdrop) = &backdrop {
+                    backdrop.class_list().add_1("hide").unwrap();
+                }
+                state.lock().unwrap().set_paused(false);
+            }) as Box<dyn FnMut()>)
+        };
+        listeners.push(Listener::add(
+            &cancel_button,
+            "click",
+            handle_cancel_button_click,
+        )?);
+    }
 
+    // pointer lock changes are announced to the whole document, so each ray tracer
+    // only responds to its own canvas gaining or losing the lock
     let handle_onpointerlockchange = {
         let canvas = canvas.clone();
         let document = document.clone();
+        let state = state.clone();
+        let mut is_locked = false;
         Closure::wrap(Box::new(move |_| {
-            let mut state = (*STATE).lock().unwrap();
-            if let Some(pointer_lock_element) = document.pointer_lock_element() {
-                let canvas_as_element: &Element = canvas.as_ref();
-                if &pointer_lock_element == canvas_as_element {
-                    hide_pause_screen(&mut state);
-                    return;
-                }
+            let mut state = state.lock().unwrap();
+            let canvas_as_element: &Element = canvas.as_ref();
+            let was_locked = is_locked;
+            is_locked = document.pointer_lock_element().as_ref() == Some(canvas_as_element);
+            if is_locked {
+                state.has_keyboard_focus = true;
+                hide_pause_screen(&mut state);
+            } else if was_locked {
+                show_pause_screen(&mut state);
             }
-            show_pause_screen(&mut state);
         }) as Box<dyn FnMut(Event)>)
     };
-    document.set_onpointerlockchange(Some(handle_onpointerlockchange.as_ref().unchecked_ref()));
-    handle_onpointerlockchange.forget();
+    listeners.push(Listener::add(
+        &document,
+        "pointerlockchange",
+        handle_onpointerlockchange,
+    )?);
+
+    // clicking on another canvas (such as another ray tracer's) takes keyboard focus away
+    let handle_document_mouse_down = {
+        let state = state.clone();
+        let canvas = canvas.clone();
+        Closure::wrap(Box::new(move |e: MouseEvent| {
+            let clicked_canvas = e
+                .target()
+                .and_then(|target| target.dyn_into::<HtmlCanvasElement>().ok());
+            if let Some(clicked_canvas) = clicked_canvas {
+                state
+                    .lock()
+                    .unwrap()
+                    .set_keyboard_focus(clicked_canvas == canvas);
+            }
+        }) as Box<dyn FnMut(MouseEvent)>)
+    };
+    listeners.push(Listener::add(
+        &document,
+        "mousedown",
+        handle_document_mouse_down,
+    )?);
+
+    let handle_double_click = {
+        let state = state.clone();
+        let canvas = canvas.clone();
+        Closure::wrap(
+            Box::new(move |e| dom::handle_double_click(&state, &canvas, e))
+                as Box<dyn FnMut(MouseEvent)>,
+        )
+    };
+    listeners.push(Listener::add(canvas, "dblclick", handle_double_click)?);
+
+    let handle_mouse_move = {
+        let state = state.clone();
+        let canvas = canvas.clone();
+        Closure::wrap(
+            Box::new(move |e| dom::handle_mouse_move(&state, &canvas, e))
+                as Box<dyn FnMut(MouseEvent)>,
+        )
+    };
+    listeners.push(Listener::add(canvas, "mousemove", handle_mouse_move)?);
 
-    let handle_mouse_move =
-        Closure::wrap(Box::new(dom::handle_mouse_move) as Box<dyn FnMut(MouseEvent)>);
-    canvas.set_onmousemove(Some(handle_mouse_move.as_ref().unchecked_ref()));
-    handle_mouse_move.forget();
+    let handle_mouse_down = {
+        let state = state.clone();
+        let canvas = canvas.clone();
+        Closure::wrap(
+            Box::new(move |e| dom::handle_mouse_down(&state, &canvas, e))
+                as Box<dyn FnMut(MouseEvent)>,
+        )
+    };
+    listeners.push(Listener::add(canvas, "mousedown", handle_mouse_down)?);
 
-    Ok(())
+    let handle_mouse_up = {
+        let state = state.clone();
+        Closure::wrap(Box::new(move || dom::handle_mouse_up(&state)) as Box<dyn FnMut()>)
+    };
+    listeners.push(Listener::add(&window, "mouseup", handle_mouse_up)?);
+
+    Ok(listeners)
+}
+
+fn create_request_pointer_lock_handler(canvas: &HtmlCanvasElement) -> Closure<dyn FnMut()> {
+    let canvas = canvas.clone();
+    Closure::wrap(Box::new(move || {
+        let element: &Element = canvas.as_ref();
+        element.request_pointer_lock();
+    }) as Box<dyn FnMut()>)
 }
 
 // limit max canvas dimensions to a reasonable number
 // (to prevent off-the-charts GPU work on large screen sizes)
-pub fn get_adjusted_screen_dimensions() -> (u32, u32) {
-    let raw_screen_width = dom::window().inner_width().unwrap().as_f64().unwrap();
-    let raw_screen_height = dom::window().inner_height().unwrap().as_f64().unwrap();
+// when `respect_dpr` is set, dimensions are in device pixels rather than CSS pixels,
+// so that the canvas isn't upscaled (and blurry) on HiDPI displays
+pub fn get_adjusted_screen_dimensions(respect_dpr: bool) -> (u32, u32) {
+    let device_pixel_ratio = if respect_dpr {
+        dom::window().device_pixel_ratio()
+    } else {
+        1.
+    };
+    let raw_screen_width =
+        dom::window().inner_width().unwrap().as_f64().unwrap() * device_pixel_ratio;
+    let raw_screen_height =
+        dom::window().inner_height().unwrap().as_f64().unwrap() * device_pixel_ratio;
     let aspect_ratio = raw_screen_width / raw_screen_height;
 
     if raw_screen_width > raw_screen_height {
@@ -290,8 +904,33 @@ pub fn get_adjusted_screen_dimensions() -> (u32, u32) {
     }
 }
 
-pub fn request_animation_frame(f: &Closure<dyn FnMut()>) {
+pub fn request_animation_frame(f: &Closure<dyn FnMut()>) -> i32 {
     dom::window()
         .request_animation_frame(f.as_ref().unchecked_ref())
-        .expect("should register `requestAnimationFrame` OK");
+        .expect("should register `requestAnimationFrame` OK")
+}
+
+pub fn cancel_animation_frame(id: i32) {
+    dom::window()
+        .cancel_animation_frame(id)
+        .expect("should cancel `requestAnimationFrame` OK");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_strings_are_decoded_as_utf8() {
+        // what `atob` returns for "é" (0xC3 0xA9) and "☀" (0xE2 0x98 0x80)
+        assert_eq!(
+            binary_string_to_utf8("\u{c3}\u{a9}\u{e2}\u{98}\u{80}"),
+            Ok("é☀".to_string())
+        );
+        assert_eq!(binary_string_to_utf8("plain"), Ok("plain".to_string()));
+        // a lone continuation byte
+        assert!(binary_string_to_utf8("\u{a9}").is_err());
+        // not a binary string at all
+        assert!(binary_string_to_utf8("☀").is_err());
+    }
 }
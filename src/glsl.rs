@@ -2,10 +2,18 @@
 //! and is intended to interop well with the GPU side of things.
 
 use super::math::{Point, Vec3};
-use crate::{ray::Ray, state::State};
-use js_sys::Math::sqrt;
+use crate::{aabb::Aabb, ray::Ray, state::State};
 use std::sync::MutexGuard;
 
+/// Length of the shader's `u_sphere_list` array (injected into the shader as `MAX_SPHERES`)
+pub const MAX_SPHERES: usize = 15;
+
+/// Length of the shader's `u_rect_light_list` array (injected into the shader as `MAX_RECT_LIGHTS`)
+pub const MAX_RECT_LIGHTS: usize = 8;
+
+/// Rects are numbered after every possible sphere, so the two never share a uuid
+pub const RECT_UUID_OFFSET: i32 = MAX_SPHERES as i32;
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum MaterialType {
     Diffuse,
@@ -23,20 +31,227 @@ impl MaterialType {
     }
 }
 
+impl TryFrom<i32> for MaterialType {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MaterialType::Diffuse),
+            1 => Ok(MaterialType::Metal),
+            2 => Ok(MaterialType::Glass),
+            _ => Err(format!(
+                "Unknown material type {value} (expected 0 = diffuse, 1 = metal, or 2 = glass)"
+            )),
+        }
+    }
+}
+
+/// The different ways of picking the direction diffuse surfaces scatter light in,
+/// from "Ray Tracing in One Weekend" (each gives a subtly different look)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DiffuseMode {
+    /// `normal + random_unit_vector` (true Lambertian reflection)
+    Lambertian,
+    /// uniformly random directions away from the surface
+    Hemisphere,
+    /// `normal + random_in_unit_sphere` (the book's first approximation)
+    UnitSphere,
+}
+
+impl DiffuseMode {
+    pub fn value(&self) -> i32 {
+        match self {
+            DiffuseMode::Lambertian => 0,
+            DiffuseMode::Hemisphere => 1,
+            DiffuseMode::UnitSphere => 2,
+        }
+    }
+}
+
+impl TryFrom<i32> for DiffuseMode {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DiffuseMode::Lambertian),
+            1 => Ok(DiffuseMode::Hemisphere),
+            2 => Ok(DiffuseMode::UnitSphere),
+            _ => Err(format!(
+                "Unknown diffuse mode {value} \
+                (expected 0 = lambertian, 1 = hemisphere, or 2 = unit sphere)"
+            )),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Material {
     pub material_type: MaterialType,
     pub albedo: Vec3,          // or "reflectance"
-    pub fuzz: f32,             // used for duller metals
+    pub fuzz: f32,             // used for duller metals (0->1, see `set_fuzz`)
     pub refraction_index: f32, // used for glass
+    pub absorption: Vec3,      // used for colored glass (zero = clear)
+    /// Marker spheres are drawn at full brightness, but are invisible to
+    /// bounced and shadow rays, so they don't affect the scene's lighting
+    pub display_only: bool,
+    /// Light given off by the surface (black = none), on top of whatever it scatters
+    pub emission: Vec3,
+}
+
+/// Common indices of refraction, so believable glass doesn't require looking them up
+pub const REFRACTION_INDEX_PRESETS: [(&str, f32); 6] = [
+    ("air", 1.0003),
+    ("ice", 1.31),
+    ("water", 1.33),
+    ("glass", 1.5),
+    ("sapphire", 1.77),
+    ("diamond", 2.42),
+];
+
+pub fn refraction_index_preset(name: &str) -> Result<f32, String> {
+    let name = name.to_lowercase();
+    REFRACTION_INDEX_PRESETS
+        .iter()
+        .find(|(preset_name, _)| *preset_name == name)
+        .map(|(_, refraction_index)| *refraction_index)
+        .ok_or_else(|| format!("Unknown refraction index preset \"{name}\""))
+}
+
+/// Plain grey diffuse
+impl Default for Material {
+    fn default() -> Self {
+        Material {
+            material_type: MaterialType::Diffuse,
+            albedo: Vec3(0.5, 0.5, 0.5),
+            fuzz: 0.,
+            refraction_index: 0.,
+            absorption: Vec3::new(),
+            display_only: false,
+            emission: Vec3::new(),
+        }
+    }
+}
+
+impl Material {
+    /// Fuzz is clamped to 0->1: larger values scatter most reflected rays
+    /// into the surface, which absorbs them and makes the metal look dark
+    pub fn set_fuzz(&mut self, fuzz: f32) {
+        self.fuzz = fuzz.clamp(0., 1.);
+    }
+
+    /// Clear glass using one of the `REFRACTION_INDEX_PRESETS`
+    pub fn glass_preset(name: &str) -> Result<Material, String> {
+        Ok(Material {
+            material_type: MaterialType::Glass,
+            albedo: Vec3(1.0, 1.0, 1.0),
+            refraction_index: refraction_index_preset(name)?,
+            ..Material::default()
+        })
+    }
 }
 
 #[derive(Clone, PartialEq, Debug)]
 pub struct Sphere {
+    /// Relative to the parent's center, when the sphere has a parent
     pub center: Vec3,
+    /// A negative radius flips the sphere's normals inward,
+    /// e.g. for the inner wall of a hollow glass bubble
     pub radius: f64,
     pub material: Material,
     pub uuid: i32,
+    /// Moves along with this sphere (translation only)
+    pub parent_uuid: Option<i32>,
+    /// Hidden spheres are skipped when rendering and picking, but stay in the scene
+    pub visible: bool,
+    /// Blended with `material` in patches of 3D noise (for weathered surfaces).
+    /// `None` uses `material` everywhere
+    pub material2: Option<Material>,
+    /// Size of the noise's features (in scene units): larger values give bigger patches
+    pub blend_scale: f64,
+}
+
+impl Default for Sphere {
+    fn default() -> Self {
+        Sphere {
+            center: Vec3(0., 0., 0.),
+            radius: 1.,
+            material: Material::default(),
+            uuid: 0,
+            parent_uuid: None,
+            visible: true,
+            material2: None,
+            blend_scale: 1.,
+        }
+    }
+}
+
+impl Sphere {
+    pub fn bounding_box(&self) -> Aabb {
+        let extent = Vec3::splat(self.radius.abs());
+        Aabb::new(&self.center - &extent, &self.center + &extent)
+    }
+
+    /// How much of `material2` shows at a (world space) point on the surface, from 0->1.
+    /// Mirrors the shader's `sphere_material_at`, which picks `material2` with this probability,
+    /// so that accumulated samples mix the two materials in this proportion.
+    /// The noise is anchored to the sphere's center, so the pattern moves along with it
+    pub fn blend_weight(&self, point: &Point) -> f64 {
+        if self.material2.is_none() {
+            return 0.;
+        }
+        let noise = value_noise(&((point - &self.center) / self.blend_scale));
+        // widens the noise's range, so that patches of each material show through on their own
+        smoothstep(0.3, 0.7, noise)
+    }
+}
+
+/// Mirrors the shader's `base_hash` (Nimitz's integer hash) bit for bit
+fn base_hash(x: u32, y: u32) -> u32 {
+    const MULTIPLIER: u32 = 1103515245;
+    let (x, y) = (
+        MULTIPLIER.wrapping_mul((x >> 1) ^ y),
+        MULTIPLIER.wrapping_mul((y >> 1) ^ x),
+    );
+    let h32 = MULTIPLIER.wrapping_mul(x ^ (y >> 3));
+    h32 ^ (h32 >> 16)
+}
+
+/// A random value (0->1) for each integer lattice point, matching the shader's `lattice_hash`
+fn lattice_hash(x: i32, y: i32, z: i32) -> f64 {
+    let h = base_hash(base_hash(x as u32, y as u32), z as u32);
+    h as f64 / u32::MAX as f64
+}
+
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+/// Smoothly interpolated 3D value noise (0->1), mirroring the shader's `value_noise`
+pub fn value_noise(point: &Point) -> f64 {
+    let cell = [point.x().floor(), point.y().floor(), point.z().floor()];
+    let [fx, fy, fz] = [
+        point.x() - cell[0],
+        point.y() - cell[1],
+        point.z() - cell[2],
+    ]
+    .map(|fraction| fraction * fraction * (3. - 2. * fraction));
+    let [x, y, z] = cell.map(|coordinate| coordinate as i32);
+    let corner = |dx, dy, dz| lattice_hash(x + dx, y + dy, z + dz);
+    let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+    lerp(
+        lerp(
+            lerp(corner(0, 0, 0), corner(1, 0, 0), fx),
+            lerp(corner(0, 1, 0), corner(1, 1, 0), fx),
+            fy,
+        ),
+        lerp(
+            lerp(corner(0, 0, 1), corner(1, 0, 1), fx),
+            lerp(corner(0, 1, 1), corner(1, 1, 1), fx),
+            fy,
+        ),
+        fz,
+    )
 }
 
 impl Hit for Sphere {
@@ -45,7 +260,11 @@ impl Hit for Sphere {
         let a = ray.direction.length_squared();
         let half_b = Vec3::dot(&oc, &ray.direction);
         let c = oc.length_squared() - self.radius.powi(2);
-        let discriminant = half_b.powi(2) - a * c;
+
+        // `half_b² - a*c` cancels catastrophically for grazing rays and huge spheres,
+        // so measure the discriminant from the ray's closest approach to the center instead
+        let closest_approach = &oc - &(&ray.direction * (half_b / a));
+        let discriminant = a * (self.radius.powi(2) - closest_approach.length_squared());
 
         // no hit
         if discriminant < 0. {
@@ -54,22 +273,30 @@ impl Hit for Sphere {
 
         // there is a hit, but it may not be within the acceptable range:
         // find the nearest root that lies in the acceptable range.
-        let sqrt_discriminant = sqrt(discriminant);
-        let mut root = (-half_b - sqrt_discriminant) / a;
-
-        // t is out of range, so count it as a no hit
-        if root < t_min || t_max < root {
-            root = (-half_b + sqrt_discriminant) / a;
-            if root < t_min || t_max < root {
-                return HitResult::NoHit;
-            }
+        // `q` never subtracts nearly-equal values, and the other root follows from `t0 * t1 = c / a`
+        let q = -(half_b + half_b.signum() * discriminant.sqrt());
+        if q == 0. {
+            return HitResult::NoHit;
         }
+        let (near, far) = {
+            let (t0, t1) = (q / a, c / q);
+            (t0.min(t1), t0.max(t1))
+        };
+        let root = if (t_min..=t_max).contains(&near) {
+            near
+        } else if (t_min..=t_max).contains(&far) {
+            far
+        } else {
+            // t is out of range, so count it as a no hit
+            return HitResult::NoHit;
+        };
 
         let hit_point = ray.at(root);
         let outward_normal = (&hit_point - &self.center) / self.radius;
 
         let hit_result_data = HitResultData::builder()
             .t(root)
+            .blend_weight(self.blend_weight(&hit_point))
             .hit_point(hit_point)
             .front_face_and_normal(ray, &outward_normal)
             .uuid(self.uuid)
@@ -81,12 +308,200 @@ impl Hit for Sphere {
     }
 }
 
+/// Which axis-aligned plane a `Rect` lies in
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RectPlane {
+    XY,
+    XZ,
+    YZ,
+}
+
+impl RectPlane {
+    pub fn value(&self) -> i32 {
+        match self {
+            RectPlane::XY => 0,
+            RectPlane::XZ => 1,
+            RectPlane::YZ => 2,
+        }
+    }
+
+    /// Indices of the (a, b) axes spanning the plane, followed by the axis `k` is measured along
+    pub fn axes(&self) -> (usize, usize, usize) {
+        match self {
+            RectPlane::XY => (0, 1, 2),
+            RectPlane::XZ => (0, 2, 1),
+            RectPlane::YZ => (1, 2, 0),
+        }
+    }
+}
+
+impl TryFrom<i32> for RectPlane {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(RectPlane::XY),
+            1 => Ok(RectPlane::XZ),
+            2 => Ok(RectPlane::YZ),
+            _ => Err(format!(
+                "Unknown rect plane {value} (expected 0 = xy, 1 = xz, or 2 = yz)"
+            )),
+        }
+    }
+}
+
+/// An axis-aligned rectangle (usually an area light), spanning `a0..a1` and `b0..b1`
+/// along the plane's first and second axes, at `k` along the third.
+/// e.g. an XZ rect covers x = a0..a1 and z = b0..b1 at y = k
+#[derive(Clone, PartialEq, Debug)]
+pub struct Rect {
+    pub plane: RectPlane,
+    pub a0: f64,
+    pub a1: f64,
+    pub b0: f64,
+    pub b1: f64,
+    pub k: f64,
+    pub material: Material,
+    pub uuid: i32,
+    /// The front face looks down the plane's third axis (toward -k) instead of up it
+    pub flip_normal: bool,
+    /// Emits light from both faces, rather than only the front one
+    pub double_sided: bool,
+}
+
+impl Rect {
+    pub fn outward_normal(&self) -> Vec3 {
+        let (_, _, k_axis) = self.plane.axes();
+        let mut normal = [0.; 3];
+        normal[k_axis] = if self.flip_normal { -1. } else { 1. };
+        Vec3(normal[0], normal[1], normal[2])
+    }
+
+    pub fn area(&self) -> f64 {
+        (self.a1 - self.a0).abs() * (self.b1 - self.b0).abs()
+    }
+
+    /// Whether light is given off toward a ray that hit the given face
+    pub fn emits_toward(&self, front_face: bool) -> bool {
+        front_face || self.double_sided
+    }
+
+    /// Padded slightly along the plane's normal, so the box isn't flat
+    pub fn bounding_box(&self) -> Aabb {
+        let (a_axis, b_axis, k_axis) = self.plane.axes();
+        let mut min = [0.; 3];
+        let mut max = [0.; 3];
+        min[a_axis] = self.a0.min(self.a1);
+        max[a_axis] = self.a0.max(self.a1);
+        min[b_axis] = self.b0.min(self.b1);
+        max[b_axis] = self.b0.max(self.b1);
+        min[k_axis] = self.k - 1e-4;
+        max[k_axis] = self.k + 1e-4;
+        Aabb::new(Vec3(min[0], min[1], min[2]), Vec3(max[0], max[1], max[2]))
+    }
+}
+
+impl Hit for Rect {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> HitResult {
+        let (a_axis, b_axis, k_axis) = self.plane.axes();
+        let origin = components(&ray.origin);
+        let direction = components(&ray.direction);
+        let t = (self.k - origin[k_axis]) / direction[k_axis];
+        // parallel rays never hit (t is infinite or NaN), so they fail this check too
+        if !(t_min..=t_max).contains(&t) {
+            return HitResult::NoHit;
+        }
+        let hit_point = ray.at(t);
+        let point = components(&hit_point);
+        let (a, b) = (point[a_axis], point[b_axis]);
+        let is_within_a = a >= self.a0.min(self.a1) && a <= self.a0.max(self.a1);
+        let is_within_b = b >= self.b0.min(self.b1) && b <= self.b0.max(self.b1);
+        if !is_within_a || !is_within_b {
+            return HitResult::NoHit;
+        }
+
+        let hit_result_data = HitResultData::builder()
+            .t(t)
+            .hit_point(hit_point)
+            .front_face_and_normal(ray, &self.outward_normal())
+            .uuid(self.uuid)
+            .build();
+
+        HitResult::Hit {
+            data: hit_result_data,
+        }
+    }
+}
+
+fn components(v: &Vec3) -> [f64; 3] {
+    [v.x(), v.y(), v.z()]
+}
+
+pub fn set_rect_uuids(rects: &mut [Rect]) {
+    for (i, rect) in rects.iter_mut().enumerate() {
+        rect.uuid = RECT_UUID_OFFSET + i as i32;
+    }
+}
+
+/// Everything in the scene that rays can hit
+pub struct World<'a> {
+    pub spheres: &'a [Sphere],
+    pub rects: &'a [Rect],
+}
+
+impl Hit for World<'_> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> HitResult {
+        closest_hit(
+            self.spheres
+                .iter()
+                .filter(|sphere| sphere.visible)
+                .map(|sphere| sphere as &dyn Hit)
+                .chain(self.rects.iter().map(|rect| rect as &dyn Hit)),
+            ray,
+            t_min,
+            t_max,
+        )
+    }
+}
+
 pub fn set_sphere_uuids(spheres: &mut Vec<Sphere>) {
     for (i, sphere) in spheres.iter_mut().enumerate() {
         sphere.uuid = i as i32;
     }
 }
 
+/// Resolves each sphere's center into world space by walking up its chain of parents
+pub fn resolve_world_spheres(spheres: &[Sphere]) -> Result<Vec<Sphere>, String> {
+    spheres
+        .iter()
+        .map(|sphere| {
+            let mut center = sphere.center.clone();
+            let mut parent_uuid = sphere.parent_uuid;
+            // a chain longer than the number of spheres must revisit one of them
+            let mut depth = 0;
+            while let Some(uuid) = parent_uuid {
+                depth += 1;
+                if depth > spheres.len() {
+                    return Err(format!(
+                        "Sphere {} is its own ancestor (parents form a cycle)",
+                        sphere.uuid
+                    ));
+                }
+                let parent = spheres
+                    .iter()
+                    .find(|parent| parent.uuid == uuid)
+                    .ok_or_else(|| format!("No parent sphere found with uuid {uuid}"))?;
+                center += parent.center.clone();
+                parent_uuid = parent.parent_uuid;
+            }
+            Ok(Sphere {
+                center,
+                ..sphere.clone()
+            })
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub enum HitResult {
     Hit { data: HitResultData },
@@ -100,6 +515,8 @@ pub struct HitResultData {
     pub t: f64,
     pub front_face: bool,
     pub uuid: i32,
+    /// How much of a sphere's `material2` shows at the hit point (see `Sphere::blend_weight`)
+    pub blend_weight: f64,
 }
 
 impl HitResultData {
@@ -115,6 +532,7 @@ pub struct HitResultDataBuilder {
     normal: Vec3,
     front_face: bool,
     uuid: i32,
+    blend_weight: f64,
 }
 
 impl HitResultDataBuilder {
@@ -137,6 +555,11 @@ impl HitResultDataBuilder {
         self
     }
 
+    pub fn blend_weight(mut self, blend_weight: f64) -> Self {
+        self.blend_weight = blend_weight;
+        self
+    }
+
     pub fn front_face_and_normal(mut self, r: &Ray, outward_normal: &Vec3) -> Self {
         self.front_face = Vec3::dot(&r.direction, outward_normal) < 0.;
         self.normal = if self.front_face {
@@ -154,6 +577,7 @@ impl HitResultDataBuilder {
             t: self.t,
             front_face: self.front_face,
             uuid: self.uuid,
+            blend_weight: self.blend_weight,
         }
     }
 }
@@ -185,45 +609,38 @@ macro_rules! hittable_list {
 
 impl Hit for HittableList {
     fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> HitResult {
-        let mut prev_hit_result = HitResult::NoHit;
-
-        for hittable in &self.list {
-            let new_hit_result = hittable.hit(ray, t_min, t_max);
-
-            // this object was a hit
-            if let HitResult::Hit { data: new_hit_data } = &new_hit_result {
-                // replace saved hit result if previous was no-hit or was behind this new one
-                match &prev_hit_result {
-                    HitResult::NoHit => prev_hit_result = new_hit_result,
-                    HitResult::Hit {
-                        data: prev_hit_data,
-                    } => {
-                        if new_hit_data.hit_point.z() > prev_hit_data.hit_point.z() {
-                            prev_hit_result = new_hit_result
-                        }
-                    }
-                }
-            }
-        }
-
-        prev_hit_result
+        closest_hit(
+            self.list.iter().map(|hittable| hittable.as_ref()),
+            ray,
+            t_min,
+            t_max,
+        )
     }
 }
 
-pub fn get_center_hit(state: &MutexGuard<State>) -> HitResult {
-    let spheres = &state.sphere_list;
-
-    let ray = Ray {
-        origin: state.camera_origin.clone(),
-        direction: &state.lower_left_corner + &state.horizontal / 2. + &state.vertical / 2.
-            - &state.camera_origin,
-    };
+impl<T: Hit> Hit for Vec<T> {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> HitResult {
+        closest_hit(
+            self.iter().map(|hittable| hittable as &dyn Hit),
+            ray,
+            t_min,
+            t_max,
+        )
+    }
+}
 
+/// Finds the nearest hit along the ray among all of the given objects
+fn closest_hit<'a>(
+    hittables: impl Iterator<Item = &'a dyn Hit>,
+    ray: &Ray,
+    t_min: f64,
+    t_max: f64,
+) -> HitResult {
     let mut prev_hit_result = HitResult::NoHit;
-    let mut closest_so_far = f64::INFINITY;
+    let mut closest_so_far = t_max;
 
-    for sphere in spheres {
-        let new_hit_result = sphere.hit(&ray, 0., closest_so_far);
+    for hittable in hittables {
+        let new_hit_result = hittable.hit(ray, t_min, closest_so_far);
 
         // this object was a hit (and implicitly was in front of the last)
         if let HitResult::Hit {
@@ -237,3 +654,341 @@ pub fn get_center_hit(state: &MutexGuard<State>) -> HitResult {
 
     prev_hit_result
 }
+
+pub fn get_center_hit(state: &MutexGuard<State>) -> HitResult {
+    get_hit_at(state, 0.5, 0.5)
+}
+
+/// Casts a ray through the viewport at (s, t), where both range from 0->1
+/// starting in the bottom-left corner (the same coordinates the shader uses)
+pub fn get_hit_at(state: &MutexGuard<State>, s: f64, t: f64) -> HitResult {
+    let ray = viewport_ray(state, s, t);
+    state
+        .hittables()
+        .hit(&ray, state.ray_epsilon, f64::INFINITY)
+}
+
+/// The ray from the camera through the point (`s`, `t`) on the viewport (0->1, from the bottom-left)
+pub fn viewport_ray(state: &State, s: f64, t: f64) -> Ray {
+    Ray {
+        origin: state.camera_origin.clone(),
+        direction: &state.lower_left_corner + &state.horizontal * s + &state.vertical * t
+            - &state.camera_origin,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::DEFAULT_RAY_EPSILON;
+
+    fn unit_sphere() -> Sphere {
+        Sphere {
+            material: Material::glass_preset("glass").unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn negative_radius_faces_inward() {
+        let ray = Ray {
+            origin: Vec3(0., 0., 5.),
+            direction: Vec3(0., 0., -1.),
+        };
+        let hit = |radius| {
+            let sphere = Sphere {
+                radius,
+                ..unit_sphere()
+            };
+            match sphere.hit(&ray, DEFAULT_RAY_EPSILON, f64::INFINITY) {
+                HitResult::Hit { data } => data,
+                HitResult::NoHit => panic!("expected the ray to hit"),
+            }
+        };
+
+        let solid = hit(1.);
+        assert!(solid.front_face);
+        assert_eq!(solid.hit_point, Vec3(0., 0., 1.));
+
+        // same surface, but entering it counts as leaving the (hollow) inside
+        let hollow = hit(-1.);
+        assert!(!hollow.front_face);
+        assert_eq!(hollow.hit_point, solid.hit_point);
+        // the normal still opposes the ray, as it would for any back face
+        assert_eq!(hollow.normal, solid.normal);
+    }
+
+    #[test]
+    fn grazing_ray_misses_huge_sphere() {
+        let ground = Sphere {
+            center: Vec3(0., -1e4, 0.),
+            radius: 1e4,
+            ..unit_sphere()
+        };
+        // skims just above the top of the sphere: `half_b² - a*c` reports a (bogus) hit here
+        let grazing_ray = Ray {
+            origin: Vec3(-1e7, 1e-7, 0.),
+            direction: Vec3(1., 0., 0.),
+        };
+        assert!(matches!(
+            ground.hit(&grazing_ray, DEFAULT_RAY_EPSILON, f64::INFINITY),
+            HitResult::NoHit
+        ));
+
+        // dipping just below the top should still hit, and land on the surface
+        let dipping_ray = Ray {
+            origin: Vec3(-1e7, -1e-3, 0.),
+            direction: Vec3(1., 0., 0.),
+        };
+        match ground.hit(&dipping_ray, DEFAULT_RAY_EPSILON, f64::INFINITY) {
+            HitResult::Hit { data } => {
+                let distance = (&data.hit_point - &ground.center).length();
+                assert!((distance - ground.radius).abs() < 1e-6);
+            }
+            HitResult::NoHit => panic!("expected the dipping ray to hit"),
+        }
+    }
+
+    #[test]
+    fn bounced_ray_does_not_rehit_its_own_surface() {
+        // floating point error leaves the bounce origin just inside the surface
+        let bounced_ray = Ray {
+            origin: Vec3(0., 0., 1. - 1e-9),
+            direction: Vec3(0., 0., 1.),
+        };
+
+        assert!(matches!(
+            unit_sphere().hit(&bounced_ray, 0., f64::INFINITY),
+            HitResult::Hit { .. }
+        ));
+        assert!(matches!(
+            unit_sphere().hit(&bounced_ray, DEFAULT_RAY_EPSILON, f64::INFINITY),
+            HitResult::NoHit
+        ));
+    }
+
+    #[test]
+    fn nearest_hittable_is_picked_regardless_of_order() {
+        let far_sphere = Sphere {
+            center: Vec3(0., 0., -10.),
+            uuid: 1,
+            ..unit_sphere()
+        };
+        let ray = Ray {
+            origin: Vec3(0., 0., 5.),
+            direction: Vec3(0., 0., -1.),
+        };
+        for spheres in [
+            vec![unit_sphere(), far_sphere.clone()],
+            vec![far_sphere, unit_sphere()],
+        ] {
+            let hittables: &dyn Hit = &spheres;
+            match hittables.hit(&ray, DEFAULT_RAY_EPSILON, f64::INFINITY) {
+                HitResult::Hit { data } => assert_eq!(data.uuid, 0),
+                HitResult::NoHit => panic!("expected a hit"),
+            }
+        }
+    }
+
+    #[test]
+    fn value_noise_is_smooth_and_bounded() {
+        for i in 0..200 {
+            let point = Vec3(i as f64 * 0.37 - 30., i as f64 * 0.11, -(i as f64) * 0.53);
+            let noise = value_noise(&point);
+            assert!((0. ..=1.).contains(&noise));
+            let nearby = value_noise(&(&point + &Vec3::splat(1e-4)));
+            assert!((noise - nearby).abs() < 1e-2);
+        }
+        // lattice points take their hash value exactly
+        assert_eq!(value_noise(&Vec3(3., -2., 7.)), lattice_hash(3, -2, 7));
+    }
+
+    #[test]
+    fn second_material_is_a_no_op_until_set() {
+        // a lattice point of the noise, where `material2` shows through completely
+        // (`lattice_hash(0, 0, 1)` is above the blend's upper edge)
+        let point = Vec3(0., 0., 1.);
+        let mut sphere = unit_sphere();
+        assert_eq!(sphere.blend_weight(&point), 0.);
+
+        sphere.material2 = Some(Material {
+            albedo: Vec3(0.6, 0.3, 0.1),
+            ..sphere.material.clone()
+        });
+        assert!(lattice_hash(0, 0, 1) > 0.7);
+        assert_eq!(sphere.blend_weight(&point), 1.);
+
+        // hits report how much of the second material shows where they land
+        let ray = Ray {
+            origin: Vec3(0., 0., 5.),
+            direction: Vec3(0., 0., -1.),
+        };
+        match sphere.hit(&ray, 0.001, f64::INFINITY) {
+            HitResult::Hit { data } => {
+                assert_eq!(data.hit_point, point);
+                assert_eq!(data.blend_weight, 1.);
+            }
+            HitResult::NoHit => panic!("expected a hit"),
+        }
+    }
+
+    #[test]
+    fn hidden_spheres_are_not_hit() {
+        let far_sphere = Sphere {
+            center: Vec3(0., 0., -10.),
+            uuid: 1,
+            ..unit_sphere()
+        };
+        let hidden_sphere = Sphere {
+            visible: false,
+            ..unit_sphere()
+        };
+        let ray = Ray {
+            origin: Vec3(0., 0., 5.),
+            direction: Vec3(0., 0., -1.),
+        };
+        let world = World {
+            spheres: &[hidden_sphere, far_sphere],
+            rects: &[],
+        };
+        match world.hit(&ray, DEFAULT_RAY_EPSILON, f64::INFINITY) {
+            HitResult::Hit { data } => assert_eq!(data.uuid, 1),
+            HitResult::NoHit => panic!("expected the sphere behind the hidden one to be hit"),
+        }
+    }
+
+    #[test]
+    fn children_move_with_their_parents() {
+        let sun = Sphere {
+            center: Vec3(1., 0., 0.),
+            ..unit_sphere()
+        };
+        let planet = Sphere {
+            center: Vec3(0., 2., 0.),
+            uuid: 1,
+            parent_uuid: Some(0),
+            ..unit_sphere()
+        };
+        let moon = Sphere {
+            center: Vec3(0., 0., 3.),
+            uuid: 2,
+            parent_uuid: Some(1),
+            ..unit_sphere()
+        };
+        let world_spheres = resolve_world_spheres(&[sun, planet, moon]).unwrap();
+        assert_eq!(world_spheres[0].center, Vec3(1., 0., 0.));
+        assert_eq!(world_spheres[1].center, Vec3(1., 2., 0.));
+        assert_eq!(world_spheres[2].center, Vec3(1., 2., 3.));
+    }
+
+    #[test]
+    fn parent_cycles_are_rejected() {
+        let a = Sphere {
+            parent_uuid: Some(1),
+            ..unit_sphere()
+        };
+        let b = Sphere {
+            uuid: 1,
+            parent_uuid: Some(0),
+            ..unit_sphere()
+        };
+        assert!(resolve_world_spheres(&[a.clone(), b]).is_err());
+        let own_parent = Sphere {
+            parent_uuid: Some(0),
+            ..unit_sphere()
+        };
+        assert!(resolve_world_spheres(&[own_parent]).is_err());
+        assert!(resolve_world_spheres(&[a]).is_err());
+    }
+
+    #[test]
+    fn rects_are_hit_within_their_bounds() {
+        // a ceiling light, facing down into the room
+        let light = Rect {
+            plane: RectPlane::XZ,
+            a0: -1.,
+            a1: 1.,
+            b0: -1.,
+            b1: 1.,
+            k: 2.,
+            material: unit_sphere().material,
+            uuid: RECT_UUID_OFFSET,
+            flip_normal: true,
+            double_sided: false,
+        };
+        let upward_ray = |x: f64| Ray {
+            origin: Vec3(x, 0., 0.5),
+            direction: Vec3(0., 1., 0.),
+        };
+        match light.hit(&upward_ray(0.5), DEFAULT_RAY_EPSILON, f64::INFINITY) {
+            HitResult::Hit { data } => {
+                assert_eq!(data.t, 2.);
+                assert!(data.front_face);
+                assert!(light.emits_toward(data.front_face));
+            }
+            HitResult::NoHit => panic!("expected a hit"),
+        }
+        assert!(matches!(
+            light.hit(&upward_ray(1.5), DEFAULT_RAY_EPSILON, f64::INFINITY),
+            HitResult::NoHit
+        ));
+
+        // from above, the ray sees the light's unlit back
+        let downward_ray = Ray {
+            origin: Vec3(0., 3., 0.),
+            direction: Vec3(0., -1., 0.),
+        };
+        match light.hit(&downward_ray, DEFAULT_RAY_EPSILON, f64::INFINITY) {
+            HitResult::Hit { data } => assert!(!light.emits_toward(data.front_face)),
+            HitResult::NoHit => panic!("expected a hit"),
+        }
+
+        let parallel_ray = Ray {
+            origin: Vec3(0., 0., 0.),
+            direction: Vec3(1., 0., 0.),
+        };
+        assert!(matches!(
+            light.hit(&parallel_ray, DEFAULT_RAY_EPSILON, f64::INFINITY),
+            HitResult::NoHit
+        ));
+    }
+
+    #[test]
+    fn fuzz_is_clamped() {
+        let mut material = unit_sphere().material;
+        material.set_fuzz(2.0);
+        assert_eq!(material.fuzz, 1.0);
+        material.set_fuzz(-0.5);
+        assert_eq!(material.fuzz, 0.);
+        material.set_fuzz(0.3);
+        assert_eq!(material.fuzz, 0.3);
+    }
+
+    #[test]
+    fn diffuse_modes_round_trip_through_their_values() {
+        for mode in [
+            DiffuseMode::Lambertian,
+            DiffuseMode::Hemisphere,
+            DiffuseMode::UnitSphere,
+        ] {
+            assert_eq!(DiffuseMode::try_from(mode.value()), Ok(mode));
+        }
+        assert!(DiffuseMode::try_from(3).is_err());
+    }
+
+    #[test]
+    fn material_type_round_trips_through_its_value() {
+        for material_type in [
+            MaterialType::Diffuse,
+            MaterialType::Metal,
+            MaterialType::Glass,
+        ] {
+            assert_eq!(
+                MaterialType::try_from(material_type.value()),
+                Ok(material_type)
+            );
+        }
+        assert!(MaterialType::try_from(3).is_err());
+        assert!(MaterialType::try_from(-1).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+/// Largest image (in pixels) that will be decoded: 8192 x 8192, well past the
+/// largest texture most GPUs can hold
+pub const MAX_PIXELS: usize = 8192 * 8192;
+
+/// A decoded Radiance `.hdr` image
+/// `data` holds linear RGB floats, row by row from the top of the image
+#[derive(Clone, Debug, PartialEq)]
+pub struct HdrImage {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<f32>,
+}
+
+/// Converts a shared-exponent RGBE pixel into linear RGB floats
+pub fn rgbe_to_rgb(rgbe: [u8; 4]) -> [f32; 3] {
+    let [r, g, b, e] = rgbe;
+    if e == 0 {
+        return [0., 0., 0.];
+    }
+    // mantissas are stored as 0->255, so divide by 256 along with applying the exponent
+    let scale = 2f32.powi(e as i32 - (128 + 8));
+    [r as f32 * scale, g as f32 * scale, b as f32 * scale]
+}
+
+/// Decodes Radiance `.hdr` (RGBE) bytes, supporting both flat and run-length encoded scanlines
+pub fn decode(bytes: &[u8]) -> Result<HdrImage, String> {
+    let mut position = 0;
+
+    // HEADER
+    let magic = read_line(bytes, &mut position)?;
+    if !magic.starts_with("#?") {
+        return Err("Not a Radiance HDR file (missing #? header)".into());
+    }
+    loop {
+        let line = read_line(bytes, &mut position)?;
+        // an empty line ends the header
+        if line.is_empty() {
+            break;
+        }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" {
+                return Err(format!("Unsupported HDR pixel format: {format}"));
+            }
+        }
+    }
+
+    // RESOLUTION (only the standard top-to-bottom, left-to-right orientation is supported)
+    let resolution = read_line(bytes, &mut position)?;
+    let (height, width) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", height, "+X", width] => (
+            height.parse::<u32>().map_err(|e| e.to_string())?,
+            width.parse::<u32>().map_err(|e| e.to_string())?,
+        ),
+        _ => return Err(format!("Unsupported HDR resolution line: {resolution}")),
+    };
+
+    // PIXELS
+    // header dimensions come straight from the file, so bound them before allocating
+    if width == 0 || height == 0 {
+        return Err(format!("HDR image is empty ({width}x{height})"));
+    }
+    let pixel_count = (width as usize)
+        .checked_mul(height as usize)
+        .filter(|&pixel_count| pixel_count <= MAX_PIXELS)
+        .ok_or_else(|| format!("HDR image is too large ({width}x{height})"))?;
+    let mut data = Vec::with_capacity(pixel_count * 3);
+    let mut scanline = vec![[0u8; 4]; width as usize];
+    for _ in 0..height {
+        read_scanline(bytes, &mut position, &mut scanline)?;
+        for &rgbe in &scanline {
+            data.extend_from_slice(&rgbe_to_rgb(rgbe));
+        }
+    }
+
+    Ok(HdrImage {
+        width,
+        height,
+        data,
+    })
+}
+
+fn read_line(bytes: &[u8], position: &mut usize) -> Result<String, String> {
+    let remaining = &bytes[*position..];
+    let length = remaining
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .ok_or("Unexpected end of HDR header")?;
+    *position += length + 1;
+    Ok(String::from_utf8_lossy(&remaining[..length])
+        .trim_end()
+        .to_string())
+}
+
+fn read_byte(bytes: &[u8], position: &mut usize) -> Result<u8, String> {
+    let byte = *bytes
+        .get(*position)
+        .ok_or("Unexpected end of HDR pixel data")?;
+    *position += 1;
+    Ok(byte)
+}
+
+fn read_pixel(bytes: &[u8], position: &mut usize) -> Result<[u8; 4], String> {
+    let pixel = bytes
+        .get(*position..*position + 4)
+        .ok_or("Unexpected end of HDR pixel data")?;
+    *position += 4;
+    Ok([pixel[0], pixel[1], pixel[2], pixel[3]])
+}
+
+fn read_scanline(
+    bytes: &[u8],
+    position: &mut usize,
+    scanline: &mut [[u8; 4]],
+) -> Result<(), String> {
+    let width = scanline.len();
+    let first_pixel = read_pixel(bytes, position)?;
+
+    // new-style run-length encoding is only used for scanlines between 8 and 32767 pixels wide,
+    // and always begins with 2, 2 followed by the (big-endian) scanline width
+    let is_run_length_encoded = (8..0x8000).contains(&width)
+        && first_pixel[0] == 2
+        && first_pixel[1] == 2
+        && first_pixel[2] & 0x80 == 0;
+    if !is_run_length_encoded {
+        scanline[0] = first_pixel;
+        for pixel in scanline.iter_mut().skip(1) {
+            *pixel = read_pixel(bytes, position)?;
+        }
+        return Ok(());
+    }
+
+    let encoded_width = ((first_pixel[2] as usize) << 8) | first_pixel[3] as usize;
+    if encoded_width != width {
+        return Err(format!(
+            "HDR scanline width mismatch (expected {width}, found {encoded_width})"
+        ));
+    }
+
+    // each channel is stored separately, as a series of runs and literal spans
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = read_byte(bytes, position)? as usize;
+            if count > 128 {
+                let run_length = count - 128;
+                if x + run_length > width {
+                    return Err("HDR run overflows scanline".into());
+                }
+                let value = read_byte(bytes, position)?;
+                for pixel in &mut scanline[x..x + run_length] {
+                    pixel[channel] = value;
+                }
+                x += run_length;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err("Invalid HDR literal span".into());
+                }
+                for pixel in &mut scanline[x..x + count] {
+                    pixel[channel] = read_byte(bytes, position)?;
+                }
+                x += count;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: u32, height: u32) -> Vec<u8> {
+        format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {height} +X {width}\n").into_bytes()
+    }
+
+    #[test]
+    fn rgbe_exponent_is_applied() {
+        assert_eq!(rgbe_to_rgb([128, 64, 0, 129]), [1., 0.5, 0.]);
+        assert_eq!(rgbe_to_rgb([128, 128, 128, 131]), [4., 4., 4.]);
+        assert_eq!(rgbe_to_rgb([255, 255, 255, 0]), [0., 0., 0.]);
+    }
+
+    #[test]
+    fn decodes_flat_scanlines() {
+        let mut bytes = header(2, 1);
+        bytes.extend_from_slice(&[128, 0, 0, 129, 0, 128, 0, 130]);
+        let image = decode(&bytes).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.data, vec![1., 0., 0., 0., 2., 0.]);
+    }
+
+    #[test]
+    fn decodes_run_length_encoded_scanlines() {
+        let mut bytes = header(8, 1);
+        bytes.extend_from_slice(&[2, 2, 0, 8]);
+        // red: a run of 8
+        bytes.extend_from_slice(&[128 + 8, 128]);
+        // green: 4 literals, then a run of 4
+        bytes.extend_from_slice(&[4, 0, 0, 0, 0, 128 + 4, 128]);
+        // blue: a run of 8 zeros
+        bytes.extend_from_slice(&[128 + 8, 0]);
+        // exponent: a run of 8
+        bytes.extend_from_slice(&[128 + 8, 129]);
+
+        let image = decode(&bytes).unwrap();
+        assert_eq!(image.data.len(), 8 * 3);
+        assert_eq!(&image.data[..3], &[1., 0., 0.]);
+        assert_eq!(&image.data[21..], &[1., 1., 0.]);
+    }
+
+    #[test]
+    fn rejects_empty_images() {
+        assert!(decode(&header(0, 1)).unwrap_err().contains("empty"));
+        assert!(decode(&header(1, 0)).unwrap_err().contains("empty"));
+    }
+
+    #[test]
+    fn rejects_oversized_images() {
+        let bytes = header(u32::MAX, u32::MAX);
+        assert!(decode(&bytes).unwrap_err().contains("too large"));
+        let bytes = header(8193, 8192);
+        assert!(decode(&bytes).unwrap_err().contains("too large"));
+    }
+
+    #[test]
+    fn rejects_non_hdr_bytes() {
+        assert!(decode(b"\x89PNG\r\n").is_err());
+    }
+}
@@ -1,124 +1,1941 @@
 #![feature(format_args_capture)]
 extern crate console_error_panic_hook;
-#[macro_use]
-extern crate lazy_static;
 
+mod aabb;
 mod dom;
 mod glsl;
+mod hdr;
 mod math;
 mod ray;
 mod state;
 mod webgl;
 
-use state::State;
+use glsl::{DiffuseMode, HitResult, Material, MaterialType, Rect, RectPlane, Sphere};
+use math::Vec3;
+use state::{EnvironmentMap, QualityPreset, RenderMode, State, StereoMode, ViewPreset};
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Once;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
-use web_sys::WebGl2RenderingContext;
+use web_sys::{HtmlCanvasElement, HtmlDivElement, WebGl2RenderingContext, WebGlFramebuffer};
+use webgl::Renderer;
 
-lazy_static! {
-    static ref STATE: Arc<Mutex<State>> = Arc::new(Mutex::new(State::default()));
+static INIT: Once = Once::new();
+
+/// Console log level until `set_log_level` is called
+const DEFAULT_LOG_LEVEL: log::LevelFilter = log::LevelFilter::Warn;
+
+/// Global (page-wide) setup that should only ever happen once,
+/// no matter how many ray tracers are created
+fn init_once() {
+    INIT.call_once(|| {
+        // enables more helpful stack traces, and makes panics visible on the page
+        // (including where they happened), since most users never open the console
+        std::panic::set_hook(Box::new(|info| {
+            console_error_panic_hook::hook(info);
+            dom::show_error_overlay(&format!("The ray tracer crashed: {info}"));
+        }));
+        // the logger itself lets everything through: filtering is done by
+        // log's global max level instead, since that can be changed at runtime
+        wasm_logger::init(wasm_logger::Config::new(log::Level::Trace));
+        log::set_max_level(DEFAULT_LOG_LEVEL);
+    });
+}
+
+/// Sets which messages are logged to the console.
+/// Accepts "off", "error", "warn" (the default), "info", "debug", or "trace".
+/// "debug" also reports every shader uniform that couldn't be found (such as misspelled names)
+#[wasm_bindgen]
+pub fn set_log_level(level: &str) -> Result<(), JsValue> {
+    init_once();
+    let level = level
+        .parse::<log::LevelFilter>()
+        .map_err(|_| format!("Unknown log level \"{level}\""))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// The most spheres a scene can contain (the length of the shader's sphere array)
+#[wasm_bindgen]
+pub fn max_spheres() -> usize {
+    glsl::MAX_SPHERES
+}
+
+/// The most rect lights a scene can contain (the length of the shader's rect array)
+#[wasm_bindgen]
+pub fn max_rect_lights() -> usize {
+    glsl::MAX_RECT_LIGHTS
+}
+
+/// Where `save_state_to_local_storage` keeps its data
+const LOCAL_STORAGE_KEY: &str = "ray-tracer-webgl-state";
+
+/// The render loop closure and its pending animation frame, shared between
+/// the `App` handle and the render loop itself (which is created asynchronously)
+#[derive(Default)]
+pub struct RenderLoop {
+    closure: Option<Closure<dyn FnMut()>>,
+    animation_frame_id: Option<i32>,
+    is_stopped: bool,
+    is_destroyed: bool,
+    benchmark: Option<Benchmark>,
+}
+
+/// A benchmark in progress: the scene that was replaced and how to report the result
+struct Benchmark {
+    duration: f64,
+    /// Time the first benchmark frame was rendered
+    start_time: Option<f64>,
+    frame_count: u32,
+    saved_state: State,
+    resolve: js_sys::Function,
+    /// Called if the app is destroyed before the benchmark finishes
+    reject: js_sys::Function,
+}
+
+/// Keeps the benchmark scene rendering continuously and, once the benchmark has run for
+/// its full duration, restores the previous scene and returns the average FPS (with its callback)
+fn update_benchmark(
+    benchmark: &mut Option<Benchmark>,
+    state: &mut State,
+    now: f64,
+    did_render: bool,
+) -> Option<(js_sys::Function, f64)> {
+    let running_benchmark = benchmark.as_mut()?;
+    if did_render {
+        let start_time = *running_benchmark.start_time.get_or_insert(now);
+        running_benchmark.frame_count += 1;
+        // every run of the benchmark uses the same sequence of random seeds
+        state.fixed_seed_time = Some(running_benchmark.frame_count as f64);
+
+        let elapsed = now - start_time;
+        if elapsed >= running_benchmark.duration {
+            let finished_benchmark = benchmark.take().unwrap();
+            // the first frame only marks the start time
+            let fps = (finished_benchmark.frame_count - 1) as f64 * 1000. / elapsed.max(1.);
+
+            let on_frame = state.on_frame.take();
+            let fragment_shader_source = std::mem::take(&mut state.fragment_shader_source);
+            *state = finished_benchmark.saved_state;
+            state.on_frame = on_frame;
+            // the shader may have been edited while the benchmark was running
+            state.fragment_shader_source = fragment_shader_source;
+            // the benchmark's camera is still what's on the GPU
+            state.camera_basis_dirty = true;
+            state.prev_now = now;
+            // the window may have been resized while the benchmark was running
+            state.should_update_render_dimensions = true;
+            state.update_geometry();
+            return Some((finished_benchmark.resolve, fps));
+        }
+    }
+    // render every frame, regardless of render mode or averaging
+    state.should_render = true;
+    None
+}
+
+impl RenderLoop {
+    fn request_animation_frame(&mut self) {
+        if self.is_stopped || self.animation_frame_id.is_some() {
+            return;
+        }
+        if let Some(closure) = &self.closure {
+            self.animation_frame_id = Some(dom::request_animation_frame(closure));
+        }
+    }
+
+    fn cancel_animation_frame(&mut self) {
+        if let Some(id) = self.animation_frame_id.take() {
+            dom::cancel_animation_frame(id);
+        }
+    }
 }
 
 /// This is the "real" main function, but since it is async, it must
-/// be called by the non-async main function
-pub async fn async_main() -> Result<(), JsValue> {
-    // GET ELEMENTS
-    let canvas = dom::canvas();
+/// be called by the non-async constructor
+pub async fn run(
+    state: Arc<Mutex<State>>,
+    canvas: HtmlCanvasElement,
+    crosshair: HtmlDivElement,
+    measurement_overlay: Rc<dom::MeasurementOverlay>,
+    profiler: HtmlDivElement,
+    renderer: Rc<RefCell<Option<Renderer>>>,
+    render_loop: Rc<RefCell<RenderLoop>>,
+) -> Result<(), JsValue> {
     let gl = canvas
         .get_context("webgl2")?
         .unwrap()
         .dyn_into::<WebGl2RenderingContext>()?;
 
-    let state = (*STATE).lock().unwrap();
-    canvas.set_width(state.width);
-    canvas.set_height(state.height);
-    drop(state);
-
-    dom::add_listeners()?;
+    {
+        let state = state.lock().unwrap();
+        canvas.set_width(state.width);
+        canvas.set_height(state.height);
+    }
 
-    let program = webgl::setup_program(&gl).await?;
+    let (program, vertex_shader, fragment_shader_source) = webgl::setup_program(&gl).await?;
     let uniforms = webgl::setup_uniforms(&gl, &program);
 
-    webgl::setup_vertex_buffer(&gl, &program)?;
-    let state = (*STATE).lock().unwrap();
-    let textures = [
-        webgl::create_texture(&gl, &state),
-        webgl::create_texture(&gl, &state),
-    ];
-    let framebuffer_objects = [
-        webgl::create_framebuffer(&gl, &textures[0]),
-        webgl::create_framebuffer(&gl, &textures[1]),
-    ];
-    webgl::set_geometry(&state, &gl, &program);
-    drop(state);
+    let overlays = webgl::Overlays::new(&gl).await?;
+    let mut state_guard = state.lock().unwrap();
+    state_guard.fragment_shader_source = fragment_shader_source;
+    let quad_buffer = webgl::setup_vertex_buffer(&gl, &program, &state_guard)?;
+    webgl::set_geometry(&state_guard, &gl, &program);
+    *renderer.borrow_mut() = Some(Renderer::new(
+        gl,
+        program,
+        vertex_shader,
+        uniforms,
+        quad_buffer,
+        overlays,
+        &state_guard,
+    ));
+    drop(state_guard);
+
+    // the app may have been destroyed while shaders were still being fetched
+    if render_loop.borrow().is_destroyed {
+        return Ok(());
+    }
 
     // RENDER LOOP
-    let f = Rc::new(RefCell::new(None));
-    let g = f.clone();
     {
-        let f = f.clone();
-        *g.borrow_mut() = Some(Closure::wrap(Box::new(move || {
+        let render_loop_handle = render_loop.clone();
+        let mut canvas_rect = dom::CanvasRect::default();
+        render_loop.borrow_mut().closure = Some(Closure::wrap(Box::new(move || {
             // it's ok to borrow this as mutable for the entire block,
             // since it is synchronous and no other function calls can
             // try to lock the mutex while it is in use
-            let mut state = (*STATE).lock().unwrap();
+            let mut state = state.lock().unwrap();
+            let mut renderer_guard = renderer.borrow_mut();
+            let renderer = renderer_guard.as_mut().unwrap();
             let now = dom::window().performance().unwrap().now();
             let dt = now - state.prev_now;
 
-            state::update_position(&mut state, dt);
+            let movement_dt = state::smooth_movement_frame_time(&mut state, dt);
+            state::update_position(&mut state, movement_dt);
+            state::update_head_bob(&mut state, now);
+            state::update_camera_shake(&mut state, now);
+            state::update_idle(&mut state, now);
+            state::update_camera_animation(&mut state, now);
+            state::update_scene_transition(&mut state, now);
+            state::update_autofocus(&mut state, dt);
+            state::update_averaging_for_movement(&mut state);
+            state::update_progressive_startup(&mut state);
 
             // don't render while paused unless trying to save
             // OR unless it's the very first frame
-            let should_render = (state.should_render && !state.is_paused)
-                || (state.should_render && state.is_paused && state.should_save)
+            let should_render = ((state.should_render && !state.is_paused())
+                || (state.should_render && state.is_paused() && state.should_save)
                 || (state.should_render
-                    && state.is_paused
+                    && state.is_paused()
                     && !state.should_save
-                    && state.render_count == 0);
+                    && state.render_count == 0))
+                && (!state.is_render_suspended_while_idle() || state.should_save);
 
             // debounce resize handler
             if state.should_update_to_match_window_size && now - state.last_resize_time > 500. {
                 state.should_update_to_match_window_size = false;
-                state::update_render_dimensions_to_match_window(
-                    &mut state, &gl, &textures, &canvas, now,
-                );
+                state.should_update_render_dimensions = true;
+            }
+            if state.should_update_render_dimensions {
+                state.should_update_render_dimensions = false;
+                state::update_render_dimensions_to_match_window(&mut state, renderer, &canvas, now);
+            }
+
+            if state.should_update_vertex_buffer {
+                state.should_update_vertex_buffer = false;
+                webgl::update_vertex_buffer(&renderer.gl, &renderer.quad_buffer, &state);
+            }
+
+            if state.should_update_accumulation_buffers {
+                state.should_update_accumulation_buffers = false;
+                renderer.create_accumulation_buffers(&state);
+            }
+
+            if state.should_update_canvas_rect {
+                state.should_update_canvas_rect = false;
+                canvas_rect = dom::CanvasRect::measure(&canvas);
+            }
+            dom::update_crosshair(&state, &crosshair, &canvas_rect);
+            dom::update_measurement_overlay(&state, &measurement_overlay, &canvas_rect);
+            dom::update_profiler(&state, &profiler, &canvas_rect);
+
+            if state.should_update_geometry {
+                state.should_update_geometry = false;
+                // sends the centers along with everything else
+                state.should_update_sphere_centers = false;
+                webgl::set_geometry(&state, &renderer.gl, &renderer.program);
+            }
+
+            if state.should_update_sphere_centers {
+                state.should_update_sphere_centers = false;
+                renderer.set_sphere_centers(&state);
             }
 
+            if let Some(environment_map) = state.pending_environment_map.take() {
+                renderer.set_environment_map(&environment_map);
+                state.has_environment_map = true;
+                state.is_environment_cubemap =
+                    matches!(environment_map, EnvironmentMap::CubeMap(_));
+                state.render_count = 0;
+            }
+
+            let mut on_frame = None;
+            let mut on_target_reached = None;
             if should_render {
-                state::update_render_globals(&mut state);
                 state::update_moving_fps_array(now, &mut state, dt);
+                state::update_auto_samples(&mut state, now);
 
-                uniforms.run_setters(&state, &gl, now);
+                if let Some(gpu_timer) = renderer.gpu_timer.as_mut() {
+                    gpu_timer.begin(&renderer.gl);
+                }
+                let has_render_target = state.target_render_count.is_some();
+                // with a frame budget, 1-sample passes are accumulated until time runs out
+                let mut passes = 0;
+                loop {
+                    state::update_render_globals(&mut state);
+                    // a distinct time per pass gives each pass its own random seed
+                    let pass_time = now + passes as f64;
+                    renderer.update_uniforms(&mut state, pass_time);
+                    webgl::render(renderer, &state);
+                    passes += 1;
 
-                webgl::render(&gl, &state, &textures, &framebuffer_objects);
+                    let frame_budget_ms = match state.frame_budget_ms {
+                        Some(budget) if state.uses_frame_budget() && state.should_render => budget,
+                        _ => break,
+                    };
+                    webgl::wait_for_gpu(&renderer.gl);
+                    let elapsed = dom::window().performance().unwrap().now() - now;
+                    if !state::has_time_for_another_pass(passes, elapsed, frame_budget_ms) {
+                        break;
+                    }
+                }
+                state.last_frame_rays = state.render_stats().rays_per_frame * passes as f64;
+                if let Some(gpu_timer) = renderer.gpu_timer.as_mut() {
+                    gpu_timer.end(&renderer.gl, state.last_frame_rays);
+                    if let Some(timing) = gpu_timer.poll(&renderer.gl) {
+                        state.gpu_timing = Some(timing);
+                    }
+                }
+                // accumulated frames are only written to while averaging
+                if state.auto_exposure && state.should_average {
+                    let average_luminance = webgl::average_log_luminance(renderer, &state);
+                    state::update_auto_exposure(&mut state, average_luminance, dt);
+                }
 
-                dom::save_image(&mut state);
+                dom::save_image(&mut state, &canvas);
+                // drawn after saving, so that saved images don't include the overlays
+                if state.should_draw_selection_outline() {
+                    renderer.overlays.outline.draw(
+                        &renderer.gl,
+                        &renderer.program,
+                        renderer.target_framebuffer.as_ref(),
+                        &state,
+                    );
+                }
+                if state.show_bounds {
+                    renderer.overlays.bounds.draw(
+                        &renderer.gl,
+                        &renderer.program,
+                        renderer.target_framebuffer.as_ref(),
+                        &state,
+                    );
+                }
                 dom::update_fps_indicator(now, &mut state);
+                on_frame = state
+                    .on_frame
+                    .clone()
+                    .map(|on_frame| (on_frame, state.average_fps(), state.render_count));
+                if has_render_target && state.target_render_count.is_none() {
+                    // the seed was only fixed while working toward the target
+                    state.fixed_seed_time = None;
+                    on_target_reached = state
+                        .on_target_reached
+                        .take()
+                        .map(|callback| (callback, state.average_fps(), state.render_count));
+                }
+            }
+            let benchmark_result = update_benchmark(
+                &mut render_loop_handle.borrow_mut().benchmark,
+                &mut state,
+                now,
+                should_render,
+            );
+            // the callback may call back into the app, so the state must be unlocked first
+            drop(state);
+            drop(renderer_guard);
+            if let Some((on_frame, fps, render_count)) = on_frame {
+                dom::call_on_frame(&on_frame, fps, render_count);
             }
-            dom::request_animation_frame((*f).borrow().as_ref().unwrap());
+            if let Some((on_target_reached, fps, render_count)) = on_target_reached {
+                dom::call_on_frame(&on_target_reached, fps, render_count);
+            }
+            if let Some((resolve, fps)) = benchmark_result {
+                if let Err(error) = resolve.call1(&JsValue::NULL, &fps.into()) {
+                    log::error!("Error resolving benchmark: {error:?}");
+                }
+            }
+            let mut render_loop = render_loop_handle.borrow_mut();
+            // this frame's request has now been fulfilled
+            render_loop.animation_frame_id = None;
+            render_loop.request_animation_frame();
         }) as Box<dyn FnMut()>));
     }
 
-    dom::request_animation_frame((*g).borrow().as_ref().unwrap());
+    render_loop.borrow_mut().request_animation_frame();
 
     Ok(())
 }
 
-/// Entry function cannot be async, so spawns a local Future for running the real main function
+/// Handle to an independent ray tracer instance, rendering to its own canvas with its own state.
+/// Multiple instances can be embedded on the same page.
 #[wasm_bindgen]
-pub fn main() -> Result<(), JsValue> {
-    // enables more helpful stack traces
-    console_error_panic_hook::set_once();
-    wasm_logger::init(wasm_logger::Config::default());
+pub struct App {
+    state: Arc<Mutex<State>>,
+    renderer: Rc<RefCell<Option<Renderer>>>,
+    render_loop: Rc<RefCell<RenderLoop>>,
+    listeners: Vec<dom::Listener>,
+    crosshair: HtmlDivElement,
+    measurement_overlay: Rc<dom::MeasurementOverlay>,
+    profiler: HtmlDivElement,
+}
 
-    spawn_local(async {
-        async_main().await.unwrap();
-    });
+#[wasm_bindgen]
+impl App {
+    /// Starts a ray tracer rendering to the canvas with the given id
+    #[wasm_bindgen(constructor)]
+    pub fn new(canvas_id: &str) -> Result<App, JsValue> {
+        let canvas = dom::canvas_by_id(canvas_id).ok_or_else(|| {
+            JsValue::from_str(&format!("No canvas found with id \"{canvas_id}\""))
+        })?;
+        App::from_canvas(canvas, None)
+    }
+
+    /// Stops rendering new frames (the render loop keeps running) by pausing both the camera
+    /// and the scene. Use `set_camera_paused` or `set_scene_paused` to pause just one of them
+    pub fn pause(&self) {
+        dom::show_pause_screen(&mut self.state.lock().unwrap());
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        dom::hide_pause_screen(&mut state);
+        state.should_render = true;
+    }
+
+    /// Ignores camera input and freezes any camera flight, while the scene keeps rendering
+    pub fn set_camera_paused(&self, value: bool) {
+        self.state.lock().unwrap().set_camera_paused(value);
+    }
+
+    /// Freezes anything animated in the scene, while the camera stays free to move
+    pub fn set_scene_paused(&self, value: bool) {
+        self.state.lock().unwrap().set_scene_paused(value);
+    }
+
+    /// Accumulates exactly `samples` frames from scratch, then pauses. Every frame uses a
+    /// fixed random seed, so the same scene always produces the same image. `on_complete`
+    /// is called with `{ fps, renderCount }` once the last frame has been rendered
+    pub fn render_samples(&self, samples: u32, on_complete: Option<js_sys::Function>) {
+        let mut state = self.state.lock().unwrap();
+        state.on_target_reached = on_complete;
+        state.render_samples(samples);
+    }
+
+    /// Cancels the render loop. No frames are rendered until `start` is called.
+    pub fn stop(&self) {
+        let mut render_loop = self.render_loop.borrow_mut();
+        render_loop.is_stopped = true;
+        render_loop.cancel_animation_frame();
+    }
+
+    /// Restarts the render loop after it has been stopped
+    pub fn start(&self) {
+        let mut render_loop = self.render_loop.borrow_mut();
+        if render_loop.is_destroyed {
+            return;
+        }
+        render_loop.is_stopped = false;
+        render_loop.request_animation_frame();
+        drop(render_loop);
+
+        // time spent stopped shouldn't count as a giant frame delta
+        let mut state = self.state.lock().unwrap();
+        state.prev_now = dom::window().performance().unwrap().now();
+    }
+
+    /// Removes all event listeners and cancels the render loop, releasing
+    /// it along with the GL resources it owns.
+    /// The handle should not be used after this is called.
+    pub fn destroy(&mut self) {
+        self.listeners.clear();
+        self.crosshair.remove();
+        self.measurement_overlay.remove();
+        self.profiler.remove();
+        let mut render_loop = self.render_loop.borrow_mut();
+        render_loop.is_destroyed = true;
+        render_loop.is_stopped = true;
+        render_loop.cancel_animation_frame();
+        render_loop.closure = None;
+        let benchmark = render_loop.benchmark.take();
+        drop(render_loop);
+        self.renderer.borrow_mut().take();
+        if let Some(benchmark) = benchmark {
+            let error = JsValue::from("The ray tracer was destroyed before the benchmark finished");
+            if let Err(error) = benchmark.reject.call1(&JsValue::NULL, &error) {
+                log::error!("Error rejecting benchmark: {error:?}");
+            }
+        }
+    }
+
+    /// Source of the fragment shader currently rendering the scene (with includes resolved).
+    /// Empty until the shaders have finished loading
+    pub fn get_shader_source(&self) -> String {
+        self.state.lock().unwrap().fragment_shader_source.clone()
+    }
+
+    /// Recompiles the ray tracer with a new fragment shader. If it fails to compile or link,
+    /// the error log is thrown and the previous shader keeps rendering
+    pub fn set_shader_source(&self, frag: &str) -> Result<(), JsValue> {
+        let mut renderer = self.renderer.borrow_mut();
+        let renderer = renderer.as_mut().ok_or("Shaders are still loading")?;
+        let mut state = self.state.lock().unwrap();
+        renderer.set_fragment_shader(frag, &state)?;
+        state.set_fragment_shader_source(frag);
+        Ok(())
+    }
+
+    /// The WebGL2 context the ray tracer renders with (undefined until the shaders have
+    /// finished loading), for creating framebuffers to pass to `set_target_framebuffer`
+    pub fn get_context(&self) -> Option<WebGl2RenderingContext> {
+        self.renderer
+            .borrow()
+            .as_ref()
+            .map(|renderer| renderer.gl.clone())
+    }
+
+    /// Draws finished frames (and overlays) into a framebuffer created with `get_context`,
+    /// rather than the canvas, so that they can be composited into a larger WebGL scene.
+    /// The framebuffer should be at least as large as the canvas.
+    /// Passing undefined goes back to drawing to the canvas. Saved images are always taken
+    /// from the canvas, so save from the embedding app while a framebuffer is targeted
+    pub fn set_target_framebuffer(
+        &self,
+        framebuffer: Option<WebGlFramebuffer>,
+    ) -> Result<(), JsValue> {
+        let mut renderer = self.renderer.borrow_mut();
+        let renderer = renderer.as_mut().ok_or("Shaders are still loading")?;
+        renderer.target_framebuffer = framebuffer;
+        self.state.lock().unwrap().request_render();
+        Ok(())
+    }
+
+    /// Sets the color (0->1 for each channel) the canvas is cleared to before drawing,
+    /// which shows through wherever the image isn't drawn, such as letterboxed bars.
+    /// Defaults to opaque black
+    pub fn set_clear_color(&self, r: f64, g: f64, b: f64, a: f64) {
+        self.state.lock().unwrap().set_clear_color(Vec3(r, g, b), a);
+    }
+
+    /// Renders at a fixed aspect ratio (width / height), with black bars filling the rest
+    /// of the canvas. Pass `undefined` to fill the whole canvas again
+    pub fn set_target_aspect_ratio(&self, value: Option<f64>) {
+        self.state.lock().unwrap().set_target_aspect_ratio(value);
+    }
+
+    /// Sets which direction is "up" in the scene, e.g. (0, 0, 1) for Z-up scenes. Defaults to (0, 1, 0)
+    pub fn set_world_up(&self, x: f64, y: f64, z: f64) {
+        self.state.lock().unwrap().set_world_up(Vec3(x, y, z));
+    }
+
+    /// When enabled (the default), every accumulated frame counts equally, converging to a
+    /// clean, unbiased image. When disabled, frames are blended using `set_last_frame_weight`
+    pub fn set_true_average(&self, value: bool) {
+        self.state.lock().unwrap().set_true_average(value);
+    }
+
+    /// Sets how strongly each new frame is blended into the accumulated image (clamped to 0->1).
+    /// Lower values give more temporal smoothing, but more ghosting while moving.
+    /// Only used when true averaging is disabled.
+    pub fn set_last_frame_weight(&self, value: f32) {
+        self.state.lock().unwrap().set_last_frame_weight(value);
+    }
+
+    /// Enables or disables averaging of previous frames together
+    pub fn set_should_average(&self, value: bool) {
+        self.state.lock().unwrap().set_should_average(value);
+    }
+
+    /// Sets how many frames the camera must be still before averaging resumes after moving
+    pub fn set_still_frame_threshold(&self, value: u32) {
+        self.state.lock().unwrap().still_frame_threshold = value;
+    }
+
+    /// Tilts the camera around its viewing direction (in degrees)
+    pub fn set_roll(&self, degrees: f64) {
+        self.state.lock().unwrap().set_roll(degrees);
+    }
+
+    /// Turns the camera around the up axis to an absolute angle (in degrees), leaving pitch as is
+    pub fn set_yaw(&self, degrees: f64) {
+        self.state.lock().unwrap().set_yaw(degrees);
+    }
+
+    /// Tilts the camera up or down to an absolute angle (in degrees, clamped to -89->89),
+    /// leaving yaw as is
+    pub fn set_pitch(&self, degrees: f64) {
+        self.state.lock().unwrap().set_pitch(degrees);
+    }
+
+    /// Returns `{ yaw, pitch, roll, fov }`, all in degrees (`fov` being the vertical field of view)
+    pub fn get_camera_angles(&self) -> Result<JsValue, JsValue> {
+        let state = self.state.lock().unwrap();
+        let object = js_sys::Object::new();
+        js_sys::Reflect::set(&object, &"yaw".into(), &state.yaw.into())?;
+        js_sys::Reflect::set(&object, &"pitch".into(), &state.pitch.into())?;
+        js_sys::Reflect::set(&object, &"roll".into(), &state.roll.into())?;
+        js_sys::Reflect::set(
+            &object,
+            &"fov".into(),
+            &state.camera_field_of_view.to_degrees().into(),
+        )?;
+        Ok(object.into())
+    }
+
+    /// Smoothly flies the camera toward the object with the given uuid
+    pub fn focus_on_object(&self, uuid: i32) -> Result<(), JsValue> {
+        let now = dom::window().performance().unwrap().now();
+        self.state.lock().unwrap().focus_on_object(uuid, now)?;
+        Ok(())
+    }
+
+    /// Registers a callback, called once per rendered frame with `{ fps, renderCount }`
+    pub fn on_frame(&self, callback: js_sys::Function) {
+        self.state.lock().unwrap().on_frame = Some(callback);
+    }
+
+    pub fn clear_on_frame(&self) {
+        self.state.lock().unwrap().on_frame = None;
+    }
+
+    /// Sets the minimum distance along a ray that counts as a hit (fixes "shadow acne")
+    pub fn set_ray_epsilon(&self, value: f64) {
+        self.state.lock().unwrap().set_ray_epsilon(value);
+    }
+
+    /// Renders the scene from two eyes, `ipd` scene units apart: either "anaglyph"
+    /// (for red/cyan glasses) or "side-by-side" (left eye on the left), or "off".
+    /// Objects at the focus distance appear at the depth of the screen.
+    /// Anaglyph rendering traces twice as many rays per pixel
+    pub fn set_stereo_mode(&self, mode: &str, ipd: f64) -> Result<(), JsValue> {
+        let mode = mode.parse::<StereoMode>()?;
+        self.state.lock().unwrap().set_stereo_mode(mode, ipd);
+        Ok(())
+    }
+
+    /// Replaces the gradient sky with a physically-based sky lit by a sun at the given
+    /// elevation and azimuth (in degrees). Turbidity ranges from about 2 (clear) to 10 (hazy)
+    pub fn set_sun(&self, elevation: f64, azimuth: f64, turbidity: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_sun(elevation, azimuth, turbidity);
+    }
+
+    /// Sets the camera's aperture (0 by default). Anything nearer or farther than the focus
+    /// distance is blurred, more so with a wider aperture
+    pub fn set_aperture(&self, aperture: f64) {
+        self.state.lock().unwrap().set_aperture(aperture);
+    }
+
+    /// Shapes out-of-focus highlights (bokeh) into polygons with this many sides,
+    /// like the blades of a real aperture. Fewer than 3 gives a circular aperture (the default)
+    pub fn set_aperture_blades(&self, blade_count: u32) {
+        self.state.lock().unwrap().set_aperture_blades(blade_count);
+    }
+
+    /// Picks how diffuse surfaces scatter light: 0 = `normal + random_unit_vector`
+    /// (Lambertian, the default), 1 = uniformly random within the hemisphere,
+    /// or 2 = `normal + random_in_unit_sphere`
+    pub fn set_diffuse_mode(&self, mode: i32) -> Result<(), JsValue> {
+        let mode = DiffuseMode::try_from(mode)?;
+        self.state.lock().unwrap().set_diffuse_mode(mode);
+        Ok(())
+    }
+
+    /// Switches between the sun + sky model and the default sky
+    pub fn set_sun_sky_enabled(&self, value: bool) {
+        self.state.lock().unwrap().set_sun_sky_enabled(value);
+    }
+
+    /// Renders at the display's device-pixel resolution for crisp output on HiDPI screens
+    /// (enabled by default). Disable to render at the lower CSS-pixel resolution for performance
+    pub fn set_respect_dpr(&self, value: bool) {
+        self.state.lock().unwrap().set_respect_dpr(value);
+    }
+
+    /// Renders at exactly `width` x `height` (e.g. 1920 x 1080) regardless of the window's size,
+    /// for consistent output across machines. The canvas is scaled to fit the page with CSS.
+    /// Dimensions are clamped to the GPU's maximum texture size
+    pub fn set_fixed_resolution(&self, width: u32, height: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_fixed_resolution(Some((width, height)));
+    }
+
+    /// Goes back to rendering at the window's size
+    pub fn clear_fixed_resolution(&self) {
+        self.state.lock().unwrap().set_fixed_resolution(None);
+    }
+
+    /// When enabled, frames are rendered at a low resolution right after the camera moves
+    /// and then quickly refined up to full resolution, so that something shows up immediately
+    pub fn set_progressive_startup(&self, value: bool) {
+        self.state.lock().unwrap().set_progressive_startup(value);
+    }
+
+    /// Renders a fixed scene from a fixed camera position (with the current quality settings)
+    /// for `seconds` and resolves with the average FPS. The previous scene and camera are
+    /// restored afterward. Random seeds are fixed, so every run renders identical frames.
+    /// Rejects if the ray tracer is destroyed before the benchmark finishes
+    pub fn run_benchmark(&self, seconds: f64) -> js_sys::Promise {
+        let mut start_benchmark = |resolve: js_sys::Function, reject: js_sys::Function| {
+            let mut render_loop = self.render_loop.borrow_mut();
+            let error = if render_loop.is_destroyed || render_loop.is_stopped {
+                Some("Cannot run a benchmark while the render loop is stopped")
+            } else if render_loop.benchmark.is_some() {
+                Some("A benchmark is already running")
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                reject.call1(&JsValue::NULL, &error.into()).unwrap();
+                return;
+            }
+
+            let mut state = self.state.lock().unwrap();
+            let saved_state = state.clone();
+            let default_state = State::default();
+            state.sphere_list = default_state.sphere_list;
+            state.camera_origin = default_state.camera_origin;
+            state.yaw = default_state.yaw;
+            state.pitch = default_state.pitch;
+            state.roll = default_state.roll;
+            state.camera_field_of_view = default_state.camera_field_of_view;
+            state.world_up = default_state.world_up;
+            state.camera_animation = None;
+            state.keydown_map = Default::default();
+            state.set_paused(false);
+            state.fixed_seed_time = Some(0.);
+            state.update_pipeline();
+            state.update_geometry();
+
+            render_loop.benchmark = Some(Benchmark {
+                duration: seconds.max(0.) * 1000.,
+                start_time: None,
+                frame_count: 0,
+                saved_state,
+                resolve,
+                reject,
+            });
+        };
+        js_sys::Promise::new(&mut start_benchmark)
+    }
+
+    /// Continuously raises `samples_per_pixel` while frames render faster than `target_fps`
+    /// allows and lowers it when they're slower, staying within `min..=max` (disabled by
+    /// default). Frames are timed on the GPU where supported, so that the display's refresh
+    /// rate doesn't hide spare time.
+    /// While paused, `max` samples are rendered instead of the usual boost
+    pub fn set_auto_samples(&self, enabled: bool, min: u32, max: u32, target_fps: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_auto_samples(enabled, min, max, target_fps);
+    }
+
+    /// Accumulates as many 1-sample passes as fit in `budget_ms` each frame, rather than one
+    /// pass of `samples_per_pixel`, to keep weak GPUs interactive. Undefined disables the budget
+    pub fn set_frame_budget_ms(&self, budget_ms: Option<f64>) {
+        self.state.lock().unwrap().set_frame_budget_ms(budget_ms);
+    }
+
+    /// Enables or disables rendering with extra samples while paused (enabled by default).
+    /// Disabling avoids a hitch when pausing on low-power devices
+    pub fn set_pause_sample_boost(&self, value: bool) {
+        self.state.lock().unwrap().set_pause_sample_boost(value);
+    }
+
+    /// Linear multiplier for the brightness of the displayed image (ignored while auto-exposure is on)
+    pub fn set_exposure(&self, value: f64) {
+        self.state.lock().unwrap().set_exposure(value);
+    }
+
+    /// Continuously eases the focus distance toward whatever is at the center of the screen,
+    /// so it stays sharp with depth of field enabled (off by default)
+    pub fn set_autofocus(&self, value: bool) {
+        self.state.lock().unwrap().set_autofocus(value);
+    }
+
+    /// Sets the lens's focal length in mm (for a full-frame sensor), which zooms by changing
+    /// the field of view. This is separate from the depth of field's focus distance
+    pub fn set_focal_length(&self, value: f64) {
+        self.state.lock().unwrap().set_focal_length(value);
+    }
+
+    /// Gently bobs the camera while moving, `amplitude` as a fraction of the distance moved
+    /// per second and `frequency` in bobs per second (off by default)
+    pub fn set_head_bob(&self, value: bool, amplitude: f64, frequency: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_head_bob(value, amplitude, frequency);
+    }
+
+    /// Briefly shakes the camera (e.g. in response to an event), by up to `intensity` scene
+    /// units, settling back to where it was after `duration_ms`
+    pub fn shake(&self, intensity: f64, duration_ms: f64) {
+        self.state.lock().unwrap().shake(intensity, duration_ms);
+    }
+
+    /// Continuously adjusts exposure based on the average luminance of the scene (off by default)
+    pub fn set_auto_exposure(&self, value: bool) {
+        self.state.lock().unwrap().set_auto_exposure(value);
+    }
+
+    /// The log-average luminance auto-exposure aims for (0.18 by default)
+    pub fn set_exposure_target(&self, value: f64) {
+        self.state.lock().unwrap().set_exposure_target(value);
+    }
+
+    /// Accepts "continuous" or "on-demand". In on-demand mode, frames are only rendered
+    /// after something changes (or `request_render` is called) until the image converges.
+    pub fn set_render_mode(&self, render_mode: &str) -> Result<(), JsValue> {
+        let render_mode = render_mode.parse::<RenderMode>()?;
+        self.state.lock().unwrap().set_render_mode(render_mode);
+        Ok(())
+    }
+
+    pub fn request_render(&self) {
+        self.state.lock().unwrap().request_render();
+    }
+
+    /// Fetches a Radiance `.hdr` (RGBE) image and uses it as an equirectangular sky
+    pub fn load_environment_hdr(&self, url: String) -> js_sys::Promise {
+        let state = self.state.clone();
+        wasm_bindgen_futures::future_to_promise(async move {
+            let bytes = webgl::fetch_bytes(&url).await?;
+            let image = hdr::decode(&bytes)?;
+            state
+                .lock()
+                .unwrap()
+                .set_environment_map(EnvironmentMap::Equirectangular(image));
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Loads six Radiance `.hdr` images as the faces of a cube map sky, ordered
+    /// +X, -X, +Y, -Y, +Z, -Z. Faces must be square and all the same size.
+    /// Replaces any equirectangular sky loaded with `load_environment_hdr`
+    pub fn load_environment_cubemap(&self, urls: Vec<String>) -> js_sys::Promise {
+        let state = self.state.clone();
+        wasm_bindgen_futures::future_to_promise(async move {
+            let faces = futures::future::try_join_all(urls.iter().map(|url| async move {
+                let bytes = webgl::fetch_bytes(url).await?;
+                Ok::<_, JsValue>(hdr::decode(&bytes)?)
+            }))
+            .await?;
+            let environment_map = EnvironmentMap::cube_map(faces)?;
+            state.lock().unwrap().set_environment_map(environment_map);
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Sets how many frames are blended together (clamped to 2->8).
+    /// 2 progressively averages every frame since the camera last moved,
+    /// while more than 2 uniformly averages only that many of the most recent frames.
+    pub fn set_accumulation_frame_count(&self, value: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_accumulation_frame_count(value);
+    }
+
+    /// Also renders the normal and depth of whatever each pixel's camera ray hits first into
+    /// extra float textures (a G-buffer), alongside every accumulated frame. Off by default,
+    /// since it costs extra memory. Fails when the browser can't render to float textures
+    pub fn set_g_buffer(&self, value: bool) -> Result<(), JsValue> {
+        if value {
+            let renderer = self.renderer.borrow();
+            let renderer = renderer
+                .as_ref()
+                .ok_or("Cannot enable the G-buffer before the renderer has been initialized")?;
+            webgl::GBuffer::check_support(&renderer.gl)?;
+        }
+        self.state.lock().unwrap().set_g_buffer(value);
+        Ok(())
+    }
+
+    /// Removes the measurement placed by alt + clicking two points (also cleared with "m")
+    pub fn clear_measurement(&self) {
+        self.state.lock().unwrap().clear_measurement();
+    }
+
+    /// Shows or hides an outline around the selected object (hidden by default)
+    pub fn set_outline_selected(&self, value: bool) {
+        self.state.lock().unwrap().set_outline_selected(value);
+    }
+
+    /// Renders a region of interest (`x`, `y`, `width`, `height` in pixels from the top-left
+    /// of the image, excluding any letterboxing) with `samples_per_pixel` samples (up to 64),
+    /// while the rest of the frame keeps rendering at the normal sample count. Useful for
+    /// inspecting noisy areas
+    pub fn set_roi(&self, x: u32, y: u32, width: u32, height: u32, samples_per_pixel: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_roi((x, y, width, height), samples_per_pixel);
+    }
+
+    /// Goes back to rendering every pixel with the normal sample count
+    pub fn clear_roi(&self) {
+        self.state.lock().unwrap().clear_roi();
+    }
 
+    /// Takes a few extra samples at pixels on the silhouette of an object (such as the horizon
+    /// of a large ground sphere), where aliasing is most visible at low sample counts.
+    /// Edges are found by casting a ray through each corner of the pixel, so this adds some
+    /// cost to every pixel (disabled by default)
+    pub fn set_edge_aa(&self, value: bool) {
+        self.state.lock().unwrap().set_edge_aa(value);
+    }
+
+    /// Shows the raw linear radiance for debugging: gamma correction, exposure, and the grid
+    /// are skipped, and the sky (including the sun) is black, so that only light given off
+    /// by objects in the scene contributes. Helps tell lighting problems from post-processing ones
+    pub fn set_raw_output(&self, value: bool) {
+        self.state.lock().unwrap().set_raw_output(value);
+    }
+
+    /// Adds ground-hugging fog of the given color (0->1 for each channel), `density` thick
+    /// at height 0 and thinning out by a factor of e every `1 / falloff` units higher
+    /// ("height" being along the world's up direction). A density of 0 (the default) disables it
+    pub fn set_height_fog(&self, density: f64, falloff: f64, r: f64, g: f64, b: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_height_fog(density, falloff, Vec3(r, g, b));
+    }
+
+    /// Shows or hides an infinite reference grid on the y = 0 plane (hidden by default),
+    /// which fades out with distance and is hidden behind objects in front of it.
+    /// Unlike the other overlays, the grid is included in saved images
+    pub fn set_show_grid(&self, value: bool) {
+        self.state.lock().unwrap().set_show_grid(value);
+    }
+
+    /// Sets the distance between grid lines (1 by default)
+    pub fn set_grid_spacing(&self, spacing: f64) {
+        self.state.lock().unwrap().set_grid_spacing(spacing);
+    }
+
+    /// Shows or hides outlines of each object's bounding box (hidden by default)
+    pub fn set_show_bounds(&self, value: bool) {
+        self.state.lock().unwrap().set_show_bounds(value);
+    }
+
+    /// Shows or hides the page's UI (fps indicator and buttons), which can also be toggled with "h"
+    pub fn set_ui_visible(&self, value: bool) {
+        let mut state = self.state.lock().unwrap();
+        dom::set_ui_hidden(&mut state, !value);
+    }
+
+    /// Allows the profiler overlay (fps, frame time, sample and sphere counts) to be toggled
+    /// with "p". Disabled by default, so that it can't end up in clean captures
+    pub fn set_profiler_enabled(&self, value: bool) {
+        self.state.lock().unwrap().set_profiler_enabled(value);
+    }
+
+    /// Shows or hides the crosshair at the center of the canvas, sized in CSS pixels
+    pub fn set_crosshair(&self, enabled: bool, size_px: f64) {
+        self.state.lock().unwrap().set_crosshair(enabled, size_px);
+    }
+
+    /// After `seconds` without keyboard or mouse input, drops to the idle quality to save power,
+    /// restoring full quality on the next input. Undefined disables the timeout (the default)
+    pub fn set_idle_timeout(&self, seconds: Option<f64>) {
+        let now = dom::window().performance().unwrap().now();
+        self.state
+            .lock()
+            .unwrap()
+            .set_idle_timeout(seconds.map(|seconds| seconds * 1000.), now);
+    }
+
+    /// Quality preset used while idle ("low" by default). Undefined stops rendering
+    /// altogether while idle. Accepts "low", "medium", "high", or "ultra"
+    pub fn set_idle_quality(&self, preset: Option<String>) -> Result<(), JsValue> {
+        let preset = preset
+            .map(|preset| preset.parse::<QualityPreset>())
+            .transpose()?;
+        self.state.lock().unwrap().set_idle_quality(preset);
+        Ok(())
+    }
+
+    /// Sets samples per pixel, max depth, averaging, and render scale together.
+    /// Accepts "low", "medium", "high", or "ultra".
+    pub fn set_quality_preset(&self, preset: &str) -> Result<(), JsValue> {
+        let preset = preset.parse::<QualityPreset>()?;
+        self.state.lock().unwrap().set_quality_preset(preset);
+        Ok(())
+    }
+
+    /// Snaps the camera to look at the center of the scene from one side, framing the whole scene.
+    /// Accepts "front", "back", "top", "bottom", "left", "right", or "iso".
+    pub fn set_view_preset(&self, preset: &str) -> Result<(), JsValue> {
+        let preset = preset.parse::<ViewPreset>()?;
+        self.state.lock().unwrap().set_view_preset(preset)?;
+        Ok(())
+    }
+
+    /// Turns the camera toward the center of the scene, keeping its current position
+    pub fn look_at_scene_center(&self) -> Result<(), JsValue> {
+        self.state.lock().unwrap().look_at_scene_center()?;
+        Ok(())
+    }
+
+    /// Returns the name of the preset matching the current settings, or "custom"
+    pub fn get_quality_preset(&self) -> String {
+        self.state
+            .lock()
+            .unwrap()
+            .quality_preset()
+            .map_or("custom", |preset| preset.name())
+            .to_string()
+    }
+
+    /// Sets a sphere's index of refraction by name ("water", "glass", "diamond", etc.)
+    pub fn set_sphere_ior_preset(&self, uuid: i32, name: &str) -> Result<(), JsValue> {
+        self.state
+            .lock()
+            .unwrap()
+            .set_sphere_refraction_index_preset(uuid, name)?;
+        Ok(())
+    }
+
+    /// Attaches a sphere to a parent sphere, so that it moves along with it (translation only),
+    /// or detaches it when `parent_uuid` is undefined. Fails if parents would form a cycle
+    pub fn set_sphere_parent(&self, uuid: i32, parent_uuid: Option<i32>) -> Result<(), JsValue> {
+        self.state
+            .lock()
+            .unwrap()
+            .set_sphere_parent(uuid, parent_uuid)?;
+        Ok(())
+    }
+
+    /// Hides or shows a sphere without deleting it. Hidden spheres aren't rendered
+    /// and can't be picked, but keep their place (and uuid) in the scene
+    pub fn set_sphere_visible(&self, uuid: i32, visible: bool) -> Result<(), JsValue> {
+        self.state
+            .lock()
+            .unwrap()
+            .set_sphere_visible(uuid, visible)?;
+        Ok(())
+    }
+
+    /// Blends a second material into a sphere's surface in patches of 3D noise, for weathered
+    /// looks like rust on metal. `material2` uses the same format as `set_sphere_material`,
+    /// starting from the sphere's current material. `blend_scale` is the size of the patches in
+    /// scene units (the current scale, 1 by default, when undefined). An undefined or null
+    /// `material2` removes the blend
+    pub fn set_sphere_blend(
+        &self,
+        uuid: i32,
+        material2: JsValue,
+        blend_scale: Option<f64>,
+    ) -> Result<(), JsValue> {
+        let mut state = self.state.lock().unwrap();
+        let sphere = state.sphere_mut(uuid)?;
+        let blend_scale = blend_scale.unwrap_or(sphere.blend_scale);
+        let material2 = if material2.is_undefined() || material2.is_null() {
+            None
+        } else {
+            let mut new_material = sphere.material.clone();
+            update_material_from_js(&mut new_material, &material2)?;
+            Some(new_material)
+        };
+        state.set_sphere_blend(uuid, material2, blend_scale)?;
+        Ok(())
+    }
+
+    /// Updates a sphere's material from
+    /// `{ type, r, g, b, fuzz, refraction_index, emission: [r, g, b], display_only }`,
+    /// where `type` is 0 (diffuse), 1 (metal), or 2 (glass). Missing fields keep their current values.
+    /// `display_only` spheres are drawn at full brightness (as markers), without affecting lighting
+    pub fn set_sphere_material(&self, uuid: i32, material: JsValue) -> Result<(), JsValue> {
+        let mut state = self.state.lock().unwrap();
+        let mut new_material = state.sphere_mut(uuid)?.material.clone();
+        update_material_from_js(&mut new_material, &material)?;
+        state.set_sphere_material(uuid, new_material)?;
+        Ok(())
+    }
+
+    /// Replaces the scene with `{ spheres: [{ center: [x, y, z], radius, material }] }`
+    /// (materials use the same format as `set_sphere_material`)
+    pub fn load_scene(&self, scene: JsValue) -> Result<(), JsValue> {
+        let spheres = scene_from_js(&scene)?;
+        self.state.lock().unwrap().set_scene(spheres)?;
+        Ok(())
+    }
+
+    /// Moves every sphere at once from a flat array of `x, y, z` triples, one per sphere in
+    /// uuid order (centers of spheres with a parent are relative to the parent's center).
+    /// Only the centers are re-uploaded, so this is the efficient way to animate from JS
+    pub fn set_sphere_positions(&self, positions: &[f32]) -> Result<(), JsValue> {
+        self.state.lock().unwrap().set_sphere_positions(positions)?;
+        Ok(())
+    }
+
+    /// Replaces every rect light with `[{ plane, a0, a1, b0, b1, k, material, flip_normal,
+    /// double_sided }]`, where `plane` is 0 = xy, 1 = xz, or 2 = yz. e.g. an xz rect covers
+    /// x = a0->a1 and z = b0->b1 at y = k. Rects with an emissive material are sampled directly
+    pub fn set_rect_lights(&self, rects: JsValue) -> Result<(), JsValue> {
+        let rects = rects_from_js(&rects)?;
+        self.state.lock().unwrap().set_rect_lights(rects)?;
+        Ok(())
+    }
+
+    /// Returns every rect light, in the same format `set_rect_lights` accepts
+    pub fn get_rect_lights(&self) -> Result<JsValue, JsValue> {
+        rects_to_js(&self.state.lock().unwrap().rect_lights)
+    }
+
+    /// Replaces the scene with spheres read from CSV rows of `x,y,z,radius,r,g,b` (colors
+    /// from 0->1), e.g. to render a 3D scatter plot. Malformed rows (and rows past
+    /// `max_spheres`) are skipped with a warning. Returns how many spheres were loaded
+    pub fn load_spheres_csv(&self, text: &str) -> Result<usize, JsValue> {
+        let (spheres, warnings) = state::spheres_from_csv(text);
+        for warning in &warnings {
+            log::warn!("{warning}");
+        }
+        let sphere_count = spheres.len();
+        self.state.lock().unwrap().set_scene(spheres)?;
+        Ok(sphere_count)
+    }
+
+    /// Replaces the scene with one of the built-in scenes ("default", "single", or "bubble"),
+    /// cross-fading from the old scene over `set_transition_duration` (only while
+    /// frames are being averaged). Pass `instant` to switch immediately
+    pub fn load_scene_preset(&self, name: &str, instant: Option<bool>) -> Result<(), JsValue> {
+        let spheres = state::scene_preset(name)?;
+        let mut renderer = self.renderer.borrow_mut();
+        let mut state = self.state.lock().unwrap();
+        let should_transition = !instant.unwrap_or(false) && state.can_transition();
+        state.set_scene(spheres)?;
+        // the accumulated frames still hold the old scene until the next render
+        match renderer.as_mut() {
+            Some(renderer) if should_transition => {
+                renderer.capture_transition_frame(&state);
+                state.start_scene_transition(dom::window().performance().unwrap().now());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Sets how long (in seconds) `load_scene_preset` takes to cross-fade between scenes.
+    /// 0 always switches instantly
+    pub fn set_transition_duration(&self, seconds: f64) {
+        self.state
+            .lock()
+            .unwrap()
+            .set_transition_duration(seconds * 1000.);
+    }
+
+    /// Whether the accumulated image has stopped changing: true once the mean absolute
+    /// difference per color channel (0->255) since the previous call falls below `threshold`.
+    /// Call once per frame (or less often) to wait for a render to settle before saving it.
+    /// Always false while frames aren't being averaged
+    pub fn is_converged(&self, threshold: f64) -> Result<bool, JsValue> {
+        let renderer = self.renderer.borrow();
+        let renderer = renderer
+            .as_ref()
+            .ok_or("Cannot check convergence before the renderer has been initialized")?;
+        let mut state = self.state.lock().unwrap();
+        if !state.should_average {
+            return Ok(false);
+        }
+        let pixels = webgl::read_accumulated_pixels(renderer, &state);
+        let render_count = state.render_count;
+        let change = state.convergence_check.update(pixels, render_count);
+        Ok(change.is_some_and(|change| change < threshold))
+    }
+
+    /// Renders a fresh frame offscreen at `aspect_ratio` (width / height) and downloads it
+    /// as a png, without changing the live view. The image keeps the current render height,
+    /// and the camera's horizontal extent expands or contracts to match, so nothing is cropped.
+    /// Fails if that would be wider than the GPU's maximum texture size
+    pub fn save_image_with_aspect(&self, aspect_ratio: f64) -> Result<(), JsValue> {
+        let renderer = self.renderer.borrow();
+        let renderer = renderer
+            .as_ref()
+            .ok_or("Cannot save an image before the renderer has been initialized")?;
+        let mut state = self.state.lock().unwrap();
+        let (width, height) =
+            state::export_dimensions(state.height, aspect_ratio, renderer.max_texture_size)?;
+        let mut pixels = webgl::render_to_bytes(renderer, &mut state, width, height);
+        dom::download_pixels(&mut pixels, width, height, "canvas.png")
+    }
+
+    /// Renders a depth map of the current view offscreen and downloads it as a grayscale png
+    /// (e.g. for compositing or training data). Each pixel is the distance from the camera to
+    /// the first surface seen through its center, measured along the ray (not along the view
+    /// direction) and without depth of field, as `255 * min(distance / far, 1)`. So black is at
+    /// the camera and white is `far` (20 by default) or more away, which includes the sky
+    pub fn save_depth_image(&self, far: Option<f64>) -> Result<(), JsValue> {
+        let far = far.unwrap_or(state::DEFAULT_DEPTH_IMAGE_FAR);
+        if !(far.is_finite() && far > 0.) {
+            return Err(format!("Invalid far distance: {far}").into());
+        }
+        let renderer = self.renderer.borrow();
+        let renderer = renderer
+            .as_ref()
+            .ok_or("Cannot save a depth image before the renderer has been initialized")?;
+        let mut state = self.state.lock().unwrap();
+        let mut pixels = webgl::render_depth_to_bytes(renderer, &mut state, far);
+        dom::download_pixels(&mut pixels, state.width, state.height, "depth.png")
+    }
+
+    /// Renders a clean `width` x `height` thumbnail (up to 2048 x 2048) with `samples` samples
+    /// per pixel (split across several passes when there are many) and returns it as a png
+    /// data URL.
+    /// The live canvas is left untouched, though its accumulation restarts afterwards
+    pub fn render_preview(&self, width: u32, height: u32, samples: u32) -> Result<String, JsValue> {
+        let size_range = 1..=state::MAX_PREVIEW_SIZE;
+        if !(size_range.contains(&width) && size_range.contains(&height)) {
+            return Err(format!("Invalid preview size: {width}x{height}").into());
+        }
+        let renderer = self.renderer.borrow();
+        let renderer = renderer
+            .as_ref()
+            .ok_or("Cannot render a preview before the renderer has been initialized")?;
+        let mut state = self.state.lock().unwrap();
+
+        // every pass renders exactly the requested samples
+        let live_settings = (
+            state.samples_per_pixel,
+            state.frame_budget_ms,
+            state.boost_samples_when_paused,
+        );
+        let (samples_per_pass, passes) = state::preview_passes(samples);
+        state.samples_per_pixel = samples_per_pass;
+        state.frame_budget_ms = None;
+        state.boost_samples_when_paused = false;
+        let mut pixels = webgl::render_passes_to_bytes(renderer, &mut state, width, height, passes);
+        (
+            state.samples_per_pixel,
+            state.frame_budget_ms,
+            state.boost_samples_when_paused,
+        ) = live_settings;
+
+        dom::pixels_to_data_url(&mut pixels, width, height)
+    }
+
+    /// Returns the scene as `{ spheres: [{ center: [x, y, z], radius, material, parent }] }`
+    /// (with `visible: false` on hidden spheres), in the same format `load_scene` accepts
+    pub fn get_scene(&self) -> Result<JsValue, JsValue> {
+        scene_to_js(&self.state.lock().unwrap().sphere_list)
+    }
+
+    /// Saves the scene, camera, and render settings to localStorage, so they survive a reload.
+    /// Fails if localStorage is unavailable or full
+    pub fn save_state_to_local_storage(&self) -> Result<(), JsValue> {
+        let saved_state = app_state_to_js(&self.state.lock().unwrap())?;
+        let json = js_sys::JSON::stringify(&saved_state)?
+            .as_string()
+            .ok_or("Couldn't serialize state")?;
+        dom::local_storage()?
+            .set_item(LOCAL_STORAGE_KEY, &json)
+            .map_err(|error| format!("Couldn't save state to localStorage: {error:?}").into())
+    }
+
+    /// Restores whatever was saved by `save_state_to_local_storage` (which is also restored
+    /// on startup when the canvas has a `data-restore-state` attribute). Returns whether any
+    /// state was restored: corrupt saved state is discarded, leaving the current state in place
+    pub fn load_state_from_local_storage(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match load_state_from_local_storage(&mut state) {
+            Ok(was_restored) => was_restored,
+            Err(error) => {
+                log::warn!("Discarding invalid saved state: {error:?}");
+                if let Ok(storage) = dom::local_storage() {
+                    let _ = storage.remove_item(LOCAL_STORAGE_KEY);
+                }
+                false
+            }
+        }
+    }
+
+    /// Renders a fresh frame with a fixed random seed and returns the mean-squared-error
+    /// between its RGBA pixels and the supplied reference pixels (useful for regression tests)
+    pub fn compare_to_reference(&self, reference: &[u8]) -> Result<f64, JsValue> {
+        let renderer = self.renderer.borrow();
+        let renderer = renderer
+            .as_ref()
+            .ok_or("Cannot compare to reference before the renderer has been initialized")?;
+        let mut state = self.state.lock().unwrap();
+        let pixels = webgl::render_reference_frame(renderer, &mut state);
+        Ok(math::mean_squared_error(&pixels, reference)?)
+    }
+
+    /// Returns an object of approximate render statistics:
+    /// `{ raysPerFrame, lastFrameTime, accumulatedSamples, raysPerSecond, isGpuTimed }`,
+    /// where `raysPerSecond` is measured on the GPU when the browser supports timer queries
+    /// (`isGpuTimed`), and otherwise estimated from the time between frames
+    pub fn get_render_stats(&self) -> Result<JsValue, JsValue> {
+        let stats = self.state.lock().unwrap().render_stats();
+        let object = js_sys::Object::new();
+        js_sys::Reflect::set(
+            &object,
+            &"raysPerFrame".into(),
+            &stats.rays_per_frame.into(),
+        )?;
+        js_sys::Reflect::set(
+            &object,
+            &"lastFrameTime".into(),
+            &stats.last_frame_time.into(),
+        )?;
+        js_sys::Reflect::set(
+            &object,
+            &"accumulatedSamples".into(),
+            &stats.accumulated_samples.into(),
+        )?;
+        js_sys::Reflect::set(
+            &object,
+            &"raysPerSecond".into(),
+            &stats.rays_per_second.into(),
+        )?;
+        js_sys::Reflect::set(&object, &"isGpuTimed".into(), &stats.is_gpu_timed.into())?;
+        Ok(object.into())
+    }
+
+    /// Returns the internals of frame accumulation, for debugging:
+    /// `{ evenOddCount, renderCount, frameCount, writeTexture, readTexture }`, where
+    /// `writeTexture` is the texture (and framebuffer) the latest frame was rendered into
+    /// and `readTexture` is the one holding the frame before it
+    pub fn get_accumulation_debug(&self) -> Result<JsValue, JsValue> {
+        let state = self.state.lock().unwrap();
+        let frame_count = match self.renderer.borrow().as_ref() {
+            Some(renderer) => renderer.textures.len() as u32,
+            None => state.accumulation_frame_count,
+        };
+        let (write_index, read_index) =
+            webgl::accumulation_indices(state.even_odd_count, frame_count);
+        let object = js_sys::Object::new();
+        js_sys::Reflect::set(
+            &object,
+            &"evenOddCount".into(),
+            &state.even_odd_count.into(),
+        )?;
+        js_sys::Reflect::set(&object, &"renderCount".into(), &state.render_count.into())?;
+        js_sys::Reflect::set(&object, &"frameCount".into(), &frame_count.into())?;
+        js_sys::Reflect::set(&object, &"writeTexture".into(), &write_index.into())?;
+        js_sys::Reflect::set(&object, &"readTexture".into(), &read_index.into())?;
+        Ok(object.into())
+    }
+
+    /// Returns the box containing every object in the scene as
+    /// `{ min: [x, y, z], max: [x, y, z] }`, or null when the scene is empty
+    pub fn get_scene_bounds(&self) -> Result<JsValue, JsValue> {
+        let state = self.state.lock().unwrap();
+        let bounds = match state.scene_bounds() {
+            Some(bounds) => bounds,
+            None => return Ok(JsValue::NULL),
+        };
+        let object = js_sys::Object::new();
+        js_sys::Reflect::set(&object, &"min".into(), &vec3_to_array(&bounds.min))?;
+        js_sys::Reflect::set(&object, &"max".into(), &vec3_to_array(&bounds.max))?;
+        Ok(object.into())
+    }
+
+    /// Casts a ray from the camera through the viewport at (u, v), where both range from 0->1
+    /// starting in the bottom-left corner. Returns `{ hit: false }` on a miss, otherwise
+    /// `{ hit: true, uuid, t, point: [x, y, z], normal: [x, y, z], frontFace, blendWeight }`,
+    /// where the normal always faces the camera (`frontFace` is false when hitting an object from
+    /// behind), and `blendWeight` is how much of a sphere's `material2` shows at the point (0->1)
+    pub fn raycast(&self, u: f64, v: f64) -> Result<JsValue, JsValue> {
+        let hit_result = glsl::get_hit_at(&self.state.lock().unwrap(), u, v);
+        let object = js_sys::Object::new();
+        match hit_result {
+            HitResult::NoHit => {
+                js_sys::Reflect::set(&object, &"hit".into(), &false.into())?;
+            }
+            HitResult::Hit { data } => {
+                js_sys::Reflect::set(&object, &"hit".into(), &true.into())?;
+                js_sys::Reflect::set(&object, &"uuid".into(), &data.uuid.into())?;
+                js_sys::Reflect::set(&object, &"t".into(), &data.t.into())?;
+                js_sys::Reflect::set(&object, &"point".into(), &vec3_to_array(&data.hit_point))?;
+                js_sys::Reflect::set(&object, &"normal".into(), &vec3_to_array(&data.normal))?;
+                js_sys::Reflect::set(&object, &"frontFace".into(), &data.front_face.into())?;
+                js_sys::Reflect::set(&object, &"blendWeight".into(), &data.blend_weight.into())?;
+            }
+        }
+        Ok(object.into())
+    }
+}
+
+/// Applies any of `{ type, r, g, b, fuzz, refraction_index, emission, display_only }`
+/// to a material
+fn update_material_from_js(material: &mut Material, value: &JsValue) -> Result<(), JsValue> {
+    if let Some(material_type) = number_field(value, "type")? {
+        material.material_type = MaterialType::try_from(material_type as i32)?;
+    }
+    material.albedo = Vec3(
+        number_field(value, "r")?.unwrap_or(material.albedo.x()),
+        number_field(value, "g")?.unwrap_or(material.albedo.y()),
+        number_field(value, "b")?.unwrap_or(material.albedo.z()),
+    );
+    if let Some(fuzz) = number_field(value, "fuzz")? {
+        material.set_fuzz(fuzz as f32);
+    }
+    if let Some(refraction_index) = number_field(value, "refraction_index")? {
+        material.refraction_index = refraction_index as f32;
+    }
+    if let Some(absorption) = vec3_field(value, "absorption")? {
+        material.absorption = absorption;
+    }
+    if let Some(emission) = vec3_field(value, "emission")? {
+        material.emission = emission;
+    }
+    if let Some(display_only) = bool_field(value, "display_only")? {
+        material.display_only = display_only;
+    }
+    Ok(())
+}
+
+/// The inverse of `update_material_from_js`
+fn material_to_js(material: &Material) -> Result<JsValue, JsValue> {
+    let object = js_sys::Object::new();
+    let albedo = &material.albedo;
+    js_sys::Reflect::set(
+        &object,
+        &"type".into(),
+        &material.material_type.value().into(),
+    )?;
+    js_sys::Reflect::set(&object, &"r".into(), &albedo.x().into())?;
+    js_sys::Reflect::set(&object, &"g".into(), &albedo.y().into())?;
+    js_sys::Reflect::set(&object, &"b".into(), &albedo.z().into())?;
+    js_sys::Reflect::set(&object, &"fuzz".into(), &material.fuzz.into())?;
+    js_sys::Reflect::set(
+        &object,
+        &"refraction_index".into(),
+        &material.refraction_index.into(),
+    )?;
+    js_sys::Reflect::set(
+        &object,
+        &"absorption".into(),
+        &vec3_to_array(&material.absorption),
+    )?;
+    js_sys::Reflect::set(
+        &object,
+        &"emission".into(),
+        &vec3_to_array(&material.emission),
+    )?;
+    js_sys::Reflect::set(
+        &object,
+        &"display_only".into(),
+        &material.display_only.into(),
+    )?;
+    Ok(object.into())
+}
+
+/// The inverse of `scene_from_js`
+fn scene_to_js(spheres: &[Sphere]) -> Result<JsValue, JsValue> {
+    let sphere_values = js_sys::Array::new();
+    for sphere in spheres {
+        let object = js_sys::Object::new();
+        js_sys::Reflect::set(&object, &"center".into(), &vec3_to_array(&sphere.center))?;
+        js_sys::Reflect::set(&object, &"radius".into(), &sphere.radius.into())?;
+        js_sys::Reflect::set(
+            &object,
+            &"material".into(),
+            &material_to_js(&sphere.material)?,
+        )?;
+        if let Some(parent_uuid) = sphere.parent_uuid {
+            // uuids are reassigned from indices when loading, so save the parent's index
+            let parent_index = spheres
+                .iter()
+                .position(|parent| parent.uuid == parent_uuid)
+                .ok_or_else(|| format!("No parent sphere found with uuid {parent_uuid}"))?;
+            js_sys::Reflect::set(&object, &"parent".into(), &parent_index.into())?;
+        }
+        if let Some(material2) = &sphere.material2 {
+            js_sys::Reflect::set(&object, &"material2".into(), &material_to_js(material2)?)?;
+            js_sys::Reflect::set(&object, &"blend_scale".into(), &sphere.blend_scale.into())?;
+        }
+        // only hidden spheres are marked, since spheres are visible by default
+        if !sphere.visible {
+            js_sys::Reflect::set(&object, &"visible".into(), &false.into())?;
+        }
+        sphere_values.push(&object);
+    }
+    let scene = js_sys::Object::new();
+    js_sys::Reflect::set(&scene, &"spheres".into(), &sphere_values)?;
+    Ok(scene.into())
+}
+
+/// Parses `{ spheres: [{ center: [x, y, z], radius, material, parent, visible, material2,
+/// blend_scale }] }`, where `parent` is the (optional) index of another sphere that `center`
+/// is relative to, and `visible` defaults to true. `material2` (optional) is blended in with
+/// noise `blend_scale` (1 by default) units across, and is based on `material`, so it only
+/// needs the fields that differ
+fn scene_from_js(scene: &JsValue) -> Result<Vec<Sphere>, JsValue> {
+    let spheres = js_sys::Reflect::get(scene, &"spheres".into())?;
+    if !js_sys::Array::is_array(&spheres) {
+        return Err("Expected \"spheres\" to be an array".into());
+    }
+    js_sys::Array::from(&spheres)
+        .iter()
+        .map(|sphere| {
+            let center = vec3_field(&sphere, "center")?.ok_or("Missing sphere \"center\"")?;
+            let radius = number_field(&sphere, "radius")?.ok_or("Missing sphere \"radius\"")?;
+            // spheres' uuids are their indices in the scene
+            let parent = number_field(&sphere, "parent")?;
+            let visible = bool_field(&sphere, "visible")?.unwrap_or(true);
+            let material = material_field(&sphere)?;
+            let material2_value = js_sys::Reflect::get(&sphere, &"material2".into())?;
+            let material2 = if material2_value.is_undefined() {
+                None
+            } else {
+                let mut material2 = material.clone();
+                update_material_from_js(&mut material2, &material2_value)?;
+                Some(material2)
+            };
+            let blend_scale = number_field(&sphere, "blend_scale")?.unwrap_or(1.);
+            Ok(Sphere {
+                center,
+                radius,
+                material,
+                uuid: 0,
+                parent_uuid: parent.map(|parent| parent as i32),
+                visible,
+                material2,
+                blend_scale,
+            })
+        })
+        .collect()
+}
+
+/// Reads an object's optional `material` property (grey diffuse when missing)
+fn material_field(object: &JsValue) -> Result<Material, JsValue> {
+    let mut material = Material::default();
+    let material_value = js_sys::Reflect::get(object, &"material".into())?;
+    if !material_value.is_undefined() {
+        update_material_from_js(&mut material, &material_value)?;
+    }
+    Ok(material)
+}
+
+/// Parses `[{ plane, a0, a1, b0, b1, k, material, flip_normal, double_sided }]`
+fn rects_from_js(rects: &JsValue) -> Result<Vec<Rect>, JsValue> {
+    if !js_sys::Array::is_array(rects) {
+        return Err("Expected rect lights to be an array".into());
+    }
+    js_sys::Array::from(rects)
+        .iter()
+        .map(|rect| {
+            let plane = number_field(&rect, "plane")?.ok_or("Missing rect \"plane\"")?;
+            let bound = |key: &str| {
+                number_field(&rect, key)?
+                    .ok_or_else(|| JsValue::from(format!("Missing rect \"{key}\"")))
+            };
+            Ok(Rect {
+                plane: RectPlane::try_from(plane as i32)?,
+                a0: bound("a0")?,
+                a1: bound("a1")?,
+                b0: bound("b0")?,
+                b1: bound("b1")?,
+                k: bound("k")?,
+                material: material_field(&rect)?,
+                uuid: 0,
+                flip_normal: bool_field(&rect, "flip_normal")?.unwrap_or(false),
+                double_sided: bool_field(&rect, "double_sided")?.unwrap_or(false),
+            })
+        })
+        .collect()
+}
+
+/// The inverse of `rects_from_js`
+fn rects_to_js(rects: &[Rect]) -> Result<JsValue, JsValue> {
+    let rect_values = js_sys::Array::new();
+    for rect in rects {
+        let object = js_sys::Object::new();
+        js_sys::Reflect::set(&object, &"plane".into(), &rect.plane.value().into())?;
+        for (key, bound) in [
+            ("a0", rect.a0),
+            ("a1", rect.a1),
+            ("b0", rect.b0),
+            ("b1", rect.b1),
+            ("k", rect.k),
+        ] {
+            js_sys::Reflect::set(&object, &key.into(), &bound.into())?;
+        }
+        js_sys::Reflect::set(
+            &object,
+            &"material".into(),
+            &material_to_js(&rect.material)?,
+        )?;
+        js_sys::Reflect::set(&object, &"flip_normal".into(), &rect.flip_normal.into())?;
+        js_sys::Reflect::set(&object, &"double_sided".into(), &rect.double_sided.into())?;
+        rect_values.push(&object);
+    }
+    Ok(rect_values.into())
+}
+
+/// Reads the canvas's `data-scene` attribute as either a preset name or base64-encoded JSON
+fn scene_from_attribute(attribute: &str) -> Result<Vec<Sphere>, JsValue> {
+    if let Ok(spheres) = state::scene_preset(attribute.trim()) {
+        return Ok(spheres);
+    }
+    let json = dom::decode_base64(attribute)?;
+    scene_from_js(&js_sys::JSON::parse(&json)?)
+}
+
+/// Reads an optional `[x, y, z]` property from a JS object
+fn vec3_field(object: &JsValue, key: &str) -> Result<Option<Vec3>, JsValue> {
+    let value = js_sys::Reflect::get(object, &key.into())?;
+    if value.is_undefined() {
+        return Ok(None);
+    }
+    let error = || format!("Expected \"{key}\" to be an array of 3 finite numbers");
+    if !js_sys::Array::is_array(&value) {
+        return Err(error().into());
+    }
+    let components = js_sys::Array::from(&value)
+        .iter()
+        .map(|component| component.as_f64().filter(|component| component.is_finite()))
+        .collect::<Option<Vec<f64>>>()
+        .ok_or_else(error)?;
+    match components[..] {
+        [x, y, z] => Ok(Some(Vec3(x, y, z))),
+        _ => Err(error().into()),
+    }
+}
+
+/// Serializes the scene (in the same format as `get_scene`), camera, and render settings as
+/// `{ scene, camera: { origin, world_up, yaw, pitch, roll, fov, focus_distance },
+/// quality: { samples_per_pixel, max_depth, should_average, render_scale }, exposure }`
+fn app_state_to_js(state: &State) -> Result<JsValue, JsValue> {
+    let camera = state.camera_snapshot();
+    let camera_value = js_sys::Object::new();
+    js_sys::Reflect::set(
+        &camera_value,
+        &"origin".into(),
+        &vec3_to_array(&camera.camera_origin),
+    )?;
+    js_sys::Reflect::set(
+        &camera_value,
+        &"world_up".into(),
+        &vec3_to_array(&camera.world_up),
+    )?;
+    js_sys::Reflect::set(&camera_value, &"yaw".into(), &camera.yaw.into())?;
+    js_sys::Reflect::set(&camera_value, &"pitch".into(), &camera.pitch.into())?;
+    js_sys::Reflect::set(&camera_value, &"roll".into(), &camera.roll.into())?;
+    js_sys::Reflect::set(
+        &camera_value,
+        &"fov".into(),
+        &camera.camera_field_of_view.into(),
+    )?;
+    js_sys::Reflect::set(
+        &camera_value,
+        &"focus_distance".into(),
+        &camera.focus_distance.into(),
+    )?;
+
+    let quality = state.quality_settings();
+    let quality_value = js_sys::Object::new();
+    js_sys::Reflect::set(
+        &quality_value,
+        &"samples_per_pixel".into(),
+        &quality.samples_per_pixel.into(),
+    )?;
+    js_sys::Reflect::set(
+        &quality_value,
+        &"max_depth".into(),
+        &quality.max_depth.into(),
+    )?;
+    js_sys::Reflect::set(
+        &quality_value,
+        &"should_average".into(),
+        &quality.should_average.into(),
+    )?;
+    js_sys::Reflect::set(
+        &quality_value,
+        &"render_scale".into(),
+        &quality.render_scale.into(),
+    )?;
+
+    let object = js_sys::Object::new();
+    js_sys::Reflect::set(&object, &"scene".into(), &scene_to_js(&state.sphere_list)?)?;
+    js_sys::Reflect::set(&object, &"camera".into(), &camera_value)?;
+    js_sys::Reflect::set(&object, &"quality".into(), &quality_value)?;
+    js_sys::Reflect::set(&object, &"exposure".into(), &state.exposure.into())?;
+    Ok(object.into())
+}
+
+/// Applies state serialized by `app_state_to_js`. Everything is parsed before anything is
+/// applied, so that invalid data leaves the state untouched
+fn apply_app_state_from_js(state: &mut State, value: &JsValue) -> Result<(), JsValue> {
+    let spheres = scene_from_js(&js_sys::Reflect::get(value, &"scene".into())?)?;
+
+    let camera_value = js_sys::Reflect::get(value, &"camera".into())?;
+    let missing = |key: &str| format!("Missing saved \"{key}\"");
+    let camera = state::CameraSnapshot {
+        camera_origin: vec3_field(&camera_value, "origin")?.ok_or_else(|| missing("origin"))?,
+        world_up: vec3_field(&camera_value, "world_up")?.ok_or_else(|| missing("world_up"))?,
+        yaw: number_field(&camera_value, "yaw")?.ok_or_else(|| missing("yaw"))?,
+        pitch: number_field(&camera_value, "pitch")?.ok_or_else(|| missing("pitch"))?,
+        roll: number_field(&camera_value, "roll")?.ok_or_else(|| missing("roll"))?,
+        camera_field_of_view: number_field(&camera_value, "fov")?.ok_or_else(|| missing("fov"))?,
+        focus_distance: number_field(&camera_value, "focus_distance")?
+            .ok_or_else(|| missing("focus_distance"))?,
+    };
+
+    let quality_value = js_sys::Reflect::get(value, &"quality".into())?;
+    let quality = state::QualitySettings {
+        samples_per_pixel: number_field(&quality_value, "samples_per_pixel")?
+            .ok_or_else(|| missing("samples_per_pixel"))?
+            .max(1.) as u32,
+        max_depth: number_field(&quality_value, "max_depth")?
+            .ok_or_else(|| missing("max_depth"))?
+            .max(1.) as u32,
+        should_average: bool_field(&quality_value, "should_average")?
+            .ok_or_else(|| missing("should_average"))?,
+        render_scale: number_field(&quality_value, "render_scale")?
+            .ok_or_else(|| missing("render_scale"))?,
+    };
+    let exposure = number_field(value, "exposure")?.ok_or_else(|| missing("exposure"))?;
+
+    state.set_scene(spheres)?;
+    state.restore_camera(&camera);
+    state.set_quality_settings(&quality);
+    state.set_exposure(exposure);
     Ok(())
 }
+
+/// Restores state saved by `save_state_to_local_storage`.
+/// Returns whether there was any saved state to restore
+fn load_state_from_local_storage(state: &mut State) -> Result<bool, JsValue> {
+    let json = match dom::local_storage()?.get_item(LOCAL_STORAGE_KEY)? {
+        Some(json) => json,
+        None => return Ok(false),
+    };
+    apply_app_state_from_js(state, &js_sys::JSON::parse(&json)?)?;
+    Ok(true)
+}
+
+/// Reads an optional boolean property from a JS object
+fn bool_field(object: &JsValue, key: &str) -> Result<Option<bool>, JsValue> {
+    let value = js_sys::Reflect::get(object, &key.into())?;
+    if value.is_undefined() {
+        return Ok(None);
+    }
+    value
+        .as_bool()
+        .map(Some)
+        .ok_or_else(|| format!("Expected \"{key}\" to be a boolean").into())
+}
+
+/// Reads an optional numeric property from a JS object
+fn number_field(object: &JsValue, key: &str) -> Result<Option<f64>, JsValue> {
+    let value = js_sys::Reflect::get(object, &key.into())?;
+    if value.is_undefined() {
+        return Ok(None);
+    }
+    value
+        .as_f64()
+        .filter(|number| number.is_finite())
+        .map(Some)
+        .ok_or_else(|| format!("Expected \"{key}\" to be a finite number").into())
+}
+
+fn vec3_to_array(vec3: &Vec3) -> js_sys::Array {
+    [vec3.x(), vec3.y(), vec3.z()]
+        .iter()
+        .map(|component| JsValue::from(*component))
+        .collect()
+}
+
+/// The original name of the `App` handle, kept so that existing `new RayTracer(canvasId)`
+/// callers keep working. Use `into_app` for the rest of the API
+#[wasm_bindgen]
+pub struct RayTracer {
+    app: App,
+}
+
+#[wasm_bindgen]
+impl RayTracer {
+    /// Starts a ray tracer rendering to the canvas with the given id
+    #[wasm_bindgen(constructor)]
+    pub fn new(canvas_id: &str) -> Result<RayTracer, JsValue> {
+        Ok(RayTracer {
+            app: App::new(canvas_id)?,
+        })
+    }
+
+    /// See `App::set_last_frame_weight`
+    pub fn set_last_frame_weight(&self, value: f32) {
+        self.app.set_last_frame_weight(value);
+    }
+
+    /// See `App::set_should_average`
+    pub fn set_should_average(&self, value: bool) {
+        self.app.set_should_average(value);
+    }
+
+    /// See `App::set_still_frame_threshold`
+    pub fn set_still_frame_threshold(&self, value: u32) {
+        self.app.set_still_frame_threshold(value);
+    }
+
+    /// See `App::get_render_stats`
+    pub fn get_render_stats(&self) -> Result<JsValue, JsValue> {
+        self.app.get_render_stats()
+    }
+
+    /// Converts this into the `App` handle it wraps (this object can't be used afterwards)
+    pub fn into_app(self) -> App {
+        self.app
+    }
+}
+
+impl App {
+    /// `options` are applied before the first render (see `init_with_options`)
+    pub fn from_canvas(
+        canvas: HtmlCanvasElement,
+        options: Option<&JsValue>,
+    ) -> Result<Self, JsValue> {
+        init_once();
+
+        let mut initial_state = State::default();
+        if let Some(attribute) = dom::scene_attribute(&canvas) {
+            let scene = scene_from_attribute(&attribute)
+                .and_then(|spheres| Ok(initial_state.set_scene(spheres)?));
+            if let Err(error) = scene {
+                log::warn!("Ignoring invalid data-scene attribute: {error:?}");
+            }
+        }
+        if let Some(options) = options {
+            apply_startup_options(&mut initial_state, options);
+        }
+        if dom::should_restore_state(&canvas) {
+            // anything that fails to load leaves the defaults in place
+            if let Err(error) = load_state_from_local_storage(&mut initial_state) {
+                log::warn!("Ignoring invalid saved state: {error:?}");
+            }
+        }
+        let state = Arc::new(Mutex::new(initial_state));
+        let renderer = Rc::new(RefCell::new(None));
+        let render_loop = Rc::new(RefCell::new(RenderLoop::default()));
+        let listeners = dom::add_listeners(&state, &canvas)?;
+        let crosshair = dom::create_crosshair()?;
+        let measurement_overlay = Rc::new(dom::MeasurementOverlay::create()?);
+        let profiler = dom::create_profiler()?;
+        {
+            let state = state.clone();
+            let crosshair = crosshair.clone();
+            let measurement_overlay = measurement_overlay.clone();
+            let profiler = profiler.clone();
+            let renderer = renderer.clone();
+            let render_loop = render_loop.clone();
+            spawn_local(async move {
+                run(
+                    state,
+                    canvas,
+                    crosshair,
+                    measurement_overlay,
+                    profiler,
+                    renderer,
+                    render_loop,
+                )
+                .await
+                .unwrap();
+            });
+        }
+
+        Ok(App {
+            state,
+            renderer,
+            render_loop,
+            listeners,
+            crosshair,
+            measurement_overlay,
+            profiler,
+        })
+    }
+}
+
+/// Convenience entry point for single-instance pages: renders to the first canvas in the document
+#[wasm_bindgen]
+pub fn main() -> Result<App, JsValue> {
+    App::from_canvas(dom::canvas(), None)
+}
+
+/// Like `main`, but starts from `{ camera: { position: [x, y, z], look_at: [x, y, z], fov },
+/// quality }` instead of the defaults, so that the first frame already shows the desired view.
+/// `fov` is the vertical field of view in degrees, and `quality` is the name of a quality preset
+/// ("low", "medium", "high", or "ultra"). Every option is optional, and any invalid option
+/// is logged and skipped, keeping its default
+#[wasm_bindgen]
+pub fn init_with_options(options: JsValue) -> Result<App, JsValue> {
+    App::from_canvas(dom::canvas(), Some(&options))
+}
+
+/// Applies the options accepted by `init_with_options`, each one independently of the others
+fn apply_startup_options(state: &mut State, options: &JsValue) {
+    let warn = |key: &str, error: JsValue| {
+        log::warn!("Ignoring invalid startup option \"{key}\": {error:?}");
+    };
+    if !options.is_object() {
+        return warn("options", "Expected an object".into());
+    }
+
+    let camera = js_sys::Reflect::get(options, &"camera".into()).unwrap_or_default();
+    if camera.is_object() {
+        match vec3_field(&camera, "position") {
+            Ok(Some(position)) => state.set_camera_origin(position),
+            Ok(None) => {}
+            Err(error) => warn("camera.position", error),
+        }
+        match vec3_field(&camera, "look_at") {
+            Ok(Some(target)) => {
+                if let Err(error) = state.look_at(&target) {
+                    warn("camera.look_at", error.into());
+                }
+            }
+            Ok(None) => {}
+            Err(error) => warn("camera.look_at", error),
+        }
+        match number_field(&camera, "fov") {
+            Ok(Some(fov)) => state.set_fov(fov.to_radians()),
+            Ok(None) => {}
+            Err(error) => warn("camera.fov", error),
+        }
+    } else if !camera.is_undefined() {
+        warn("camera", "Expected an object".into());
+    }
+
+    let quality = js_sys::Reflect::get(options, &"quality".into()).unwrap_or_default();
+    if let Some(quality) = quality.as_string() {
+        match quality.parse::<QualityPreset>() {
+            Ok(preset) => state.set_quality_preset(preset),
+            Err(error) => warn("quality", error.into()),
+        }
+    } else if !quality.is_undefined() {
+        warn("quality", "Expected the name of a quality preset".into());
+    }
+}
@@ -49,6 +49,10 @@ impl Vec3 {
         self.length_squared().sqrt()
     }
 
+    pub fn distance(a: &Vec3, b: &Vec3) -> f64 {
+        (a - b).length()
+    }
+
     pub fn length_squared(&self) -> f64 {
         self.0.powi(2) + self.1.powi(2) + self.2.powi(2)
     }
@@ -74,6 +78,11 @@ impl Vec3 {
         (1. + self.clone()) * 0.5
     }
 
+    /// linear interpolation from a (t = 0) to b (t = 1)
+    pub fn lerp(a: &Vec3, b: &Vec3, t: f64) -> Vec3 {
+        a + (b - a) * t
+    }
+
     pub fn random_with_range(min: f64, max: f64) -> Self {
         Vec3(
             math::random_with_range(min, max),
@@ -99,6 +108,23 @@ impl Vec3 {
         Vec3(x, y, z)
     }
 
+    /// INSIDE the unit disk in the xy plane, uniformly distributed (z = 0)
+    pub fn random_in_unit_disk() -> Vec3 {
+        Vec3::random_in_aperture(0)
+    }
+
+    /// Mirrors the lens sampling in the shader's `random_in_aperture`:
+    /// INSIDE the unit disk for fewer than 3 blades, otherwise inside the regular polygon
+    /// (with that many sides) inscribed in it, uniformly distributed (z = 0)
+    pub fn random_in_aperture(blade_count: u32) -> Vec3 {
+        math::aperture_point(
+            js_sys::Math::random(),
+            js_sys::Math::random(),
+            js_sys::Math::random(),
+            blade_count,
+        )
+    }
+
     /// along the EDGE of the unit sphere
     pub fn random_unit_vector() -> Vec3 {
         Vec3::normalize(Vec3::random_point_in_unit_sphere())
@@ -377,6 +403,170 @@ pub fn degrees_to_radians(degrees: f64) -> f64 {
     (degrees * PI) / 180.
 }
 
+/// Maps three uniform random numbers (0->1) to a uniformly distributed point in a camera
+/// aperture (see `Vec3::random_in_aperture`). Polygons are split into one triangle per blade,
+/// with the first corner pointing straight up
+pub fn aperture_point(u: f64, v: f64, w: f64, blade_count: u32) -> Vec3 {
+    // the square root keeps points from bunching up toward the center
+    let r = u.sqrt();
+    if blade_count < 3 {
+        let angle = 2. * PI * v;
+        return Vec3(r * angle.cos(), r * angle.sin(), 0.);
+    }
+    let blade_angle = 2. * PI / blade_count as f64;
+    let blade = (w * blade_count as f64)
+        .floor()
+        .min(blade_count as f64 - 1.);
+    let start_angle = PI / 2. + blade * blade_angle;
+    let start = Vec3(start_angle.cos(), start_angle.sin(), 0.);
+    let end_angle = start_angle + blade_angle;
+    let end = Vec3(end_angle.cos(), end_angle.sin(), 0.);
+    Vec3::lerp(&start, &end, v) * r
+}
+
 pub fn random_with_range(min: f64, max: f64) -> f64 {
     min + (max - min) * js_sys::Math::random()
 }
+
+/// Geometric mean of the luminance of gamma-corrected (gamma 2) RGBA pixels
+/// A small delta keeps pure black pixels from pulling the average down to 0
+pub fn log_average_luminance(pixels: &[u8]) -> f64 {
+    const DELTA: f64 = 1e-4;
+    let pixel_count = pixels.len() / 4;
+    if pixel_count == 0 {
+        return 0.;
+    }
+
+    let sum_of_logs: f64 = pixels
+        .chunks_exact(4)
+        .map(|pixel| {
+            let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(|channel| {
+                let gamma_corrected = channel as f64 / 255.;
+                gamma_corrected * gamma_corrected
+            });
+            let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            (DELTA + luminance).ln()
+        })
+        .sum();
+
+    (sum_of_logs / pixel_count as f64).exp()
+}
+
+/// Averages same-sized images together, byte by byte, keeping a running sum
+/// rather than every image
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ImageAverage {
+    sums: Vec<u32>,
+    count: u32,
+}
+
+impl ImageAverage {
+    pub fn add(&mut self, image: &[u8]) -> Result<(), String> {
+        if self.count == 0 {
+            self.sums = vec![0; image.len()];
+        } else if image.len() != self.sums.len() {
+            return Err("Cannot average images of different sizes".into());
+        }
+        for (sum, &byte) in self.sums.iter_mut().zip(image) {
+            *sum += byte as u32;
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn average(&self) -> Result<Vec<u8>, String> {
+        if self.count == 0 {
+            return Err("Cannot average zero images".into());
+        }
+        let count = self.count;
+        Ok(self
+            .sums
+            .iter()
+            // rounded to the nearest value
+            .map(|&sum| ((sum + count / 2) / count) as u8)
+            .collect())
+    }
+}
+
+/// Average of the absolute differences between each pair of bytes
+pub fn mean_absolute_difference(a: &[u8], b: &[u8]) -> Result<f64, String> {
+    if a.len() != b.len() {
+        return Err(format!(
+            "Cannot compare images of different sizes ({} and {} bytes)",
+            a.len(),
+            b.len()
+        ));
+    }
+    if a.is_empty() {
+        return Ok(0.);
+    }
+
+    let sum_of_differences: f64 = a.iter().zip(b).map(|(a, b)| a.abs_diff(*b) as f64).sum();
+
+    Ok(sum_of_differences / a.len() as f64)
+}
+
+/// Average of the squared differences between each pair of bytes
+pub fn mean_squared_error(a: &[u8], b: &[u8]) -> Result<f64, String> {
+    if a.len() != b.len() {
+        return Err(format!(
+            "Cannot compare images of different sizes ({} and {} bytes)",
+            a.len(),
+            b.len()
+        ));
+    }
+    if a.is_empty() {
+        return Ok(0.);
+    }
+
+    let sum_of_squares: f64 = a
+        .iter()
+        .zip(b)
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum();
+
+    Ok(sum_of_squares / a.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_are_averaged_byte_by_byte() {
+        let mut average = ImageAverage::default();
+        assert!(average.average().is_err());
+        average.add(&[0, 255, 10]).unwrap();
+        average.add(&[255, 255, 11]).unwrap();
+        average.add(&[0, 255, 12]).unwrap();
+        assert_eq!(average.average().unwrap(), vec![85, 255, 11]);
+        assert!(average.add(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn aperture_points_stay_inside_the_aperture() {
+        let steps = [0., 0.25, 0.5, 0.999, 1.];
+        for blade_count in [0, 5, 6] {
+            // distance from the center to the middle of each polygon edge
+            let apothem = if blade_count < 3 {
+                1.
+            } else {
+                (PI / blade_count as f64).cos()
+            };
+            for u in steps {
+                for v in steps {
+                    for w in steps {
+                        let point = aperture_point(u, v, w, blade_count);
+                        assert!(point.length() <= 1. + 1e-12);
+                        if u == 1. && v == 0.5 {
+                            assert!((point.length() - apothem).abs() < 1e-12);
+                        }
+                    }
+                }
+            }
+        }
+        // the first blade's corner points straight up
+        let corner = aperture_point(1., 0., 0., 6);
+        assert!((corner.y() - 1.).abs() < 1e-12 && corner.x().abs() < 1e-12);
+    }
+}
@@ -1,13 +1,102 @@
 use crate::{
+    aabb::Aabb,
     dom,
-    glsl::{self, HitResult, Material, MaterialType, Sphere},
-    math::{degrees_to_radians, Point, Vec3},
+    glsl::{self, DiffuseMode, HitResult, Material, MaterialType, Rect, Sphere, World},
+    hdr::HdrImage,
+    math::{self, degrees_to_radians, Point, Vec3},
+    ray::Ray,
+    webgl::Renderer,
 };
-use std::{f64::consts::PI, sync::MutexGuard};
-use web_sys::{HtmlCanvasElement, WebGl2RenderingContext, WebGlTexture};
+use std::{f64::consts::PI, str::FromStr, sync::MutexGuard};
+use web_sys::HtmlCanvasElement;
 
 pub const MOVEMENT_SPEED: f64 = 0.001;
 
+/// degrees per millisecond
+pub const ROLL_SPEED: f64 = 0.05;
+
+/// longest frame (in milliseconds) that movement is integrated over, so that a long pause
+/// (refocusing the tab, garbage collection) doesn't teleport the camera
+pub const MAX_MOVEMENT_FRAME_TIME: f64 = 100.;
+
+/// how many recent (clamped) frame times movement is averaged over,
+/// so that a single slow frame only nudges the camera a little further
+pub const MOVEMENT_FRAME_TIME_SAMPLES: usize = 5;
+
+/// how long it takes the camera to fly to an object (in milliseconds)
+pub const CAMERA_ANIMATION_DURATION: f64 = 500.;
+
+/// how many radii away from a focused object the camera should end up,
+/// on top of the distance needed to fit it in the field of view
+pub const FRAMING_DISTANCE_MARGIN: f64 = 1.5;
+
+/// the most frames that can be blended together
+/// (must match `MAX_HISTORY_FRAMES` + 1 in the fragment shader)
+pub const MAX_ACCUMULATION_FRAMES: u32 = 8;
+
+/// the most 1-sample passes rendered per animation frame when using a frame budget,
+/// in case timing is too coarse to ever exceed the budget
+pub const MAX_PASSES_PER_FRAME: u32 = 64;
+
+/// height of a full-frame (35mm) camera sensor in mm, which relates focal length to field of view
+pub const SENSOR_HEIGHT: f64 = 24.;
+
+/// minimum distance along a ray that counts as a hit,
+/// so that bounced rays don't re-hit the surface they started on
+pub const DEFAULT_RAY_EPSILON: f64 = 0.001;
+
+/// the "key" (middle-grey) luminance auto-exposure aims for by default
+pub const DEFAULT_EXPOSURE_TARGET: f64 = 0.18;
+
+/// time constant (in ms) auto-exposure eases with -- roughly how long it takes to adapt
+pub const AUTO_EXPOSURE_ADAPTATION_TIME: f64 = 500.;
+
+/// time constant (in ms) autofocus eases with -- roughly how long it takes to refocus
+pub const AUTOFOCUS_ADAPTATION_TIME: f64 = 150.;
+
+/// default height of the head bob, as a fraction of the distance moved per second
+pub const DEFAULT_HEAD_BOB_AMPLITUDE: f64 = 0.02;
+
+/// default number of head bobs per second
+pub const DEFAULT_HEAD_BOB_FREQUENCY: f64 = 2.;
+
+/// default time (in ms) to cross-fade between scenes in `load_scene_preset`
+pub const DEFAULT_TRANSITION_DURATION: f64 = 500.;
+
+/// accumulated frames are scaled to fit the new size when resizing by at most this fraction
+/// in each dimension (and changing aspect ratio by at most `MAX_PRESERVED_ASPECT_CHANGE`)
+pub const MAX_PRESERVED_RESIZE: f64 = 0.25;
+pub const MAX_PRESERVED_ASPECT_CHANGE: f64 = 0.05;
+
+/// default distance between the eyes in stereo modes (in scene units)
+pub const DEFAULT_IPD: f64 = 0.064;
+
+/// how often (in ms) auto samples re-evaluates the sample count, leaving time for the
+/// moving fps average to reflect the last change (so it doesn't depend on the frame rate)
+pub const AUTO_SAMPLES_INTERVAL: f64 = 1000.;
+
+/// default frame rate auto samples aims to keep up
+pub const DEFAULT_AUTO_SAMPLES_TARGET_FPS: f64 = 30.;
+
+/// default distance (in scene units) that shows up as white in exported depth images
+pub const DEFAULT_DEPTH_IMAGE_FAR: f64 = 20.;
+
+/// keeps auto-exposure from blowing out (or crushing) nearly black (or white) scenes
+pub const MIN_EXPOSURE: f64 = 0.05;
+pub const MAX_EXPOSURE: f64 = 20.;
+
+/// fraction of the full resolution that progressive startup begins rendering at
+pub const PROGRESSIVE_STARTUP_SCALE: f64 = 0.25;
+
+/// the range of render scales spanned by the quality presets: anything higher could
+/// allocate enormous textures, and anything lower is too coarse to be useful
+pub const MIN_RENDER_SCALE: f64 = 0.5;
+pub const MAX_RENDER_SCALE: f64 = 1.;
+
+/// well past the highest quality preset, but low enough that a single frame can't hang the GPU
+pub const MAX_SAMPLES_PER_PIXEL: u32 = 64;
+pub const MAX_MAX_DEPTH: u32 = 128;
+
 /// so high that it's unlikely to be a real id of an object in the shader
 pub const NO_SELECTED_OBJECT_ID: i32 = 1000;
 
@@ -17,13 +106,343 @@ pub struct KeydownMap {
     pub a: bool,
     pub s: bool,
     pub d: bool,
+    /// Moves straight up along `world_up`, no matter which way the camera is looking
     pub space: bool,
+    /// Moves straight down along `world_up`, no matter which way the camera is looking
     pub shift: bool,
+    pub q: bool,
+    pub e: bool,
 }
 
 impl KeydownMap {
     pub fn all_false(&self) -> bool {
-        !self.w && !self.a && !self.s && !self.d && !self.space && !self.shift
+        !self.w && !self.a && !self.s && !self.d && !self.space && !self.shift && !self.q && !self.e
+    }
+
+    /// Whether any key that moves (rather than just rolls) the camera is pressed
+    pub fn is_moving(&self) -> bool {
+        self.w || self.a || self.s || self.d || self.space || self.shift
+    }
+}
+
+/// A world axis that dragged spheres can be locked to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Axis::X => "X",
+            Axis::Y => "Y",
+            Axis::Z => "Z",
+        }
+    }
+
+    /// The axis held down with the x, y, or z key
+    pub fn from_key(key: &str) -> Option<Axis> {
+        match key {
+            "x" | "X" => Some(Axis::X),
+            "y" | "Y" => Some(Axis::Y),
+            "z" | "Z" => Some(Axis::Z),
+            _ => None,
+        }
+    }
+}
+
+/// A sphere being dragged across the plane (facing the camera) that it was grabbed on
+#[derive(Clone, Debug, PartialEq)]
+pub struct SphereDrag {
+    pub uuid: i32,
+    /// Where the sphere was grabbed
+    pub grab_point: Point,
+    /// The camera's `w` when the sphere was grabbed
+    pub plane_normal: Vec3,
+    /// The sphere's center (relative to any parent) when it was grabbed
+    pub start_center: Point,
+    pub has_moved: bool,
+}
+
+/// Coherent combinations of render settings, from fastest to best looking
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QualityPreset {
+    Low,
+    Medium,
+    High,
+    Ultra,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QualitySettings {
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub should_average: bool,
+    pub render_scale: f64,
+}
+
+impl QualityPreset {
+    pub const ALL: [QualityPreset; 4] = [
+        QualityPreset::Low,
+        QualityPreset::Medium,
+        QualityPreset::High,
+        QualityPreset::Ultra,
+    ];
+
+    pub fn settings(&self) -> QualitySettings {
+        match self {
+            QualityPreset::Low => QualitySettings {
+                samples_per_pixel: 1,
+                max_depth: 4,
+                should_average: true,
+                render_scale: 0.5,
+            },
+            QualityPreset::Medium => QualitySettings {
+                samples_per_pixel: 1,
+                max_depth: 8,
+                should_average: true,
+                render_scale: 1.,
+            },
+            QualityPreset::High => QualitySettings {
+                samples_per_pixel: 2,
+                max_depth: 16,
+                should_average: true,
+                render_scale: 1.,
+            },
+            QualityPreset::Ultra => QualitySettings {
+                samples_per_pixel: 4,
+                max_depth: 32,
+                should_average: true,
+                render_scale: 1.,
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QualityPreset::Low => "low",
+            QualityPreset::Medium => "medium",
+            QualityPreset::High => "high",
+            QualityPreset::Ultra => "ultra",
+        }
+    }
+}
+
+impl FromStr for QualityPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        QualityPreset::ALL
+            .into_iter()
+            .find(|preset| preset.name() == s)
+            .ok_or_else(|| format!("Unknown quality preset \"{s}\""))
+    }
+}
+
+/// Camera directions for inspecting the whole scene, relative to the world's axes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ViewPreset {
+    Front,
+    Back,
+    Top,
+    Bottom,
+    Left,
+    Right,
+    Iso,
+}
+
+impl ViewPreset {
+    /// Direction the camera looks in, given in the world's (x, up, z) axes
+    pub fn view_direction(&self) -> Vec3 {
+        match self {
+            ViewPreset::Front => Vec3(0., 0., -1.),
+            ViewPreset::Back => Vec3(0., 0., 1.),
+            ViewPreset::Top => Vec3(0., -1., 0.),
+            ViewPreset::Bottom => Vec3(0., 1., 0.),
+            ViewPreset::Left => Vec3(1., 0., 0.),
+            ViewPreset::Right => Vec3(-1., 0., 0.),
+            ViewPreset::Iso => Vec3::normalize(Vec3(-1., -1., -1.)),
+        }
+    }
+}
+
+impl FromStr for ViewPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "front" => Ok(ViewPreset::Front),
+            "back" => Ok(ViewPreset::Back),
+            "top" => Ok(ViewPreset::Top),
+            "bottom" => Ok(ViewPreset::Bottom),
+            "left" => Ok(ViewPreset::Left),
+            "right" => Ok(ViewPreset::Right),
+            "iso" => Ok(ViewPreset::Iso),
+            _ => Err(format!("Unknown view preset \"{s}\"")),
+        }
+    }
+}
+
+/// How (and whether) the scene is rendered from two eyes at once
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StereoMode {
+    Off,
+    /// red from the left eye, green and blue from the right (for red/cyan glasses)
+    Anaglyph,
+    /// the left eye's view in the left half of the image, the right eye's in the right half
+    SideBySide,
+}
+
+impl StereoMode {
+    /// Matches the `STEREO_*` constants in the fragment shader
+    pub fn value(&self) -> i32 {
+        match self {
+            StereoMode::Off => 0,
+            StereoMode::Anaglyph => 1,
+            StereoMode::SideBySide => 2,
+        }
+    }
+
+    /// How many eyes are traced at each pixel
+    pub fn eyes_per_pixel(&self) -> u32 {
+        match self {
+            StereoMode::Anaglyph => 2,
+            StereoMode::Off | StereoMode::SideBySide => 1,
+        }
+    }
+
+    /// Aspect ratio of each eye's view, given the aspect ratio of the whole image
+    pub fn eye_aspect_ratio(&self, aspect_ratio: f64) -> f64 {
+        match self {
+            StereoMode::SideBySide => aspect_ratio / 2.,
+            StereoMode::Off | StereoMode::Anaglyph => aspect_ratio,
+        }
+    }
+}
+
+impl FromStr for StereoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(StereoMode::Off),
+            "anaglyph" => Ok(StereoMode::Anaglyph),
+            "side-by-side" => Ok(StereoMode::SideBySide),
+            _ => Err(format!("Unknown stereo mode \"{s}\"")),
+        }
+    }
+}
+
+/// A smooth camera flight from one position to another
+#[derive(Debug, PartialEq, Clone)]
+pub struct CameraAnimation {
+    pub from: Point,
+    pub to: Point,
+    pub start_time: f64,
+    pub duration: f64,
+}
+
+/// A transient, decaying shake of the camera's position
+#[derive(Debug, PartialEq, Clone)]
+pub struct CameraShake {
+    /// Largest offset from the camera's resting position (in scene units)
+    pub intensity: f64,
+    /// In milliseconds
+    pub duration: f64,
+    /// Set on the first frame the shake is applied
+    pub start_time: Option<f64>,
+    /// The offset currently added to the camera's position when rendering.
+    /// `camera_origin` itself never moves, so snapshots and animations ignore the shake
+    pub offset: Vec3,
+}
+
+/// Everything the camera's basis is derived from
+#[derive(Debug, PartialEq, Clone)]
+pub struct CameraInputs {
+    pub camera_origin: Point,
+    pub world_up: Vec3,
+    pub yaw: f64,
+    pub pitch: f64,
+    pub roll: f64,
+    pub camera_field_of_view: f64,
+    pub focus_distance: f64,
+    pub aspect_ratio: f64,
+}
+
+/// Everything needed to put the camera back where it was (such as after a page reload)
+#[derive(Debug, PartialEq, Clone)]
+pub struct CameraSnapshot {
+    pub camera_origin: Point,
+    pub world_up: Vec3,
+    pub yaw: f64,
+    pub pitch: f64,
+    pub roll: f64,
+    pub camera_field_of_view: f64,
+    pub focus_distance: f64,
+}
+
+/// Remembers which inputs the camera's basis was computed from,
+/// so that it's only recomputed when one of them actually changes
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct CameraBasisCache {
+    inputs: Option<CameraInputs>,
+}
+
+impl CameraBasisCache {
+    /// Returns whether the basis needs to be recomputed for these inputs
+    pub fn update(&mut self, inputs: CameraInputs) -> bool {
+        if self.inputs.as_ref() == Some(&inputs) {
+            return false;
+        }
+        self.inputs = Some(inputs);
+        true
+    }
+}
+
+/// An HDR sky image, either as a single panorama or as the six faces of a cube
+#[derive(Debug, PartialEq, Clone)]
+pub enum EnvironmentMap {
+    Equirectangular(HdrImage),
+    /// Faces are ordered +X, -X, +Y, -Y, +Z, -Z
+    CubeMap(Box<[HdrImage; 6]>),
+}
+
+impl EnvironmentMap {
+    /// Cube map faces must all be square and the same size
+    pub fn cube_map(faces: Vec<HdrImage>) -> Result<Self, String> {
+        let faces: [HdrImage; 6] = faces.try_into().map_err(|faces: Vec<HdrImage>| {
+            format!("Expected 6 cube map faces, found {}", faces.len())
+        })?;
+        let size = faces[0].width;
+        if faces
+            .iter()
+            .any(|face| face.width != size || face.height != size)
+        {
+            return Err("Cube map faces must all be square and the same size".into());
+        }
+        Ok(EnvironmentMap::CubeMap(Box::new(faces)))
+    }
+}
+
+/// Whether the render loop keeps rendering indefinitely or only after something changes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenderMode {
+    Continuous,
+    /// Renders until `on_demand_render_count` frames have been averaged, then idles
+    OnDemand,
+}
+
+impl FromStr for RenderMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "continuous" => Ok(RenderMode::Continuous),
+            "on-demand" => Ok(RenderMode::OnDemand),
+            _ => Err(format!("Unknown render mode \"{s}\"")),
+        }
     }
 }
 
@@ -31,15 +450,61 @@ impl KeydownMap {
 pub struct State {
     pub width: u32,
     pub height: u32,
+    /// Fraction of the (adjusted) screen dimensions to render at
+    pub render_scale: f64,
+    /// Whether to render at the display's full device-pixel resolution
+    pub respect_dpr: bool,
+    /// Exact render dimensions to use instead of following the window's size
+    pub fixed_resolution: Option<(u32, u32)>,
     pub aspect_ratio: f64,
+    /// Fixed aspect ratio for the image, which is letterboxed within the canvas
+    pub target_aspect_ratio: Option<f64>,
+    /// What the canvas is cleared to before drawing (shows through wherever the image isn't drawn)
+    pub clear_color: Vec3,
+    pub clear_alpha: f64,
+    /// Source of the fragment shader currently in use (can be edited live)
+    pub fragment_shader_source: String,
+    pub should_update_vertex_buffer: bool,
     pub samples_per_pixel: u32,
+    /// Render with extra samples while paused (when frames aren't continuously rendered)
+    pub boost_samples_when_paused: bool,
+    /// Continuously adjusts `samples_per_pixel` (within `min_auto_samples..=max_auto_samples`)
+    /// to keep the frame rate near `auto_samples_target_fps`
+    pub auto_samples: bool,
+    pub min_auto_samples: u32,
+    pub max_auto_samples: u32,
+    pub auto_samples_target_fps: f64,
+    pub prev_auto_samples_update_time: f64,
+    /// When set, as many 1-sample passes as fit in this many milliseconds are accumulated
+    /// each animation frame (instead of a single pass of `samples_per_pixel`)
+    pub frame_budget_ms: Option<f64>,
+    /// A region of interest (x, y, width, height in pixels from the top-left of the render)
+    /// that is rendered with `roi_samples_per_pixel` instead
+    pub roi: Option<(u32, u32, u32, u32)>,
+    pub roi_samples_per_pixel: u32,
+    /// Take extra samples at pixels on an object's silhouette, where aliasing is most visible
+    pub edge_aa: bool,
     pub max_depth: u32,
+    pub ray_epsilon: f64,
+    /// Linear multiplier applied to the displayed image (not to accumulated frames)
+    pub exposure: f64,
+    pub auto_exposure: bool,
+    /// Log-average luminance that auto-exposure drives the displayed image toward
+    pub exposure_target: f64,
+    /// Focal length of the lens in mm, for a full-frame sensor. This is just another way of
+    /// expressing `camera_field_of_view` (longer lenses zoom in), and is unrelated to
+    /// `focus_distance`, which is how far away objects are in focus with depth of field
     pub focal_length: f64,
     pub camera_origin: Point,
     pub pitch: f64,
     pub yaw: f64,
+    /// tilt of the camera around its viewing direction (in degrees)
+    pub roll: f64,
     pub camera_front: Point,
-    pub vup: Vec3,
+    pub camera_animation: Option<CameraAnimation>,
+    pub camera_shake: Option<CameraShake>,
+    /// The direction that is "up" in the scene (yaw rotates around it, pitch tilts toward it)
+    pub world_up: Vec3,
     /// stored in radians
     pub camera_field_of_view: f64,
     pub u: Vec3,
@@ -47,17 +512,61 @@ pub struct State {
     pub w: Vec3,
     pub aperture: f64,
     pub lens_radius: f64,
+    /// Number of aperture blades, which shapes out-of-focus highlights (bokeh) into polygons
+    /// (fewer than 3 gives a perfectly circular aperture)
+    pub aperture_blades: u32,
     pub focus_distance: f64,
+    /// Anaglyph rendering traces both eyes at every pixel (doubling the cost of each frame)
+    pub stereo_mode: StereoMode,
+    /// Distance between the eyes in stereo modes (objects at the focus distance
+    /// appear at the depth of the screen)
+    pub ipd: f64,
+    /// How diffuse surfaces pick the direction to scatter light in
+    pub diffuse_mode: DiffuseMode,
+    /// Continuously focuses on whatever is at the center of the screen
+    pub autofocus: bool,
+    /// Gently bobs the camera up and down while moving
+    pub head_bob: bool,
+    /// Height of the bob, as a fraction of the distance moved per second
+    pub head_bob_amplitude: f64,
+    /// Bobs per second
+    pub head_bob_frequency: f64,
+    /// How far the rendered camera is currently displaced along `world_up` by the bob
+    /// (`camera_origin` itself never moves)
+    pub head_bob_offset: f64,
     pub viewport_height: f64,
     pub viewport_width: f64,
     pub horizontal: Vec3,
     pub vertical: Vec3,
     pub lower_left_corner: Point,
+    /// Inputs the camera's basis was last computed from
+    camera_basis_cache: CameraBasisCache,
+    /// Whether the camera's basis has changed since it was last uploaded to the GPU
+    pub camera_basis_dirty: bool,
+    /// The dimensions and render scale frames were last accumulated at
+    accumulation_target: Option<(u32, u32, f64)>,
     pub sphere_list: Vec<Sphere>,
+    /// `sphere_list` with every center resolved into world space, kept up to date by `update_geometry`
+    pub world_spheres: Vec<Sphere>,
+    /// Axis-aligned rectangles, sampled directly as area lights when emissive
+    pub rect_lights: Vec<Rect>,
+    /// Union of the bounding boxes of every visible sphere and rect,
+    /// kept up to date by `update_geometry`
+    scene_bounds: Option<Aabb>,
+    /// Time (in ms) to cross-fade from the old scene's last frame when switching scenes
+    /// (0 switches instantly)
+    pub transition_duration: f64,
+    /// When the current cross-fade started (None when not transitioning)
+    pub transition_start_time: Option<f64>,
+    /// 0 -> 1 as the new scene fades in (1 when not transitioning)
+    pub transition_progress: f64,
 
     // RENDER STATE
-    /// is the modal up that asks the user to enable first-person viewing mode?
-    pub is_paused: bool,
+    /// Ignores camera input and freezes camera movement (see `is_paused`)
+    pub camera_paused: bool,
+    /// Freezes anything in the scene that changes over time, such as the cross-fade
+    /// between scenes (see `is_paused`). On its own this doesn't stop rendering
+    pub scene_paused: bool,
     /// If the render should render incrementally, pubaveraging together previous frames
     pub should_average: bool,
     /// Unless averaging is taking place, pubthis is set to false after revery render
@@ -65,19 +574,120 @@ pub struct State {
     pub should_render: bool,
     /// Whether the browser should save a screenshot of the canvas
     pub should_save: bool,
+    /// The accumulated frame that `is_converged` last compared against
+    pub convergence_check: ConvergenceCheck,
     /// Used to alternate which framebuffer to render to
     pub even_odd_count: u32,
+    /// Number of textures/framebuffers used for accumulating frames
+    pub accumulation_frame_count: u32,
+    pub should_update_accumulation_buffers: bool,
+    /// Also write the primary hit's normal and depth into extra attachments of the
+    /// accumulation framebuffers (see `webgl::GBuffer`), which costs extra memory
+    pub g_buffer: bool,
     /// Used for averaging previous frames together
     pub render_count: u32,
+    /// Whether accumulated frames are averaged with equal weight (converging to an unbiased mean),
+    /// rather than weighted by `last_frame_weight`
+    pub true_average: bool,
     /// The weight of the last frame compared to the each frame before.
     pub last_frame_weight: f32,
     /// Limiting the counted renders allows creating a sliding average of frames
     pub max_render_count: u32,
+    pub render_mode: RenderMode,
+    /// In on-demand mode, the image is considered converged after this many frames
+    pub on_demand_render_count: u32,
+    /// When set, exactly this many frames are accumulated (each with a fixed random seed),
+    /// after which everything is paused (see `render_samples`)
+    pub target_render_count: Option<u32>,
     /// Used for calculating time delta in animation loop
     pub prev_now: f64,
     /// this is necessary after the user resizes their viewport
     pub should_update_to_match_window_size: bool,
+    /// Resizes immediately (rather than debounced like window resizes)
+    pub should_update_render_dimensions: bool,
+    /// The canvas has moved or resized on the page, so overlays need its new position
+    pub should_update_canvas_rect: bool,
+    /// Render at a low resolution after the camera changes, then ramp up to full resolution
+    pub progressive_startup: bool,
+    /// Multiplied with `render_scale` while progressive startup is ramping up
+    pub progressive_scale: f64,
     pub last_resize_time: f64,
+    /// Sphere data is only sent to the GPU when it changes
+    pub should_update_geometry: bool,
+    /// Only sphere centers have changed, so the rest of the geometry needn't be re-sent
+    pub should_update_sphere_centers: bool,
+    /// HDR sky that has been loaded, but not yet uploaded to the GPU
+    pub pending_environment_map: Option<EnvironmentMap>,
+    pub has_environment_map: bool,
+    /// Whether the uploaded sky is a cube map (rather than equirectangular)
+    pub is_environment_cubemap: bool,
+    /// Analytic sky model with a sun, used instead of the gradient sky when enabled
+    pub sun_sky_enabled: bool,
+    /// Angle of the sun above the horizon (in degrees)
+    pub sun_elevation: f64,
+    /// Angle of the sun around the up axis (in degrees)
+    pub sun_azimuth: f64,
+    /// Haziness of the atmosphere (roughly 2 for a clear sky, up to 10 for a hazy one)
+    pub turbidity: f64,
+    /// Fog density at height 0 (zero = no fog), thinning out exponentially with height
+    pub height_fog_density: f64,
+    /// How quickly the fog thins out going up: the density falls by a factor of e every
+    /// `1 / height_fog_falloff` units
+    pub height_fog_falloff: f64,
+    pub height_fog_color: Vec3,
+    /// Debugging view of the raw linear radiance: no gamma, exposure, or grid,
+    /// and a black sky (without the sun), so only the scene's own lights contribute
+    pub raw_output: bool,
+    /// Averaging is temporarily turned off while the camera moves to prevent smearing
+    pub is_averaging_suspended: bool,
+    /// How many consecutive frames the camera must be still before averaging resumes
+    pub still_frame_threshold: u32,
+    pub still_frame_count: u32,
+    /// After this many milliseconds without input, quality drops to `idle_quality` to save power
+    pub idle_timeout: Option<f64>,
+    /// Quality used while idle (`None` stops rendering altogether)
+    pub idle_quality: Option<QualityPreset>,
+    /// Time of the most recent keyboard/mouse input
+    pub last_input_time: f64,
+    /// The quality settings to restore on the next input, while idle
+    pub pre_idle_quality: Option<QualitySettings>,
+    /// Camera values from the previous frame, used for detecting movement
+    pub prev_camera_origin: Point,
+    pub prev_yaw: f64,
+    pub prev_pitch: f64,
+    pub prev_roll: f64,
+    pub prev_camera_field_of_view: f64,
+
+    // UI
+    pub crosshair_enabled: bool,
+    /// Width and height of the crosshair in CSS pixels
+    pub crosshair_size: f64,
+    /// Whether key presses go to this ray tracer: only the one whose canvas was clicked last
+    /// (or has pointer lock) responds, when several are on the same page
+    pub has_keyboard_focus: bool,
+    /// Page UI (fps indicator, buttons) is hidden, e.g. for taking clean screenshots
+    pub ui_hidden: bool,
+    /// Draw each object's bounding box over the render
+    pub show_bounds: bool,
+    /// Draw an outline around the selected object
+    pub outline_selected: bool,
+    /// Draw a reference grid on the y = 0 plane (behind any objects in front of it)
+    pub show_grid: bool,
+    /// Distance between grid lines, in scene units
+    pub grid_spacing: f64,
+    /// World-space points picked with the measuring tool
+    pub measurement_start: Option<Point>,
+    pub measurement_end: Option<Point>,
+    /// The "p" key only toggles the profiler overlay when this is set,
+    /// so that it never shows up in clean captures
+    pub profiler_enabled: bool,
+    /// Show the profiler overlay (fps, frame time, sample and sphere counts)
+    pub show_profiler: bool,
+
+    /// JS function called after every rendered frame with `{ fps, renderCount }`
+    pub on_frame: Option<js_sys::Function>,
+    /// JS function called with `{ fps, renderCount }` once `target_render_count` is reached
+    pub on_target_reached: Option<js_sys::Function>,
 
     // MOVEMENT
     pub keydown_map: KeydownMap,
@@ -87,220 +697,374 @@ pub struct State {
     pub enable_debugging: i32,
     pub cursor_point: Point,
     pub selected_object: i32,
+    /// The sphere currently being dragged with the mouse
+    pub sphere_drag: Option<SphereDrag>,
+    /// Held down during a drag to only move along one world axis
+    pub axis_lock: Option<Axis>,
+    /// Set after a drag, so that releasing the mouse doesn't also count as a click
+    pub should_ignore_next_click: bool,
 
     // ANALYTICS
     pub prev_fps_update_time: f64,
     pub prev_fps: [f64; 50],
+    /// Recent frame times (already clamped) that movement is smoothed over
+    pub prev_movement_frame_times: [f64; MOVEMENT_FRAME_TIME_SAMPLES],
+    /// Time (in ms) taken by the most recent rendered frame
+    pub last_frame_time: f64,
+    /// Rays traced in the most recent rendered frame
+    pub last_frame_rays: f64,
+    /// The most recent frame timed on the GPU (None when GPU timer queries are unsupported)
+    pub gpu_timing: Option<GpuTiming>,
+    /// When set, the shader's random seed is derived from this rather than
+    /// the current time, so that frames can be reproduced exactly
+    pub fixed_seed_time: Option<f64>,
+}
+
+/// The accumulated frame the last convergence check compared against
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConvergenceCheck {
+    pixels: Vec<u8>,
+    render_count: u32,
+    change: Option<f64>,
+}
+
+impl ConvergenceCheck {
+    /// Compares a newly read accumulated frame against the one from the last check,
+    /// returning the mean absolute difference between them per byte
+    /// (None until two frames of the same accumulation have been compared)
+    pub fn update(&mut self, pixels: Vec<u8>, render_count: u32) -> Option<f64> {
+        // nothing new has been accumulated since the last check
+        if render_count == self.render_count && pixels.len() == self.pixels.len() {
+            return self.change;
+        }
+        // accumulation restarted (or the image was resized) since the last check
+        self.change = if render_count < self.render_count || pixels.len() != self.pixels.len() {
+            None
+        } else {
+            math::mean_absolute_difference(&pixels, &self.pixels).ok()
+        };
+        self.pixels = pixels;
+        self.render_count = render_count;
+        self.change
+    }
+}
+
+/// How long the GPU spent rendering a frame, as measured by a timer query
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuTiming {
+    /// in ms
+    pub time: f64,
+    pub rays: f64,
+}
+
+/// Approximate cost of the current render settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderStats {
+    pub rays_per_frame: f64,
+    pub last_frame_time: f64,
+    pub accumulated_samples: u32,
+    /// Measured on the GPU when timer queries are supported,
+    /// otherwise estimated from the time between frames
+    pub rays_per_second: f64,
+    pub is_gpu_timed: bool,
 }
 
 impl Default for State {
     fn default() -> Self {
-        let (width, height) = dom::get_adjusted_screen_dimensions();
+        let (width, height) = dom::get_adjusted_screen_dimensions(true);
+        State::with_dimensions(width, height)
+    }
+}
+
+impl State {
+    /// The default state, rendering at `width` x `height` rather than matching the window
+    /// (which is only available in the browser)
+    pub fn with_dimensions(width: u32, height: u32) -> Self {
+        let respect_dpr = true;
+        let fixed_resolution = None;
+        let render_scale = 1.;
         let aspect_ratio = (width as f64) / (height as f64);
         let aperture = 0.;
         let focus_distance = 0.75;
+        let autofocus = false;
+        let head_bob = false;
+        let head_bob_amplitude = DEFAULT_HEAD_BOB_AMPLITUDE;
+        let head_bob_frequency = DEFAULT_HEAD_BOB_FREQUENCY;
+        let head_bob_offset = 0.;
         let lens_radius = aperture / 2.0;
+        let aperture_blades = 0;
+        let stereo_mode = StereoMode::Off;
+        let ipd = DEFAULT_IPD;
+        let diffuse_mode = DiffuseMode::Lambertian;
 
         let camera_field_of_view = PI / 3.;
         let camera_h = (camera_field_of_view / 2.).tan();
         let camera_origin = Point(0., 0., 1.);
         let pitch = 0.;
         let yaw = -90.; // look down the z axis by default
-        let camera_front = Point(
-            f64::cos(degrees_to_radians(yaw)) * f64::cos(degrees_to_radians(pitch)),
-            f64::sin(degrees_to_radians(pitch)),
-            f64::sin(degrees_to_radians(yaw)) * f64::cos(degrees_to_radians(pitch)),
-        );
+        let roll = 0.;
+        let world_up = Vec3(0., 1., 0.);
+        let camera_front = camera_front_from_angles(&world_up, yaw, pitch);
+        let camera_animation = None;
+        let camera_shake = None;
         let look_at = &camera_origin + &camera_front;
-        let vup = Vec3(0., 1., 0.);
         let w = Vec3::normalize(&camera_origin - &look_at);
-        let u = Vec3::normalize(Vec3::cross(&vup, &w));
+        let u = Vec3::normalize(Vec3::cross(&world_up, &w));
         let v = Vec3::cross(&w, &u);
         let viewport_height = 2. * camera_h;
         let viewport_width = viewport_height * aspect_ratio;
         let horizontal = focus_distance * viewport_width * &u;
         let vertical = focus_distance * viewport_height * &v;
-        let focal_length = 1.;
+        let focal_length = focal_length_from_field_of_view(camera_field_of_view);
         let lower_left_corner =
             &camera_origin - &horizontal / 2. - &vertical / 2. - focus_distance * &w;
 
         let samples_per_pixel = 1;
+        let boost_samples_when_paused = true;
+        let auto_samples = false;
+        let min_auto_samples = 1;
+        let max_auto_samples = 16;
+        let auto_samples_target_fps = DEFAULT_AUTO_SAMPLES_TARGET_FPS;
+        let prev_auto_samples_update_time = 0.;
+        let frame_budget_ms = None;
+        let roi = None;
+        let roi_samples_per_pixel = 16;
+        let edge_aa = false;
         let max_depth = 8;
+        let ray_epsilon = DEFAULT_RAY_EPSILON;
+        let exposure = 1.;
+        let auto_exposure = false;
+        let exposure_target = DEFAULT_EXPOSURE_TARGET;
         let should_average = true;
         let should_render = true;
         let should_save = false;
+        let convergence_check = ConvergenceCheck::default();
         let even_odd_count = 0;
+        let accumulation_frame_count = 2;
+        let should_update_accumulation_buffers = false;
+        let g_buffer = false;
         let render_count = 0;
+        let true_average = true;
         let last_frame_weight = 1.;
         let max_render_count = 100_000;
+        let render_mode = RenderMode::Continuous;
+        let on_demand_render_count = 100;
+        let target_render_count = None;
         let prev_now = 0.;
         let should_update_to_match_window_size = false;
+        let should_update_render_dimensions = false;
+        let should_update_canvas_rect = true;
+        let target_aspect_ratio = None;
+        let clear_color = Vec3(0., 0., 0.);
+        let clear_alpha = 1.;
+        let fragment_shader_source = String::new();
+        let should_update_vertex_buffer = false;
+        let progressive_startup = false;
+        let progressive_scale = 1.;
         let last_resize_time = 0.;
+        let should_update_geometry = false;
+        let should_update_sphere_centers = false;
+        let pending_environment_map = None;
+        let has_environment_map = false;
+        let is_environment_cubemap = false;
+        let sun_sky_enabled = false;
+        let sun_elevation = 45.;
+        let sun_azimuth = 0.;
+        let turbidity = 2.5;
+        let height_fog_density = 0.;
+        let height_fog_falloff = 1.;
+        let height_fog_color = Vec3(0.7, 0.75, 0.8);
+        let raw_output = false;
+        let is_averaging_suspended = false;
+        let still_frame_threshold = 5;
+        let still_frame_count = 0;
+        let idle_timeout = None;
+        let idle_quality = Some(QualityPreset::Low);
+        let last_input_time = 0.;
+        let pre_idle_quality = None;
+        let prev_camera_origin = camera_origin.clone();
+        let prev_yaw = yaw;
+        let prev_pitch = pitch;
+        let prev_roll = roll;
+        let prev_camera_field_of_view = camera_field_of_view;
+
+        // the modal asking the user to enable first-person viewing mode starts out visible
+        let camera_paused = true;
+        let scene_paused = true;
 
-        let is_paused = true;
+        let crosshair_enabled = true;
+        let crosshair_size = 12.;
+        let has_keyboard_focus = true;
+        let ui_hidden = false;
+        let profiler_enabled = false;
+        let show_profiler = false;
+        let show_bounds = false;
+        let outline_selected = false;
+        let show_grid = false;
+        let grid_spacing = 1.;
+        let measurement_start = None;
+        let measurement_end = None;
+
+        let on_frame = None;
+        let on_target_reached = None;
 
         let look_sensitivity = 0.1;
         let keydown_map = KeydownMap::default();
 
         let prev_fps_update_time = 0.;
         let prev_fps = [0.; 50];
+        // assume 60fps until real frames have been timed
+        let prev_movement_frame_times = [1000. / 60.; MOVEMENT_FRAME_TIME_SAMPLES];
+        let last_frame_time = 0.;
+        let last_frame_rays = 0.;
+        let gpu_timing = None;
+        let fixed_seed_time = None;
 
-        let mut sphere_list = vec![
-            // ground
-            Sphere {
-                center: Vec3(0., -100.5, -1.),
-                radius: 100.,
-                material: Material {
-                    material_type: MaterialType::Diffuse,
-                    albedo: Vec3(0.75, 0.6, 0.5),
-                    fuzz: 0.,
-                    refraction_index: 0.,
-                },
-                uuid: 0,
-            },
-            // center (blue)
-            Sphere {
-                center: Vec3(0., 0., -1.),
-                radius: 0.5,
-                material: Material {
-                    material_type: MaterialType::Diffuse,
-                    albedo: Vec3(0.3, 0.3, 0.4),
-                    fuzz: 0.,
-                    refraction_index: 0.,
-                },
-                uuid: 0,
-            },
-            // left
-            Sphere {
-                center: Vec3(-1.1, 0., -1.),
-                radius: 0.5,
-                material: Material {
-                    material_type: MaterialType::Metal,
-                    albedo: Vec3(1.0, 1.0, 1.0),
-                    fuzz: 0.,
-                    refraction_index: 0.,
-                },
-                uuid: 0,
-            },
-            // right
-            Sphere {
-                center: Vec3(1.1, 0., -1.),
-                radius: 0.5,
-                material: Material {
-                    material_type: MaterialType::Glass,
-                    albedo: Vec3(1.0, 1.0, 1.0),
-                    fuzz: 0.,
-                    refraction_index: 1.5,
-                },
-                uuid: 0,
-            },
-            // back left (shiny)
-            Sphere {
-                center: Vec3(-0.5, -0.35, -0.55),
-                radius: -0.15,
-                material: Material {
-                    material_type: MaterialType::Metal,
-                    albedo: Vec3(1.0, 1.0, 1.0),
-                    fuzz: 0.,
-                    refraction_index: 0.,
-                },
-                uuid: 0,
-            },
-            // front left (fuzzy)
-            Sphere {
-                center: Vec3(-0.75, -0.4, -0.35),
-                radius: -0.1,
-                material: Material {
-                    material_type: MaterialType::Metal,
-                    albedo: Vec3(1.0, 1.0, 1.0),
-                    fuzz: 0.,
-                    refraction_index: 0.,
-                },
-                uuid: 0,
-            },
-            // behind
-            Sphere {
-                center: Vec3(0., 1.2, 4.),
-                radius: 2.,
-                material: Material {
-                    material_type: MaterialType::Diffuse,
-                    albedo: Vec3(1.0, 0.8, 0.8),
-                    fuzz: 0.,
-                    refraction_index: 0.,
-                },
-                uuid: 0,
-            },
-            // distant (moon)
-            Sphere {
-                center: Vec3(150., 20., -500.),
-                radius: 100.,
-                material: Material {
-                    material_type: MaterialType::Diffuse,
-                    albedo: Vec3(0.95, 0.95, 1.0),
-                    fuzz: 0.,
-                    refraction_index: 0.,
-                },
-                uuid: 0,
-            },
-            // distant moon's moon
-            Sphere {
-                center: Vec3(170., -20., -350.),
-                radius: 30.,
-                material: Material {
-                    material_type: MaterialType::Diffuse,
-                    albedo: Vec3(1.0, 1.0, 1.0),
-                    fuzz: 0.,
-                    refraction_index: 0.,
-                },
-                uuid: 0,
-            },
-        ];
+        let mut sphere_list = default_scene();
 
         let enable_debugging = 0;
         let cursor_point = Point(0., 0., 0.);
         let selected_object = NO_SELECTED_OBJECT_ID;
+        let sphere_drag = None;
+        let axis_lock = None;
+        let should_ignore_next_click = false;
 
         glsl::set_sphere_uuids(&mut sphere_list);
 
         State {
             width,
             height,
+            render_scale,
+            respect_dpr,
+            fixed_resolution,
             aperture,
             u,
             v,
             w,
             focus_distance,
+            autofocus,
+            head_bob,
+            head_bob_amplitude,
+            head_bob_frequency,
+            head_bob_offset,
             lens_radius,
+            aperture_blades,
+            stereo_mode,
+            ipd,
+            diffuse_mode,
             aspect_ratio,
+            target_aspect_ratio,
+            clear_color,
+            clear_alpha,
+            fragment_shader_source,
+            should_update_vertex_buffer,
             samples_per_pixel,
+            boost_samples_when_paused,
+            auto_samples,
+            min_auto_samples,
+            max_auto_samples,
+            auto_samples_target_fps,
+            prev_auto_samples_update_time,
+            frame_budget_ms,
+            roi,
+            roi_samples_per_pixel,
+            edge_aa,
             max_depth,
+            ray_epsilon,
+            exposure,
+            auto_exposure,
+            exposure_target,
             focal_length,
             pitch,
             yaw,
+            roll,
             camera_origin,
             camera_front,
-            vup,
+            camera_animation,
+            camera_shake,
+            world_up,
             camera_field_of_view,
             viewport_height,
             viewport_width,
             horizontal,
             vertical,
             lower_left_corner,
+            camera_basis_cache: CameraBasisCache::default(),
+            camera_basis_dirty: true,
+            accumulation_target: None,
 
-            is_paused,
+            camera_paused,
+            scene_paused,
             should_average,
             should_render,
             should_save,
+            convergence_check,
             even_odd_count,
+            accumulation_frame_count,
+            should_update_accumulation_buffers,
+            g_buffer,
             render_count,
+            true_average,
             last_frame_weight,
             max_render_count,
+            render_mode,
+            on_demand_render_count,
+            target_render_count,
             prev_now,
             should_update_to_match_window_size,
+            should_update_render_dimensions,
+            should_update_canvas_rect,
+            progressive_startup,
+            progressive_scale,
             last_resize_time,
+            should_update_geometry,
+            should_update_sphere_centers,
+            pending_environment_map,
+            has_environment_map,
+            is_environment_cubemap,
+            sun_sky_enabled,
+            sun_elevation,
+            sun_azimuth,
+            turbidity,
+            height_fog_density,
+            height_fog_falloff,
+            height_fog_color,
+            raw_output,
+            is_averaging_suspended,
+            still_frame_threshold,
+            still_frame_count,
+            idle_timeout,
+            idle_quality,
+            last_input_time,
+            pre_idle_quality,
+            prev_camera_origin,
+            prev_yaw,
+            prev_pitch,
+            prev_roll,
+            prev_camera_field_of_view,
 
             prev_fps_update_time,
             prev_fps,
+            prev_movement_frame_times,
+            last_frame_time,
+            last_frame_rays,
+            gpu_timing,
+            fixed_seed_time,
+
+            crosshair_enabled,
+            crosshair_size,
+            has_keyboard_focus,
+            ui_hidden,
+            profiler_enabled,
+            show_profiler,
+            show_bounds,
+            outline_selected,
+            show_grid,
+            grid_spacing,
+            measurement_start,
+            measurement_end,
+            on_frame,
+            on_target_reached,
 
             keydown_map,
             look_sensitivity,
@@ -308,8 +1072,17 @@ impl Default for State {
             enable_debugging,
             cursor_point,
             selected_object,
+            sphere_drag,
+            axis_lock,
+            should_ignore_next_click,
 
+            scene_bounds: compute_scene_bounds(&sphere_list, &[]),
+            world_spheres: sphere_list.clone(),
             sphere_list,
+            rect_lights: Vec::new(),
+            transition_duration: DEFAULT_TRANSITION_DURATION,
+            transition_start_time: None,
+            transition_progress: 1.,
         }
     }
 }
@@ -317,22 +1090,48 @@ impl Default for State {
 impl State {
     // updates all "downstream" variables once a rendering/camera variable has been changed
     pub fn update_pipeline(&mut self) {
-        // for comparing if any changes occured
-        let prev_state = self.clone();
+        self.aspect_ratio = self
+            .target_aspect_ratio
+            .unwrap_or((self.width as f64) / (self.height as f64));
+        let inputs = CameraInputs {
+            camera_origin: self.camera_origin.clone(),
+            world_up: self.world_up.clone(),
+            yaw: self.yaw,
+            pitch: self.pitch,
+            roll: self.roll,
+            camera_field_of_view: self.camera_field_of_view,
+            focus_distance: self.focus_distance,
+            aspect_ratio: self.stereo_mode.eye_aspect_ratio(self.aspect_ratio),
+        };
+        // frames accumulated at another size can't be averaged in, even if the camera didn't move
+        let accumulation_target = Some((
+            self.width,
+            self.height,
+            self.render_scale * self.progressive_scale,
+        ));
+        if self.accumulation_target != accumulation_target {
+            self.accumulation_target = accumulation_target;
+            self.render_count = 0;
+            self.should_render = true;
+        }
+        if !self.camera_basis_cache.update(inputs) {
+            return;
+        }
+        self.focal_length = focal_length_from_field_of_view(self.camera_field_of_view);
 
-        self.aspect_ratio = (self.width as f64) / (self.height as f64);
         let camera_h = (self.camera_field_of_view / 2.).tan();
-        self.camera_front = Point(
-            f64::cos(degrees_to_radians(self.yaw)) * f64::cos(degrees_to_radians(self.pitch)),
-            f64::sin(degrees_to_radians(self.pitch)),
-            f64::sin(degrees_to_radians(self.yaw)) * f64::cos(degrees_to_radians(self.pitch)),
-        );
+        self.camera_front = camera_front_from_angles(&self.world_up, self.yaw, self.pitch);
         let look_at = &self.camera_origin + &self.camera_front;
         self.w = Vec3::normalize(&self.camera_origin - &look_at);
-        self.u = Vec3::normalize(Vec3::cross(&self.vup, &self.w));
-        self.v = Vec3::cross(&self.w, &self.u);
+        let u = Vec3::normalize(Vec3::cross(&self.world_up, &self.w));
+        let v = Vec3::cross(&self.w, &u);
+        // roll rotates the camera's horizontal and vertical axes around w
+        let (sin_roll, cos_roll) = degrees_to_radians(self.roll).sin_cos();
+        self.u = &u * cos_roll + &v * sin_roll;
+        self.v = &v * cos_roll - &u * sin_roll;
         self.viewport_height = 2. * camera_h;
-        self.viewport_width = self.viewport_height * self.aspect_ratio;
+        self.viewport_width =
+            self.viewport_height * self.stereo_mode.eye_aspect_ratio(self.aspect_ratio);
         self.horizontal = self.focus_distance * self.viewport_width * &self.u;
         self.vertical = self.focus_distance * self.viewport_height * &self.v;
         self.lower_left_corner = &self.camera_origin
@@ -340,127 +1139,1904 @@ impl State {
             - &self.vertical / 2.
             - self.focus_distance * &self.w;
 
-        if self != &prev_state {
-            self.render_count = 0;
-            self.should_render = true;
-        }
+        self.camera_basis_dirty = true;
+        self.render_count = 0;
+        self.should_render = true;
     }
 
     pub fn set_fov(&mut self, new_fov_radians: f64) {
-        self.camera_field_of_view = new_fov_radians.clamp(0.0001, PI * 0.75);
+        self.camera_field_of_view = sanitize_fov(new_fov_radians, self.camera_field_of_view);
         self.update_pipeline();
     }
 
-    pub fn set_camera_angles(&mut self, yaw: f64, pitch: f64) {
-        self.yaw = yaw;
-        self.pitch = f64::clamp(pitch, -89., 89.);
-        self.update_pipeline();
+    /// Head bob and camera shake, which only move the camera as it's rendered
+    fn camera_render_offset(&self) -> Vec3 {
+        let shake_offset = self
+            .camera_shake
+            .as_ref()
+            .map(|shake| shake.offset.clone())
+            .unwrap_or_default();
+        shake_offset + &self.world_up * self.head_bob_offset
     }
-}
 
-unsafe impl Send for State {}
-unsafe impl Sync for State {}
+    /// Where rays start from: `camera_origin`, plus any head bob or camera shake underway
+    pub fn render_camera_origin(&self) -> Point {
+        &self.camera_origin + &self.camera_render_offset()
+    }
 
-pub fn update_render_dimensions_to_match_window(
-    state: &mut MutexGuard<State>,
-    gl: &WebGl2RenderingContext,
-    textures: &[WebGlTexture; 2],
-    canvas: &HtmlCanvasElement,
-    now: f64,
-) {
-    // update state
-    state.last_resize_time = now;
-    let (width, height) = dom::get_adjusted_screen_dimensions();
-    state.width = width;
-    state.height = height;
-    state.update_pipeline();
+    /// `lower_left_corner`, moved along with the camera by any head bob or camera shake
+    pub fn render_lower_left_corner(&self) -> Point {
+        &self.lower_left_corner + &self.camera_render_offset()
+    }
 
-    // sync width/height-dependent objects with state
-    canvas.set_width(state.width);
-    canvas.set_height(state.height);
-    gl.viewport(0, 0, state.width as i32, state.height as i32);
-    for texture in textures.iter() {
-        gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(texture));
-        // load empty texture into gpu -- this will get rendered into later
-        gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
-            WebGl2RenderingContext::TEXTURE_2D,
-            0,
-            WebGl2RenderingContext::RGBA as i32,
-            state.width as i32,
-            state.height as i32,
-            0,
-            WebGl2RenderingContext::RGBA,
-            WebGl2RenderingContext::UNSIGNED_BYTE,
-            None,
-        )
-        .unwrap();
+    pub fn camera_snapshot(&self) -> CameraSnapshot {
+        CameraSnapshot {
+            camera_origin: self.camera_origin.clone(),
+            world_up: self.world_up.clone(),
+            yaw: self.yaw,
+            pitch: self.pitch,
+            roll: self.roll,
+            camera_field_of_view: self.camera_field_of_view,
+            focus_distance: self.focus_distance,
+        }
     }
-}
 
-pub fn update_moving_fps_array(now: f64, state: &mut MutexGuard<State>, dt: f64) {
-    // calculate moving fps
-    state.prev_now = now;
-    let fps = 1000. / dt;
-    let last_index = state.prev_fps.len() - 1;
-    for (i, el) in state.prev_fps.into_iter().skip(1).enumerate() {
-        state.prev_fps[i] = el;
+    /// Puts the camera back where it was when the snapshot was taken
+    pub fn restore_camera(&mut self, snapshot: &CameraSnapshot) {
+        self.camera_origin =
+            sanitize_vec3(snapshot.camera_origin.clone(), self.camera_origin.clone());
+        self.focus_distance = sanitize_positive(snapshot.focus_distance, self.focus_distance);
+        self.camera_animation = None;
+        self.set_world_up(snapshot.world_up.clone());
+        self.set_roll(snapshot.roll);
+        self.set_camera_angles(snapshot.yaw, snapshot.pitch);
+        self.set_fov(snapshot.camera_field_of_view);
     }
-    state.prev_fps[last_index] = fps;
-}
 
-pub fn update_position(state: &mut MutexGuard<State>, dt: f64) {
-    if state.keydown_map.all_false() {
-        return;
+    /// Zooms by changing the lens's focal length (in mm), which sets the field of view.
+    /// The focus distance is unchanged
+    pub fn set_focal_length(&mut self, focal_length: f64) {
+        let focal_length = sanitize_positive(focal_length, self.focal_length);
+        self.set_fov(field_of_view_from_focal_length(focal_length));
     }
 
-    let camera_front = state.camera_front.clone();
-    let vup = state.vup.clone();
-    // move slower when more "zoomed in"
-    let fov = state.camera_field_of_view;
-    if state.keydown_map.w {
-        state.camera_origin += &camera_front * MOVEMENT_SPEED * dt * fov;
+    pub fn set_camera_angles(&mut self, yaw: f64, pitch: f64) {
+        self.yaw = sanitize(yaw, self.yaw);
+        self.pitch = f64::clamp(sanitize(pitch, self.pitch), -89., 89.);
+        self.update_pipeline();
     }
-    if state.keydown_map.a {
-        state.camera_origin -= Vec3::cross(&camera_front, &vup) * MOVEMENT_SPEED * dt * fov;
+
+    pub fn set_yaw(&mut self, yaw: f64) {
+        self.set_camera_angles(yaw, self.pitch);
     }
-    if state.keydown_map.s {
-        state.camera_origin -= &camera_front * MOVEMENT_SPEED * dt * fov;
+
+    pub fn set_pitch(&mut self, pitch: f64) {
+        self.set_camera_angles(self.yaw, pitch);
     }
-    if state.keydown_map.d {
-        state.camera_origin += Vec3::cross(&camera_front, &vup) * MOVEMENT_SPEED * dt * fov;
+
+    /// Ignores zero-length (and non-finite) vectors, since they have no direction
+    pub fn set_world_up(&mut self, world_up: Vec3) {
+        let world_up = sanitize_vec3(world_up, Vec3::new());
+        if world_up.length_squared() == 0. {
+            return;
+        }
+        self.world_up = Vec3::normalize(world_up);
+        self.update_pipeline();
     }
-    if state.keydown_map.space {
-        state.camera_origin += &vup * MOVEMENT_SPEED * dt * fov;
+
+    pub fn set_roll(&mut self, roll: f64) {
+        // keep within -180->180 degrees
+        self.roll = (sanitize(roll, self.roll) + 180.).rem_euclid(360.) - 180.;
+        self.update_pipeline();
     }
-    if state.keydown_map.shift {
-        state.camera_origin -= &vup * MOVEMENT_SPEED * dt * fov;
+
+    pub fn set_true_average(&mut self, true_average: bool) {
+        self.true_average = true_average;
+        self.render_count = 0;
+        self.should_render = true;
     }
 
-    update_cursor_position_in_world(state);
-    state.update_pipeline();
-}
+    /// The weight of each new frame relative to the frames already accumulated.
+    /// Lower weights retain old frames longer: more temporal smoothing,
+    /// but more ghosting while the camera is moving.
+    pub fn set_last_frame_weight(&mut self, last_frame_weight: f32) {
+        self.last_frame_weight =
+            sanitize(last_frame_weight as f64, self.last_frame_weight as f64).clamp(0., 1.) as f32;
+        self.render_count = 0;
+        self.should_render = true;
+    }
 
-pub fn update_render_globals(state: &mut MutexGuard<State>) {
-    if !state.should_average {
-        // only continuously render when averaging is being done
-        state.should_render = false;
+    pub fn set_should_average(&mut self, should_average: bool) {
+        // an explicit choice overrides any automatic suspension
+        self.is_averaging_suspended = false;
+        if self.should_average != should_average {
+            // previous frames are stale (or absent) after switching modes
+            self.render_count = 0;
+        }
+        self.should_average = should_average;
+        self.should_render = true;
     }
-    state.even_odd_count += 1;
-    state.render_count = (state.render_count + 1).min(state.max_render_count);
-}
 
-/// focus on whatever object is selected by the cursor if there was a collision
-pub fn update_cursor_position_in_world(state: &mut MutexGuard<State>) {
-    if let HitResult::Hit { data } = glsl::get_center_hit(state) {
-        let distance = (&data.hit_point - &state.camera_origin).length();
-        if state.aperture > 0. {
-            // there is no blurring if aperture is zerp
-            state.focus_distance = distance;
+    pub fn set_quality_preset(&mut self, preset: QualityPreset) {
+        self.set_quality_settings(&preset.settings());
+    }
+
+    pub fn set_quality_settings(&mut self, settings: &QualitySettings) {
+        // settings may come from saved (and possibly corrupted) state
+        self.samples_per_pixel = settings.samples_per_pixel.clamp(1, MAX_SAMPLES_PER_PIXEL);
+        self.max_depth = settings.max_depth.clamp(1, MAX_MAX_DEPTH);
+        self.should_average = settings.should_average;
+        self.is_averaging_suspended = false;
+        let render_scale = sanitize(settings.render_scale, self.render_scale)
+            .clamp(MIN_RENDER_SCALE, MAX_RENDER_SCALE);
+        if self.render_scale != render_scale {
+            self.render_scale = render_scale;
+            self.should_update_to_match_window_size = true;
+        }
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// The current render settings (as they'll be once averaging resumes)
+    pub fn quality_settings(&self) -> QualitySettings {
+        QualitySettings {
+            samples_per_pixel: self.samples_per_pixel,
+            max_depth: self.max_depth,
+            should_average: self.should_average || self.is_averaging_suspended,
+            render_scale: self.render_scale,
+        }
+    }
+
+    /// The preset matching the current render settings, if any
+    pub fn quality_preset(&self) -> Option<QualityPreset> {
+        let current_settings = self.quality_settings();
+        QualityPreset::ALL
+            .into_iter()
+            .find(|preset| preset.settings() == current_settings)
+    }
+
+    /// `None` disables the idle timeout. The timeout is counted from `now`,
+    /// rather than from whenever input was last received
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<f64>, now: f64) {
+        self.idle_timeout = idle_timeout.filter(|timeout| timeout.is_finite() && *timeout >= 0.);
+        if self.idle_timeout.is_some() {
+            self.last_input_time = now;
+        } else {
+            self.exit_idle();
+        }
+    }
+
+    /// `None` stops rendering altogether while idle
+    pub fn set_idle_quality(&mut self, idle_quality: Option<QualityPreset>) {
+        self.idle_quality = idle_quality;
+        // apply the new quality the next time the renderer goes idle
+        self.exit_idle();
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pre_idle_quality.is_some()
+    }
+
+    /// Whether rendering has stopped, rather than just dropping in quality, while idle
+    pub fn is_render_suspended_while_idle(&self) -> bool {
+        self.is_idle() && self.idle_quality.is_none()
+    }
+
+    /// Call on every keyboard/mouse input: restores full quality if idle
+    pub fn register_input(&mut self, now: f64) {
+        self.last_input_time = now;
+        self.exit_idle();
+    }
+
+    fn exit_idle(&mut self) {
+        if let Some(settings) = self.pre_idle_quality.take() {
+            self.set_quality_settings(&settings);
+        }
+    }
+
+    fn enter_idle(&mut self) {
+        if self.is_idle() {
+            return;
+        }
+        self.pre_idle_quality = Some(self.quality_settings());
+        if let Some(idle_quality) = self.idle_quality {
+            self.set_quality_preset(idle_quality);
+        }
+    }
+
+    /// Flies the camera toward an object (keeping the current viewing direction)
+    /// until the object fills the view
+    pub fn focus_on_object(&mut self, uuid: i32, now: f64) -> Result<(), String> {
+        let sphere = self
+            .world_spheres
+            .iter()
+            .find(|sphere| sphere.uuid == uuid)
+            .ok_or_else(|| format!("No sphere found with uuid {uuid}"))?;
+        let center = sphere.center.clone();
+        let radius = sphere.radius.abs();
+
+        let fit_distance = radius / (self.camera_field_of_view / 2.).tan();
+        let framing_distance = fit_distance + radius * FRAMING_DISTANCE_MARGIN;
+        let to = &center - &self.camera_front * framing_distance;
+
+        self.camera_animation = Some(CameraAnimation {
+            from: self.camera_origin.clone(),
+            to,
+            start_time: now,
+            duration: CAMERA_ANIMATION_DURATION,
+        });
+        Ok(())
+    }
+
+    /// The box containing every visible object in the scene (None when there are none)
+    pub fn scene_bounds(&self) -> Option<&Aabb> {
+        self.scene_bounds.as_ref()
+    }
+
+    /// Snaps the camera to look at the center of the scene from one side,
+    /// far enough away for the whole scene to be in view
+    pub fn set_view_preset(&mut self, preset: ViewPreset) -> Result<(), String> {
+        let bounds = self.scene_bounds().ok_or("Cannot frame an empty scene")?;
+        let center = bounds.center();
+        let radius = bounds.bounding_radius();
+
+        let (x_axis, up, z_axis) = world_basis(&self.world_up);
+        let direction = preset.view_direction();
+        let direction = x_axis * direction.x() + up * direction.y() + z_axis * direction.z();
+        let (yaw, pitch) = angles_from_camera_front(&self.world_up, &direction);
+        // pitch can't quite reach straight up/down, so look along the clamped direction instead
+        self.set_camera_angles(yaw, pitch);
+
+        let fit_distance = radius / (self.camera_field_of_view / 2.).tan();
+        let framing_distance = fit_distance + radius * FRAMING_DISTANCE_MARGIN;
+        self.camera_origin = &center - &self.camera_front * framing_distance;
+        self.camera_animation = None;
+        self.update_pipeline();
+        Ok(())
+    }
+
+    /// Turns the camera toward the center of the scene without moving it
+    pub fn look_at_scene_center(&mut self) -> Result<(), String> {
+        let center = self
+            .scene_bounds()
+            .ok_or("Cannot look at the center of an empty scene")?
+            .center();
+        self.look_at(&center)
+            .map_err(|_| "The camera is already at the center of the scene".into())
+    }
+
+    /// Turns the camera toward `target`, keeping its current position
+    pub fn look_at(&mut self, target: &Point) -> Result<(), String> {
+        let direction = target - &self.camera_origin;
+        if direction.length_squared() == 0. {
+            return Err("Cannot look at the camera's own position".into());
+        }
+        let (yaw, pitch) = angles_from_camera_front(&self.world_up, &direction);
+        self.camera_animation = None;
+        self.set_camera_angles(yaw, pitch);
+        Ok(())
+    }
+
+    /// Moves the camera, keeping the direction it's facing
+    pub fn set_camera_origin(&mut self, camera_origin: Point) {
+        self.camera_origin = sanitize_vec3(camera_origin, self.camera_origin.clone());
+        self.camera_animation = None;
+        self.update_pipeline();
+    }
+
+    pub fn set_ray_epsilon(&mut self, ray_epsilon: f64) {
+        self.ray_epsilon = sanitize_non_negative(ray_epsilon, self.ray_epsilon);
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// Exposure only affects what is displayed, so accumulation can continue
+    pub fn set_exposure(&mut self, exposure: f64) {
+        self.exposure =
+            sanitize_positive(exposure, self.exposure).clamp(MIN_EXPOSURE, MAX_EXPOSURE);
+        self.should_render = true;
+    }
+
+    pub fn set_autofocus(&mut self, autofocus: bool) {
+        self.autofocus = autofocus;
+        self.should_render = true;
+    }
+
+    /// Shakes the camera for `duration` ms, by up to `intensity` scene units at first,
+    /// calming down smoothly to its resting position. Replaces any shake already underway
+    pub fn shake(&mut self, intensity: f64, duration: f64) {
+        self.camera_shake = Some(CameraShake {
+            intensity: sanitize_non_negative(intensity, 0.),
+            duration: sanitize_non_negative(duration, 0.),
+            start_time: None,
+            offset: Vec3::new(),
+        });
+        self.should_render = true;
+    }
+
+    /// The bob only applies while moving, so static shots stay stable
+    pub fn set_head_bob(&mut self, head_bob: bool, amplitude: f64, frequency: f64) {
+        self.head_bob = head_bob;
+        self.head_bob_amplitude = sanitize_non_negative(amplitude, self.head_bob_amplitude);
+        self.head_bob_frequency = sanitize_non_negative(frequency, self.head_bob_frequency);
+    }
+
+    pub fn set_auto_exposure(&mut self, auto_exposure: bool) {
+        self.auto_exposure = auto_exposure;
+        self.should_render = true;
+    }
+
+    pub fn set_exposure_target(&mut self, exposure_target: f64) {
+        self.exposure_target = sanitize_non_negative(exposure_target, self.exposure_target);
+        self.should_render = true;
+    }
+
+    pub fn set_accumulation_frame_count(&mut self, accumulation_frame_count: u32) {
+        let accumulation_frame_count = accumulation_frame_count.clamp(2, MAX_ACCUMULATION_FRAMES);
+        if self.accumulation_frame_count != accumulation_frame_count {
+            self.accumulation_frame_count = accumulation_frame_count;
+            self.should_update_accumulation_buffers = true;
+        }
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    pub fn set_g_buffer(&mut self, g_buffer: bool) {
+        if self.g_buffer != g_buffer {
+            self.g_buffer = g_buffer;
+            self.should_update_accumulation_buffers = true;
+        }
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// The sky is swapped in once it has been uploaded by the render loop
+    pub fn set_environment_map(&mut self, environment_map: EnvironmentMap) {
+        self.pending_environment_map = Some(environment_map);
+        self.should_render = true;
+    }
+
+    /// Renders a view for each eye, `ipd` (interpupillary distance) scene units apart
+    pub fn set_stereo_mode(&mut self, stereo_mode: StereoMode, ipd: f64) {
+        self.stereo_mode = stereo_mode;
+        self.ipd = sanitize_non_negative(ipd, self.ipd);
+        // side-by-side views have half the width
+        self.update_pipeline();
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// A wider aperture blurs everything away from the focus distance more strongly
+    /// (0 keeps everything in focus)
+    pub fn set_aperture(&mut self, aperture: f64) {
+        self.aperture = sanitize_non_negative(aperture, self.aperture);
+        self.lens_radius = self.aperture / 2.;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    pub fn set_aperture_blades(&mut self, blade_count: u32) {
+        self.aperture_blades = blade_count;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    pub fn set_diffuse_mode(&mut self, diffuse_mode: DiffuseMode) {
+        self.diffuse_mode = diffuse_mode;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// Positions the sun (and enables the sun + sky model)
+    pub fn set_sun(&mut self, elevation: f64, azimuth: f64, turbidity: f64) {
+        self.sun_elevation = sanitize(elevation, self.sun_elevation).clamp(0., 90.);
+        self.sun_azimuth = sanitize(azimuth, self.sun_azimuth);
+        self.turbidity = sanitize(turbidity, self.turbidity).clamp(1.7, 10.);
+        self.sun_sky_enabled = true;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    pub fn set_height_fog(&mut self, density: f64, falloff: f64, color: Vec3) {
+        self.height_fog_density = sanitize_non_negative(density, self.height_fog_density);
+        self.height_fog_falloff = sanitize_non_negative(falloff, self.height_fog_falloff);
+        self.height_fog_color = sanitize_vec3(color, self.height_fog_color.clone());
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    pub fn set_edge_aa(&mut self, edge_aa: bool) {
+        self.edge_aa = edge_aa;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    pub fn set_raw_output(&mut self, raw_output: bool) {
+        self.raw_output = raw_output;
+        // accumulated frames were stored gamma corrected (or not), so they can't be mixed
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    pub fn set_sun_sky_enabled(&mut self, sun_sky_enabled: bool) {
+        self.sun_sky_enabled = sun_sky_enabled;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// World-space direction toward the sun
+    pub fn sun_direction(&self) -> Vec3 {
+        // elevation is pitch and azimuth is yaw, relative to the world's up direction
+        camera_front_from_angles(&self.world_up, self.sun_azimuth, self.sun_elevation)
+    }
+
+    /// Disabling this renders at CSS-pixel resolution on HiDPI displays (faster, but blurrier)
+    pub fn set_respect_dpr(&mut self, respect_dpr: bool) {
+        if self.respect_dpr != respect_dpr {
+            self.respect_dpr = respect_dpr;
+            self.should_update_render_dimensions = true;
+        }
+        self.should_render = true;
+    }
+
+    /// Renders at exactly `width` x `height`, regardless of the window's size
+    pub fn set_fixed_resolution(&mut self, fixed_resolution: Option<(u32, u32)>) {
+        self.fixed_resolution =
+            fixed_resolution.map(|(width, height)| (width.max(1), height.max(1)));
+        self.should_update_render_dimensions = true;
+        self.should_render = true;
+    }
+
+    /// Enabling restarts rendering from a coarse frame
+    pub fn set_progressive_startup(&mut self, progressive_startup: bool) {
+        self.progressive_startup = progressive_startup;
+        let progressive_scale = if progressive_startup {
+            PROGRESSIVE_STARTUP_SCALE
+        } else {
+            1.
+        };
+        if self.progressive_scale != progressive_scale {
+            self.progressive_scale = progressive_scale;
+            self.should_update_render_dimensions = true;
+        }
+        self.should_render = true;
+    }
+
+    /// Bounds are swapped if given in the wrong order. Disabling leaves
+    /// `samples_per_pixel` wherever auto samples last put it
+    pub fn set_auto_samples(&mut self, enabled: bool, min: u32, max: u32, target_fps: f64) {
+        self.auto_samples = enabled;
+        self.min_auto_samples = min.min(max).max(1);
+        self.max_auto_samples = min.max(max).max(1);
+        self.auto_samples_target_fps = sanitize_positive(target_fps, self.auto_samples_target_fps);
+        if enabled {
+            self.samples_per_pixel = self
+                .samples_per_pixel
+                .clamp(self.min_auto_samples, self.max_auto_samples);
+        }
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// `None` goes back to rendering one pass of `samples_per_pixel` per frame
+    pub fn set_frame_budget_ms(&mut self, frame_budget_ms: Option<f64>) {
+        self.frame_budget_ms = frame_budget_ms.filter(|budget| budget.is_finite() && *budget > 0.);
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// Spends `samples_per_pixel` samples on every pixel inside the region (x, y, width, height
+    /// in pixels from the top-left of the image), while the rest of the frame keeps rendering
+    /// at the normal sample count
+    pub fn set_roi(&mut self, roi: (u32, u32, u32, u32), samples_per_pixel: u32) {
+        self.roi = Some(roi);
+        self.roi_samples_per_pixel = samples_per_pixel.clamp(1, MAX_ROI_SAMPLES_PER_PIXEL);
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// The region of interest as `[x, y, width, height]` in canvas pixels from the bottom-left
+    /// (like `gl_FragCoord`), shifted past any letterboxing bars
+    pub fn roi_on_canvas(&self) -> Option<[f32; 4]> {
+        let roi = self.roi?;
+        let (scale_x, scale_y) = self.letterbox_scale();
+        let (width, height) = (self.width as f32, self.height as f32);
+        let (image_width, image_height) = (width * scale_x as f32, height * scale_y as f32);
+        let [x, y, roi_width, roi_height] = roi_from_bottom_left(roi, image_height);
+        let left = (width - image_width) / 2.;
+        let bottom = (height - image_height) / 2.;
+        Some([x + left, y + bottom, roi_width, roi_height])
+    }
+
+    pub fn clear_roi(&mut self) {
+        self.roi = None;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// Whether frames are time-sliced into 1-sample passes (only while accumulating)
+    pub fn uses_frame_budget(&self) -> bool {
+        self.frame_budget_ms.is_some() && self.should_average && !self.is_paused()
+    }
+
+    /// Whether both the camera and the scene are paused, in which case nothing can change,
+    /// so new frames stop being rendered
+    pub fn is_paused(&self) -> bool {
+        self.camera_paused && self.scene_paused
+    }
+
+    /// Restarts accumulation, rendering exactly `samples` frames before pausing
+    pub fn render_samples(&mut self, samples: u32) {
+        self.target_render_count = Some(samples.max(1));
+        self.set_paused(false);
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// Pauses (or resumes) the camera and the scene together
+    pub fn set_paused(&mut self, is_paused: bool) {
+        self.camera_paused = is_paused;
+        self.scene_paused = is_paused;
+    }
+
+    pub fn set_keyboard_focus(&mut self, has_keyboard_focus: bool) {
+        if !has_keyboard_focus {
+            // key releases stop arriving once focus moves elsewhere,
+            // so anything held down would otherwise stay held forever
+            self.keydown_map = Default::default();
+            self.axis_lock = None;
+        }
+        self.has_keyboard_focus = has_keyboard_focus;
+    }
+
+    pub fn set_camera_paused(&mut self, camera_paused: bool) {
+        self.camera_paused = camera_paused;
+        if camera_paused {
+            // keys held down when pausing would otherwise keep moving the camera on resume
+            self.keydown_map = Default::default();
+            self.camera_animation = None;
+        }
+        self.should_render = true;
+    }
+
+    pub fn set_scene_paused(&mut self, scene_paused: bool) {
+        self.scene_paused = scene_paused;
+        self.should_render = true;
+    }
+
+    pub fn set_pause_sample_boost(&mut self, boost_samples_when_paused: bool) {
+        if self.boost_samples_when_paused != boost_samples_when_paused && self.is_paused() {
+            // the sample count of the paused frame changes
+            self.render_count = 0;
+            self.should_render = true;
+        }
+        self.boost_samples_when_paused = boost_samples_when_paused;
+    }
+
+    pub fn set_render_mode(&mut self, render_mode: RenderMode) {
+        self.render_mode = render_mode;
+        self.should_render = true;
+    }
+
+    /// Re-renders from scratch (mostly useful in on-demand mode)
+    pub fn request_render(&mut self) {
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    /// Picking a point after both have been chosen starts a new measurement
+    pub fn add_measurement_point(&mut self, point: Point) {
+        if self.measurement_start.is_none() || self.measurement_end.is_some() {
+            self.measurement_start = Some(point);
+            self.measurement_end = None;
+        } else {
+            self.measurement_end = Some(point);
+        }
+    }
+
+    pub fn clear_measurement(&mut self) {
+        self.measurement_start = None;
+        self.measurement_end = None;
+    }
+
+    pub fn measured_distance(&self) -> Option<f64> {
+        match (&self.measurement_start, &self.measurement_end) {
+            (Some(start), Some(end)) => Some(Vec3::distance(start, end)),
+            _ => None,
+        }
+    }
+
+    /// The inverse of casting a ray through the viewport: finds the viewport (s, t)
+    /// coordinates a world-space point appears at, or None if it's behind the camera
+    pub fn project_to_viewport(&self, point: &Point) -> Option<(f64, f64)> {
+        let direction = point - &self.camera_origin;
+        let to_viewport = &self.lower_left_corner - &self.camera_origin;
+        // the viewport lies in front of the camera, along -w
+        let depth = -Vec3::dot(&direction, &self.w);
+        if depth <= 0. {
+            return None;
+        }
+        let on_viewport = direction * (-Vec3::dot(&to_viewport, &self.w) / depth) - to_viewport;
+        let s = Vec3::dot(&on_viewport, &self.horizontal) / self.horizontal.length_squared();
+        let t = Vec3::dot(&on_viewport, &self.vertical) / self.vertical.length_squared();
+        Some((s, t))
+    }
+
+    /// Call once a new fragment shader has been successfully swapped in
+    pub fn set_fragment_shader_source(&mut self, source: &str) {
+        self.fragment_shader_source = source.to_string();
+        // the new program starts out without any uniforms set
+        self.camera_basis_dirty = true;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    pub fn set_clear_color(&mut self, clear_color: Vec3, clear_alpha: f64) {
+        self.clear_color = sanitize_vec3(clear_color, self.clear_color.clone());
+        self.clear_alpha = sanitize(clear_alpha, self.clear_alpha).clamp(0., 1.);
+        self.should_render = true;
+    }
+
+    /// `None` fills the whole canvas; otherwise the image is letterboxed to this aspect ratio
+    pub fn set_target_aspect_ratio(&mut self, target_aspect_ratio: Option<f64>) {
+        self.target_aspect_ratio =
+            target_aspect_ratio.filter(|ratio| ratio.is_finite() && *ratio > 0.);
+        self.should_update_vertex_buffer = true;
+        self.update_pipeline();
+    }
+
+    /// Fraction of the canvas's width and height covered by the (letterboxed) image
+    pub fn letterbox_scale(&self) -> (f64, f64) {
+        let canvas_aspect_ratio = (self.width as f64) / (self.height as f64);
+        letterbox_scale(canvas_aspect_ratio, self.target_aspect_ratio)
+    }
+
+    /// Maps coordinates on the canvas (0->1) to coordinates on the image (0->1)
+    pub fn canvas_to_viewport(&self, s: f64, t: f64) -> (f64, f64) {
+        let (scale_x, scale_y) = self.letterbox_scale();
+        ((s - 0.5) / scale_x + 0.5, (t - 0.5) / scale_y + 0.5)
+    }
+
+    /// Maps coordinates on the image (0->1) to coordinates on the canvas (0->1)
+    pub fn viewport_to_canvas(&self, s: f64, t: f64) -> (f64, f64) {
+        let (scale_x, scale_y) = self.letterbox_scale();
+        ((s - 0.5) * scale_x + 0.5, (t - 0.5) * scale_y + 0.5)
+    }
+
+    pub fn set_outline_selected(&mut self, outline_selected: bool) {
+        self.outline_selected = outline_selected;
+        // the outline is only drawn when a frame is rendered
+        self.should_render = true;
+    }
+
+    /// Whether there's a selected object to draw an outline around
+    pub fn should_draw_selection_outline(&self) -> bool {
+        self.outline_selected && self.selected_object != NO_SELECTED_OBJECT_ID
+    }
+
+    /// The grid is only composited over the displayed image, so accumulation carries on
+    pub fn set_show_grid(&mut self, show_grid: bool) {
+        self.show_grid = show_grid;
+        self.should_render = true;
+    }
+
+    pub fn set_grid_spacing(&mut self, grid_spacing: f64) {
+        self.grid_spacing = sanitize_positive(grid_spacing, self.grid_spacing);
+        self.should_render = true;
+    }
+
+    pub fn set_show_bounds(&mut self, show_bounds: bool) {
+        self.show_bounds = show_bounds;
+        // the overlay is only drawn when a frame is rendered
+        self.should_render = true;
+    }
+
+    pub fn set_crosshair(&mut self, enabled: bool, size: f64) {
+        self.crosshair_enabled = enabled;
+        self.crosshair_size = sanitize_non_negative(size, self.crosshair_size);
+    }
+
+    /// Disabling the profiler also hides it
+    pub fn set_profiler_enabled(&mut self, enabled: bool) {
+        self.profiler_enabled = enabled;
+        self.show_profiler &= enabled;
+    }
+
+    pub fn toggle_profiler(&mut self) {
+        self.show_profiler = !self.show_profiler && self.profiler_enabled;
+    }
+
+    /// Everything that can be picked or focused on from the CPU side
+    pub fn hittables(&self) -> World<'_> {
+        World {
+            spheres: &self.world_spheres,
+            rects: &self.rect_lights,
+        }
+    }
+
+    pub fn sphere_mut(&mut self, uuid: i32) -> Result<&mut Sphere, String> {
+        self.sphere_list
+            .iter_mut()
+            .find(|sphere| sphere.uuid == uuid)
+            .ok_or_else(|| format!("No sphere found with uuid {uuid}"))
+    }
+
+    /// Grabs a sphere at `grab_point` (on its surface), to be moved by `update_sphere_drag`
+    pub fn start_sphere_drag(&mut self, uuid: i32, grab_point: Point) -> Result<(), String> {
+        let start_center = self.sphere_mut(uuid)?.center.clone();
+        self.sphere_drag = Some(SphereDrag {
+            uuid,
+            grab_point,
+            plane_normal: self.w.clone(),
+            start_center,
+            has_moved: false,
+        });
+        Ok(())
+    }
+
+    /// Moves the dragged sphere to follow the point on the viewport at (`s`, `t`),
+    /// only along `axis_lock` when one is held
+    pub fn update_sphere_drag(&mut self, s: f64, t: f64) -> Result<(), String> {
+        let drag = match &self.sphere_drag {
+            Some(drag) => drag.clone(),
+            None => return Ok(()),
+        };
+        let ray = glsl::viewport_ray(self, s, t);
+        let point = match intersect_plane(&ray, &drag.grab_point, &drag.plane_normal) {
+            Some(point) => point,
+            // the plane is edge-on (or behind the camera), so there's nowhere to move to
+            None => return Ok(()),
+        };
+        // parents only translate their children, so world displacements apply unchanged
+        let displacement = constrain_to_axis(&(point - &drag.grab_point), self.axis_lock);
+        self.sphere_mut(drag.uuid)?.center = &drag.start_center + &displacement;
+        self.update_geometry();
+        if let Some(drag) = &mut self.sphere_drag {
+            drag.has_moved = true;
+        }
+        Ok(())
+    }
+
+    /// Lets go of the dragged sphere, returning whether it was moved
+    pub fn end_sphere_drag(&mut self) -> bool {
+        self.sphere_drag.take().is_some_and(|drag| drag.has_moved)
+    }
+
+    /// Call after editing `sphere_list` to upload the changes to the GPU on the next frame
+    pub fn update_geometry(&mut self) {
+        self.update_world_spheres();
+        self.should_update_geometry = true;
+        self.render_count = 0;
+        self.should_render = true;
+    }
+
+    fn update_world_spheres(&mut self) {
+        // parents are validated whenever they're set, so this should never fail
+        self.world_spheres =
+            glsl::resolve_world_spheres(&self.sphere_list).unwrap_or_else(|error| {
+                log::error!("{error}");
+                self.sphere_list.clone()
+            });
+        self.scene_bounds = compute_scene_bounds(&self.world_spheres, &self.rect_lights);
+    }
+
+    /// Moves every sphere at once from `x, y, z` triples, one per sphere in uuid order
+    /// (relative to the parent's center for spheres with a parent). Cheaper than
+    /// `update_geometry`, since only the centers are sent to the GPU
+    pub fn set_sphere_positions(&mut self, positions: &[f32]) -> Result<(), String> {
+        if positions.len() != self.sphere_list.len() * 3 {
+            return Err(format!(
+                "Expected {} positions (x, y, z for each of the {} spheres), found {}",
+                self.sphere_list.len() * 3,
+                self.sphere_list.len(),
+                positions.len()
+            ));
+        }
+        if !positions.iter().all(|position| position.is_finite()) {
+            return Err("Sphere positions contain a non-finite number".into());
+        }
+        for (sphere, position) in self.sphere_list.iter_mut().zip(positions.chunks_exact(3)) {
+            sphere.center = Vec3(position[0] as f64, position[1] as f64, position[2] as f64);
+        }
+        self.update_world_spheres();
+        self.should_update_sphere_centers = true;
+        self.render_count = 0;
+        self.should_render = true;
+        Ok(())
+    }
+
+    /// Replaces every sphere in the scene
+    pub fn set_scene(&mut self, mut spheres: Vec<Sphere>) -> Result<(), String> {
+        if spheres.len() > glsl::MAX_SPHERES {
+            return Err(format!(
+                "Scenes can contain at most {} spheres (found {})",
+                glsl::MAX_SPHERES,
+                spheres.len()
+            ));
+        }
+        spheres.iter().try_for_each(validate_sphere)?;
+        glsl::set_sphere_uuids(&mut spheres);
+        glsl::resolve_world_spheres(&spheres)?;
+        self.sphere_list = spheres;
+        self.selected_object = NO_SELECTED_OBJECT_ID;
+        self.update_geometry();
+        Ok(())
+    }
+
+    /// Replaces every rect in the scene
+    pub fn set_rect_lights(&mut self, mut rects: Vec<Rect>) -> Result<(), String> {
+        if rects.len() > glsl::MAX_RECT_LIGHTS {
+            return Err(format!(
+                "Scenes can contain at most {} rect lights (found {})",
+                glsl::MAX_RECT_LIGHTS,
+                rects.len()
+            ));
+        }
+        rects.iter().try_for_each(validate_rect)?;
+        glsl::set_rect_uuids(&mut rects);
+        self.rect_lights = rects;
+        self.selected_object = NO_SELECTED_OBJECT_ID;
+        self.update_geometry();
+        Ok(())
+    }
+
+    pub fn set_transition_duration(&mut self, transition_duration: f64) {
+        self.transition_duration =
+            sanitize_non_negative(transition_duration, self.transition_duration);
+    }
+
+    /// Whether switching scenes now can cross-fade: there must be an accumulated frame
+    /// of the old scene to fade from
+    pub fn can_transition(&self) -> bool {
+        self.transition_duration > 0. && self.should_average && self.render_count > 0
+    }
+
+    /// Begins fading in the current scene over the last frame of the previous one
+    /// (which must already have been captured by the renderer)
+    pub fn start_scene_transition(&mut self, now: f64) {
+        self.transition_start_time = Some(now);
+        self.transition_progress = 0.;
+        self.should_render = true;
+    }
+
+    /// Attaches a sphere to a parent (or detaches it, with `None`), so that it moves along
+    /// with the parent from then on. The sphere stays where it is in the world
+    pub fn set_sphere_parent(&mut self, uuid: i32, parent_uuid: Option<i32>) -> Result<(), String> {
+        let mut spheres = self.sphere_list.clone();
+        let world_center = |spheres: &[Sphere], uuid: i32| {
+            glsl::resolve_world_spheres(spheres).and_then(|world_spheres| {
+                world_spheres
+                    .into_iter()
+                    .find(|sphere| sphere.uuid == uuid)
+                    .map(|sphere| sphere.center)
+                    .ok_or_else(|| format!("No sphere found with uuid {uuid}"))
+            })
+        };
+        let center = world_center(&spheres, uuid)?;
+        let parent_center = match parent_uuid {
+            Some(parent_uuid) => world_center(&spheres, parent_uuid)?,
+            None => Vec3::new(),
+        };
+        let sphere = spheres
+            .iter_mut()
+            .find(|sphere| sphere.uuid == uuid)
+            .ok_or_else(|| format!("No sphere found with uuid {uuid}"))?;
+        sphere.center = center - parent_center;
+        sphere.parent_uuid = parent_uuid;
+        // rejects cycles
+        glsl::resolve_world_spheres(&spheres)?;
+
+        self.sphere_list = spheres;
+        self.update_geometry();
+        Ok(())
+    }
+
+    /// Hides or shows a sphere without removing it from the scene
+    pub fn set_sphere_visible(&mut self, uuid: i32, visible: bool) -> Result<(), String> {
+        self.sphere_mut(uuid)?.visible = visible;
+        self.update_geometry();
+        Ok(())
+    }
+
+    /// Blends `material2` into a sphere's surface in patches of noise `blend_scale` units across,
+    /// or goes back to a single material when `material2` is `None`
+    pub fn set_sphere_blend(
+        &mut self,
+        uuid: i32,
+        material2: Option<Material>,
+        blend_scale: f64,
+    ) -> Result<(), String> {
+        if let Some(material2) = &material2 {
+            validate_material(material2)?;
+        }
+        let sphere = self.sphere_mut(uuid)?;
+        sphere.blend_scale = sanitize_positive(blend_scale, sphere.blend_scale);
+        sphere.material2 = material2;
+        self.update_geometry();
+        Ok(())
+    }
+
+    pub fn set_sphere_material(&mut self, uuid: i32, material: Material) -> Result<(), String> {
+        validate_material(&material)?;
+        self.sphere_mut(uuid)?.material = material;
+        self.update_geometry();
+        Ok(())
+    }
+
+    pub fn set_sphere_refraction_index_preset(
+        &mut self,
+        uuid: i32,
+        name: &str,
+    ) -> Result<(), String> {
+        let refraction_index = glsl::refraction_index_preset(name)?;
+        self.sphere_mut(uuid)?.material.refraction_index = refraction_index;
+        self.update_geometry();
+        Ok(())
+    }
+
+    pub fn average_fps(&self) -> f64 {
+        self.prev_fps.iter().sum::<f64>() / (self.prev_fps.len() as f64)
+    }
+
+    /// Samples per pixel actually rendered: the sample rate is increased while paused
+    /// (such as on first render and when resizing), since frames aren't being continually rendered
+    pub fn effective_samples_per_pixel(&self) -> u32 {
+        if self.is_paused() && self.boost_samples_when_paused && self.auto_samples {
+            // there's no frame rate to keep up while paused
+            self.max_auto_samples
+        } else if self.is_paused() && self.boost_samples_when_paused {
+            self.samples_per_pixel.max(25)
+        } else if self.uses_frame_budget() {
+            1
+        } else {
+            self.samples_per_pixel
+        }
+    }
+
+    pub fn render_stats(&self) -> RenderStats {
+        let samples_per_pixel = self.effective_samples_per_pixel();
+        // the shader doesn't report how many bounces each ray actually takes,
+        // so estimate that most paths terminate (hit the sky) halfway to max depth
+        let average_bounces = (self.max_depth as f64 / 2.).max(1.);
+        let rays_per_frame = (self.width as f64)
+            * (self.height as f64)
+            * (samples_per_pixel as f64)
+            * (self.stereo_mode.eyes_per_pixel() as f64)
+            * average_bounces;
+        let accumulated_samples = if self.should_average {
+            self.render_count.saturating_mul(samples_per_pixel)
+        } else {
+            samples_per_pixel
+        };
+
+        let rays_per_second = match self.gpu_timing {
+            Some(timing) => rays_per_second(timing.rays, timing.time),
+            None => rays_per_second(self.last_frame_rays, self.last_frame_time),
+        };
+
+        RenderStats {
+            rays_per_frame,
+            last_frame_time: self.last_frame_time,
+            accumulated_samples,
+            rays_per_second,
+            is_gpu_timed: self.gpu_timing.is_some(),
+        }
+    }
+}
+
+/// Names of the built-in scenes accepted by `scene_preset`
+pub const SCENE_PRESETS: [&str; 3] = ["default", "single", "bubble"];
+
+/// The spheres making up one of the built-in scenes (uuids are assigned when the scene is set)
+pub fn scene_preset(name: &str) -> Result<Vec<Sphere>, String> {
+    match name {
+        "default" => Ok(default_scene()),
+        "single" => Ok(single_sphere_scene()),
+        "bubble" => Ok(bubble_scene()),
+        _ => Err(format!(
+            "Unknown scene preset \"{name}\" (expected one of: {})",
+            SCENE_PRESETS.join(", ")
+        )),
+    }
+}
+
+fn default_scene() -> Vec<Sphere> {
+    vec![
+        // ground
+        Sphere {
+            center: Vec3(0., -100.5, -1.),
+            radius: 100.,
+            material: Material {
+                albedo: Vec3(0.75, 0.6, 0.5),
+                ..Material::default()
+            },
+            ..Default::default()
+        },
+        // center (blue)
+        Sphere {
+            center: Vec3(0., 0., -1.),
+            radius: 0.5,
+            material: Material {
+                albedo: Vec3(0.3, 0.3, 0.4),
+                ..Material::default()
+            },
+            ..Default::default()
+        },
+        // left
+        Sphere {
+            center: Vec3(-1.1, 0., -1.),
+            radius: 0.5,
+            material: Material {
+                material_type: MaterialType::Metal,
+                albedo: Vec3(1.0, 1.0, 1.0),
+                ..Material::default()
+            },
+            ..Default::default()
+        },
+        // right
+        Sphere {
+            center: Vec3(1.1, 0., -1.),
+            radius: 0.5,
+            material: Material::glass_preset("glass").unwrap(),
+            ..Default::default()
+        },
+        // back left (shiny)
+        Sphere {
+            center: Vec3(-0.5, -0.35, -0.55),
+            radius: -0.15,
+            material: Material {
+                material_type: MaterialType::Metal,
+                albedo: Vec3(1.0, 1.0, 1.0),
+                ..Material::default()
+            },
+            ..Default::default()
+        },
+        // front left (fuzzy)
+        Sphere {
+            center: Vec3(-0.75, -0.4, -0.35),
+            radius: -0.1,
+            material: Material {
+                material_type: MaterialType::Metal,
+                albedo: Vec3(1.0, 1.0, 1.0),
+                ..Material::default()
+            },
+            ..Default::default()
+        },
+        // behind
+        Sphere {
+            center: Vec3(0., 1.2, 4.),
+            radius: 2.,
+            material: Material {
+                albedo: Vec3(1.0, 0.8, 0.8),
+                ..Material::default()
+            },
+            ..Default::default()
+        },
+        // distant (moon)
+        Sphere {
+            center: Vec3(150., 20., -500.),
+            radius: 100.,
+            material: Material {
+                albedo: Vec3(0.95, 0.95, 1.0),
+                ..Material::default()
+            },
+            ..Default::default()
+        },
+        // distant moon's moon
+        Sphere {
+            center: Vec3(170., -20., -350.),
+            radius: 30.,
+            material: Material {
+                albedo: Vec3(1.0, 1.0, 1.0),
+                ..Material::default()
+            },
+            ..Default::default()
+        },
+    ]
+}
+
+/// A single diffuse sphere resting on the ground
+fn single_sphere_scene() -> Vec<Sphere> {
+    let ground = default_scene().remove(0);
+    vec![
+        ground,
+        Sphere {
+            center: Vec3(0., 0., -1.),
+            radius: 0.5,
+            ..Default::default()
+        },
+    ]
+}
+
+/// A hollow glass bubble resting on the ground: a glass sphere containing
+/// a slightly smaller one with a negative radius, which forms the inner wall
+fn bubble_scene() -> Vec<Sphere> {
+    let ground = default_scene().remove(0);
+    vec![
+        ground,
+        Sphere {
+            center: Vec3(0., 0., -1.),
+            radius: 0.5,
+            material: Material::glass_preset("glass").unwrap(),
+            ..Default::default()
+        },
+        // moves along with the outer wall
+        Sphere {
+            center: Vec3::new(),
+            radius: -0.45,
+            material: Material::glass_preset("glass").unwrap(),
+            parent_uuid: Some(1),
+            ..Default::default()
+        },
+    ]
+}
+
+/// Parses CSV rows of `x,y,z,radius,r,g,b` (colors from 0->1) into diffuse spheres.
+/// Rows that can't be parsed (including a header) are skipped, and described in the returned
+/// warnings, as are any rows past the shader's `MAX_SPHERES` limit
+pub fn spheres_from_csv(text: &str) -> (Vec<Sphere>, Vec<String>) {
+    let mut spheres = Vec::new();
+    let mut warnings = Vec::new();
+    let rows = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    for (i, line) in rows {
+        let row = i + 1;
+        let values: Result<Vec<f64>, _> = line
+            .split(',')
+            .map(|value| value.trim().parse::<f64>())
+            .collect();
+        let sphere = match values.as_deref() {
+            Ok(&[x, y, z, radius, r, g, b]) => Sphere {
+                center: Vec3(x, y, z),
+                radius,
+                material: Material {
+                    albedo: Vec3(r.clamp(0., 1.), g.clamp(0., 1.), b.clamp(0., 1.)),
+                    ..Material::default()
+                },
+                ..Default::default()
+            },
+            Ok(values) => {
+                warnings.push(format!(
+                    "Skipped row {row}: expected 7 values (x,y,z,radius,r,g,b), found {}",
+                    values.len()
+                ));
+                continue;
+            }
+            Err(error) => {
+                warnings.push(format!("Skipped row {row}: {error}"));
+                continue;
+            }
+        };
+        if let Err(error) = validate_sphere(&sphere) {
+            warnings.push(format!("Skipped row {row}: {error}"));
+            continue;
+        }
+        if spheres.len() == glsl::MAX_SPHERES {
+            warnings.push(format!(
+                "Skipped row {row}: scenes can contain at most {} spheres",
+                glsl::MAX_SPHERES
+            ));
+            continue;
+        }
+        spheres.push(sphere);
+    }
+    (spheres, warnings)
+}
+
+unsafe impl Send for State {}
+unsafe impl Sync for State {}
+
+pub fn update_render_dimensions_to_match_window(
+    state: &mut MutexGuard<State>,
+    renderer: &Renderer,
+    canvas: &HtmlCanvasElement,
+    now: f64,
+) {
+    // update state
+    let previous_dimensions = (state.width, state.height);
+    let render_count = state.render_count;
+    state.last_resize_time = now;
+    if let Some((width, height)) = state.fixed_resolution {
+        // anything bigger can't be allocated as a texture
+        let max_size = renderer.max_texture_size;
+        let clamped = (width.min(max_size), height.min(max_size));
+        if clamped != (width, height) {
+            log::warn!("{width}x{height} is larger than this GPU's maximum texture size ({max_size}), so it was clamped to {}x{}", clamped.0, clamped.1);
+            state.fixed_resolution = Some(clamped);
+        }
+        (state.width, state.height) = clamped;
+    } else {
+        let (width, height) = dom::get_adjusted_screen_dimensions(state.respect_dpr);
+        let scale = state.render_scale * state.progressive_scale;
+        state.width = ((width as f64 * scale) as u32).max(1);
+        state.height = ((height as f64 * scale) as u32).max(1);
+    }
+    state.should_update_vertex_buffer = true;
+    state.update_pipeline();
+
+    // small resizes keep what has been accumulated so far (scaled to the new size),
+    // while anything bigger starts over
+    let should_preserve = render_count > 0
+        && can_preserve_accumulation(previous_dimensions, (state.width, state.height));
+    if should_preserve {
+        // the camera barely changed, so keep accumulating where the old frames left off
+        state.render_count = render_count;
+    } else {
+        state.render_count = 0;
+    }
+
+    // sync width/height-dependent objects with state
+    canvas.set_width(state.width);
+    canvas.set_height(state.height);
+    state.should_update_canvas_rect = true;
+    renderer
+        .gl
+        .viewport(0, 0, state.width as i32, state.height as i32);
+    renderer.resize_accumulation_buffers(state, should_preserve.then_some(previous_dimensions));
+}
+
+/// Whether a resize is small enough that accumulated frames can be scaled to the new size
+/// without noticeably distorting (or blurring) them
+pub fn can_preserve_accumulation(previous: (u32, u32), new: (u32, u32)) -> bool {
+    let relative_change = |from: u32, to: u32| (to as f64 / from.max(1) as f64 - 1.).abs();
+    let aspect_ratio = |(width, height): (u32, u32)| width as f64 / height.max(1) as f64;
+    let aspect_change = (aspect_ratio(new) / aspect_ratio(previous) - 1.).abs();
+    relative_change(previous.0, new.0) <= MAX_PRESERVED_RESIZE
+        && relative_change(previous.1, new.1) <= MAX_PRESERVED_RESIZE
+        && aspect_change <= MAX_PRESERVED_ASPECT_CHANGE
+}
+
+/// Returns `value` if it's a finite number, otherwise `fallback`.
+/// Keeps a single bad JS call from poisoning `State` with `NaN` (which blanks the render)
+pub fn sanitize(value: f64, fallback: f64) -> f64 {
+    if value.is_finite() {
+        value
+    } else {
+        fallback
+    }
+}
+
+/// Returns `value` if it's finite and greater than zero, otherwise `fallback`
+pub fn sanitize_positive(value: f64, fallback: f64) -> f64 {
+    if value.is_finite() && value > 0. {
+        value
+    } else {
+        fallback
+    }
+}
+
+/// Returns `value` if it's finite and not negative, otherwise `fallback`
+pub fn sanitize_non_negative(value: f64, fallback: f64) -> f64 {
+    if value.is_finite() && value >= 0. {
+        value
+    } else {
+        fallback
+    }
+}
+
+fn is_finite_vec3(value: &Vec3) -> bool {
+    value.x().is_finite() && value.y().is_finite() && value.z().is_finite()
+}
+
+/// Returns `value` if all of its components are finite, otherwise `fallback`
+pub fn sanitize_vec3(value: Vec3, fallback: Vec3) -> Vec3 {
+    if is_finite_vec3(&value) {
+        value
+    } else {
+        fallback
+    }
+}
+
+/// Field of view after validation: invalid values leave the current field of view unchanged
+pub fn sanitize_fov(new_fov_radians: f64, current_fov_radians: f64) -> f64 {
+    sanitize_positive(new_fov_radians, current_fov_radians).clamp(0.0001, PI * 0.75)
+}
+
+/// Checks that every number describing a material is usable by the shader
+pub fn validate_material(material: &Material) -> Result<(), String> {
+    let colors_are_finite = [&material.albedo, &material.absorption, &material.emission]
+        .into_iter()
+        .all(is_finite_vec3);
+    if !colors_are_finite || !material.fuzz.is_finite() || !material.refraction_index.is_finite() {
+        return Err("Material contains a non-finite number".into());
+    }
+    // negative absorption would add light to rays passing through glass
+    let absorption = &material.absorption;
+    if [absorption.x(), absorption.y(), absorption.z()]
+        .iter()
+        .any(|&a| a < 0.)
+    {
+        return Err("Material absorption can't be negative".into());
+    }
+    Ok(())
+}
+
+/// Checks that every number describing a sphere is usable by the shader
+pub fn validate_sphere(sphere: &Sphere) -> Result<(), String> {
+    if !is_finite_vec3(&sphere.center) {
+        return Err("Sphere center contains a non-finite number".into());
+    }
+    // negative radii are allowed, and flip the sphere inside out
+    if !(sphere.radius.is_finite() && sphere.radius != 0.) {
+        return Err(format!("Invalid sphere radius: {}", sphere.radius));
+    }
+    if !(sphere.blend_scale.is_finite() && sphere.blend_scale > 0.) {
+        return Err(format!("Invalid blend scale: {}", sphere.blend_scale));
+    }
+    if let Some(material2) = &sphere.material2 {
+        validate_material(material2)?;
+    }
+    validate_material(&sphere.material)
+}
+
+/// Checks that every number describing a rect is usable by the shader
+pub fn validate_rect(rect: &Rect) -> Result<(), String> {
+    let bounds = [rect.a0, rect.a1, rect.b0, rect.b1, rect.k];
+    if !bounds.iter().all(|bound| bound.is_finite()) {
+        return Err("Rect bounds contain a non-finite number".into());
+    }
+    if rect.area() == 0. {
+        return Err("Rects must have a non-zero area".into());
+    }
+    validate_material(&rect.material)
+}
+
+/// Vertical field of view (in radians) of a lens with this focal length (in mm)
+pub fn field_of_view_from_focal_length(focal_length: f64) -> f64 {
+    2. * (SENSOR_HEIGHT / (2. * focal_length)).atan()
+}
+
+/// Focal length (in mm) of a lens with this vertical field of view (in radians)
+pub fn focal_length_from_field_of_view(camera_field_of_view: f64) -> f64 {
+    SENSOR_HEIGHT / (2. * (camera_field_of_view / 2.).tan())
+}
+
+/// Most samples per pixel traced in a single preview pass: any more are split into
+/// several passes, so that no single draw takes long enough for the browser to reset the GPU
+pub const MAX_PREVIEW_SAMPLES_PER_PASS: u32 = 64;
+
+/// Most samples per pixel traced inside a region of interest, for the same reason
+pub const MAX_ROI_SAMPLES_PER_PIXEL: u32 = MAX_PREVIEW_SAMPLES_PER_PASS;
+
+/// Largest preview width or height, since every pass is read back at full size
+pub const MAX_PREVIEW_SIZE: u32 = 2048;
+
+/// Splits a preview's samples per pixel into `(samples per pass, passes)`
+pub fn preview_passes(samples: u32) -> (u32, u32) {
+    let samples = samples.max(1);
+    let passes = samples.div_ceil(MAX_PREVIEW_SAMPLES_PER_PASS);
+    (samples.div_ceil(passes), passes)
+}
+
+/// Dimensions of an image exported at `aspect_ratio`: the height is kept,
+/// and the width grows or shrinks to match (up to `max_size`)
+pub fn export_dimensions(
+    height: u32,
+    aspect_ratio: f64,
+    max_size: u32,
+) -> Result<(u32, u32), String> {
+    if !(aspect_ratio.is_finite() && aspect_ratio > 0.) {
+        return Err(format!("Invalid aspect ratio: {aspect_ratio}"));
+    }
+    let width = ((height as f64) * aspect_ratio).round().max(1.);
+    if width > max_size as f64 {
+        return Err(format!(
+            "Aspect ratio {aspect_ratio} would make the image wider than {max_size} pixels"
+        ));
+    }
+    Ok((width as u32, height.max(1)))
+}
+
+/// Shrinks the image along whichever axis is too long to fit the target aspect ratio
+pub fn letterbox_scale(canvas_aspect_ratio: f64, target_aspect_ratio: Option<f64>) -> (f64, f64) {
+    match target_aspect_ratio {
+        // bars on the left and right
+        Some(target) if target < canvas_aspect_ratio => (target / canvas_aspect_ratio, 1.),
+        // bars on the top and bottom
+        Some(target) => (1., canvas_aspect_ratio / target),
+        None => (1., 1.),
+    }
+}
+
+pub fn update_moving_fps_array(now: f64, state: &mut MutexGuard<State>, dt: f64) {
+    // calculate moving fps
+    state.prev_now = now;
+    state.last_frame_time = dt;
+    let fps = 1000. / dt;
+    let last_index = state.prev_fps.len() - 1;
+    for (i, el) in state.prev_fps.into_iter().skip(1).enumerate() {
+        state.prev_fps[i] = el;
+    }
+    state.prev_fps[last_index] = fps;
+}
+
+/// Throughput of tracing `rays` in `time` ms (0 when nothing has been timed yet)
+pub fn rays_per_second(rays: f64, time: f64) -> f64 {
+    if time > 0. {
+        rays / time * 1000.
+    } else {
+        0.
+    }
+}
+
+/// Frame time to use for movement, limited to `MAX_MOVEMENT_FRAME_TIME`
+pub fn clamp_movement_frame_time(dt: f64) -> f64 {
+    dt.clamp(0., MAX_MOVEMENT_FRAME_TIME)
+}
+
+/// Records this frame's `dt` and returns the frame time to move by:
+/// the average of the last few clamped frame times
+pub fn smooth_movement_frame_time(state: &mut MutexGuard<State>, dt: f64) -> f64 {
+    let last_index = state.prev_movement_frame_times.len() - 1;
+    state.prev_movement_frame_times.copy_within(1.., 0);
+    state.prev_movement_frame_times[last_index] = clamp_movement_frame_time(dt);
+    state.prev_movement_frame_times.iter().sum::<f64>()
+        / (state.prev_movement_frame_times.len() as f64)
+}
+
+/// How far the camera moves in `dt` milliseconds: slower when more "zoomed in"
+pub fn movement_distance(dt: f64, camera_field_of_view: f64) -> f64 {
+    MOVEMENT_SPEED * dt * camera_field_of_view
+}
+
+/// Combines all pressed movement keys into a single displacement of length `distance`,
+/// so that moving diagonally isn't any faster than moving in a single direction.
+/// W/S move along the viewing direction, while space/shift ascend/descend along `vup`
+/// (the world's up direction), independent of the camera's pitch
+pub fn movement_displacement(
+    keydown_map: &KeydownMap,
+    camera_front: &Vec3,
+    vup: &Vec3,
+    distance: f64,
+) -> Vec3 {
+    let right = Vec3::normalize(Vec3::cross(camera_front, vup));
+    let mut direction = Vec3::new();
+    if keydown_map.w {
+        direction += camera_front.clone();
+    }
+    if keydown_map.a {
+        direction -= right.clone();
+    }
+    if keydown_map.s {
+        direction -= camera_front.clone();
+    }
+    if keydown_map.d {
+        direction += right.clone();
+    }
+    if keydown_map.space {
+        direction += vup.clone();
+    }
+    if keydown_map.shift {
+        direction -= vup.clone();
+    }
+
+    // opposing keys can cancel each other out
+    if direction.length() == 0. {
+        return direction;
+    }
+    Vec3::normalize(direction) * distance
+}
+
+pub fn update_position(state: &mut MutexGuard<State>, dt: f64) {
+    if state.camera_paused || state.keydown_map.all_false() {
+        return;
+    }
+
+    // manual movement takes over from any automatic camera flight
+    state.camera_animation = None;
+
+    let distance = movement_distance(dt, state.camera_field_of_view);
+    let displacement = movement_displacement(
+        &state.keydown_map,
+        &state.camera_front,
+        &state.world_up,
+        distance,
+    );
+    state.camera_origin += displacement;
+    if state.keydown_map.q {
+        let roll = state.roll - ROLL_SPEED * dt;
+        state.set_roll(roll);
+    }
+    if state.keydown_map.e {
+        let roll = state.roll + ROLL_SPEED * dt;
+        state.set_roll(roll);
+    }
+
+    update_cursor_position_in_world(state);
+    state.update_pipeline();
+}
+
+/// Vertical displacement of the camera `now` ms into a walk moving at `speed` units per second
+pub fn head_bob_offset(now: f64, amplitude: f64, frequency: f64, speed: f64) -> f64 {
+    amplitude * speed * (2. * PI * frequency * now / 1000.).sin()
+}
+
+/// Bobs the camera along `world_up` while moving, and settles it back once stationary.
+/// Moving the camera resets accumulation, so the bob does too
+pub fn update_head_bob(state: &mut MutexGuard<State>, now: f64) {
+    let offset = if state.head_bob && state.keydown_map.is_moving() {
+        let speed = movement_distance(1000., state.camera_field_of_view);
+        head_bob_offset(
+            now,
+            state.head_bob_amplitude,
+            state.head_bob_frequency,
+            speed,
+        )
+    } else {
+        0.
+    };
+    if offset == state.head_bob_offset {
+        return;
+    }
+    state.head_bob_offset = offset;
+    // only what's sent to the GPU moves: the camera's basis is unchanged
+    state.camera_basis_dirty = true;
+    state.render_count = 0;
+    state.should_render = true;
+}
+
+/// Eases exposure toward whatever brings the scene's measured
+/// log-average luminance to `exposure_target`
+pub fn update_auto_exposure(state: &mut MutexGuard<State>, average_luminance: f64, dt: f64) {
+    if average_luminance <= 0. {
+        return;
+    }
+    let target_exposure =
+        (state.exposure_target / average_luminance).clamp(MIN_EXPOSURE, MAX_EXPOSURE);
+    // frame-rate independent exponential easing avoids flickering from frame-to-frame noise
+    let t = 1. - (-dt.max(0.) / AUTO_EXPOSURE_ADAPTATION_TIME).exp();
+    state.exposure += (target_exposure - state.exposure) * t;
+}
+
+/// Orthonormal axes (x, up, z) that play the part of the usual Y-up world axes
+/// for any `world_up` direction (the standard axes are returned for Y-up)
+pub fn world_basis(world_up: &Vec3) -> (Vec3, Vec3, Vec3) {
+    let up = Vec3::normalize(world_up.clone());
+    // any reference direction works, as long as it isn't parallel to up
+    let reference = if Vec3::dot(&up, &Vec3(1., 0., 0.)).abs() < 0.999 {
+        Vec3(1., 0., 0.)
+    } else {
+        Vec3(0., 1., 0.)
+    };
+    let z_axis = Vec3::normalize(Vec3::cross(&reference, &up));
+    let x_axis = Vec3::cross(&up, &z_axis);
+    (x_axis, up, z_axis)
+}
+
+fn compute_scene_bounds(sphere_list: &[Sphere], rects: &[Rect]) -> Option<Aabb> {
+    sphere_list
+        .iter()
+        // hidden spheres shouldn't be framed or looked at
+        .filter(|sphere| sphere.visible)
+        .map(Sphere::bounding_box)
+        .chain(rects.iter().map(Rect::bounding_box))
+        .reduce(|a, b| Aabb::union(&a, &b))
+}
+
+/// Yaw/pitch (in degrees) that face in a direction -- the inverse of `camera_front_from_angles`
+pub fn angles_from_camera_front(world_up: &Vec3, camera_front: &Vec3) -> (f64, f64) {
+    let (x_axis, up, z_axis) = world_basis(world_up);
+    let camera_front = Vec3::normalize(camera_front.clone());
+    let pitch = Vec3::dot(&camera_front, &up).clamp(-1., 1.).asin();
+    let yaw = Vec3::dot(&camera_front, &z_axis).atan2(Vec3::dot(&camera_front, &x_axis));
+    (yaw.to_degrees(), pitch.to_degrees())
+}
+
+/// Viewing direction for a yaw/pitch (in degrees) relative to the world's up direction
+pub fn camera_front_from_angles(world_up: &Vec3, yaw: f64, pitch: f64) -> Vec3 {
+    let (x_axis, up, z_axis) = world_basis(world_up);
+    let (sin_yaw, cos_yaw) = degrees_to_radians(yaw).sin_cos();
+    let (sin_pitch, cos_pitch) = degrees_to_radians(pitch).sin_cos();
+    x_axis * (cos_yaw * cos_pitch) + up * sin_pitch + z_axis * (sin_yaw * cos_pitch)
+}
+
+/// Turns averaging off while the camera is moving and back on
+/// once it has been still for `still_frame_threshold` frames
+pub fn update_averaging_for_movement(state: &mut MutexGuard<State>) {
+    let is_moving = state.camera_origin != state.prev_camera_origin
+        || state.yaw != state.prev_yaw
+        || state.pitch != state.prev_pitch
+        || state.roll != state.prev_roll
+        || state.camera_field_of_view != state.prev_camera_field_of_view;
+
+    state.prev_camera_origin = state.camera_origin.clone();
+    state.prev_yaw = state.yaw;
+    state.prev_pitch = state.pitch;
+    state.prev_roll = state.roll;
+    state.prev_camera_field_of_view = state.camera_field_of_view;
+
+    if is_moving {
+        state.still_frame_count = 0;
+        if state.should_average {
+            state.should_average = false;
+            state.is_averaging_suspended = true;
+        }
+        return;
+    }
+
+    state.still_frame_count = state.still_frame_count.saturating_add(1);
+    if state.is_averaging_suspended && state.still_frame_count >= state.still_frame_threshold {
+        state.is_averaging_suspended = false;
+        state.should_average = true;
+        state.render_count = 0;
+        state.should_render = true;
+    }
+}
+
+/// While progressive startup is enabled, drops to a coarse resolution whenever the camera moves,
+/// then doubles the resolution after each frame is rendered until it is back to full resolution.
+/// Each step resets accumulation, so the converged image is the same as without it
+pub fn update_progressive_startup(state: &mut MutexGuard<State>) {
+    let progressive_scale = if !state.progressive_startup {
+        1.
+    } else if state.still_frame_count == 0 {
+        PROGRESSIVE_STARTUP_SCALE
+    } else if state.render_count >= 1 {
+        (state.progressive_scale * 2.).min(1.)
+    } else {
+        state.progressive_scale
+    };
+    if progressive_scale != state.progressive_scale {
+        state.progressive_scale = progressive_scale;
+        state.should_update_render_dimensions = true;
+    }
+}
+
+/// Converts a region of interest from pixels measured from the top-left into
+/// `[x, y, width, height]` measured from the bottom-left (like `gl_FragCoord`)
+pub fn roi_from_bottom_left(roi: (u32, u32, u32, u32), height: f32) -> [f32; 4] {
+    let (x, y, width, roi_height) = roi;
+    let bottom = height - (y as f32 + roi_height as f32);
+    [x as f32, bottom, width as f32, roi_height as f32]
+}
+
+/// Where a ray crosses the plane through `plane_point` (None if it never does, going forward)
+pub fn intersect_plane(ray: &Ray, plane_point: &Point, plane_normal: &Vec3) -> Option<Point> {
+    let denominator = Vec3::dot(&ray.direction, plane_normal);
+    if denominator.abs() < 1e-8 {
+        return None;
+    }
+    let t = Vec3::dot(&(plane_point - &ray.origin), plane_normal) / denominator;
+    if t < 0. {
+        return None;
+    }
+    Some(ray.at(t))
+}
+
+/// Only the locked component of a displacement (or all of it, with no lock)
+pub fn constrain_to_axis(displacement: &Vec3, axis_lock: Option<Axis>) -> Vec3 {
+    match axis_lock {
+        Some(Axis::X) => Vec3(displacement.x(), 0., 0.),
+        Some(Axis::Y) => Vec3(0., displacement.y(), 0.),
+        Some(Axis::Z) => Vec3(0., 0., displacement.z()),
+        None => displacement.clone(),
+    }
+}
+
+/// How far (0 -> 1) a cross-fade that started at `start_time` has progressed
+pub fn transition_progress(now: f64, start_time: f64, duration: f64) -> f64 {
+    if duration <= 0. {
+        return 1.;
+    }
+    ((now - start_time) / duration).clamp(0., 1.)
+}
+
+pub fn update_scene_transition(state: &mut MutexGuard<State>, now: f64) {
+    let start_time = match state.transition_start_time {
+        Some(start_time) => start_time,
+        None => return,
+    };
+    if state.scene_paused {
+        // hold the cross-fade where it is by moving its start along with the clock
+        let elapsed = state.transition_progress * state.transition_duration;
+        state.transition_start_time = Some(now - elapsed);
+        return;
+    }
+    state.transition_progress = transition_progress(now, start_time, state.transition_duration);
+    if state.transition_progress >= 1. {
+        state.transition_start_time = None;
+    }
+}
+
+pub fn update_camera_animation(state: &mut MutexGuard<State>, now: f64) {
+    let animation = match &state.camera_animation {
+        Some(animation) if !state.camera_paused => animation.clone(),
+        _ => return,
+    };
+
+    let progress = ((now - animation.start_time) / animation.duration).clamp(0., 1.);
+    // ease in and out
+    let t = progress * progress * (3. - 2. * progress);
+    state.camera_origin = Vec3::lerp(&animation.from, &animation.to, t);
+    if progress >= 1. {
+        state.camera_animation = None;
+    }
+
+    update_cursor_position_in_world(state);
+}
+
+/// Offset of a camera shake `elapsed` ms into it, which decays smoothly to zero by `duration`.
+/// Each axis follows its own mix of sine waves, so the motion looks random without jumping
+pub fn camera_shake_offset(intensity: f64, elapsed: f64, duration: f64) -> Vec3 {
+    let progress = (elapsed / duration.max(1.)).clamp(0., 1.);
+    let decay = (1. - progress).powi(2);
+    let seconds = elapsed / 1000.;
+    let wave = |frequency: f64, phase: f64| {
+        let angle = 2. * PI * frequency * seconds + phase;
+        (angle.sin() + (2.3 * angle + 1.7).sin() * 0.5) / 1.5
+    };
+    Vec3(wave(11., 0.), wave(13., 2.1), wave(7., 4.2)) * (intensity * decay)
+}
+
+pub fn update_camera_shake(state: &mut MutexGuard<State>, now: f64) {
+    let shake = match &mut state.camera_shake {
+        Some(shake) => shake,
+        None => return,
+    };
+    let start_time = *shake.start_time.get_or_insert(now);
+    let elapsed = now - start_time;
+    let is_finished = elapsed >= shake.duration;
+    let offset = if is_finished {
+        // end exactly where the camera was resting
+        Vec3::new()
+    } else {
+        camera_shake_offset(shake.intensity, elapsed, shake.duration)
+    };
+    shake.offset = offset;
+    if is_finished {
+        state.camera_shake = None;
+    }
+    // only what's sent to the GPU moves: the camera's basis is unchanged
+    state.camera_basis_dirty = true;
+    state.render_count = 0;
+    state.should_render = true;
+}
+
+/// Runs an offscreen render (such as an export) with the live view's render target and fixed
+/// seed set aside, so that its passes neither use up the target (pausing the live view) nor
+/// all share the same seed
+pub fn render_offscreen<T>(
+    state: &mut MutexGuard<State>,
+    render: impl FnOnce(&mut MutexGuard<State>) -> T,
+) -> T {
+    let target_render_count = state.target_render_count.take();
+    let fixed_seed_time = state.fixed_seed_time.take();
+    let result = render(state);
+    state.target_render_count = target_render_count;
+    state.fixed_seed_time = fixed_seed_time;
+    result
+}
+
+pub fn update_render_globals(state: &mut MutexGuard<State>) {
+    if !state.should_average {
+        // only continuously render when averaging is being done
+        state.should_render = false;
+    }
+    state.even_odd_count += 1;
+    state.render_count = (state.render_count + 1).min(state.max_render_count);
+    if let Some(target_render_count) = state.target_render_count {
+        // seeding each frame by its place in the accumulation makes the result reproducible
+        state.fixed_seed_time = Some(state.render_count as f64);
+        if state.render_count >= target_render_count.min(state.max_render_count) {
+            state.target_render_count = None;
+            state.set_paused(true);
+            state.should_render = false;
+        }
+    }
+    if state.render_mode == RenderMode::OnDemand
+        && state.render_count >= state.on_demand_render_count
+    {
+        // converged: idle until something changes
+        state.should_render = false;
+    }
+}
+
+/// The sample count that should bring the frame time closest to that of `target_fps`, assuming
+/// frame time scales with the number of samples. Changes by at most a factor of 2 at a time
+pub fn next_auto_samples(
+    samples: u32,
+    frame_time: f64,
+    target_fps: f64,
+    (min, max): (u32, u32),
+) -> u32 {
+    if !(frame_time.is_finite() && frame_time > 0.) {
+        return samples.clamp(min, max);
+    }
+    let target_frame_time = 1000. / target_fps;
+    let ideal_samples = (samples as f64 * target_frame_time / frame_time).round();
+    let ideal_samples = ideal_samples.clamp(samples as f64 / 2., samples as f64 * 2.);
+    (ideal_samples as u32).clamp(min, max)
+}
+
+/// How long (in ms) a frame takes to render at the current settings. The GPU's own timing is
+/// preferred, since the measured frame rate can never exceed the display's refresh rate,
+/// however much time is to spare
+pub fn auto_samples_frame_time(state: &State) -> f64 {
+    match state.gpu_timing {
+        // timings arrive a few frames late, so scale them to the current number of rays
+        Some(timing) if timing.rays > 0. => {
+            timing.time / timing.rays * state.render_stats().rays_per_frame
+        }
+        _ => 1000. / state.average_fps(),
+    }
+}
+
+/// Ramps `samples_per_pixel` up while frames render faster than the target frame rate, and
+/// back down when they're slower, so that interaction stays smooth while still using any
+/// spare GPU time
+pub fn update_auto_samples(state: &mut MutexGuard<State>, now: f64) {
+    // only a continuously rendering (and averaging) loop has a meaningful frame time
+    if !state.auto_samples || state.is_paused() || state.uses_frame_budget() {
+        return;
+    }
+    if now - state.prev_auto_samples_update_time < AUTO_SAMPLES_INTERVAL {
+        return;
+    }
+    state.prev_auto_samples_update_time = now;
+    state.samples_per_pixel = next_auto_samples(
+        state.samples_per_pixel,
+        auto_samples_frame_time(state),
+        state.auto_samples_target_fps,
+        (state.min_auto_samples, state.max_auto_samples),
+    );
+}
+
+/// Whether another pass fits in the frame budget, after `passes` have taken `elapsed` ms
+pub fn has_time_for_another_pass(passes: u32, elapsed: f64, frame_budget_ms: f64) -> bool {
+    if passes == 0 {
+        return true;
+    }
+    // assume the next pass takes as long as the average pass so far
+    let average_pass_time = elapsed / passes as f64;
+    passes < MAX_PASSES_PER_FRAME && elapsed + average_pass_time <= frame_budget_ms
+}
+
+/// Whether enough time has passed without input to count as idle
+pub fn has_idle_timeout_elapsed(last_input_time: f64, now: f64, idle_timeout: Option<f64>) -> bool {
+    idle_timeout.is_some_and(|idle_timeout| now - last_input_time >= idle_timeout)
+}
+
+/// Drops to the idle quality once no input has been received for `idle_timeout`
+pub fn update_idle(state: &mut MutexGuard<State>, now: f64) {
+    if has_idle_timeout_elapsed(state.last_input_time, now, state.idle_timeout) {
+        state.enter_idle();
+    }
+}
+
+/// Eases `focus_distance` toward whatever is at the center of the screen, if anything
+pub fn update_autofocus(state: &mut MutexGuard<State>, dt: f64) {
+    if !state.autofocus {
+        return;
+    }
+    let target_distance = match glsl::get_center_hit(state) {
+        HitResult::Hit { data } => (&data.hit_point - &state.camera_origin).length(),
+        HitResult::NoHit => return,
+    };
+    // stop once close enough, so that accumulation isn't reset every frame
+    if (target_distance - state.focus_distance).abs() < target_distance * 1e-4 {
+        return;
+    }
+    // frame-rate independent exponential easing avoids jittering between nearby surfaces
+    let t = 1. - (-dt.max(0.) / AUTOFOCUS_ADAPTATION_TIME).exp();
+    state.focus_distance += (target_distance - state.focus_distance) * t;
+    state.update_pipeline();
+}
+
+/// focus on whatever object is selected by the cursor if there was a collision
+pub fn update_cursor_position_in_world(state: &mut MutexGuard<State>) {
+    if let HitResult::Hit { data } = glsl::get_center_hit(state) {
+        let distance = (&data.hit_point - &state.camera_origin).length();
+        // autofocus eases toward the new distance instead of snapping to it
+        if state.aperture > 0. && !state.autofocus {
+            // there is no blurring if aperture is zerp
+            state.focus_distance = distance;
         }
         state.cursor_point = data.hit_point.clone();
         state.selected_object = data.uuid;
     } else {
-        if state.aperture > 0. {
+        if state.aperture > 0. && !state.autofocus {
             // there is no blurring if aperture is zerp
             state.focus_distance = 10.;
         }
@@ -469,3 +3045,657 @@ pub fn update_cursor_position_in_world(state: &mut MutexGuard<State>) {
     }
     state.update_pipeline();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_rows_become_spheres() {
+        let csv =
+            "x,y,z,radius,r,g,b\n0, 1, -2, 0.5, 1, 0, 0\n\n1,2,3\n4,5,6,0,0,0,0\n1,1,1,2,0,2,0.5\n";
+        let (spheres, warnings) = spheres_from_csv(csv);
+        assert_eq!(spheres.len(), 2);
+        assert_eq!(spheres[0].center, Vec3(0., 1., -2.));
+        assert_eq!(spheres[0].material.albedo, Vec3(1., 0., 0.));
+        assert_eq!(spheres[1].material.albedo, Vec3(0., 1., 0.5));
+        // the header, the short row, and the zero radius
+        assert_eq!(warnings.len(), 3);
+
+        let too_many = "0,0,0,1,0,0,0\n".repeat(glsl::MAX_SPHERES + 2);
+        let (spheres, warnings) = spheres_from_csv(&too_many);
+        assert_eq!(spheres.len(), glsl::MAX_SPHERES);
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn auto_samples_follow_the_frame_time() {
+        let bounds = (1, 16);
+        let fps = |fps: f64| 1000. / fps;
+        // twice as fast as needed: the frame time can double
+        assert_eq!(next_auto_samples(4, fps(60.), 30., bounds), 8);
+        // nearly on target: stays put
+        assert_eq!(next_auto_samples(4, fps(32.), 30., bounds), 4);
+        // too slow: drops, but by no more than half at a time
+        assert_eq!(next_auto_samples(4, fps(20.), 30., bounds), 3);
+        assert_eq!(next_auto_samples(8, fps(5.), 30., bounds), 4);
+        // a frame rate capped just below the display's refresh rate still ramps up
+        assert_eq!(next_auto_samples(1, fps(59.8), 30., bounds), 2);
+        // rendering faster than the refresh rate (as timed on the GPU) ramps up too
+        assert_eq!(next_auto_samples(4, 4., 60., bounds), 8);
+        // never leaves the bounds
+        assert_eq!(next_auto_samples(16, fps(120.), 30., bounds), 16);
+        assert_eq!(next_auto_samples(1, fps(5.), 30., bounds), 1);
+        // no measurement yet
+        assert_eq!(next_auto_samples(4, 0., 30., bounds), 4);
+    }
+
+    #[test]
+    fn auto_samples_prefer_gpu_timing() {
+        let mut state = State::with_dimensions(800, 600);
+        state.set_paused(false);
+        state.samples_per_pixel = 2;
+        state.gpu_timing = Some(GpuTiming {
+            time: 5.,
+            rays: state.render_stats().rays_per_frame,
+        });
+        assert_eq!(auto_samples_frame_time(&state), 5.);
+        // the timing was for half as many samples
+        state.samples_per_pixel = 4;
+        assert_eq!(auto_samples_frame_time(&state), 10.);
+    }
+
+    #[test]
+    fn accumulated_samples_saturate() {
+        let mut state = State::with_dimensions(800, 600);
+        state.set_paused(false);
+        state.should_average = true;
+        state.samples_per_pixel = 16;
+        state.render_count = u32::MAX / 2;
+        assert_eq!(state.render_stats().accumulated_samples, u32::MAX);
+    }
+
+    #[test]
+    fn camera_shake_decays_to_rest() {
+        let intensity = 0.2;
+        for elapsed in [0., 40., 250., 499.] {
+            let offset = camera_shake_offset(intensity, elapsed, 500.);
+            assert!(offset.length() <= intensity * 3f64.sqrt());
+        }
+        assert!(camera_shake_offset(intensity, 37., 500.).length() > 0.);
+        assert_eq!(camera_shake_offset(intensity, 500., 500.), Vec3::new());
+    }
+
+    #[test]
+    fn camera_shake_only_moves_the_rendered_camera() {
+        let state = std::sync::Mutex::new(State::with_dimensions(800, 600));
+        let mut state = state.lock().unwrap();
+        let snapshot = state.camera_snapshot();
+        state.shake(0.2, 500.);
+        update_camera_shake(&mut state, 1000.);
+        update_camera_shake(&mut state, 1037.);
+        assert_eq!(state.camera_snapshot(), snapshot);
+        let offset = &state.render_camera_origin() - &state.camera_origin;
+        assert!(offset.length() > 0.);
+        let corner_offset = &state.render_lower_left_corner() - &state.lower_left_corner;
+        assert!((&corner_offset - &offset).length() < 1e-12);
+        // back at rest once it's over
+        update_camera_shake(&mut state, 1500.);
+        assert!(state.camera_shake.is_none());
+        assert_eq!(state.render_camera_origin(), state.camera_origin);
+    }
+
+    #[test]
+    fn head_bob_is_bounded_and_still_when_stationary() {
+        let speed = movement_distance(1000., PI / 3.);
+        for now in [0., 125., 250., 333., 1000.] {
+            assert!(head_bob_offset(now, 0.02, 2., speed).abs() <= 0.02 * speed);
+            assert_eq!(head_bob_offset(now, 0.02, 2., 0.), 0.);
+        }
+        assert!((head_bob_offset(125., 0.02, 2., speed) - 0.02 * speed).abs() < 1e-12);
+    }
+
+    #[test]
+    fn losing_keyboard_focus_releases_held_keys() {
+        let mut state = State::with_dimensions(800, 600);
+        state.keydown_map.w = true;
+        state.axis_lock = Some(Axis::X);
+        state.set_keyboard_focus(true);
+        assert!(state.keydown_map.w);
+        state.set_keyboard_focus(false);
+        assert!(!state.has_keyboard_focus);
+        assert!(state.keydown_map.all_false());
+        assert_eq!(state.axis_lock, None);
+    }
+
+    #[test]
+    fn head_bob_only_moves_the_rendered_camera() {
+        let state = std::sync::Mutex::new(State::with_dimensions(800, 600));
+        let mut state = state.lock().unwrap();
+        state.set_head_bob(true, 0.02, 2.);
+        state.keydown_map.w = true;
+        let snapshot = state.camera_snapshot();
+        update_head_bob(&mut state, 125.);
+        assert!(state.head_bob_offset > 0.);
+        assert_eq!(state.camera_snapshot(), snapshot);
+        let offset = &state.render_camera_origin() - &state.camera_origin;
+        assert!((&offset - &(&state.world_up * state.head_bob_offset)).length() < 1e-12);
+        // settles back down once movement stops
+        state.keydown_map.w = false;
+        update_head_bob(&mut state, 250.);
+        assert_eq!(state.render_camera_origin(), state.camera_origin);
+    }
+
+    #[test]
+    fn convergence_is_measured_between_frames_of_one_accumulation() {
+        let mut check = ConvergenceCheck::default();
+        assert_eq!(check.update(vec![10, 10, 10, 10], 1), None);
+        assert_eq!(check.update(vec![12, 8, 10, 10], 5), Some(1.));
+        // same frame: nothing new to compare
+        assert_eq!(check.update(vec![0, 0, 0, 0], 5), Some(1.));
+        assert_eq!(check.update(vec![12, 8, 10, 10], 6), Some(0.));
+        // accumulation restarted
+        assert_eq!(check.update(vec![12, 8, 10, 10], 2), None);
+    }
+
+    #[test]
+    fn passes_stop_at_the_frame_budget() {
+        assert!(has_time_for_another_pass(0, 0., 12.));
+        assert!(has_time_for_another_pass(2, 6., 12.));
+        assert!(!has_time_for_another_pass(3, 10.5, 12.));
+        assert!(!has_time_for_another_pass(1, 20., 12.));
+        assert!(!has_time_for_another_pass(MAX_PASSES_PER_FRAME, 0., 12.));
+    }
+
+    #[test]
+    fn side_by_side_eyes_each_get_half_the_width() {
+        assert_eq!("side-by-side".parse(), Ok(StereoMode::SideBySide));
+        assert_eq!(StereoMode::SideBySide.eye_aspect_ratio(2.), 1.);
+        assert_eq!(StereoMode::Anaglyph.eye_aspect_ratio(2.), 2.);
+        assert!("cross-eyed".parse::<StereoMode>().is_err());
+    }
+
+    #[test]
+    fn rays_per_second_scales_from_milliseconds() {
+        assert_eq!(rays_per_second(1_000_000., 10.), 100_000_000.);
+        assert_eq!(rays_per_second(1_000_000., 0.), 0.);
+    }
+
+    #[test]
+    fn focal_length_converts_to_field_of_view() {
+        // a "normal" 43mm lens sees about 31.2 degrees vertically
+        let fov = field_of_view_from_focal_length(43.);
+        assert!((fov.to_degrees() - 31.2).abs() < 0.1);
+        for focal_length in [14., 50., 200.] {
+            let round_trip =
+                focal_length_from_field_of_view(field_of_view_from_focal_length(focal_length));
+            assert!((round_trip - focal_length).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn idle_timeout_starts_when_enabled() {
+        let mut state = State::with_dimensions(800, 600);
+        state.set_idle_timeout(Some(30_000.), 100_000.);
+        assert!(!has_idle_timeout_elapsed(
+            state.last_input_time,
+            100_001.,
+            state.idle_timeout
+        ));
+        assert!(has_idle_timeout_elapsed(
+            state.last_input_time,
+            130_000.,
+            state.idle_timeout
+        ));
+    }
+
+    #[test]
+    fn idle_timeout_is_disabled_by_default() {
+        assert!(!has_idle_timeout_elapsed(0., 1e9, None));
+        assert!(!has_idle_timeout_elapsed(1000., 30_999., Some(30_000.)));
+        assert!(has_idle_timeout_elapsed(1000., 31_000., Some(30_000.)));
+    }
+
+    #[test]
+    fn only_small_resizes_preserve_accumulation() {
+        assert!(can_preserve_accumulation((800, 600), (840, 630)));
+        // progressive startup doubles the resolution
+        assert!(!can_preserve_accumulation((400, 300), (800, 600)));
+        // same size, but a noticeably different shape
+        assert!(!can_preserve_accumulation((800, 600), (840, 580)));
+    }
+
+    #[test]
+    fn roi_is_flipped_to_start_from_the_bottom() {
+        assert_eq!(
+            roi_from_bottom_left((10, 20, 30, 40), 100.),
+            [10., 40., 30., 40.]
+        );
+    }
+
+    #[test]
+    fn roi_skips_letterboxing_bars() {
+        let mut state = State::with_dimensions(200, 100);
+        state.set_roi((10, 20, 30, 40), 1_000_000);
+        assert_eq!(state.roi_samples_per_pixel, MAX_ROI_SAMPLES_PER_PIXEL);
+        assert_eq!(state.roi_on_canvas(), Some([10., 40., 30., 40.]));
+        // a square image leaves 50 pixel bars on the left and right
+        state.set_target_aspect_ratio(Some(1.));
+        assert_eq!(state.roi_on_canvas(), Some([60., 40., 30., 40.]));
+        // a wide image leaves 25 pixel bars on the top and bottom
+        state.set_target_aspect_ratio(Some(4.));
+        assert_eq!(state.roi_on_canvas(), Some([10., 15., 30., 40.]));
+    }
+
+    #[test]
+    fn axis_locks_keep_only_one_component() {
+        let displacement = Vec3(1., 2., 3.);
+        assert_eq!(constrain_to_axis(&displacement, None), displacement);
+        assert_eq!(
+            constrain_to_axis(&displacement, Some(Axis::Y)),
+            Vec3(0., 2., 0.)
+        );
+        assert_eq!(Axis::from_key("Z"), Some(Axis::Z));
+    }
+
+    #[test]
+    fn drags_follow_the_ray_across_the_plane() {
+        let ray = Ray {
+            origin: Point(0., 0., 0.),
+            direction: Vec3(1., 0., -1.),
+        };
+        let normal = Vec3(0., 0., 1.);
+        assert_eq!(
+            intersect_plane(&ray, &Point(5., 5., -2.), &normal),
+            Some(Point(2., 0., -2.))
+        );
+        // behind the camera
+        assert_eq!(intersect_plane(&ray, &Point(0., 0., 2.), &normal), None);
+    }
+
+    #[test]
+    fn transitions_progress_linearly_and_can_be_instant() {
+        assert_eq!(transition_progress(100., 100., 500.), 0.);
+        assert_eq!(transition_progress(350., 100., 500.), 0.5);
+        assert_eq!(transition_progress(1000., 100., 500.), 1.);
+        assert_eq!(transition_progress(100., 100., 0.), 1.);
+    }
+
+    #[test]
+    fn transitions_hold_while_the_scene_is_paused() {
+        let state = std::sync::Mutex::new(State::with_dimensions(800, 600));
+        let mut state = state.lock().unwrap();
+        state.set_paused(false);
+        state.transition_duration = 500.;
+        state.start_scene_transition(1000.);
+        update_scene_transition(&mut state, 1250.);
+        assert_eq!(state.transition_progress, 0.5);
+        state.set_scene_paused(true);
+        update_scene_transition(&mut state, 5000.);
+        assert_eq!(state.transition_progress, 0.5);
+        // picks up where it left off
+        state.set_scene_paused(false);
+        update_scene_transition(&mut state, 5125.);
+        assert_eq!(state.transition_progress, 0.75);
+    }
+
+    #[test]
+    fn previews_are_split_into_bounded_passes() {
+        assert_eq!(preview_passes(0), (1, 1));
+        assert_eq!(preview_passes(64), (64, 1));
+        assert_eq!(preview_passes(100), (50, 2));
+        assert_eq!(preview_passes(1000), (63, 16));
+    }
+
+    #[test]
+    fn exports_keep_the_height() {
+        assert_eq!(export_dimensions(900, 16. / 9., 4096), Ok((1600, 900)));
+        assert_eq!(export_dimensions(900, 1., 4096), Ok((900, 900)));
+        assert!(export_dimensions(900, 0., 4096).is_err());
+        assert!(export_dimensions(900, f64::NAN, 4096).is_err());
+        assert!(export_dimensions(900, 1e12, 4096).is_err());
+        assert!(export_dimensions(900, f64::MAX, 4096).is_err());
+    }
+
+    #[test]
+    fn non_finite_fov_is_rejected() {
+        let current = PI / 4.;
+        assert_eq!(sanitize_fov(f64::NAN, current), current);
+        assert_eq!(sanitize_fov(f64::INFINITY, current), current);
+        assert_eq!(sanitize_fov(f64::NEG_INFINITY, current), current);
+        assert_eq!(sanitize_fov(-1., current), current);
+        assert_eq!(sanitize_fov(PI / 2., current), PI / 2.);
+        assert_eq!(sanitize_fov(10., current), PI * 0.75);
+    }
+
+    #[test]
+    fn non_finite_spheres_are_rejected() {
+        let mut sphere = single_sphere_scene().remove(0);
+        assert!(validate_sphere(&sphere).is_ok());
+        sphere.center = Vec3(f64::NAN, 0., 0.);
+        assert!(validate_sphere(&sphere).is_err());
+        sphere.center = Vec3::new();
+        sphere.radius = f64::INFINITY;
+        assert!(validate_sphere(&sphere).is_err());
+        sphere.radius = 0.;
+        assert!(validate_sphere(&sphere).is_err());
+        sphere.radius = -1.;
+        assert!(validate_sphere(&sphere).is_ok());
+        sphere.radius = 1.;
+        sphere.material.fuzz = f32::NAN;
+        assert!(validate_sphere(&sphere).is_err());
+    }
+
+    #[test]
+    fn ascending_ignores_pitch() {
+        let vup = Vec3(0., 1., 0.);
+        let looking_down = camera_front_from_angles(&vup, -90., -60.);
+        let up = KeydownMap {
+            space: true,
+            ..Default::default()
+        };
+        let down = KeydownMap {
+            shift: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            movement_displacement(&up, &looking_down, &vup, 2.),
+            Vec3(0., 2., 0.)
+        );
+        assert_eq!(
+            movement_displacement(&down, &looking_down, &vup, 2.),
+            Vec3(0., -2., 0.)
+        );
+    }
+
+    #[test]
+    fn diagonal_movement_is_not_faster() {
+        let camera_front = Vec3(0., 0., -1.);
+        let vup = Vec3(0., 1., 0.);
+        let forward = KeydownMap {
+            w: true,
+            ..Default::default()
+        };
+        let diagonal = KeydownMap {
+            w: true,
+            d: true,
+            ..Default::default()
+        };
+
+        let forward_displacement = movement_displacement(&forward, &camera_front, &vup, 2.);
+        let diagonal_displacement = movement_displacement(&diagonal, &camera_front, &vup, 2.);
+
+        assert!((forward_displacement.length() - 2.).abs() < 1e-10);
+        assert!((diagonal_displacement.length() - forward_displacement.length()).abs() < 1e-10);
+    }
+
+    #[test]
+    fn long_frames_produce_bounded_displacement() {
+        let state = std::sync::Mutex::new(State::with_dimensions(800, 600));
+        let mut state = state.lock().unwrap();
+        state.set_paused(false);
+        state.keydown_map.w = true;
+        let start = state.camera_origin.clone();
+        // alt-tabbing away for 2 seconds
+        let dt = smooth_movement_frame_time(&mut state, 2000.);
+        update_position(&mut state, dt);
+        let moved = (&state.camera_origin - &start).length();
+        assert!(moved > 0.);
+        let max_distance = movement_distance(MAX_MOVEMENT_FRAME_TIME, state.camera_field_of_view);
+        assert!(moved <= max_distance + 1e-10);
+    }
+
+    #[test]
+    fn movement_frame_times_are_smoothed() {
+        let state = std::sync::Mutex::new(State::with_dimensions(800, 600));
+        let mut state = state.lock().unwrap();
+        for _ in 0..MOVEMENT_FRAME_TIME_SAMPLES {
+            smooth_movement_frame_time(&mut state, 10.);
+        }
+        assert!((smooth_movement_frame_time(&mut state, 10.) - 10.).abs() < 1e-10);
+        // one slow frame is spread across the average
+        let spike = smooth_movement_frame_time(&mut state, 60.);
+        assert!((spike - 20.).abs() < 1e-10);
+        // and is forgotten once enough frames have passed
+        for _ in 0..MOVEMENT_FRAME_TIME_SAMPLES {
+            smooth_movement_frame_time(&mut state, 10.);
+        }
+        assert!((smooth_movement_frame_time(&mut state, 10.) - 10.).abs() < 1e-10);
+    }
+
+    #[test]
+    fn scene_presets_fit_in_the_shader() {
+        for name in SCENE_PRESETS {
+            let spheres = scene_preset(name).unwrap();
+            assert!(!spheres.is_empty() && spheres.len() <= glsl::MAX_SPHERES);
+            // presets must also pass the validation applied to user-provided scenes
+            for sphere in &spheres {
+                assert_eq!(validate_sphere(sphere), Ok(()), "{name}");
+            }
+        }
+        assert!(scene_preset("missing").is_err());
+    }
+
+    #[test]
+    fn static_camera_is_not_recomputed() {
+        let inputs = CameraInputs {
+            camera_origin: Point(0., 0., 0.),
+            world_up: Vec3(0., 1., 0.),
+            yaw: -90.,
+            pitch: 0.,
+            roll: 0.,
+            camera_field_of_view: PI / 3.,
+            focus_distance: 0.75,
+            aspect_ratio: 16. / 9.,
+        };
+        let mut cache = CameraBasisCache::default();
+        assert!(cache.update(inputs.clone()));
+        assert!(!cache.update(inputs.clone()));
+        assert!(!cache.update(inputs.clone()));
+
+        let turned = CameraInputs {
+            yaw: -80.,
+            ..inputs
+        };
+        assert!(cache.update(turned));
+    }
+
+    #[test]
+    fn cube_maps_need_six_matching_square_faces() {
+        let face = |width, height| HdrImage {
+            width,
+            height,
+            data: vec![0.; (width * height * 3) as usize],
+        };
+        assert!(EnvironmentMap::cube_map(vec![face(4, 4); 6]).is_ok());
+        assert!(EnvironmentMap::cube_map(vec![face(4, 4); 5]).is_err());
+        assert!(EnvironmentMap::cube_map(vec![face(4, 2); 6]).is_err());
+
+        let mut faces = vec![face(4, 4); 6];
+        faces[3] = face(8, 8);
+        assert!(EnvironmentMap::cube_map(faces).is_err());
+    }
+
+    #[test]
+    fn resizing_resets_accumulation() {
+        let mut state = State::with_dimensions(800, 600);
+        state.update_pipeline();
+        state.render_count = 10;
+        state.update_pipeline();
+        assert_eq!(state.render_count, 10);
+
+        // the same shape, so the camera's basis doesn't change
+        state.width *= 2;
+        state.height *= 2;
+        state.update_pipeline();
+        assert_eq!(state.render_count, 0);
+
+        state.render_count = 10;
+        state.progressive_scale = PROGRESSIVE_STARTUP_SCALE;
+        state.update_pipeline();
+        assert_eq!(state.render_count, 0);
+    }
+
+    #[test]
+    fn offscreen_renders_leave_the_render_target_alone() {
+        let state = std::sync::Mutex::new(State::with_dimensions(800, 600));
+        let mut state = state.lock().unwrap();
+        state.render_samples(4);
+        update_render_globals(&mut state);
+
+        let seeds = render_offscreen(&mut state, |state| {
+            (0..8)
+                .map(|_| {
+                    state.render_count = 0;
+                    update_render_globals(state);
+                    state.fixed_seed_time
+                })
+                .collect::<Vec<_>>()
+        });
+        // each pass is seeded by the time it's rendered at instead
+        assert_eq!(seeds, vec![None; 8]);
+        assert_eq!(state.target_render_count, Some(4));
+        assert_eq!(state.fixed_seed_time, Some(1.));
+        assert!(!state.is_paused());
+
+        // the live view still reaches its target
+        update_render_globals(&mut state);
+        assert_eq!(state.fixed_seed_time, Some(2.));
+        update_render_globals(&mut state);
+        update_render_globals(&mut state);
+        assert_eq!(state.target_render_count, None);
+        assert!(state.is_paused());
+    }
+
+    #[test]
+    fn restored_render_scale_is_clamped() {
+        for preset in QualityPreset::ALL {
+            let render_scale = preset.settings().render_scale;
+            assert!((MIN_RENDER_SCALE..=MAX_RENDER_SCALE).contains(&render_scale));
+        }
+
+        let mut state = State::with_dimensions(800, 600);
+        let saved_settings = |render_scale| QualitySettings {
+            render_scale,
+            ..QualityPreset::Medium.settings()
+        };
+        state.set_quality_settings(&saved_settings(1e6));
+        assert_eq!(state.render_scale, MAX_RENDER_SCALE);
+        state.set_quality_settings(&saved_settings(0.));
+        assert_eq!(state.render_scale, MIN_RENDER_SCALE);
+        state.set_quality_settings(&saved_settings(f64::NAN));
+        assert_eq!(state.render_scale, MIN_RENDER_SCALE);
+    }
+
+    #[test]
+    fn restored_samples_and_depth_are_clamped() {
+        for preset in QualityPreset::ALL {
+            let settings = preset.settings();
+            assert!(settings.samples_per_pixel <= MAX_SAMPLES_PER_PIXEL);
+            assert!(settings.max_depth <= MAX_MAX_DEPTH);
+        }
+
+        let mut state = State::with_dimensions(800, 600);
+        state.set_quality_settings(&QualitySettings {
+            samples_per_pixel: u32::MAX,
+            max_depth: 2_000_000_000,
+            ..QualityPreset::Medium.settings()
+        });
+        assert_eq!(state.samples_per_pixel, MAX_SAMPLES_PER_PIXEL);
+        assert_eq!(state.max_depth, MAX_MAX_DEPTH);
+        state.set_quality_settings(&QualitySettings {
+            samples_per_pixel: 0,
+            max_depth: 0,
+            ..QualityPreset::Medium.settings()
+        });
+        assert_eq!(state.samples_per_pixel, 1);
+        assert_eq!(state.max_depth, 1);
+    }
+
+    #[test]
+    fn hidden_spheres_are_outside_the_scene_bounds() {
+        let spheres = [
+            Sphere::default(),
+            Sphere {
+                center: Vec3(10., 0., 0.),
+                uuid: 1,
+                visible: false,
+                ..Default::default()
+            },
+        ];
+        let bounds = compute_scene_bounds(&spheres, &[]).unwrap();
+        assert_eq!(bounds.max, Vec3(1., 1., 1.));
+        assert!(compute_scene_bounds(&spheres[1..], &[]).is_none());
+    }
+
+    #[test]
+    fn projection_inverts_viewport_rays() {
+        let mut state = State::with_dimensions(800, 600);
+        state.update_pipeline();
+        for (s, t) in [(0.5, 0.5), (0., 0.), (0.25, 0.9), (1.2, -0.3)] {
+            let ray = glsl::viewport_ray(&state, s, t);
+            let (projected_s, projected_t) = state.project_to_viewport(&ray.at(3.)).unwrap();
+            assert!((projected_s - s).abs() < 1e-9 && (projected_t - t).abs() < 1e-9);
+        }
+        // behind the camera
+        let behind = &state.camera_origin + &state.w;
+        assert_eq!(state.project_to_viewport(&behind), None);
+    }
+
+    #[test]
+    fn absorption_must_not_be_negative() {
+        let tinted_glass = |absorption| Material {
+            absorption,
+            ..Material::glass_preset("glass").unwrap()
+        };
+        assert!(validate_material(&tinted_glass(Vec3(0., 0.5, 2.))).is_ok());
+        assert!(validate_material(&tinted_glass(Vec3(0., -0.5, 2.))).is_err());
+        assert!(validate_material(&tinted_glass(Vec3(f64::NAN, 0., 0.))).is_err());
+    }
+
+    #[test]
+    fn opposing_keys_cancel_out() {
+        let keydown_map = KeydownMap {
+            w: true,
+            s: true,
+            ..Default::default()
+        };
+        let displacement =
+            movement_displacement(&keydown_map, &Vec3(0., 0., -1.), &Vec3(0., 1., 0.), 2.);
+        assert_eq!(displacement, Vec3::new());
+    }
+
+    #[test]
+    fn angles_round_trip_through_camera_front() {
+        let z_up = Vec3(0., 0., 1.);
+        let camera_front = camera_front_from_angles(&z_up, 30., -45.);
+        let (yaw, pitch) = angles_from_camera_front(&z_up, &camera_front);
+        assert!((yaw - 30.).abs() < 1e-10);
+        assert!((pitch + 45.).abs() < 1e-10);
+    }
+
+    #[test]
+    fn y_up_basis_matches_world_axes() {
+        let (x_axis, up, z_axis) = world_basis(&Vec3(0., 1., 0.));
+        assert!((x_axis - Vec3(1., 0., 0.)).length() < 1e-10);
+        assert!((up - Vec3(0., 1., 0.)).length() < 1e-10);
+        assert!((z_axis - Vec3(0., 0., 1.)).length() < 1e-10);
+    }
+
+    #[test]
+    fn pitching_up_looks_toward_world_up() {
+        let z_up = Vec3(0., 0., 1.);
+        let camera_front = camera_front_from_angles(&z_up, -90., 89.);
+        assert!(Vec3::dot(&camera_front, &z_up) > 0.99);
+        let camera_front = camera_front_from_angles(&z_up, -90., 0.);
+        assert!(Vec3::dot(&camera_front, &z_up).abs() < 1e-10);
+    }
+
+    #[test]
+    fn letterboxing_shrinks_the_longer_axis() {
+        assert_eq!(letterbox_scale(2., None), (1., 1.));
+        assert_eq!(letterbox_scale(2., Some(1.)), (0.5, 1.));
+        assert_eq!(letterbox_scale(1., Some(2.)), (1., 0.5));
+    }
+}
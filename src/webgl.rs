@@ -1,18 +1,421 @@
+use std::collections::VecDeque;
 use std::sync::MutexGuard;
 
-use crate::{dom, state::State};
-use futures::try_join;
+use crate::{
+    aabb::AABB_EDGES,
+    dom,
+    glsl::{self, Material},
+    hdr::HdrImage,
+    math::{self, ImageAverage, Vec3},
+    state::{self, EnvironmentMap, GpuTiming, State},
+};
+use futures::{future::LocalBoxFuture, try_join, FutureExt};
 use wasm_bindgen::{JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    Request, Response, WebGl2RenderingContext, WebGlFramebuffer, WebGlProgram, WebGlShader,
-    WebGlTexture, WebGlUniformLocation,
+    Request, Response, WebGl2RenderingContext, WebGlBuffer, WebGlFramebuffer, WebGlProgram,
+    WebGlQuery, WebGlShader, WebGlTexture, WebGlUniformLocation, WebGlVertexArrayObject,
 };
 
+/// from `EXT_disjoint_timer_query_webgl2`, which web-sys doesn't expose constants for
+const TIME_ELAPSED_EXT: u32 = 0x88BF;
+const GPU_DISJOINT_EXT: u32 = 0x8FBB;
+
+/// timer query results arrive a few frames late: past this many unread queries,
+/// frames are left untimed rather than queueing up more
+const MAX_PENDING_TIMER_QUERIES: usize = 4;
+
+/// units 0 and 1->7 are taken by the previous frame and the frame history
+pub const ENVIRONMENT_MAP_TEXTURE_UNIT: u32 = 8;
+pub const ENVIRONMENT_CUBEMAP_TEXTURE_UNIT: u32 = 9;
+/// holds the previous scene's last frame while cross-fading to a new scene
+pub const TRANSITION_TEXTURE_UNIT: u32 = 10;
+
+/// color, plus the G-buffer's normal and depth attachments
+pub const G_BUFFER_ATTACHMENT_COUNT: u32 = 3;
+
+/// accumulated frames are mipmapped down to roughly this size before being read back
+pub const LUMINANCE_REDUCTION_SIZE: u32 = 16;
+
 pub const SIMPLE_QUAD_VERTICES: [f32; 12] = [
     -1.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0, -1.0, 1.0, 1.0, 1.0, -1.0,
 ];
 
+/// GPU resources owned by a single ray tracer instance
+pub struct Renderer {
+    pub gl: WebGl2RenderingContext,
+    pub program: WebGlProgram,
+    /// Kept around for relinking whenever the fragment shader is swapped out
+    vertex_shader: WebGlShader,
+    pub uniforms: Uniforms,
+    pub camera_uniforms: Uniforms,
+    /// One texture (and framebuffer rendering into it) per accumulated frame
+    pub textures: Vec<WebGlTexture>,
+    pub framebuffer_objects: Vec<WebGlFramebuffer>,
+    pub environment_map: Option<WebGlTexture>,
+    pub environment_cubemap: Option<WebGlTexture>,
+    pub quad_buffer: WebGlBuffer,
+    pub overlays: Overlays,
+    /// Used for reading back downsampled frames for auto-exposure
+    luminance_framebuffer: WebGlFramebuffer,
+    store_unexposed_location: Option<WebGlUniformLocation>,
+    /// Cached, so that animating spheres doesn't look up a uniform by name for each of them
+    sphere_center_locations: Vec<Option<WebGlUniformLocation>>,
+    /// Only available when the browser supports GPU timer queries
+    pub gpu_timer: Option<GpuTimer>,
+    /// Where finished frames are drawn: the canvas when None, otherwise a framebuffer
+    /// supplied by an embedding app (created with this renderer's context)
+    pub target_framebuffer: Option<WebGlFramebuffer>,
+    /// A copy of the last accumulated frame before switching scenes, to cross-fade from
+    transition_texture: Option<WebGlTexture>,
+    /// Extra attachments on every accumulation framebuffer, when `state.g_buffer` is set
+    pub g_buffer: Option<GBuffer>,
+    /// Largest width or height the accumulation textures can have
+    pub max_texture_size: u32,
+}
+
+impl Renderer {
+    pub fn new(
+        gl: WebGl2RenderingContext,
+        program: WebGlProgram,
+        vertex_shader: WebGlShader,
+        uniforms: Uniforms,
+        quad_buffer: WebGlBuffer,
+        overlays: Overlays,
+        state: &MutexGuard<State>,
+    ) -> Self {
+        let store_unexposed_location = get_uniform_location(&gl, &program, "u_store_unexposed");
+        let sphere_center_locations = get_sphere_center_locations(&gl, &program);
+        let camera_uniforms = setup_camera_uniforms(&gl, &program);
+        let luminance_framebuffer = gl.create_framebuffer().unwrap();
+        let gpu_timer = GpuTimer::new(&gl);
+        // WebGL2 guarantees at least 2048
+        let max_texture_size = gl
+            .get_parameter(WebGl2RenderingContext::MAX_TEXTURE_SIZE)
+            .ok()
+            .and_then(|size| size.as_f64())
+            .map_or(2048, |size| size as u32);
+        let mut renderer = Renderer {
+            gl,
+            program,
+            vertex_shader,
+            uniforms,
+            camera_uniforms,
+            textures: Vec::new(),
+            framebuffer_objects: Vec::new(),
+            environment_map: None,
+            environment_cubemap: None,
+            quad_buffer,
+            overlays,
+            luminance_framebuffer,
+            store_unexposed_location,
+            sphere_center_locations,
+            gpu_timer,
+            target_framebuffer: None,
+            transition_texture: None,
+            g_buffer: None,
+            max_texture_size,
+        };
+        renderer.create_accumulation_buffers(state);
+        renderer
+    }
+
+    /// (Re)creates a texture and framebuffer for every frame being accumulated
+    pub fn create_accumulation_buffers(&mut self, state: &MutexGuard<State>) {
+        for texture in self.textures.drain(..) {
+            self.gl.delete_texture(Some(&texture));
+        }
+        for framebuffer_object in self.framebuffer_objects.drain(..) {
+            self.gl.delete_framebuffer(Some(&framebuffer_object));
+        }
+        if let Some(g_buffer) = self.g_buffer.take() {
+            g_buffer.delete(&self.gl);
+        }
+        if state.g_buffer {
+            self.g_buffer = GBuffer::new(&self.gl, state.width, state.height)
+                .map_err(|error| log::error!("Couldn't create the G-buffer: {error}"))
+                .ok();
+        }
+        for _ in 0..state.accumulation_frame_count {
+            let texture = create_texture(&self.gl, state);
+            self.framebuffer_objects.push(create_framebuffer(
+                &self.gl,
+                &texture,
+                self.g_buffer.as_ref(),
+            ));
+            self.textures.push(texture);
+        }
+    }
+
+    /// Resizes every accumulation texture to match the state. When the previous dimensions
+    /// are given, the accumulated frames are scaled into the resized textures, rather than
+    /// being cleared
+    pub fn resize_accumulation_buffers(
+        &self,
+        state: &MutexGuard<State>,
+        preserve_from: Option<(u32, u32)>,
+    ) {
+        let gl = &self.gl;
+        let (width, height) = (state.width, state.height);
+        if let Some(g_buffer) = &self.g_buffer {
+            g_buffer.allocate(gl, width, height);
+        }
+        for (texture, framebuffer) in self.textures.iter().zip(&self.framebuffer_objects) {
+            let preserved = preserve_from.map(|(old_width, old_height)| {
+                // set the old frame aside, since resizing its texture clears it
+                let copy = create_texture_with_size(gl, old_width, old_height);
+                let copy_framebuffer = create_framebuffer(gl, &copy, None);
+                blit(
+                    gl,
+                    (framebuffer, old_width, old_height),
+                    (&copy_framebuffer, old_width, old_height),
+                );
+                (copy, copy_framebuffer, (old_width, old_height))
+            });
+
+            gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(texture));
+            allocate_texture(gl, width, height);
+
+            if let Some((copy, copy_framebuffer, (old_width, old_height))) = preserved {
+                // blitting writes to every draw buffer, but only color is being preserved
+                gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, Some(framebuffer));
+                set_draw_buffers(gl, 1);
+                blit(
+                    gl,
+                    (&copy_framebuffer, old_width, old_height),
+                    (framebuffer, width, height),
+                );
+                if self.g_buffer.is_some() {
+                    set_draw_buffers(gl, G_BUFFER_ATTACHMENT_COUNT);
+                }
+                gl.delete_framebuffer(Some(&copy_framebuffer));
+                gl.delete_texture(Some(&copy));
+            }
+        }
+        gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+    }
+
+    /// Uploads the current state to the shader. Camera uniforms are only uploaded
+    /// when the camera has changed since they were last uploaded
+    pub fn update_uniforms(&self, state: &mut MutexGuard<State>, now: f64) {
+        self.uniforms.run_setters(state, &self.gl, now);
+        if state.camera_basis_dirty {
+            self.camera_uniforms.run_setters(state, &self.gl, now);
+            state.camera_basis_dirty = false;
+        }
+    }
+
+    /// Compiles a new fragment shader and swaps it in for the current one.
+    /// On failure, the compile/link log is returned and the current program is left in place
+    pub fn set_fragment_shader(
+        &mut self,
+        source: &str,
+        state: &MutexGuard<State>,
+    ) -> Result<(), String> {
+        let gl = &self.gl;
+        let fragment_shader = compile_shader(
+            gl,
+            WebGl2RenderingContext::FRAGMENT_SHADER,
+            &inject_defines(source),
+        )?;
+        let program = link_program(gl, &self.vertex_shader, &fragment_shader);
+        // the shader is only actually freed once the program it's attached to is deleted
+        gl.delete_shader(Some(&fragment_shader));
+        let program = program?;
+
+        gl.use_program(Some(&program));
+        gl.delete_program(Some(&self.program));
+        bind_quad_attribute(gl, &program, &self.quad_buffer);
+        self.uniforms.update_locations(gl, &program);
+        self.camera_uniforms.update_locations(gl, &program);
+        self.store_unexposed_location = get_uniform_location(gl, &program, "u_store_unexposed");
+        self.sphere_center_locations = get_sphere_center_locations(gl, &program);
+        set_geometry(state, gl, &program);
+        self.program = program;
+        Ok(())
+    }
+
+    /// Uploads just the sphere centers (see `State::set_sphere_positions`)
+    pub fn set_sphere_centers(&self, state: &MutexGuard<State>) {
+        for (sphere, location) in state
+            .world_spheres
+            .iter()
+            .zip(self.sphere_center_locations.iter())
+        {
+            self.gl
+                .uniform3fv_with_f32_array(location.as_ref(), &sphere.center.to_array());
+        }
+    }
+
+    /// Copies the most recently accumulated frame, so that the next scene can fade in over it
+    pub fn capture_transition_frame(&mut self, state: &MutexGuard<State>) {
+        let gl = &self.gl;
+        if let Some(texture) = self.transition_texture.take() {
+            gl.delete_texture(Some(&texture));
+        }
+        let frame_count = self.framebuffer_objects.len() as u32;
+        let texture = create_texture(gl, state);
+        gl.bind_framebuffer(
+            WebGl2RenderingContext::FRAMEBUFFER,
+            Some(&self.framebuffer_objects[(state.even_odd_count % frame_count) as usize]),
+        );
+        gl.copy_tex_sub_image_2d(
+            WebGl2RenderingContext::TEXTURE_2D,
+            0,
+            0,
+            0,
+            0,
+            0,
+            state.width as i32,
+            state.height as i32,
+        );
+        gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+        self.transition_texture = Some(texture);
+    }
+
+    /// Uploads an HDR sky to the GPU, replacing any previous one
+    pub fn set_environment_map(&mut self, environment_map: &EnvironmentMap) {
+        let gl = &self.gl;
+        for texture in [self.environment_map.take(), self.environment_cubemap.take()]
+            .iter()
+            .flatten()
+        {
+            gl.delete_texture(Some(texture));
+        }
+
+        match environment_map {
+            EnvironmentMap::Equirectangular(image) => {
+                self.environment_map = create_environment_texture(gl, image);
+            }
+            EnvironmentMap::CubeMap(faces) => {
+                self.environment_cubemap = create_environment_cubemap(gl, faces);
+            }
+        }
+    }
+}
+
+/// Uploads an equirectangular HDR image to be sampled as the sky, with mipmaps for blurrier
+/// reflections on rougher surfaces
+pub fn create_environment_texture(
+    gl: &WebGl2RenderingContext,
+    image: &HdrImage,
+) -> Option<WebGlTexture> {
+    let texture = gl.create_texture();
+    gl.active_texture(WebGl2RenderingContext::TEXTURE0 + ENVIRONMENT_MAP_TEXTURE_UNIT);
+    gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, texture.as_ref());
+
+    // wrap horizontally, so there is no seam where the image's left and right edges meet
+    // (WebGL2 supports repeating and mipmapping non-power-of-two textures)
+    gl.tex_parameteri(
+        WebGl2RenderingContext::TEXTURE_2D,
+        WebGl2RenderingContext::TEXTURE_WRAP_S,
+        WebGl2RenderingContext::REPEAT as i32,
+    );
+    gl.tex_parameteri(
+        WebGl2RenderingContext::TEXTURE_2D,
+        WebGl2RenderingContext::TEXTURE_WRAP_T,
+        WebGl2RenderingContext::CLAMP_TO_EDGE as i32,
+    );
+    set_mipmap_filtering(gl, WebGl2RenderingContext::TEXTURE_2D);
+    upload_hdr_image(gl, WebGl2RenderingContext::TEXTURE_2D, image);
+    gl.generate_mipmap(WebGl2RenderingContext::TEXTURE_2D);
+
+    gl.active_texture(WebGl2RenderingContext::TEXTURE0);
+    texture
+}
+
+/// Uploads six HDR faces (+X, -X, +Y, -Y, +Z, -Z) to be sampled as the sky,
+/// which avoids the seam and pole artifacts of equirectangular images
+pub fn create_environment_cubemap(
+    gl: &WebGl2RenderingContext,
+    faces: &[HdrImage; 6],
+) -> Option<WebGlTexture> {
+    let texture = gl.create_texture();
+    gl.active_texture(WebGl2RenderingContext::TEXTURE0 + ENVIRONMENT_CUBEMAP_TEXTURE_UNIT);
+    gl.bind_texture(WebGl2RenderingContext::TEXTURE_CUBE_MAP, texture.as_ref());
+
+    for wrap in [
+        WebGl2RenderingContext::TEXTURE_WRAP_S,
+        WebGl2RenderingContext::TEXTURE_WRAP_T,
+        WebGl2RenderingContext::TEXTURE_WRAP_R,
+    ] {
+        gl.tex_parameteri(
+            WebGl2RenderingContext::TEXTURE_CUBE_MAP,
+            wrap,
+            WebGl2RenderingContext::CLAMP_TO_EDGE as i32,
+        );
+    }
+    set_mipmap_filtering(gl, WebGl2RenderingContext::TEXTURE_CUBE_MAP);
+    for (i, face) in faces.iter().enumerate() {
+        upload_hdr_image(
+            gl,
+            WebGl2RenderingContext::TEXTURE_CUBE_MAP_POSITIVE_X + i as u32,
+            face,
+        );
+    }
+    gl.generate_mipmap(WebGl2RenderingContext::TEXTURE_CUBE_MAP);
+
+    gl.active_texture(WebGl2RenderingContext::TEXTURE0);
+    texture
+}
+
+fn set_mipmap_filtering(gl: &WebGl2RenderingContext, target: u32) {
+    gl.tex_parameteri(
+        target,
+        WebGl2RenderingContext::TEXTURE_MIN_FILTER,
+        WebGl2RenderingContext::LINEAR_MIPMAP_LINEAR as i32,
+    );
+    gl.tex_parameteri(
+        target,
+        WebGl2RenderingContext::TEXTURE_MAG_FILTER,
+        WebGl2RenderingContext::LINEAR as i32,
+    );
+}
+
+/// Uploads an HDR image to the currently bound texture's `target` (a 2D texture or cube face).
+/// Mipmaps can only be generated for formats that can be rendered to,
+/// so without float render target support, the image is clamped to LDR
+fn upload_hdr_image(gl: &WebGl2RenderingContext, target: u32, image: &HdrImage) {
+    // RGBA, since RGB float formats can't be rendered to (and so can't be mipmapped)
+    let rgba = image
+        .data
+        .chunks_exact(3)
+        .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 1.]);
+    let supports_float_mipmaps = matches!(gl.get_extension("EXT_color_buffer_float"), Ok(Some(_)));
+    if supports_float_mipmaps {
+        // half floats are filterable without any extension
+        let data = js_sys::Float32Array::from(&rgba.collect::<Vec<_>>()[..]);
+        gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_array_buffer_view(
+            target,
+            0,
+            WebGl2RenderingContext::RGBA16F as i32,
+            image.width as i32,
+            image.height as i32,
+            0,
+            WebGl2RenderingContext::RGBA,
+            WebGl2RenderingContext::FLOAT,
+            Some(&data),
+        )
+        .unwrap();
+    } else {
+        let data: Vec<u8> = rgba
+            .map(|value| (value.clamp(0., 1.) * 255.).round() as u8)
+            .collect();
+        gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+            target,
+            0,
+            WebGl2RenderingContext::RGBA8 as i32,
+            image.width as i32,
+            image.height as i32,
+            0,
+            WebGl2RenderingContext::RGBA,
+            WebGl2RenderingContext::UNSIGNED_BYTE,
+            Some(&data),
+        )
+        .unwrap();
+    }
+}
+
 pub fn compile_shader(
     gl: &WebGl2RenderingContext,
     shader_type: u32,
@@ -63,7 +466,27 @@ pub fn link_program(
     }
 }
 
-pub async fn setup_program(gl: &WebGl2RenderingContext) -> Result<WebGlProgram, JsValue> {
+/// Defines constants shared with the ray tracing shader, so they can't get out of sync.
+/// They're inserted after `#version`, which must stay the first line of the shader
+pub fn inject_defines(source: &str) -> String {
+    let defines = format!(
+        "#define MAX_SPHERES {}\n#define MAX_RECT_LIGHTS {}\n",
+        glsl::MAX_SPHERES,
+        glsl::MAX_RECT_LIGHTS
+    );
+    match source.split_once('\n') {
+        Some((first_line, rest)) if first_line.trim_start().starts_with("#version") => {
+            format!("{first_line}\n{defines}{rest}")
+        }
+        _ => format!("{defines}{source}"),
+    }
+}
+
+/// Fetches, compiles, and links the ray tracing shaders. The vertex shader and
+/// fragment shader source are returned as well, so that the fragment shader can be edited later
+pub async fn setup_program(
+    gl: &WebGl2RenderingContext,
+) -> Result<(WebGlProgram, WebGlShader, String), JsValue> {
     let (fragment_source, vertex_source) =
         try_join!(fetch_shader("./shader.frag"), fetch_shader("./shader.vert"))?;
 
@@ -71,15 +494,310 @@ pub async fn setup_program(gl: &WebGl2RenderingContext) -> Result<WebGlProgram,
     let fragment_shader = compile_shader(
         gl,
         WebGl2RenderingContext::FRAGMENT_SHADER,
-        &fragment_source,
+        &inject_defines(&fragment_source),
     )?;
     let program = link_program(gl, &vertex_shader, &fragment_shader)?;
     gl.use_program(Some(&program));
 
-    Ok(program)
+    Ok((program, vertex_shader, fragment_source))
+}
+
+/// Editor feedback drawn over the rendered image (and left out of saved images)
+pub struct Overlays {
+    pub bounds: BoundsOverlay,
+    pub outline: OutlineOverlay,
+}
+
+impl Overlays {
+    pub async fn new(gl: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+        let (bounds, outline) = try_join!(BoundsOverlay::new(gl), OutlineOverlay::new(gl))?;
+        Ok(Overlays { bounds, outline })
+    }
+}
+
+/// Draws the bounding box of every sphere as lines over the rendered image
+pub struct BoundsOverlay {
+    program: WebGlProgram,
+    vertex_array: WebGlVertexArrayObject,
+    buffer: WebGlBuffer,
+    camera_origin_location: Option<WebGlUniformLocation>,
+    w_location: Option<WebGlUniformLocation>,
+    clip_x_axis_location: Option<WebGlUniformLocation>,
+    clip_y_axis_location: Option<WebGlUniformLocation>,
+}
+
+impl BoundsOverlay {
+    /// Floats per vertex: position (xyz), then color (rgb)
+    const VERTEX_SIZE: i32 = 6;
+
+    pub async fn new(gl: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+        let (fragment_source, vertex_source) =
+            try_join!(fetch_shader("./bounds.frag"), fetch_shader("./bounds.vert"))?;
+        let vertex_shader =
+            compile_shader(gl, WebGl2RenderingContext::VERTEX_SHADER, &vertex_source)?;
+        let fragment_shader = compile_shader(
+            gl,
+            WebGl2RenderingContext::FRAGMENT_SHADER,
+            &fragment_source,
+        )?;
+        let program = link_program(gl, &vertex_shader, &fragment_shader)?;
+
+        // attributes are kept in their own vertex array, so that
+        // the ray tracer's full-screen quad is left untouched
+        let vertex_array = gl
+            .create_vertex_array()
+            .ok_or("failed to create vertex array")?;
+        let buffer = gl.create_buffer().ok_or("failed to create buffer")?;
+        gl.bind_vertex_array(Some(&vertex_array));
+        gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
+        let stride = Self::VERTEX_SIZE * std::mem::size_of::<f32>() as i32;
+        for (name, offset) in [("a_position", 0), ("a_color", 3)] {
+            let location = gl.get_attrib_location(&program, name) as u32;
+            gl.enable_vertex_attrib_array(location);
+            gl.vertex_attrib_pointer_with_i32(
+                location,
+                3,
+                WebGl2RenderingContext::FLOAT,
+                false,
+                stride,
+                offset * std::mem::size_of::<f32>() as i32,
+            );
+        }
+        gl.bind_vertex_array(None);
+
+        Ok(BoundsOverlay {
+            camera_origin_location: get_uniform_location(gl, &program, "u_camera_origin"),
+            w_location: get_uniform_location(gl, &program, "u_w"),
+            clip_x_axis_location: get_uniform_location(gl, &program, "u_clip_x_axis"),
+            clip_y_axis_location: get_uniform_location(gl, &program, "u_clip_y_axis"),
+            program,
+            vertex_array,
+            buffer,
+        })
+    }
+
+    /// Draws onto the canvas, leaving `main_program` in use afterward
+    pub fn draw(
+        &self,
+        gl: &WebGl2RenderingContext,
+        main_program: &WebGlProgram,
+        target_framebuffer: Option<&WebGlFramebuffer>,
+        state: &MutexGuard<State>,
+    ) {
+        let mut vertices = Vec::new();
+        let bounding_boxes = state
+            .world_spheres
+            .iter()
+            .filter(|sphere| sphere.visible)
+            .map(|sphere| (sphere.uuid, sphere.bounding_box()))
+            .chain(
+                state
+                    .rect_lights
+                    .iter()
+                    .map(|rect| (rect.uuid, rect.bounding_box())),
+            );
+        for (uuid, bounding_box) in bounding_boxes {
+            // highlight the selected object, the same as the shader's debugging outline
+            let color = if uuid == state.selected_object {
+                [1., 0., 0.]
+            } else {
+                [0., 1., 0.]
+            };
+            let corners = bounding_box.corners();
+            for (start, end) in AABB_EDGES {
+                for corner in [&corners[start], &corners[end]] {
+                    vertices.extend_from_slice(&corner.to_array());
+                    vertices.extend_from_slice(&color);
+                }
+            }
+        }
+
+        // scaling by the viewport size maps points on the viewport to -1->1
+        let clip_x_axis =
+            &state.horizontal * (2. * state.focus_distance / state.horizontal.length_squared());
+        let clip_y_axis =
+            &state.vertical * (2. * state.focus_distance / state.vertical.length_squared());
+
+        gl.use_program(Some(&self.program));
+        gl.uniform3fv_with_f32_array(
+            self.camera_origin_location.as_ref(),
+            &state.render_camera_origin().to_array(),
+        );
+        gl.uniform3fv_with_f32_array(self.w_location.as_ref(), &state.w.to_array());
+        gl.uniform3fv_with_f32_array(self.clip_x_axis_location.as_ref(), &clip_x_axis.to_array());
+        gl.uniform3fv_with_f32_array(self.clip_y_axis_location.as_ref(), &clip_y_axis.to_array());
+
+        gl.bind_vertex_array(Some(&self.vertex_array));
+        gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&self.buffer));
+        let vertex_array = js_sys::Float32Array::from(&vertices[..]);
+        gl.buffer_data_with_array_buffer_view(
+            WebGl2RenderingContext::ARRAY_BUFFER,
+            &vertex_array,
+            WebGl2RenderingContext::DYNAMIC_DRAW,
+        );
+        gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, target_framebuffer);
+        gl.draw_arrays(
+            WebGl2RenderingContext::LINES,
+            0,
+            vertices.len() as i32 / Self::VERTEX_SIZE,
+        );
+
+        gl.bind_vertex_array(None);
+        gl.use_program(Some(main_program));
+    }
+}
+
+/// Color of the outline drawn around the selected object
+pub const OUTLINE_COLOR: [f32; 3] = [1., 0.6, 0.];
+
+/// Outlines the selected object: the ray tracer first renders which object is visible
+/// at each pixel into an id buffer, then the outline is drawn wherever that changes
+pub struct OutlineOverlay {
+    program: WebGlProgram,
+    vertex_array: WebGlVertexArrayObject,
+    /// The id buffer, recreated whenever the render dimensions change
+    id_target: Option<IdTarget>,
+    id_texture_location: Option<WebGlUniformLocation>,
+    selected_id_location: Option<WebGlUniformLocation>,
+    outline_color_location: Option<WebGlUniformLocation>,
+}
+
+struct IdTarget {
+    texture: WebGlTexture,
+    framebuffer: WebGlFramebuffer,
+    width: u32,
+    height: u32,
+}
+
+impl OutlineOverlay {
+    pub async fn new(gl: &WebGl2RenderingContext) -> Result<Self, JsValue> {
+        let (fragment_source, vertex_source) = try_join!(
+            fetch_shader("./outline.frag"),
+            fetch_shader("./outline.vert")
+        )?;
+        let vertex_shader =
+            compile_shader(gl, WebGl2RenderingContext::VERTEX_SHADER, &vertex_source)?;
+        let fragment_shader = compile_shader(
+            gl,
+            WebGl2RenderingContext::FRAGMENT_SHADER,
+            &fragment_source,
+        )?;
+        let program = link_program(gl, &vertex_shader, &fragment_shader)?;
+
+        // the outline always covers the whole canvas, so it gets its own (unletterboxed) quad
+        let vertex_array = gl
+            .create_vertex_array()
+            .ok_or("failed to create vertex array")?;
+        let buffer = gl.create_buffer().ok_or("failed to create buffer")?;
+        gl.bind_vertex_array(Some(&vertex_array));
+        gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
+        let vertices = js_sys::Float32Array::from(&SIMPLE_QUAD_VERTICES[..]);
+        gl.buffer_data_with_array_buffer_view(
+            WebGl2RenderingContext::ARRAY_BUFFER,
+            &vertices,
+            WebGl2RenderingContext::STATIC_DRAW,
+        );
+        let location = gl.get_attrib_location(&program, "a_position") as u32;
+        gl.enable_vertex_attrib_array(location);
+        gl.vertex_attrib_pointer_with_i32(location, 2, WebGl2RenderingContext::FLOAT, false, 0, 0);
+        gl.bind_vertex_array(None);
+
+        Ok(OutlineOverlay {
+            id_texture_location: get_uniform_location(gl, &program, "u_id_texture"),
+            selected_id_location: get_uniform_location(gl, &program, "u_selected_id"),
+            outline_color_location: get_uniform_location(gl, &program, "u_outline_color"),
+            program,
+            vertex_array,
+            id_target: None,
+        })
+    }
+
+    /// Renders the id buffer with `main_program`, then draws the outline onto the canvas,
+    /// leaving `main_program` in use afterward
+    pub fn draw(
+        &mut self,
+        gl: &WebGl2RenderingContext,
+        main_program: &WebGlProgram,
+        target_framebuffer: Option<&WebGlFramebuffer>,
+        state: &MutexGuard<State>,
+    ) {
+        let is_stale =
+            |target: &IdTarget| (target.width, target.height) != (state.width, state.height);
+        if self.id_target.as_ref().is_none_or(is_stale) {
+            if let Some(target) = self.id_target.take() {
+                gl.delete_framebuffer(Some(&target.framebuffer));
+                gl.delete_texture(Some(&target.texture));
+            }
+            let texture = create_texture(gl, state);
+            let framebuffer = create_framebuffer(gl, &texture, None);
+            self.id_target = Some(IdTarget {
+                texture,
+                framebuffer,
+                width: state.width,
+                height: state.height,
+            });
+        }
+        let id_target = self.id_target.as_ref().unwrap();
+
+        // ID PASS
+        // the id texture must not be bound to a texture unit while it's being rendered into
+        gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
+        let output_id_location = get_uniform_location(gl, main_program, "u_output_id");
+        gl.uniform1i(output_id_location.as_ref(), 1);
+        gl.bind_framebuffer(
+            WebGl2RenderingContext::FRAMEBUFFER,
+            Some(&id_target.framebuffer),
+        );
+        // cleared to 0 (nothing hit), regardless of the canvas's clear color
+        gl.clear_color(0., 0., 0., 0.);
+        gl.viewport(0, 0, state.width as i32, state.height as i32);
+        gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
+        gl.draw_arrays(
+            WebGl2RenderingContext::TRIANGLES,
+            0,
+            (SIMPLE_QUAD_VERTICES.len() / 2) as i32,
+        );
+        gl.uniform1i(output_id_location.as_ref(), 0);
+
+        // OUTLINE PASS
+        gl.use_program(Some(&self.program));
+        gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&id_target.texture));
+        gl.uniform1i(self.id_texture_location.as_ref(), 0);
+        gl.uniform1i(
+            self.selected_id_location.as_ref(),
+            state.selected_object + 1,
+        );
+        gl.uniform3fv_with_f32_array(self.outline_color_location.as_ref(), &OUTLINE_COLOR);
+
+        gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, target_framebuffer);
+        gl.enable(WebGl2RenderingContext::BLEND);
+        gl.blend_func(
+            WebGl2RenderingContext::SRC_ALPHA,
+            WebGl2RenderingContext::ONE_MINUS_SRC_ALPHA,
+        );
+        gl.bind_vertex_array(Some(&self.vertex_array));
+        gl.draw_arrays(
+            WebGl2RenderingContext::TRIANGLES,
+            0,
+            (SIMPLE_QUAD_VERTICES.len() / 2) as i32,
+        );
+        gl.bind_vertex_array(None);
+        gl.disable(WebGl2RenderingContext::BLEND);
+        gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, None);
+
+        gl.use_program(Some(main_program));
+    }
 }
 
 pub fn create_texture(gl: &WebGl2RenderingContext, state: &MutexGuard<State>) -> WebGlTexture {
+    create_texture_with_size(gl, state.width, state.height)
+}
+
+pub fn create_texture_with_size(
+    gl: &WebGl2RenderingContext,
+    width: u32,
+    height: u32,
+) -> WebGlTexture {
     let texture = gl.create_texture();
     gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, texture.as_ref());
 
@@ -105,38 +823,79 @@ pub fn create_texture(gl: &WebGl2RenderingContext, state: &MutexGuard<State>) ->
         WebGl2RenderingContext::LINEAR as i32,
     );
 
+    allocate_texture(gl, width, height);
+
+    texture.unwrap()
+}
+
+/// Gives the currently bound texture empty storage of the given size
+fn allocate_texture(gl: &WebGl2RenderingContext, width: u32, height: u32) {
     // load empty texture into gpu -- this will get rendered into later
     gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
         WebGl2RenderingContext::TEXTURE_2D,
         0,
         WebGl2RenderingContext::RGBA as i32,
-        state.width as i32,
-        state.height as i32,
+        width as i32,
+        height as i32,
         0,
         WebGl2RenderingContext::RGBA,
         WebGl2RenderingContext::UNSIGNED_BYTE,
         None,
     )
     .unwrap();
+}
 
-    texture.unwrap()
+/// Copies (and scales) the contents of one framebuffer into another
+fn blit(
+    gl: &WebGl2RenderingContext,
+    from: (&WebGlFramebuffer, u32, u32),
+    to: (&WebGlFramebuffer, u32, u32),
+) {
+    let (from, from_width, from_height) = from;
+    let (to, to_width, to_height) = to;
+    gl.bind_framebuffer(WebGl2RenderingContext::READ_FRAMEBUFFER, Some(from));
+    gl.bind_framebuffer(WebGl2RenderingContext::DRAW_FRAMEBUFFER, Some(to));
+    gl.blit_framebuffer(
+        0,
+        0,
+        from_width as i32,
+        from_height as i32,
+        0,
+        0,
+        to_width as i32,
+        to_height as i32,
+        WebGl2RenderingContext::COLOR_BUFFER_BIT,
+        WebGl2RenderingContext::LINEAR,
+    );
+}
+
+/// Full-screen quad, shrunk horizontally or vertically to letterbox the image
+pub fn quad_vertices(state: &MutexGuard<State>) -> [f32; 12] {
+    let (scale_x, scale_y) = state.letterbox_scale();
+    let mut vertices = SIMPLE_QUAD_VERTICES;
+    for position in vertices.chunks_exact_mut(2) {
+        position[0] *= scale_x as f32;
+        position[1] *= scale_y as f32;
+    }
+    vertices
 }
 
 pub fn setup_vertex_buffer(
     gl: &WebGl2RenderingContext,
     program: &WebGlProgram,
-) -> Result<(), JsValue> {
-    let vertex_attribute_position = gl.get_attrib_location(program, "a_position") as u32;
+    state: &MutexGuard<State>,
+) -> Result<WebGlBuffer, JsValue> {
     let buffer = gl.create_buffer().ok_or("failed to create buffer")?;
-    gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(&buffer));
-    // requires `unsafe` since we're creating a raw view into wasm memory,
-    // but this array is static, so it shouldn't cause any issues
-    let vertex_array = unsafe { js_sys::Float32Array::view(&SIMPLE_QUAD_VERTICES) };
-    gl.buffer_data_with_array_buffer_view(
-        WebGl2RenderingContext::ARRAY_BUFFER,
-        &vertex_array,
-        WebGl2RenderingContext::STATIC_DRAW,
-    );
+    update_vertex_buffer(gl, &buffer, state);
+    bind_quad_attribute(gl, program, &buffer);
+
+    Ok(buffer)
+}
+
+/// Points the program's `a_position` attribute at the quad's vertices
+fn bind_quad_attribute(gl: &WebGl2RenderingContext, program: &WebGlProgram, buffer: &WebGlBuffer) {
+    let vertex_attribute_position = gl.get_attrib_location(program, "a_position") as u32;
+    gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(buffer));
     gl.enable_vertex_attrib_array(vertex_attribute_position);
     gl.vertex_attrib_pointer_with_i32(
         vertex_attribute_position,
@@ -146,11 +905,30 @@ pub fn setup_vertex_buffer(
         0,
         0,
     );
+}
 
-    Ok(())
+/// Re-uploads the quad (when the canvas or target aspect ratio changes)
+pub fn update_vertex_buffer(
+    gl: &WebGl2RenderingContext,
+    buffer: &WebGlBuffer,
+    state: &MutexGuard<State>,
+) {
+    gl.bind_buffer(WebGl2RenderingContext::ARRAY_BUFFER, Some(buffer));
+    let vertex_array = js_sys::Float32Array::from(&quad_vertices(state)[..]);
+    gl.buffer_data_with_array_buffer_view(
+        WebGl2RenderingContext::ARRAY_BUFFER,
+        &vertex_array,
+        WebGl2RenderingContext::STATIC_DRAW,
+    );
 }
 
-pub fn create_framebuffer(gl: &WebGl2RenderingContext, texture: &WebGlTexture) -> WebGlFramebuffer {
+/// Creates a framebuffer rendering into `texture`, along with the G-buffer's
+/// attachments (when given), which the shader writes the primary hit's normal and depth to
+pub fn create_framebuffer(
+    gl: &WebGl2RenderingContext,
+    texture: &WebGlTexture,
+    g_buffer: Option<&GBuffer>,
+) -> WebGlFramebuffer {
     let framebuffer_object = gl.create_framebuffer();
     gl.bind_framebuffer(
         WebGl2RenderingContext::FRAMEBUFFER,
@@ -163,11 +941,152 @@ pub fn create_framebuffer(gl: &WebGl2RenderingContext, texture: &WebGlTexture) -
         Some(texture),
         0,
     );
+    if let Some(g_buffer) = g_buffer {
+        for (attachment, texture) in [
+            (WebGl2RenderingContext::COLOR_ATTACHMENT1, &g_buffer.normal),
+            (WebGl2RenderingContext::COLOR_ATTACHMENT2, &g_buffer.depth),
+        ] {
+            gl.framebuffer_texture_2d(
+                WebGl2RenderingContext::FRAMEBUFFER,
+                attachment,
+                WebGl2RenderingContext::TEXTURE_2D,
+                Some(texture),
+                0,
+            );
+        }
+        set_draw_buffers(gl, G_BUFFER_ATTACHMENT_COUNT);
+    }
     framebuffer_object.unwrap()
 }
 
+/// Routes the shader's first `count` outputs to the bound framebuffer's color attachments
+fn set_draw_buffers(gl: &WebGl2RenderingContext, count: u32) {
+    let buffers: js_sys::Array = (0..count)
+        .map(|i| JsValue::from(WebGl2RenderingContext::COLOR_ATTACHMENT0 + i))
+        .collect();
+    gl.draw_buffers(&buffers);
+}
+
+/// Auxiliary render targets holding the surface normal (xyz, in world space) and depth
+/// (distance along the camera ray, in the red channel) of whatever each pixel's camera ray
+/// hits first, for guiding a denoiser or compositing. Pixels that see the sky are zeroed
+pub struct GBuffer {
+    pub normal: WebGlTexture,
+    pub depth: WebGlTexture,
+}
+
+impl GBuffer {
+    /// Fails when the browser can't render to float textures,
+    /// or can't render to enough attachments at once
+    pub fn check_support(gl: &WebGl2RenderingContext) -> Result<(), String> {
+        if !matches!(gl.get_extension("EXT_color_buffer_float"), Ok(Some(_))) {
+            return Err(
+                "Rendering to float textures (EXT_color_buffer_float) is unsupported".into(),
+            );
+        }
+        // WebGL2 guarantees at least 4 of each, but check anyway
+        for (name, parameter) in [
+            ("MAX_DRAW_BUFFERS", WebGl2RenderingContext::MAX_DRAW_BUFFERS),
+            (
+                "MAX_COLOR_ATTACHMENTS",
+                WebGl2RenderingContext::MAX_COLOR_ATTACHMENTS,
+            ),
+        ] {
+            let limit = gl
+                .get_parameter(parameter)
+                .ok()
+                .and_then(|limit| limit.as_f64())
+                .unwrap_or(0.) as u32;
+            if limit < G_BUFFER_ATTACHMENT_COUNT {
+                return Err(format!(
+                    "{name} is {limit}, but {G_BUFFER_ATTACHMENT_COUNT} are needed"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn new(gl: &WebGl2RenderingContext, width: u32, height: u32) -> Result<Self, String> {
+        GBuffer::check_support(gl)?;
+        let create_target = || {
+            let texture = gl
+                .create_texture()
+                .ok_or("Couldn't create G-buffer texture")?;
+            gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(&texture));
+            // float textures aren't filterable without another extension
+            for (parameter, value) in [
+                (
+                    WebGl2RenderingContext::TEXTURE_WRAP_S,
+                    WebGl2RenderingContext::CLAMP_TO_EDGE,
+                ),
+                (
+                    WebGl2RenderingContext::TEXTURE_WRAP_T,
+                    WebGl2RenderingContext::CLAMP_TO_EDGE,
+                ),
+                (
+                    WebGl2RenderingContext::TEXTURE_MIN_FILTER,
+                    WebGl2RenderingContext::NEAREST,
+                ),
+                (
+                    WebGl2RenderingContext::TEXTURE_MAG_FILTER,
+                    WebGl2RenderingContext::NEAREST,
+                ),
+            ] {
+                gl.tex_parameteri(WebGl2RenderingContext::TEXTURE_2D, parameter, value as i32);
+            }
+            Ok::<_, String>(texture)
+        };
+        let g_buffer = GBuffer {
+            normal: create_target()?,
+            depth: create_target()?,
+        };
+        g_buffer.allocate(gl, width, height);
+        Ok(g_buffer)
+    }
+
+    /// Gives both targets empty storage of the given size
+    pub fn allocate(&self, gl: &WebGl2RenderingContext, width: u32, height: u32) {
+        for (texture, internal_format, format) in [
+            (
+                &self.normal,
+                WebGl2RenderingContext::RGBA16F,
+                WebGl2RenderingContext::RGBA,
+            ),
+            (
+                &self.depth,
+                WebGl2RenderingContext::R32F,
+                WebGl2RenderingContext::RED,
+            ),
+        ] {
+            gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(texture));
+            gl.tex_image_2d_with_i32_and_i32_and_i32_and_format_and_type_and_opt_u8_array(
+                WebGl2RenderingContext::TEXTURE_2D,
+                0,
+                internal_format as i32,
+                width as i32,
+                height as i32,
+                0,
+                format,
+                WebGl2RenderingContext::FLOAT,
+                None,
+            )
+            .unwrap();
+        }
+    }
+
+    pub fn delete(self, gl: &WebGl2RenderingContext) {
+        gl.delete_texture(Some(&self.normal));
+        gl.delete_texture(Some(&self.depth));
+    }
+}
+
 pub fn draw(gl: &WebGl2RenderingContext, state: &MutexGuard<State>) {
-    gl.clear_color(0.0, 0.0, 0.0, 1.0);
+    gl.clear_color(
+        state.clear_color.x() as f32,
+        state.clear_color.y() as f32,
+        state.clear_color.z() as f32,
+        state.clear_alpha as f32,
+    );
     gl.viewport(0, 0, state.width as i32, state.height as i32);
     gl.clear(WebGl2RenderingContext::COLOR_BUFFER_BIT);
     gl.draw_arrays(
@@ -177,100 +1096,682 @@ pub fn draw(gl: &WebGl2RenderingContext, state: &MutexGuard<State>) {
     );
 }
 
-pub fn render(
-    gl: &WebGl2RenderingContext,
-    state: &MutexGuard<State>,
-    textures: &[WebGlTexture; 2],
-    framebuffer_objects: &[WebGlFramebuffer; 2],
-) {
+/// Which accumulation texture the current frame is written to,
+/// and which one holds the previous frame (the textures are used round-robin)
+pub fn accumulation_indices(even_odd_count: u32, frame_count: u32) -> (u32, u32) {
+    let write_index = even_odd_count % frame_count;
+    let read_index = (write_index + frame_count - 1) % frame_count;
+    (write_index, read_index)
+}
+
+pub fn render(renderer: &Renderer, state: &MutexGuard<State>) {
+    let gl = &renderer.gl;
+    let frame_count = renderer.textures.len() as u32;
+    let (write_index, read_index) = accumulation_indices(state.even_odd_count, frame_count);
+
     // use texture previously rendered to
     gl.bind_texture(
         WebGl2RenderingContext::TEXTURE_2D,
-        Some(&textures[((state.even_odd_count + 1) % 2) as usize]),
+        Some(&renderer.textures[read_index as usize]),
     );
 
-    // draw to canvas
-    gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+    if state.transition_start_time.is_some() {
+        gl.active_texture(WebGl2RenderingContext::TEXTURE0 + TRANSITION_TEXTURE_UNIT);
+        gl.bind_texture(
+            WebGl2RenderingContext::TEXTURE_2D,
+            renderer.transition_texture.as_ref(),
+        );
+        gl.active_texture(WebGl2RenderingContext::TEXTURE0);
+    }
+
+    // when blending more than 2 frames, every previous frame is made available,
+    // from most to least recent (texture unit 0 is reserved for `u_texture`)
+    if frame_count > 2 {
+        for i in 0..frame_count - 1 {
+            let texture_index = (write_index + frame_count - 1 - i) % frame_count;
+            gl.active_texture(WebGl2RenderingContext::TEXTURE1 + i);
+            gl.bind_texture(
+                WebGl2RenderingContext::TEXTURE_2D,
+                Some(&renderer.textures[texture_index as usize]),
+            );
+        }
+        gl.active_texture(WebGl2RenderingContext::TEXTURE0);
+    }
+
+    // draw to canvas (or the embedder's framebuffer)
+    gl.uniform1i(renderer.store_unexposed_location.as_ref(), 0);
+    gl.bind_framebuffer(
+        WebGl2RenderingContext::FRAMEBUFFER,
+        renderer.target_framebuffer.as_ref(),
+    );
     draw(gl, state);
 
     // only need to draw to framebuffer when doing averages of previous frames
     if state.should_average {
         // RENDER (TO FRAMEBUFFER)
+        // frames are stored unexposed (and un-averaged when blending more than 2 frames,
+        // so that they can be averaged uniformly later)
+        gl.uniform1i(renderer.store_unexposed_location.as_ref(), 1);
         gl.bind_framebuffer(
             WebGl2RenderingContext::FRAMEBUFFER,
-            Some(&framebuffer_objects[(state.even_odd_count % 2) as usize]),
+            Some(&renderer.framebuffer_objects[write_index as usize]),
         );
         draw(gl, state);
     }
 }
 
-pub async fn fetch_shader(url: &str) -> Result<String, JsValue> {
-    let request = Request::new_with_str(url)?;
-    let resp_value = JsFuture::from(dom::window().fetch_with_request(&request)).await?;
-
-    // `resp_value` is a `Response` object.
-    assert!(resp_value.is_instance_of::<Response>());
-    let resp: Response = resp_value.dyn_into()?;
-
-    // Convert this other `Promise` into a rust `Future`.
-    let text = JsFuture::from(resp.text()?)
-        .await?
-        .as_string()
-        .ok_or("Couldn't convert shader source into String")?;
+/// Measures the log-average luminance of the most recently accumulated frame.
+/// The GPU does most of the reduction by generating mipmaps, so that only a
+/// tiny mip level needs to be read back and averaged on the CPU
+pub fn average_log_luminance(renderer: &Renderer, state: &MutexGuard<State>) -> f64 {
+    let gl = &renderer.gl;
+    let frame_count = renderer.textures.len() as u32;
+    let texture = &renderer.textures[(state.even_odd_count % frame_count) as usize];
 
-    Ok(text)
-}
+    gl.bind_texture(WebGl2RenderingContext::TEXTURE_2D, Some(texture));
+    gl.generate_mipmap(WebGl2RenderingContext::TEXTURE_2D);
 
-// iterates through list of hittable geometry and sets uniforms at initialization time
-pub fn set_geometry(
-    state: &MutexGuard<State>,
-    gl: &WebGl2RenderingContext,
-    program: &WebGlProgram,
-) {
-    for (i, sphere) in state.sphere_list.iter().enumerate() {
-        let sphere_center_location =
-            gl.get_uniform_location(program, &format!("u_sphere_list[{}].center", i));
-        gl.uniform3fv_with_f32_array(sphere_center_location.as_ref(), &sphere.center.to_array());
+    let mut level = 0;
+    while (state.width.max(state.height) >> level) > LUMINANCE_REDUCTION_SIZE {
+        level += 1;
+    }
+    let width = (state.width >> level).max(1);
+    let height = (state.height >> level).max(1);
 
-        let sphere_radius_location =
-            gl.get_uniform_location(program, &format!("u_sphere_list[{}].radius", i));
-        gl.uniform1f(sphere_radius_location.as_ref(), sphere.radius as f32);
+    gl.bind_framebuffer(
+        WebGl2RenderingContext::FRAMEBUFFER,
+        Some(&renderer.luminance_framebuffer),
+    );
+    gl.framebuffer_texture_2d(
+        WebGl2RenderingContext::FRAMEBUFFER,
+        WebGl2RenderingContext::COLOR_ATTACHMENT0,
+        WebGl2RenderingContext::TEXTURE_2D,
+        Some(texture),
+        level,
+    );
+    let mut pixels = vec![0; (width * height * 4) as usize];
+    gl.read_pixels_with_opt_u8_array(
+        0,
+        0,
+        width as i32,
+        height as i32,
+        WebGl2RenderingContext::RGBA,
+        WebGl2RenderingContext::UNSIGNED_BYTE,
+        Some(&mut pixels),
+    )
+    .unwrap();
+    gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
 
-        let sphere_material_type_location =
-            gl.get_uniform_location(program, &format!("u_sphere_list[{}].material.type", i));
-        gl.uniform1i(
-            sphere_material_type_location.as_ref(),
-            sphere.material.material_type.value(),
-        );
+    math::log_average_luminance(&pixels)
+}
 
-        let sphere_material_albedo_location =
-            gl.get_uniform_location(program, &format!("u_sphere_list[{}].material.albedo", i));
-        gl.uniform3fv_with_f32_array(
-            sphere_material_albedo_location.as_ref(),
-            &sphere.material.albedo.to_array(),
+/// Measures how long the GPU actually spends rendering frames,
+/// using `EXT_disjoint_timer_query_webgl2`. Results are read back asynchronously,
+/// a few frames after each query is issued
+pub struct GpuTimer {
+    active_query: Option<WebGlQuery>,
+    /// Issued queries waiting on results, along with the number of rays traced during each
+    pending_queries: VecDeque<(WebGlQuery, f64)>,
+}
+
+impl GpuTimer {
+    /// Returns None when timer queries aren't supported
+    pub fn new(gl: &WebGl2RenderingContext) -> Option<Self> {
+        gl.get_extension("EXT_disjoint_timer_query_webgl2")
+            .ok()
+            .flatten()?;
+        Some(GpuTimer {
+            active_query: None,
+            pending_queries: VecDeque::new(),
+        })
+    }
+
+    /// Starts timing everything drawn until `end`
+    pub fn begin(&mut self, gl: &WebGl2RenderingContext) {
+        if self.active_query.is_some() || self.pending_queries.len() >= MAX_PENDING_TIMER_QUERIES {
+            return;
+        }
+        if let Some(query) = gl.create_query() {
+            gl.begin_query(TIME_ELAPSED_EXT, &query);
+            self.active_query = Some(query);
+        }
+    }
+
+    /// Stops timing, recording how many rays were traced while timed
+    pub fn end(&mut self, gl: &WebGl2RenderingContext, rays: f64) {
+        if let Some(query) = self.active_query.take() {
+            gl.end_query(TIME_ELAPSED_EXT);
+            self.pending_queries.push_back((query, rays));
+        }
+    }
+
+    /// Returns the most recent timing that has become available since the last poll
+    pub fn poll(&mut self, gl: &WebGl2RenderingContext) -> Option<GpuTiming> {
+        // a disjoint operation (such as the GPU changing clock speed) invalidates
+        // every query that was in flight at the time
+        if gl.get_parameter(GPU_DISJOINT_EXT).ok()?.as_bool() == Some(true) {
+            for (query, _) in self.pending_queries.drain(..) {
+                gl.delete_query(Some(&query));
+            }
+            return None;
+        }
+
+        let mut latest_timing = None;
+        while let Some((query, rays)) = self.pending_queries.front() {
+            let is_available = gl
+                .get_query_parameter(query, WebGl2RenderingContext::QUERY_RESULT_AVAILABLE)
+                .as_bool()
+                .unwrap_or(false);
+            if !is_available {
+                break;
+            }
+            let nanoseconds = gl
+                .get_query_parameter(query, WebGl2RenderingContext::QUERY_RESULT)
+                .as_f64()
+                .unwrap_or(0.);
+            latest_timing = Some(GpuTiming {
+                time: nanoseconds / 1_000_000.,
+                rays: *rays,
+            });
+            gl.delete_query(Some(query));
+            self.pending_queries.pop_front();
+        }
+        latest_timing
+    }
+}
+
+/// Blocks until the GPU has finished all submitted work, by reading a single pixel back
+/// (`gl.finish()` isn't guaranteed to wait), so that rendering can be timed
+pub fn wait_for_gpu(gl: &WebGl2RenderingContext) {
+    let mut pixel = [0; 4];
+    gl.read_pixels_with_opt_u8_array(
+        0,
+        0,
+        1,
+        1,
+        WebGl2RenderingContext::RGBA,
+        WebGl2RenderingContext::UNSIGNED_BYTE,
+        Some(&mut pixel),
+    )
+    .unwrap();
+}
+
+/// Reads back the RGBA pixels of whichever framebuffer is currently bound
+pub fn read_pixels(gl: &WebGl2RenderingContext, state: &MutexGuard<State>) -> Vec<u8> {
+    let mut pixels = vec![0; (state.width * state.height * 4) as usize];
+    gl.read_pixels_with_opt_u8_array(
+        0,
+        0,
+        state.width as i32,
+        state.height as i32,
+        WebGl2RenderingContext::RGBA,
+        WebGl2RenderingContext::UNSIGNED_BYTE,
+        Some(&mut pixels),
+    )
+    .unwrap();
+    pixels
+}
+
+/// Reads back the most recently accumulated frame (which holds the running average
+/// when blending 2 frames, but only the latest frame when blending more)
+pub fn read_accumulated_pixels(renderer: &Renderer, state: &MutexGuard<State>) -> Vec<u8> {
+    let gl = &renderer.gl;
+    let frame_count = renderer.framebuffer_objects.len() as u32;
+    gl.bind_framebuffer(
+        WebGl2RenderingContext::FRAMEBUFFER,
+        Some(&renderer.framebuffer_objects[(state.even_odd_count % frame_count) as usize]),
+    );
+    let pixels = read_pixels(gl, state);
+    gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+    pixels
+}
+
+/// Reverses the order of an RGBA image's rows (WebGL reads pixels back bottom row first)
+pub fn flip_rows(pixels: &[u8], width: u32) -> Vec<u8> {
+    let row_length = (width * 4) as usize;
+    pixels
+        .chunks_exact(row_length)
+        .rev()
+        .flatten()
+        .copied()
+        .collect()
+}
+
+/// Renders a single fresh frame into an offscreen framebuffer of `width` x `height`
+/// and returns its RGBA pixels, top row first. The camera's horizontal extent grows or
+/// shrinks to match the new aspect ratio, and the live view is left as it was
+pub fn render_to_bytes(
+    renderer: &Renderer,
+    state: &mut MutexGuard<State>,
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    render_passes_to_bytes(renderer, state, width, height, 1)
+}
+
+/// Like `render_to_bytes`, but averages `passes` frames (each with its own random seed)
+pub fn render_passes_to_bytes(
+    renderer: &Renderer,
+    state: &mut MutexGuard<State>,
+    width: u32,
+    height: u32,
+    passes: u32,
+) -> Vec<u8> {
+    let gl = &renderer.gl;
+    let live_dimensions = (state.width, state.height, state.target_aspect_ratio);
+
+    state.width = width;
+    state.height = height;
+    state.target_aspect_ratio = None;
+    state.update_pipeline();
+    update_vertex_buffer(gl, &renderer.quad_buffer, state);
+
+    let texture = create_texture(gl, state);
+    let framebuffer = create_framebuffer(gl, &texture, None);
+    // `u_texture` must not sample from the texture being rendered into
+    gl.bind_texture(
+        WebGl2RenderingContext::TEXTURE_2D,
+        Some(&renderer.textures[0]),
+    );
+
+    let now = dom::window().performance().unwrap().now();
+    let mut average = ImageAverage::default();
+    state::render_offscreen(state, |state| {
+        for pass in 0..passes.max(1) {
+            state.render_count = 0;
+            state::update_render_globals(state);
+            // a distinct time per pass gives each pass its own random seed
+            renderer.update_uniforms(state, now + pass as f64);
+            gl.uniform1i(renderer.store_unexposed_location.as_ref(), 0);
+            draw(gl, state);
+            average.add(&read_pixels(gl, state)).unwrap();
+        }
+    });
+    let pixels = flip_rows(&average.average().unwrap(), width);
+
+    gl.bind_framebuffer(WebGl2RenderingContext::FRAMEBUFFER, None);
+    gl.delete_framebuffer(Some(&framebuffer));
+    gl.delete_texture(Some(&texture));
+
+    // restore the live view (which starts accumulating from scratch)
+    (state.width, state.height, state.target_aspect_ratio) = live_dimensions;
+    state.update_pipeline();
+    update_vertex_buffer(gl, &renderer.quad_buffer, state);
+    state.render_count = 0;
+    state.should_render = true;
+
+    pixels
+}
+
+/// Renders a depth map of the current view offscreen, and returns its RGBA pixels, top row
+/// first. Each pixel is the distance from the camera to the first thing seen through its
+/// center (without depth of field) divided by `far`, as a grey level from 0 (black) to 255
+/// (white). Anything `far` or more away, including the sky, is white
+pub fn render_depth_to_bytes(
+    renderer: &Renderer,
+    state: &mut MutexGuard<State>,
+    far: f64,
+) -> Vec<u8> {
+    let gl = &renderer.gl;
+    let output_depth_location = get_uniform_location(gl, &renderer.program, "u_output_depth");
+    let depth_far_location = get_uniform_location(gl, &renderer.program, "u_depth_far");
+    gl.uniform1i(output_depth_location.as_ref(), 1);
+    gl.uniform1f(depth_far_location.as_ref(), far as f32);
+    let (width, height) = (state.width, state.height);
+    let pixels = render_to_bytes(renderer, state, width, height);
+    gl.uniform1i(output_depth_location.as_ref(), 0);
+    pixels
+}
+
+/// Renders a single fresh frame at a fixed time (so that the shader's random seed
+/// is always the same) and reads its pixels back from the GPU
+pub fn render_reference_frame(renderer: &Renderer, state: &mut MutexGuard<State>) -> Vec<u8> {
+    state.render_count = 0;
+    state::render_offscreen(state, |state| {
+        state::update_render_globals(state);
+        renderer.update_uniforms(state, 0.);
+        render(renderer, state);
+    });
+    // read back what was drawn to the canvas (framebuffers may hold un-averaged frames)
+    renderer.gl.bind_framebuffer(
+        WebGl2RenderingContext::FRAMEBUFFER,
+        renderer.target_framebuffer.as_ref(),
+    );
+    let pixels = read_pixels(&renderer.gl, state);
+
+    // continue accumulating normally, starting from this frame
+    state.should_render = true;
+
+    pixels
+}
+
+/// Fetches a shader's source, inlining any `#include "file"` directives
+pub async fn fetch_shader(url: &str) -> Result<String, JsValue> {
+    resolve_includes(url.to_string(), Vec::new()).await
+}
+
+async fn fetch_text(url: &str) -> Result<String, JsValue> {
+    let request = Request::new_with_str(url)?;
+    let resp_value = JsFuture::from(dom::window().fetch_with_request(&request)).await?;
+
+    // `resp_value` is a `Response` object.
+    assert!(resp_value.is_instance_of::<Response>());
+    let resp: Response = resp_value.dyn_into()?;
+    if !resp.ok() {
+        return Err(format!("Failed to fetch {url} (status {})", resp.status()).into());
+    }
+
+    // Convert this other `Promise` into a rust `Future`.
+    let text = JsFuture::from(resp.text()?)
+        .await?
+        .as_string()
+        .ok_or("Couldn't convert shader source into String")?;
+
+    Ok(text)
+}
+
+/// A line of shader source, as seen by the include preprocessor
+#[derive(Debug, PartialEq)]
+enum ShaderLine<'a> {
+    Code(&'a str),
+    /// The path of the included file, as written in the directive
+    Include(&'a str),
+}
+
+/// Finds `#include "file"` directives (ignoring any inside comments)
+fn parse_shader_lines(source: &str) -> Result<Vec<ShaderLine<'_>>, String> {
+    let mut is_in_block_comment = false;
+    let mut lines = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let starts_in_block_comment = is_in_block_comment;
+        // track whether the next line starts inside a /* block comment */
+        let mut rest = line;
+        loop {
+            if is_in_block_comment {
+                match rest.find("*/") {
+                    Some(end) => {
+                        is_in_block_comment = false;
+                        rest = &rest[end + 2..];
+                    }
+                    None => break,
+                }
+            } else {
+                let block_start = rest.find("/*");
+                let line_comment_start = rest.find("//");
+                match (block_start, line_comment_start) {
+                    (Some(start), Some(comment)) if comment < start => break,
+                    (Some(start), _) => {
+                        is_in_block_comment = true;
+                        rest = &rest[start + 2..];
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        let directive = line.trim_start();
+        if starts_in_block_comment || !directive.starts_with("#include") {
+            lines.push(ShaderLine::Code(line));
+            continue;
+        }
+        let path = directive["#include".len()..]
+            .trim()
+            .strip_prefix('"')
+            .and_then(|path| path.split_once('"'))
+            .filter(|(path, trailing)| {
+                // only a line comment may follow the included path
+                let trailing = trailing.trim();
+                !path.is_empty() && (trailing.is_empty() || trailing.starts_with("//"))
+            })
+            .map(|(path, _)| path)
+            .ok_or_else(|| format!("Malformed #include on line {}: {line}", i + 1))?;
+        lines.push(ShaderLine::Include(path));
+    }
+    Ok(lines)
+}
+
+/// Includes are resolved relative to the file that includes them
+fn resolve_include_url(including_url: &str, path: &str) -> String {
+    match including_url.rfind('/') {
+        Some(directory_end) => format!("{}{path}", &including_url[..=directory_end]),
+        None => path.to_string(),
+    }
+}
+
+/// Recursively fetches a shader and every file it includes.
+/// `include_stack` holds the files currently being included, for detecting cycles
+fn resolve_includes(
+    url: String,
+    mut include_stack: Vec<String>,
+) -> LocalBoxFuture<'static, Result<String, JsValue>> {
+    async move {
+        if include_stack.contains(&url) {
+            include_stack.push(url);
+            return Err(format!("Circular #include: {}", include_stack.join(" -> ")).into());
+        }
+        let source = fetch_text(&url).await?;
+        include_stack.push(url.clone());
+
+        let mut resolved = String::with_capacity(source.len());
+        for line in parse_shader_lines(&source).map_err(|error| format!("{url}: {error}"))? {
+            match line {
+                ShaderLine::Code(code) => resolved.push_str(code),
+                ShaderLine::Include(path) => {
+                    let include_url = resolve_include_url(&url, path);
+                    let included = resolve_includes(include_url, include_stack.clone())
+                        .await
+                        .map_err(|error| {
+                            format!("Could not #include \"{path}\" in {url}: {error:?}")
+                        })?;
+                    resolved.push_str(&included);
+                }
+            }
+            resolved.push('\n');
+        }
+        Ok(resolved)
+    }
+    .boxed_local()
+}
+
+pub async fn fetch_bytes(url: &str) -> Result<Vec<u8>, JsValue> {
+    let request = Request::new_with_str(url)?;
+    let resp_value = JsFuture::from(dom::window().fetch_with_request(&request)).await?;
+    let resp: Response = resp_value.dyn_into()?;
+    if !resp.ok() {
+        return Err(format!("Failed to fetch {url} (status {})", resp.status()).into());
+    }
+
+    let array_buffer = JsFuture::from(resp.array_buffer()?).await?;
+    Ok(js_sys::Uint8Array::new(&array_buffer).to_vec())
+}
+
+// iterates through list of hittable geometry and sets uniforms at initialization time
+fn get_sphere_center_locations(
+    gl: &WebGl2RenderingContext,
+    program: &WebGlProgram,
+) -> Vec<Option<WebGlUniformLocation>> {
+    (0..glsl::MAX_SPHERES)
+        .map(|i| get_uniform_location(gl, program, &format!("u_sphere_list[{i}].center")))
+        .collect()
+}
+
+pub fn set_geometry(
+    state: &MutexGuard<State>,
+    gl: &WebGl2RenderingContext,
+    program: &WebGlProgram,
+) {
+    let sphere_count = state.world_spheres.len().min(glsl::MAX_SPHERES);
+    if state.world_spheres.len() > glsl::MAX_SPHERES {
+        log::warn!(
+            "The shader can only draw {} spheres, so the last {} won't be rendered",
+            glsl::MAX_SPHERES,
+            state.world_spheres.len() - glsl::MAX_SPHERES
         );
+    }
+    for (i, sphere) in state.world_spheres.iter().take(sphere_count).enumerate() {
+        let sphere_center_location =
+            get_uniform_location(gl, program, &format!("u_sphere_list[{}].center", i));
+        gl.uniform3fv_with_f32_array(sphere_center_location.as_ref(), &sphere.center.to_array());
 
-        let sphere_material_fuzz_location =
-            gl.get_uniform_location(program, &format!("u_sphere_list[{}].material.fuzz", i));
-        gl.uniform1f(sphere_material_fuzz_location.as_ref(), sphere.material.fuzz);
+        let sphere_radius_location =
+            get_uniform_location(gl, program, &format!("u_sphere_list[{}].radius", i));
+        gl.uniform1f(sphere_radius_location.as_ref(), sphere.radius as f32);
 
-        let sphere_material_refraction_index_location = gl.get_uniform_location(
+        set_material_uniforms(
+            gl,
             program,
-            &format!("u_sphere_list[{}].material.refraction_index", i),
+            &format!("u_sphere_list[{i}].material"),
+            &sphere.material,
         );
-        gl.uniform1f(
-            sphere_material_refraction_index_location.as_ref(),
-            sphere.material.refraction_index,
+
+        // a scale of 0 tells the shader not to blend at all
+        set_material_uniforms(
+            gl,
+            program,
+            &format!("u_sphere_list[{i}].material2"),
+            sphere.material2.as_ref().unwrap_or(&sphere.material),
         );
+        let blend_scale_location =
+            get_uniform_location(gl, program, &format!("u_sphere_list[{i}].blend_scale"));
+        let blend_scale = if sphere.material2.is_some() {
+            sphere.blend_scale
+        } else {
+            0.
+        };
+        gl.uniform1f(blend_scale_location.as_ref(), blend_scale as f32);
 
         let sphere_is_active_location =
-            gl.get_uniform_location(program, &format!("u_sphere_list[{}].is_active", i));
-        gl.uniform1i(sphere_is_active_location.as_ref(), 1);
+            get_uniform_location(gl, program, &format!("u_sphere_list[{}].is_active", i));
+        gl.uniform1i(sphere_is_active_location.as_ref(), sphere.visible as i32);
 
         let sphere_uuid_location =
-            gl.get_uniform_location(program, &format!("u_sphere_list[{}].uuid", i));
+            get_uniform_location(gl, program, &format!("u_sphere_list[{}].uuid", i));
         gl.uniform1i(sphere_uuid_location.as_ref(), sphere.uuid as i32);
     }
+
+    // any spheres left over from a previous (larger) scene are ignored
+    let sphere_count_location = get_uniform_location(gl, program, "u_sphere_count");
+    gl.uniform1i(sphere_count_location.as_ref(), sphere_count as i32);
+
+    set_rect_light_uniforms(state, gl, program);
+}
+
+fn set_rect_light_uniforms(
+    state: &MutexGuard<State>,
+    gl: &WebGl2RenderingContext,
+    program: &WebGlProgram,
+) {
+    let rect_count = state.rect_lights.len().min(glsl::MAX_RECT_LIGHTS);
+    for (i, rect) in state.rect_lights.iter().take(rect_count).enumerate() {
+        let plane_location =
+            get_uniform_location(gl, program, &format!("u_rect_light_list[{i}].plane"));
+        gl.uniform1i(plane_location.as_ref(), rect.plane.value());
+
+        let bounds_location =
+            get_uniform_location(gl, program, &format!("u_rect_light_list[{i}].bounds"));
+        gl.uniform4f(
+            bounds_location.as_ref(),
+            rect.a0 as f32,
+            rect.a1 as f32,
+            rect.b0 as f32,
+            rect.b1 as f32,
+        );
+
+        let k_location = get_uniform_location(gl, program, &format!("u_rect_light_list[{i}].k"));
+        gl.uniform1f(k_location.as_ref(), rect.k as f32);
+
+        let flip_normal_location =
+            get_uniform_location(gl, program, &format!("u_rect_light_list[{i}].flip_normal"));
+        gl.uniform1i(flip_normal_location.as_ref(), rect.flip_normal as i32);
+
+        let double_sided_location =
+            get_uniform_location(gl, program, &format!("u_rect_light_list[{i}].double_sided"));
+        gl.uniform1i(double_sided_location.as_ref(), rect.double_sided as i32);
+
+        set_material_uniforms(
+            gl,
+            program,
+            &format!("u_rect_light_list[{i}].material"),
+            &rect.material,
+        );
+
+        let uuid_location =
+            get_uniform_location(gl, program, &format!("u_rect_light_list[{i}].uuid"));
+        gl.uniform1i(uuid_location.as_ref(), rect.uuid);
+    }
+    let rect_count_location = get_uniform_location(gl, program, "u_rect_light_count");
+    gl.uniform1i(rect_count_location.as_ref(), rect_count as i32);
+
+    // only emissive rects are worth sampling directly
+    let emissive_rects: Vec<i32> = state
+        .rect_lights
+        .iter()
+        .take(rect_count)
+        .enumerate()
+        .filter(|(_, rect)| rect.material.emission != Vec3::new() && !rect.material.display_only)
+        .map(|(i, _)| i as i32)
+        .collect();
+    for (i, rect_index) in emissive_rects.iter().enumerate() {
+        let index_location =
+            get_uniform_location(gl, program, &format!("u_emissive_rect_indices[{i}]"));
+        gl.uniform1i(index_location.as_ref(), *rect_index);
+    }
+    let emissive_count_location = get_uniform_location(gl, program, "u_emissive_rect_count");
+    gl.uniform1i(
+        emissive_count_location.as_ref(),
+        emissive_rects.len() as i32,
+    );
+}
+
+/// Uploads a material to the shader struct at `prefix` (e.g. `u_sphere_list[0].material`)
+fn set_material_uniforms(
+    gl: &WebGl2RenderingContext,
+    program: &WebGlProgram,
+    prefix: &str,
+    material: &Material,
+) {
+    let type_location = get_uniform_location(gl, program, &format!("{prefix}.type"));
+    gl.uniform1i(type_location.as_ref(), material.material_type.value());
+
+    let albedo_location = get_uniform_location(gl, program, &format!("{prefix}.albedo"));
+    gl.uniform3fv_with_f32_array(albedo_location.as_ref(), &material.albedo.to_array());
+
+    let fuzz_location = get_uniform_location(gl, program, &format!("{prefix}.fuzz"));
+    gl.uniform1f(fuzz_location.as_ref(), material.fuzz);
+
+    let refraction_index_location =
+        get_uniform_location(gl, program, &format!("{prefix}.refraction_index"));
+    gl.uniform1f(
+        refraction_index_location.as_ref(),
+        material.refraction_index,
+    );
+
+    let absorption_location = get_uniform_location(gl, program, &format!("{prefix}.absorption"));
+    gl.uniform3fv_with_f32_array(
+        absorption_location.as_ref(),
+        &material.absorption.to_array(),
+    );
+
+    let emission_location = get_uniform_location(gl, program, &format!("{prefix}.emission"));
+    gl.uniform3fv_with_f32_array(emission_location.as_ref(), &material.emission.to_array());
+
+    let display_only_location =
+        get_uniform_location(gl, program, &format!("{prefix}.display_only"));
+    gl.uniform1i(display_only_location.as_ref(), material.display_only as i32);
+}
+
+/// Looks up a uniform, logging (at the debug level) when the linked program doesn't have it,
+/// which happens when a name is misspelled or the uniform is unused and optimized away
+pub fn get_uniform_location(
+    gl: &WebGl2RenderingContext,
+    program: &WebGlProgram,
+    name: &str,
+) -> Option<WebGlUniformLocation> {
+    let location = gl.get_uniform_location(program, name);
+    if location.is_none() {
+        log::debug!("Uniform \"{name}\" wasn't found in the linked shader program");
+    }
+    location
 }
 
 /// Kind of hacky, but allows setting up uniform names and how to update them once.
@@ -283,122 +1784,325 @@ pub fn setup_uniforms(gl: &WebGl2RenderingContext, program: &WebGlProgram) -> Un
         vec![
             Uniform {
                 location: None,
-                name: "u_texture",
+                name: "u_texture",
+                updater: Box::new(
+                    |_: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), 0);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_width",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.width as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_height",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.height as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_time",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     now: f64| {
+                        let time = state.fixed_seed_time.unwrap_or(now);
+                        gl.uniform1f(location.as_ref(), time as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_samples_per_pixel",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(
+                            location.as_ref(),
+                            state.effective_samples_per_pixel() as i32,
+                        );
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_aspect_ratio",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.aspect_ratio as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_viewport_height",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.viewport_height as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_viewport_width",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.viewport_width as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_focal_length",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.focal_length as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_max_depth",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), state.max_depth as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_render_count",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), state.render_count as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_should_average",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), state.should_average as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_true_average",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), state.true_average as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_last_frame_weight",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.last_frame_weight as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_lens_radius",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.lens_radius as f32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_has_roi",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), state.roi.is_some() as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_roi",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        if let Some(roi) = state.roi_on_canvas() {
+                            gl.uniform4fv_with_f32_array(location.as_ref(), &roi);
+                        }
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_roi_samples_per_pixel",
                 updater: Box::new(
-                    |_: &MutexGuard<State>,
+                    |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1i(location.as_ref(), 0);
+                        gl.uniform1i(location.as_ref(), state.roi_samples_per_pixel as i32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_width",
+                name: "u_g_buffer",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1f(location.as_ref(), state.width as f32);
+                        gl.uniform1i(location.as_ref(), state.g_buffer as i32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_height",
+                name: "u_show_grid",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1f(location.as_ref(), state.height as f32);
+                        gl.uniform1i(location.as_ref(), state.show_grid as i32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_time",
+                name: "u_grid_spacing",
                 updater: Box::new(
-                    |_: &MutexGuard<State>,
+                    |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
-                     now: f64| {
-                        gl.uniform1f(location.as_ref(), now as f32);
+                     _: f64| {
+                        gl.uniform1f(location.as_ref(), state.grid_spacing as f32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_samples_per_pixel",
+                name: "u_aperture_blades",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        // increase sample rate when paused (such as on first render and when resizing)
-                        // it's ok to do some heavy lifting here, since it's not being continually rendered at this output
-                        let samples_per_pixel = if state.is_paused {
-                            state.samples_per_pixel.max(25)
-                        } else {
-                            state.samples_per_pixel
-                        };
-                        gl.uniform1i(location.as_ref(), samples_per_pixel as i32);
+                        gl.uniform1i(location.as_ref(), state.aperture_blades as i32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_aspect_ratio",
+                name: "u_diffuse_mode",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1f(location.as_ref(), state.aspect_ratio as f32);
+                        gl.uniform1i(location.as_ref(), state.diffuse_mode.value());
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_viewport_height",
+                name: "u_stereo_mode",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1f(location.as_ref(), state.viewport_height as f32);
+                        gl.uniform1i(location.as_ref(), state.stereo_mode.value());
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_viewport_width",
+                name: "u_ipd",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1f(location.as_ref(), state.viewport_width as f32);
+                        gl.uniform1f(location.as_ref(), state.ipd as f32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_focal_length",
+                name: "u_selected_object",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1f(location.as_ref(), state.focal_length as f32);
+                        gl.uniform1i(location.as_ref(), state.selected_object);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_camera_origin",
+                name: "u_cursor_point",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
@@ -406,41 +2110,131 @@ pub fn setup_uniforms(gl: &WebGl2RenderingContext, program: &WebGlProgram) -> Un
                      _: f64| {
                         gl.uniform3fv_with_f32_array(
                             location.as_ref(),
-                            &state.camera_origin.to_array(),
+                            &state.cursor_point.to_array(),
                         );
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_horizontal",
+                name: "u_history",
+                updater: Box::new(
+                    |_: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        // history frames are bound to texture units 1 and up
+                        let texture_units: Vec<i32> =
+                            (1..state::MAX_ACCUMULATION_FRAMES as i32).collect();
+                        gl.uniform1iv_with_i32_array(location.as_ref(), &texture_units);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_history_count",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform3fv_with_f32_array(
-                            location.as_ref(),
-                            &state.horizontal.to_array(),
-                        );
+                        // 2 frames uses the original progressive averaging instead
+                        let history_count = if state.accumulation_frame_count > 2 {
+                            state.accumulation_frame_count - 1
+                        } else {
+                            0
+                        };
+                        gl.uniform1i(location.as_ref(), history_count as i32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_vertical",
+                name: "u_environment_map",
+                updater: Box::new(
+                    |_: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), ENVIRONMENT_MAP_TEXTURE_UNIT as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_transition_texture",
+                updater: Box::new(
+                    |_: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), TRANSITION_TEXTURE_UNIT as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_transition",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform3fv_with_f32_array(location.as_ref(), &state.vertical.to_array());
+                        gl.uniform1f(location.as_ref(), state.transition_progress as f32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_lower_left_corner",
+                name: "u_env_cubemap",
+                updater: Box::new(
+                    |_: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), ENVIRONMENT_CUBEMAP_TEXTURE_UNIT as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_env_is_cubemap",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), state.is_environment_cubemap as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_has_environment_map",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), state.has_environment_map as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_sun_sky_enabled",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform1i(location.as_ref(), state.sun_sky_enabled as i32);
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_sun_direction",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
@@ -448,122 +2242,153 @@ pub fn setup_uniforms(gl: &WebGl2RenderingContext, program: &WebGlProgram) -> Un
                      _: f64| {
                         gl.uniform3fv_with_f32_array(
                             location.as_ref(),
-                            &state.lower_left_corner.to_array(),
+                            &state.sun_direction().to_array(),
                         );
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_max_depth",
+                name: "u_turbidity",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1i(location.as_ref(), state.max_depth as i32);
+                        gl.uniform1f(location.as_ref(), state.turbidity as f32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_render_count",
+                name: "u_edge_aa",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1i(location.as_ref(), state.render_count as i32);
+                        gl.uniform1i(location.as_ref(), state.edge_aa as i32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_should_average",
+                name: "u_raw_output",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1i(location.as_ref(), state.should_average as i32);
+                        gl.uniform1i(location.as_ref(), state.raw_output as i32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_last_frame_weight",
+                name: "u_height_fog_density",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1f(location.as_ref(), state.last_frame_weight as f32);
+                        gl.uniform1f(location.as_ref(), state.height_fog_density as f32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_lens_radius",
+                name: "u_height_fog_falloff",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1f(location.as_ref(), state.lens_radius as f32);
+                        gl.uniform1f(location.as_ref(), state.height_fog_falloff as f32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_u",
+                name: "u_height_fog_color",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform3fv_with_f32_array(location.as_ref(), &state.u.to_array());
+                        gl.uniform3fv_with_f32_array(
+                            location.as_ref(),
+                            &state.height_fog_color.to_array(),
+                        );
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_v",
+                name: "u_exposure",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform3fv_with_f32_array(location.as_ref(), &state.v.to_array());
+                        gl.uniform1f(location.as_ref(), state.exposure as f32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_w",
+                name: "u_ray_epsilon",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform3fv_with_f32_array(location.as_ref(), &state.w.to_array());
+                        gl.uniform1f(location.as_ref(), state.ray_epsilon as f32);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_selected_object",
+                name: "u_enable_debugging",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1i(location.as_ref(), state.selected_object);
+                        gl.uniform1i(location.as_ref(), state.enable_debugging);
+                    },
+                ),
+            },
+        ],
+    )
+}
+
+/// Uniforms derived from the camera's basis, which are only uploaded when the camera changes
+pub fn setup_camera_uniforms(gl: &WebGl2RenderingContext, program: &WebGlProgram) -> Uniforms {
+    Uniforms::create(
+        gl,
+        program,
+        vec![
+            Uniform {
+                location: None,
+                name: "u_world_basis",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        // columns are the x, up, and z axes of the world
+                        let (x_axis, up, z_axis) = state::world_basis(&state.world_up);
+                        let mut basis = [0.; 9];
+                        basis[0..3].copy_from_slice(&x_axis.to_array());
+                        basis[3..6].copy_from_slice(&up.to_array());
+                        basis[6..9].copy_from_slice(&z_axis.to_array());
+                        gl.uniform_matrix3fv_with_f32_array(location.as_ref(), false, &basis);
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_cursor_point",
+                name: "u_camera_origin",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
@@ -571,20 +2396,86 @@ pub fn setup_uniforms(gl: &WebGl2RenderingContext, program: &WebGlProgram) -> Un
                      _: f64| {
                         gl.uniform3fv_with_f32_array(
                             location.as_ref(),
-                            &state.cursor_point.to_array(),
+                            &state.render_camera_origin().to_array(),
                         );
                     },
                 ),
             },
             Uniform {
                 location: None,
-                name: "u_enable_debugging",
+                name: "u_horizontal",
                 updater: Box::new(
                     |state: &MutexGuard<State>,
                      location: &Option<WebGlUniformLocation>,
                      gl: &WebGl2RenderingContext,
                      _: f64| {
-                        gl.uniform1i(location.as_ref(), state.enable_debugging);
+                        gl.uniform3fv_with_f32_array(
+                            location.as_ref(),
+                            &state.horizontal.to_array(),
+                        );
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_vertical",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform3fv_with_f32_array(location.as_ref(), &state.vertical.to_array());
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_lower_left_corner",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform3fv_with_f32_array(
+                            location.as_ref(),
+                            &state.render_lower_left_corner().to_array(),
+                        );
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_u",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform3fv_with_f32_array(location.as_ref(), &state.u.to_array());
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_v",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform3fv_with_f32_array(location.as_ref(), &state.v.to_array());
+                    },
+                ),
+            },
+            Uniform {
+                location: None,
+                name: "u_w",
+                updater: Box::new(
+                    |state: &MutexGuard<State>,
+                     location: &Option<WebGlUniformLocation>,
+                     gl: &WebGl2RenderingContext,
+                     _: f64| {
+                        gl.uniform3fv_with_f32_array(location.as_ref(), &state.w.to_array());
                     },
                 ),
             },
@@ -617,7 +2508,7 @@ impl Uniforms {
             list: uniform_list
                 .into_iter()
                 .map(|uniform| Uniform {
-                    location: gl.get_uniform_location(program, uniform.name),
+                    location: get_uniform_location(gl, program, uniform.name),
                     name: uniform.name,
                     updater: uniform.updater,
                 })
@@ -625,6 +2516,13 @@ impl Uniforms {
         }
     }
 
+    /// Looks up every uniform's location again (after the program has been relinked)
+    pub fn update_locations(&mut self, gl: &WebGl2RenderingContext, program: &WebGlProgram) {
+        for uniform in self.list.iter_mut() {
+            uniform.location = get_uniform_location(gl, program, uniform.name);
+        }
+    }
+
     // set uniforms with current state
     pub fn run_setters(&self, state: &MutexGuard<State>, gl: &WebGl2RenderingContext, now: f64) {
         for uniform in self.list.iter() {
@@ -632,3 +2530,83 @@ impl Uniforms {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flipping_reverses_rows() {
+        let pixels = [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4];
+        assert_eq!(
+            flip_rows(&pixels, 1),
+            vec![4, 4, 4, 4, 3, 3, 3, 3, 2, 2, 2, 2, 1, 1, 1, 1]
+        );
+        assert_eq!(
+            flip_rows(&pixels, 2),
+            vec![3, 3, 3, 3, 4, 4, 4, 4, 1, 1, 1, 1, 2, 2, 2, 2]
+        );
+    }
+
+    #[test]
+    fn defines_are_injected_after_the_version() {
+        let source = inject_defines("#version 300 es\nprecision highp float;\n");
+        assert_eq!(
+            source,
+            format!(
+                "#version 300 es\n#define MAX_SPHERES {}\n#define MAX_RECT_LIGHTS {}\n\
+                precision highp float;\n",
+                glsl::MAX_SPHERES,
+                glsl::MAX_RECT_LIGHTS
+            )
+        );
+        assert!(inject_defines("void main() {}").starts_with("#define MAX_SPHERES"));
+    }
+
+    #[test]
+    fn accumulation_textures_are_used_round_robin() {
+        assert_eq!(accumulation_indices(0, 2), (0, 1));
+        assert_eq!(accumulation_indices(1, 2), (1, 0));
+        assert_eq!(accumulation_indices(7, 4), (3, 2));
+        assert_eq!(accumulation_indices(8, 4), (0, 3));
+    }
+
+    #[test]
+    fn finds_include_directives() {
+        let lines =
+            parse_shader_lines("#version 300 es\n  #include \"utils.glsl\" // helpers\n").unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                ShaderLine::Code("#version 300 es"),
+                ShaderLine::Include("utils.glsl")
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_commented_out_includes() {
+        let source = "// #include \"a.glsl\"\n/*\n#include \"b.glsl\"\n*/\nvoid main() {}";
+        let lines = parse_shader_lines(source).unwrap();
+        assert!(lines.iter().all(|line| matches!(line, ShaderLine::Code(_))));
+    }
+
+    #[test]
+    fn rejects_malformed_includes() {
+        assert!(parse_shader_lines("#include utils.glsl").is_err());
+        assert!(parse_shader_lines("#include \"\"").is_err());
+        assert!(parse_shader_lines("#include \"utils.glsl\" junk").is_err());
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        assert_eq!(
+            resolve_include_url("./shader.frag", "utils.glsl"),
+            "./utils.glsl"
+        );
+        assert_eq!(
+            resolve_include_url("./lib/sky.glsl", "noise.glsl"),
+            "./lib/noise.glsl"
+        );
+    }
+}
@@ -0,0 +1,21 @@
+#version 300 es
+
+// world-space line vertices, projected through the same pinhole camera as the ray tracer
+in vec3 a_position;
+in vec3 a_color;
+
+uniform vec3 u_camera_origin;
+uniform vec3 u_w;
+// camera axes scaled so that projected points land in clip space (-1->1)
+uniform vec3 u_clip_x_axis;
+uniform vec3 u_clip_y_axis;
+
+out vec3 v_color;
+
+void main() {
+  vec3 offset = a_position - u_camera_origin;
+  // depth is used as w, so lines behind the camera are clipped by the GPU
+  float depth = -dot(offset, u_w);
+  gl_Position = vec4(dot(offset, u_clip_x_axis), dot(offset, u_clip_y_axis), 0., depth);
+  v_color = a_color;
+}
@@ -0,0 +1,33 @@
+#version 300 es
+precision mediump float;
+
+// object ids rendered by the ray tracer: uuid + 1 in the red channel (0 = nothing was hit)
+uniform sampler2D u_id_texture;
+// id (uuid + 1) of the object to outline
+uniform int u_selected_id;
+uniform vec3 u_outline_color;
+
+out vec4 o_color;
+
+int id_at(ivec2 pixel) {
+  return int(texelFetch(u_id_texture, pixel, 0).r * 255. + 0.5);
+}
+
+void main() {
+  ivec2 pixel = ivec2(gl_FragCoord.xy);
+  ivec2 max_pixel = textureSize(u_id_texture, 0) - 1;
+  bool is_selected = id_at(pixel) == u_selected_id;
+
+  // count the neighbors on the other side of the selected object's boundary:
+  // using the fraction as coverage gives a soft (antialiased) edge instead of a jagged one
+  float boundary_count = 0.;
+  for (int x = -1; x <= 1; x++) {
+    for (int y = -1; y <= 1; y++) {
+      ivec2 neighbor = clamp(pixel + ivec2(x, y), ivec2(0), max_pixel);
+      boundary_count += float((id_at(neighbor) == u_selected_id) != is_selected);
+    }
+  }
+  float coverage = min(boundary_count / 3., 1.);
+
+  o_color = vec4(u_outline_color, coverage);
+}
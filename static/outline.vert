@@ -0,0 +1,8 @@
+#version 300 es
+
+// full-screen quad
+in vec2 a_position;
+
+void main() {
+  gl_Position = vec4(a_position, 0., 1.);
+}
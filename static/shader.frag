@@ -3,7 +3,16 @@ precision mediump float;
 
 #define PI 3.141592653589793
 #define MAX_T 1e5
-#define MIN_T 0.001
+// must match `MAX_ACCUMULATION_FRAMES` - 1 in state.rs
+#define MAX_HISTORY_FRAMES 7
+// the sun is drawn (and sampled) larger than it really is to keep noise down
+#define SUN_ANGULAR_RADIUS 0.01
+// light falling on a surface facing the sun (before atmospheric attenuation)
+#define SUN_IRRADIANCE 2.5
+// the Preetham model gives luminance in kcd/m^2, which is far brighter than the display range
+#define SKY_LUMINANCE_SCALE 0.06
+// extra samples taken at pixels that straddle an object's silhouette (when u_edge_aa is set)
+#define EDGE_AA_SAMPLES 4
 
 // PSEUDO-RANDOM NUMBER GENERATORS //////////////////////////////////////////////////////
 // global seed is initialized in main, and then each hash function alters 
@@ -18,6 +27,15 @@ uint base_hash(uvec2 p) {
   return h32 ^ (h32 >> 16);
 }
 
+// Decorrelates every pixel of every frame: the pixel's coordinates, the frame number,
+// and the time are each mixed in through a full integer hash (rather than being added
+// together as floats, which loses the time's low bits and correlates neighboring pixels)
+uint pixel_hash(uvec2 pixel, uint frame, uint time_bits) {
+  uint h = base_hash(pixel);
+  h = base_hash(uvec2(h, frame));
+  return base_hash(uvec2(h, time_bits));
+}
+
 float hash1(inout float seed) {
     uint n = base_hash(floatBitsToUint(vec2(seed += .1, seed += .1)));
     return float(n)*(1.0/float(0xffffffffU));
@@ -48,15 +66,36 @@ struct Ray {
 struct Material {
   int type;
   vec3 albedo; // or "reflectance"
-  float fuzz; // used for duller metals
+  float fuzz; // used for duller metals (clamped to 0->1 by the ray tracer)
   float refraction_index; // used for glass
+  vec3 absorption; // used for colored glass (zero = clear)
+  int display_only; // markers: drawn at full brightness, but ignored when lighting the scene
+  vec3 emission; // light given off by the surface (black = none)
 };
 
 struct Sphere {
   vec3 center;
   float radius;
   Material material;
-  int is_active;
+  int is_active; // 0 for hidden spheres
+  int uuid;
+  // blended in with `material` in noise patches `blend_scale` across (0 for no blending)
+  Material material2;
+  float blend_scale;
+};
+
+// an axis-aligned rectangle spanning a0->a1 and b0->b1 along the plane's first two axes,
+// at k along the third (see `glsl::Rect`)
+#define RECT_XY 0
+#define RECT_XZ 1
+#define RECT_YZ 2
+struct RectLight {
+  int plane;
+  vec4 bounds; // a0, a1, b0, b1
+  float k;
+  int flip_normal; // the front face looks toward -k rather than +k
+  int double_sided; // emits from both faces, rather than only the front one
+  Material material;
   int uuid;
 };
 
@@ -67,13 +106,17 @@ struct HitRecord {
   bool front_face;
   Material material;
   int uuid;
+  bool is_rect_light;
 };
 
 
 // INPUTS / OUTPUTS //////////////////////////////////////////////////////
 in vec2 v_position;
 
-out vec4 o_color;
+layout(location = 0) out vec4 o_color;
+// G-buffer outputs (see `webgl::GBuffer`), discarded unless the framebuffer has the attachments
+layout(location = 1) out vec4 o_normal;
+layout(location = 2) out vec4 o_depth;
 
 // video frame, received as a 2d texture
 uniform sampler2D u_texture;
@@ -81,26 +124,94 @@ uniform float u_width;
 uniform float u_height;
 uniform float u_time;
 uniform int u_samples_per_pixel;
+// pixels inside the region of interest (x, y, width, height from the bottom-left)
+// are rendered with u_roi_samples_per_pixel instead
+uniform bool u_has_roi;
+uniform vec4 u_roi;
+uniform int u_roi_samples_per_pixel;
+uniform bool u_edge_aa;
 uniform float u_aspect_ratio;
 uniform float u_viewport_height;
 uniform float u_viewport_width;
 uniform float u_focal_length;
 uniform vec3 u_camera_origin;
+// columns are the world's x, up, and z axes (the identity for Y-up scenes)
+uniform mat3 u_world_basis;
 uniform vec3 u_horizontal;
 uniform vec3 u_vertical;
 uniform vec3 u_lower_left_corner;
 uniform int u_max_depth;
 uniform int u_render_count;
 uniform bool u_should_average;
+uniform bool u_true_average;
 uniform float u_last_frame_weight;
+// previous frames (most recent first), used for uniformly averaging the last N frames
+uniform sampler2D u_history[MAX_HISTORY_FRAMES];
+// 0 means frames are progressively averaged using u_texture instead
+uniform int u_history_count;
+// when rendering into a framebuffer, the frame is stored unexposed
+// (and un-averaged when blending history frames)
+uniform bool u_store_unexposed;
+// instead of a color, output which object is visible at each pixel (for the selection outline)
+uniform bool u_output_id;
+// also output the normal and depth of the camera ray's first hit
+uniform bool u_g_buffer;
+// renders distance from the camera as a grey level instead of color (for exporting depth maps)
+uniform bool u_output_depth;
+uniform float u_depth_far;
+// brightness multiplier for the displayed image (frames are accumulated unexposed)
+uniform float u_exposure;
+// debugging view of linear radiance: no gamma, exposure, or grid, and a black sky
+uniform bool u_raw_output;
+uniform bool u_show_grid;
+uniform float u_grid_spacing;
+// the previous scene's last frame, which the current scene fades in over as u_transition goes 0 -> 1
+uniform sampler2D u_transition_texture;
+uniform float u_transition;
 uniform float u_lens_radius;
+uniform int u_aperture_blades;
+// matches `DiffuseMode::value`
+#define DIFFUSE_LAMBERTIAN 0
+#define DIFFUSE_HEMISPHERE 1
+#define DIFFUSE_UNIT_SPHERE 2
+uniform int u_diffuse_mode;
+// matches `StereoMode::value`
+#define STEREO_OFF 0
+#define STEREO_ANAGLYPH 1
+#define STEREO_SIDE_BY_SIDE 2
+uniform int u_stereo_mode;
+uniform float u_ipd;
 uniform vec3 u_u;
 uniform vec3 u_v;
 uniform vec3 u_w;
 uniform int u_enable_debugging;
 uniform int u_selected_object;
 uniform vec3 u_cursor_point;
-uniform Sphere[15] u_sphere_list;
+// minimum distance along a ray that counts as a hit (prevents "shadow acne")
+uniform float u_ray_epsilon;
+// equirectangular sky image, used in place of the gradient when loaded
+uniform sampler2D u_environment_map;
+uniform bool u_has_environment_map;
+uniform samplerCube u_env_cubemap;
+uniform bool u_env_is_cubemap;
+// analytic sun + sky model, used in place of the gradient (and environment map) when enabled
+uniform bool u_sun_sky_enabled;
+uniform vec3 u_sun_direction;
+uniform float u_turbidity;
+// fog of u_height_fog_density * exp(-u_height_fog_falloff * height), where height is
+// measured along the world's up direction (zero density = no fog)
+uniform float u_height_fog_density;
+uniform float u_height_fog_falloff;
+uniform vec3 u_height_fog_color;
+// MAX_SPHERES is defined by the ray tracer when the shader is compiled (see `glsl::MAX_SPHERES`)
+uniform Sphere[MAX_SPHERES] u_sphere_list;
+uniform int u_sphere_count;
+// MAX_RECT_LIGHTS is defined by the ray tracer as well (see `glsl::MAX_RECT_LIGHTS`)
+uniform RectLight[MAX_RECT_LIGHTS] u_rect_light_list;
+uniform int u_rect_light_count;
+// which rects give off light, so that they can be sampled directly
+uniform int u_emissive_rect_indices[MAX_RECT_LIGHTS];
+uniform int u_emissive_rect_count;
 
 // FUNCTIONS //////////////////////////////////////////////////////
 vec3 ray_at(in Ray r, float hit_t) {
@@ -128,10 +239,40 @@ vec2 random_in_unit_circle() {
   return vec2(x, y);
 }
 
+// uniformly distributed inside the unit circle for fewer than 3 blades, otherwise inside
+// the regular polygon inscribed in it (one triangle per blade, with the first corner pointing up)
+vec2 random_in_aperture(in int blade_count) {
+  if (blade_count < 3) {
+    return random_in_unit_circle();
+  }
+  float blade_angle = 2. * PI / float(blade_count);
+  float blade = min(floor(hash1(global_seed) * float(blade_count)), float(blade_count - 1));
+  float start_angle = PI / 2. + blade * blade_angle;
+  float end_angle = start_angle + blade_angle;
+  float r = sqrt(hash1(global_seed));
+  float t = hash1(global_seed);
+  return r * mix(vec2(cos(start_angle), sin(start_angle)), vec2(cos(end_angle), sin(end_angle)), t);
+}
+
 vec3 random_unit_vec() {
   return normalize(random_in_unit_sphere());
 }
 
+vec3 random_in_hemisphere(in vec3 normal) {
+  vec3 direction = random_unit_vec();
+  return dot(direction, normal) > 0. ? direction : -direction;
+}
+
+vec3 diffuse_scatter_direction(in vec3 normal) {
+  if (u_diffuse_mode == DIFFUSE_HEMISPHERE) {
+    return random_in_hemisphere(normal);
+  }
+  if (u_diffuse_mode == DIFFUSE_UNIT_SPHERE) {
+    return normal + random_in_unit_sphere();
+  }
+  return normal + random_unit_vec();
+}
+
 // records whether a hit happened to the front or back face of an object
 void set_hit_record_front_face(inout HitRecord hit_record, in Ray r, in vec3 outward_normal) {
   hit_record.front_face = dot(r.direction, outward_normal) < 0.;
@@ -142,37 +283,138 @@ void set_hit_record_front_face(inout HitRecord hit_record, in Ray r, in vec3 out
   }
 }
 
+// a random value (0->1) for each integer lattice point (see `glsl::value_noise`)
+float lattice_hash(ivec3 p) {
+  uint h = base_hash(uvec2(uint(p.x), uint(p.y)));
+  return float(base_hash(uvec2(h, uint(p.z)))) / float(0xffffffffU);
+}
+
+// smoothly interpolated 3D value noise (0->1)
+float value_noise(vec3 p) {
+  vec3 cell = floor(p);
+  vec3 f = p - cell;
+  f = f * f * (3. - 2. * f);
+  ivec3 i = ivec3(cell);
+  return mix(
+    mix(
+      mix(lattice_hash(i), lattice_hash(i + ivec3(1, 0, 0)), f.x),
+      mix(lattice_hash(i + ivec3(0, 1, 0)), lattice_hash(i + ivec3(1, 1, 0)), f.x),
+      f.y
+    ),
+    mix(
+      mix(lattice_hash(i + ivec3(0, 0, 1)), lattice_hash(i + ivec3(1, 0, 1)), f.x),
+      mix(lattice_hash(i + ivec3(0, 1, 1)), lattice_hash(i + ivec3(1, 1, 1)), f.x),
+      f.y
+    ),
+    f.z
+  );
+}
+
+// picks one of the sphere's materials at random, weighted by the noise at the hit point,
+// so that the transition between them averages out into a soft blend over many samples
+Material sphere_material_at(in Sphere sphere, in vec3 point) {
+  if (sphere.blend_scale <= 0.)
+    return sphere.material;
+  float weight = smoothstep(0.3, 0.7, value_noise((point - sphere.center) / sphere.blend_scale));
+  return hash1(global_seed) < weight ? sphere.material2 : sphere.material;
+}
+
 bool hit_sphere(in Sphere sphere, in Ray r, in float t_min, in float t_max, inout HitRecord hit_record) {
   vec3 oc = r.origin - sphere.center;
   float a = length_squared(r.direction);
   float half_b = dot(oc, r.direction);
-  float c = length_squared(oc) - pow(sphere.radius, 2.);
-  float discriminant = pow(half_b, 2.) - a * c;
+  // pow() is undefined for negative bases, and radii may be negative (for hollow spheres)
+  float radius_squared = sphere.radius * sphere.radius;
+  float c = length_squared(oc) - radius_squared;
+  // `half_b² - a*c` cancels catastrophically for grazing rays and huge spheres,
+  // so measure the discriminant from the ray's closest approach to the center instead
+  vec3 closest_approach = oc - r.direction * (half_b / a);
+  float discriminant = a * (radius_squared - length_squared(closest_approach));
 
   // no hit
   if (discriminant < 0.)
     return false;
 
   // there was a hit, but it's not within an acceptable range
-  float sqrtd = sqrt(discriminant);
-  float root = (-half_b - sqrtd) / a;
+  // (`q` never subtracts nearly-equal values, and the other root follows from t0 * t1 = c / a)
+  float q = -(half_b + (half_b < 0. ? -1. : 1.) * sqrt(discriminant));
+  if (q == 0.)
+    return false;
+  float t0 = q / a;
+  float t1 = c / q;
+  float root = min(t0, t1);
   if (root < t_min || t_max < root) {
-    root = (-half_b + sqrtd) / a;
+    root = max(t0, t1);
     if (root < t_min || t_max < root) {
       return false;
     }
   }
 
-  hit_record.material = sphere.material;
   hit_record.hit_t = root;
   hit_record.hit_point = ray_at(r, hit_record.hit_t);
+  hit_record.material = sphere_material_at(sphere, hit_record.hit_point);
   hit_record.uuid = sphere.uuid;
+  hit_record.is_rect_light = false;
   vec3 outward_normal = (hit_record.hit_point - sphere.center) / sphere.radius;
   set_hit_record_front_face(hit_record, r, outward_normal);
   return true;
 }
 
-bool hit_world(in Ray r, in float t_min, in float t_max, inout HitRecord hit_record) {
+// swizzles a point into (a, b, k) for a rect in the given plane
+vec3 to_rect_space(in vec3 p, in int plane) {
+  if (plane == RECT_XZ) {
+    return p.xzy;
+  }
+  if (plane == RECT_YZ) {
+    return p.yzx;
+  }
+  return p;
+}
+
+// the inverse of to_rect_space
+vec3 from_rect_space(in vec3 abk, in int plane) {
+  if (plane == RECT_XZ) {
+    return abk.xzy;
+  }
+  if (plane == RECT_YZ) {
+    return abk.zxy;
+  }
+  return abk;
+}
+
+vec3 rect_outward_normal(in RectLight rect) {
+  return from_rect_space(vec3(0., 0., rect.flip_normal != 0 ? -1. : 1.), rect.plane);
+}
+
+bool hit_rect(in RectLight rect, in Ray r, in float t_min, in float t_max, inout HitRecord hit_record) {
+  vec3 origin = to_rect_space(r.origin, rect.plane);
+  vec3 direction = to_rect_space(r.direction, rect.plane);
+  float t = (rect.k - origin.z) / direction.z;
+  // parallel rays give an infinite (or NaN) t, which fails this check too
+  if (!(t >= t_min && t <= t_max)) {
+    return false;
+  }
+  vec2 ab = origin.xy + t * direction.xy;
+  vec2 a_range = vec2(min(rect.bounds.x, rect.bounds.y), max(rect.bounds.x, rect.bounds.y));
+  vec2 b_range = vec2(min(rect.bounds.z, rect.bounds.w), max(rect.bounds.z, rect.bounds.w));
+  if (ab.x < a_range.x || ab.x > a_range.y || ab.y < b_range.x || ab.y > b_range.y) {
+    return false;
+  }
+
+  hit_record.material = rect.material;
+  hit_record.hit_t = t;
+  hit_record.hit_point = ray_at(r, t);
+  hit_record.uuid = rect.uuid;
+  hit_record.is_rect_light = true;
+  set_hit_record_front_face(hit_record, r, rect_outward_normal(rect));
+  // single-sided lights are dark from behind
+  if (!hit_record.front_face && rect.double_sided == 0) {
+    hit_record.material.emission = vec3(0.);
+  }
+  return true;
+}
+
+bool hit_world(in Ray r, in float t_min, in float t_max, in bool include_markers, inout HitRecord hit_record) {
   // test whether any geometry was hit. If it was, the hit_record will be updated with
   // the new hit data if the new hit was closer to the camera than the previous hit
   bool hit_anything = false;
@@ -180,9 +422,16 @@ bool hit_world(in Ray r, in float t_min, in float t_max, inout HitRecord hit_rec
   HitRecord temp_hit_record;
 
   for(int i = 0; i < u_sphere_list.length(); i++) {
+    if (i >= u_sphere_count) {
+      break;
+    }
     Sphere sphere = u_sphere_list[i];
+    // hidden spheres stay in the list, so that every sphere keeps its index
     if (sphere.is_active == 0) {
-      break;
+      continue;
+    }
+    if (sphere.material.display_only != 0 && !include_markers) {
+      continue;
     }
 
     if (hit_sphere(sphere, r, t_min, closest_so_far, temp_hit_record)) {
@@ -192,6 +441,22 @@ bool hit_world(in Ray r, in float t_min, in float t_max, inout HitRecord hit_rec
     }
   } 
 
+  for (int i = 0; i < MAX_RECT_LIGHTS; i++) {
+    if (i >= u_rect_light_count) {
+      break;
+    }
+    RectLight rect = u_rect_light_list[i];
+    if (rect.material.display_only != 0 && !include_markers) {
+      continue;
+    }
+
+    if (hit_rect(rect, r, t_min, closest_so_far, temp_hit_record)) {
+      hit_anything = true;
+      closest_so_far = temp_hit_record.hit_t;
+      hit_record = temp_hit_record;
+    }
+  }
+
   return hit_anything;
 }
 
@@ -214,7 +479,7 @@ bool scatter(in Ray r, in HitRecord hit_record, out vec3 attenuation, out Ray sc
     attenuation = hit_record.material.albedo;
 
     // shoot ray off in random direction again
-    vec3 scatter_direction = hit_record.normal + random_unit_vec();
+    vec3 scatter_direction = diffuse_scatter_direction(hit_record.normal);
 
     // ignore when scattered direction becomes close to 0 for now
     // scatter direction can become close to 0 if opposite the normal vector 
@@ -251,6 +516,13 @@ bool scatter(in Ray r, in HitRecord hit_record, out vec3 attenuation, out Ray sc
     // color attenuation on reflection
     attenuation = hit_record.material.albedo;
 
+    // hitting a back face means the ray has been traveling inside the glass,
+    // so absorb light based on the distance traveled (Beer-Lambert law)
+    if (!hit_record.front_face) {
+      float distance_inside = hit_record.hit_t * length(r.direction);
+      attenuation *= exp(-hit_record.material.absorption * distance_inside);
+    }
+
     // refraction differs when colliding from the front or back face
     float refraction_ratio = hit_record.front_face ? (1.0 / hit_record.material.refraction_index) : hit_record.material.refraction_index;
 
@@ -285,9 +557,187 @@ bool scatter(in Ray r, in HitRecord hit_record, out vec3 attenuation, out Ray sc
   return false;
 }
 
+// SUN + SKY //////////////////////////////////////////////////////
+// Preetham, Shirley & Smits, "A Practical Analytic Model for Daylight" (1999)
+
+// Perez distribution: relative sky brightness at a zenith angle (theta)
+// and angle away from the sun (gamma), for one set of coefficients
+float perez(in float cos_theta, in float gamma, in float cos_gamma, in float[5] c) {
+  return (1. + c[0] * exp(c[1] / cos_theta)) * (1. + c[2] * exp(c[3] * gamma) + c[4] * cos_gamma * cos_gamma);
+}
+
+vec3 xyY_to_rgb(in vec3 xyY) {
+  float Y = xyY.z;
+  vec3 XYZ = vec3(xyY.x * Y / xyY.y, Y, (1. - xyY.x - xyY.y) * Y / xyY.y);
+  mat3 XYZ_to_linear_srgb = mat3(
+    3.2406, -0.9689, 0.0557,
+    -1.5372, 1.8758, -0.2040,
+    -0.4986, 0.0415, 1.0570
+  );
+  return max(XYZ_to_linear_srgb * XYZ, vec3(0.));
+}
+
+// sky color in a direction (both given with Y as up)
+vec3 preetham_sky(in vec3 direction, in vec3 sun_direction) {
+  float T = u_turbidity;
+  // treat everything below the horizon as the horizon
+  float cos_theta = max(direction.y, 0.01);
+  float theta_s = acos(clamp(sun_direction.y, 0., 1.));
+  float cos_gamma = clamp(dot(direction, sun_direction), -1., 1.);
+  float gamma = acos(cos_gamma);
+
+  float[5] Y_coefficients = float[5](0.1787 * T - 1.4630, -0.3554 * T + 0.4275, -0.0227 * T + 5.3251, 0.1206 * T - 2.5771, -0.0670 * T + 0.3703);
+  float[5] x_coefficients = float[5](-0.0193 * T - 0.2592, -0.0665 * T + 0.0008, -0.0004 * T + 0.2125, -0.0641 * T - 0.8989, -0.0033 * T + 0.0452);
+  float[5] y_coefficients = float[5](-0.0167 * T - 0.2608, -0.0950 * T + 0.0092, -0.0079 * T + 0.2102, -0.0441 * T - 1.6537, -0.0109 * T + 0.0529);
+
+  // sky color directly overhead
+  float chi = (4. / 9. - T / 120.) * (PI - 2. * theta_s);
+  float Y_zenith = (4.0453 * T - 4.9710) * tan(chi) - 0.2155 * T + 2.4192;
+  vec3 theta_s_powers = vec3(theta_s * theta_s * theta_s, theta_s * theta_s, theta_s);
+  float x_zenith = T * T * dot(vec4(0.00166, -0.00375, 0.00209, 0.), vec4(theta_s_powers, 1.))
+    + T * dot(vec4(-0.02903, 0.06377, -0.03202, 0.00394), vec4(theta_s_powers, 1.))
+    + dot(vec4(0.11693, -0.21196, 0.06052, 0.25886), vec4(theta_s_powers, 1.));
+  float y_zenith = T * T * dot(vec4(0.00275, -0.00610, 0.00317, 0.), vec4(theta_s_powers, 1.))
+    + T * dot(vec4(-0.04214, 0.08970, -0.04153, 0.00516), vec4(theta_s_powers, 1.))
+    + dot(vec4(0.15346, -0.26756, 0.06670, 0.26688), vec4(theta_s_powers, 1.));
+
+  // scale the zenith color by the relative brightness in this direction
+  float cos_theta_s = cos(theta_s);
+  float Y = Y_zenith * perez(cos_theta, gamma, cos_gamma, Y_coefficients) / perez(1., theta_s, cos_theta_s, Y_coefficients);
+  float x = x_zenith * perez(cos_theta, gamma, cos_gamma, x_coefficients) / perez(1., theta_s, cos_theta_s, x_coefficients);
+  float y = y_zenith * perez(cos_theta, gamma, cos_gamma, y_coefficients) / perez(1., theta_s, cos_theta_s, y_coefficients);
+
+  return xyY_to_rgb(vec3(x, y, max(Y, 0.) * SKY_LUMINANCE_SCALE));
+}
+
+// radiance of the sun disk, reddened by the amount of atmosphere the light travels through
+vec3 sun_radiance(in vec3 sun_direction) {
+  float zenith_angle = acos(clamp(sun_direction.y, 0., 1.));
+  // relative optical air mass (Kasten & Young)
+  float air_mass = 1. / (cos(zenith_angle) + 0.50572 * pow(96.07995 - degrees(zenith_angle), -1.6364));
+  vec3 transmittance = exp(-air_mass * (vec3(0.03, 0.07, 0.17) + 0.01 * u_turbidity));
+  float solid_angle = PI * SUN_ANGULAR_RADIUS * SUN_ANGULAR_RADIUS;
+  return transmittance * SUN_IRRADIANCE / solid_angle;
+}
+
+// random direction toward some point on the sun disk
+vec3 sample_sun_direction() {
+  vec3 tangent = normalize(cross(u_sun_direction, abs(u_sun_direction.y) < 0.999 ? vec3(0., 1., 0.) : vec3(1., 0., 0.)));
+  vec3 bitangent = cross(u_sun_direction, tangent);
+  vec2 offset = random_in_unit_circle() * SUN_ANGULAR_RADIUS;
+  return normalize(u_sun_direction + tangent * offset.x + bitangent * offset.y);
+}
+
+// light arriving directly from the sun at a diffuse surface (zero when shadowed)
+vec3 direct_sun_light(in HitRecord hit_record) {
+  vec3 light_direction = sample_sun_direction();
+  float cos_incidence = dot(hit_record.normal, light_direction);
+  if (cos_incidence <= 0.) {
+    return vec3(0.);
+  }
+  HitRecord shadow_hit_record;
+  if (hit_world(Ray(hit_record.hit_point, light_direction), u_ray_epsilon, MAX_T, false, shadow_hit_record)) {
+    return vec3(0.);
+  }
+  vec3 sun_direction = transpose(u_world_basis) * u_sun_direction;
+  // lambertian BRDF (albedo / PI) * incoming radiance * projected solid angle
+  float solid_angle = PI * SUN_ANGULAR_RADIUS * SUN_ANGULAR_RADIUS;
+  return hit_record.material.albedo / PI * sun_radiance(sun_direction) * cos_incidence * solid_angle;
+}
+
+// light arriving directly from a random point on one of the emissive rects at a diffuse
+// surface (zero when shadowed, or when the light faces away)
+vec3 direct_rect_light(in HitRecord hit_record) {
+  int light_index = min(int(hash1(global_seed) * float(u_emissive_rect_count)), u_emissive_rect_count - 1);
+  RectLight rect = u_rect_light_list[u_emissive_rect_indices[light_index]];
+  vec2 random = hash2(global_seed);
+  vec3 light_point = from_rect_space(vec3(
+    mix(rect.bounds.x, rect.bounds.y, random.x),
+    mix(rect.bounds.z, rect.bounds.w, random.y),
+    rect.k
+  ), rect.plane);
+
+  vec3 to_light = light_point - hit_record.hit_point;
+  float distance_squared = dot(to_light, to_light);
+  vec3 light_direction = to_light / sqrt(distance_squared);
+  float cos_incidence = dot(hit_record.normal, light_direction);
+  float cos_emission = dot(rect_outward_normal(rect), -light_direction);
+  if (rect.double_sided != 0) {
+    cos_emission = abs(cos_emission);
+  }
+  if (cos_incidence <= 0. || cos_emission <= 0.) {
+    return vec3(0.);
+  }
+  // stop just short of the light itself
+  HitRecord shadow_hit_record;
+  if (hit_world(Ray(hit_record.hit_point, to_light), u_ray_epsilon, 1. - u_ray_epsilon, false, shadow_hit_record)) {
+    return vec3(0.);
+  }
+  float area = abs((rect.bounds.y - rect.bounds.x) * (rect.bounds.w - rect.bounds.z));
+  // picking one of the lights at random is made up for by counting it once per light
+  float solid_angle = area * cos_emission / distance_squared * float(u_emissive_rect_count);
+  // lambertian BRDF (albedo / PI) * incoming radiance * projected solid angle
+  return hit_record.material.albedo / PI * rect.material.emission * cos_incidence * solid_angle;
+}
+
+// fraction of light that makes it through the height fog along a ray segment,
+// found by integrating the fog's density along the segment analytically
+float height_fog_transmittance(in Ray r, in float segment_t) {
+  // heights are measured in the usual Y-up axes
+  vec3 origin = transpose(u_world_basis) * r.origin;
+  vec3 direction = transpose(u_world_basis) * normalize(r.direction);
+  float distance = segment_t * length(r.direction);
+  float climb = u_height_fog_falloff * direction.y;
+  // level rays see a constant density (and would divide by ~0)
+  float integral = abs(climb) < 1e-5 ? distance : (1. - exp(-climb * distance)) / climb;
+  float optical_depth = u_height_fog_density * exp(-u_height_fog_falloff * origin.y) * integral;
+  return exp(-optical_depth);
+}
+
+// fogs the light arriving along a segment: whatever the segment ends at is dimmed,
+// and fog color is scattered in its place
+void apply_height_fog(in Ray r, in float segment_t, inout vec3 color, inout vec3 direct_light) {
+  if (u_height_fog_density <= 0.) {
+    return;
+  }
+  float transmittance = height_fog_transmittance(r, segment_t);
+  direct_light += color * u_height_fog_color * (1. - transmittance);
+  color *= transmittance;
+}
+
 // default background color when no intersection color was found
-vec3 background(in Ray r) {
-  vec3 unit_direction = normalize(r.direction);
+// the sun disk is left out for rays that already sampled the sun directly
+// roughness (0->1) blurs the environment map, by sampling from smaller mip levels
+vec3 background(in Ray r, in bool include_sun, in float roughness) {
+  if (u_raw_output) {
+    return vec3(0.);
+  }
+  // the sky is defined with Y as up, so convert into the world's (possibly rotated) axes
+  vec3 unit_direction = transpose(u_world_basis) * normalize(r.direction);
+  if (u_sun_sky_enabled) {
+    vec3 sun_direction = transpose(u_world_basis) * u_sun_direction;
+    vec3 sky = preetham_sky(unit_direction, sun_direction);
+    bool is_in_sun_disk = dot(unit_direction, sun_direction) > cos(SUN_ANGULAR_RADIUS);
+    if (include_sun && is_in_sun_disk) {
+      sky += sun_radiance(sun_direction);
+    }
+    return sky;
+  }
+  if (u_has_environment_map && u_env_is_cubemap) {
+    float max_lod = log2(float(textureSize(u_env_cubemap, 0).x));
+    return textureLod(u_env_cubemap, unit_direction, roughness * max_lod).rgb;
+  }
+  if (u_has_environment_map) {
+    // longitude maps to s and latitude to t (the top row of the image is straight up)
+    vec2 st = vec2(
+      0.5 + atan(unit_direction.z, unit_direction.x) / (2. * PI),
+      acos(clamp(unit_direction.y, -1., 1.)) / PI
+    );
+    // explicit LODs also avoid a visible seam where s wraps around
+    ivec2 size = textureSize(u_environment_map, 0);
+    float max_lod = log2(float(max(size.x, size.y)));
+    return textureLod(u_environment_map, st, roughness * max_lod).rgb;
+  }
   float t = 0.5 * (unit_direction.y + 1.0);
   vec3 gradient = mix(vec3(1.0, 1.0, 1.0), vec3(0.5, 0.7, 1.0), t);
   return gradient;
@@ -296,12 +746,21 @@ vec3 background(in Ray r) {
 // determine the color that a ray should be
 vec3 ray_color(in Ray r) {
   vec3 color = vec3(1.);
+  // light gathered along the way by sampling the sun directly
+  vec3 direct_light = vec3(0.);
+  bool sampled_sun = false;
+  // rect lights seen by a ray from a surface that already sampled them aren't counted twice
+  bool sampled_rect_lights = false;
+  // fuzz of the last reflection, which blurs the environment it reflects
+  float roughness = 0.;
 
   for(int i = 0; i < u_max_depth; i++) {
     // test for collisions with any geometry
     // hit record gets modified with hit details if there was a hit
     HitRecord hit_record;
-    if (hit_world(r, MIN_T, MAX_T, hit_record)) {
+    // markers are only visible to rays coming straight from the camera
+    bool is_camera_ray = i == 0;
+    if (hit_world(r, u_ray_epsilon, MAX_T, is_camera_ray, hit_record)) {
 
       // color using debugging tools
       if (u_enable_debugging != 0) {
@@ -317,32 +776,56 @@ vec3 ray_color(in Ray r) {
         }
       }
 
+      if (hit_record.material.display_only != 0) {
+        return hit_record.material.albedo;
+      }
+
+      apply_height_fog(r, hit_record.hit_t, color, direct_light);
+
+      // any surface can glow, while still scattering light as usual
+      if (!(hit_record.is_rect_light && sampled_rect_lights)) {
+        direct_light += color * hit_record.material.emission;
+      }
+
       // color using normal ray calculations
       vec3 attenuation;
       Ray scattered_ray;
       bool did_scatter = scatter(r, hit_record, attenuation, scattered_ray);
+      if (u_sun_sky_enabled && !u_raw_output) {
+        sampled_sun = hit_record.material.type == DIFFUSE;
+        if (sampled_sun) {
+          direct_light += color * direct_sun_light(hit_record);
+        }
+      }
+      sampled_rect_lights = hit_record.material.type == DIFFUSE && u_emissive_rect_count > 0;
+      if (sampled_rect_lights) {
+        direct_light += color * direct_rect_light(hit_record);
+      }
+      roughness = hit_record.material.type == METAL ? hit_record.material.fuzz : 0.;
       if (did_scatter) {
         r = scattered_ray;
         color *= attenuation;
       } else {
-        return vec3(0.);
+        return direct_light;
       }
 
     } else {
         // no hit, return the sky gradient background
-      vec3 background_gradient = background(r);
-      return color * background_gradient;
+      apply_height_fog(r, MAX_T / length(r.direction), color, direct_light);
+      vec3 background_gradient = background(r, !sampled_sun, roughness);
+      return direct_light + color * background_gradient;
     }
   }
 
-  return color;
+  return direct_light + color;
 }
 
-// create ray from camera origin to viewport
-Ray get_ray_from_camera(in vec2 st) {
+// create ray from camera origin (moved sideways by eye_offset for stereo) to viewport.
+// the viewport stays put, so both eyes converge on the focus plane
+Ray get_ray_from_camera(in vec2 st, in float eye_offset) {
   // adding a camera lens offset allows simulating a depth of field effect
-  vec2 random_point_on_camera_lens = u_lens_radius * random_in_unit_circle();
-  vec3 viewport_offset = u_u * random_point_on_camera_lens.x + u_v * random_point_on_camera_lens.y;
+  vec2 random_point_on_camera_lens = u_lens_radius * random_in_aperture(u_aperture_blades);
+  vec3 viewport_offset = u_u * (random_point_on_camera_lens.x + eye_offset) + u_v * random_point_on_camera_lens.y;
 
   // direction from camera origin to the viewport
   vec3 ray_direction = u_lower_left_corner + st.s * u_horizontal + st.t * u_vertical - u_camera_origin - viewport_offset;
@@ -350,49 +833,152 @@ Ray get_ray_from_camera(in vec2 st) {
   return Ray(u_camera_origin + viewport_offset, ray_direction);
 }
 
+// a ray straight from the camera origin through the viewport (without depth of field or stereo)
+Ray get_viewport_ray(in vec2 st) {
+  return Ray(u_camera_origin, u_lower_left_corner + st.s * u_horizontal + st.t * u_vertical - u_camera_origin);
+}
+
+// in side-by-side stereo, each half of the image holds one eye's entire view
+vec2 side_by_side_st(in vec2 st) {
+  return vec2(fract(st.s * 2.), st.t);
+}
+
+float side_by_side_eye_offset(in vec2 st) {
+  return st.s < 0.5 ? -u_ipd / 2. : u_ipd / 2.;
+}
+
 // set up global seed for simmulated randomness
 void init_global_seed() {
-  // I got this seed initialization from reinder https://www.shadertoy.com/view/llVcDz
-  global_seed = float(base_hash(floatBitsToUint(v_position))) / float(0xffffffffU) + u_time;
+  // based on the seed initialization from reinder https://www.shadertoy.com/view/llVcDz
+  uint h = pixel_hash(uvec2(gl_FragCoord.xy), uint(u_render_count), floatBitsToUint(u_time));
+  global_seed = float(h) / float(0xffffffffU);
+}
+
+bool is_in_roi() {
+  vec2 pixel = gl_FragCoord.xy - u_roi.xy;
+  return u_has_roi && all(greaterThanEqual(pixel, vec2(0.))) && all(lessThan(pixel, u_roi.zw));
+}
+
+// whether different objects (or an object and the sky) are seen through the corners of the
+// area a pixel samples: a cheap check for the hard silhouettes (like a ground sphere's horizon)
+// that alias the most
+bool is_on_edge(in vec2 st) {
+  vec2 pixel_size = 1. / vec2(u_width, u_height);
+  int uuids[4];
+  for (int i = 0; i < 4; i++) {
+    vec2 corner = st + vec2(i % 2, i / 2) * pixel_size;
+    HitRecord hit_record;
+    uuids[i] = hit_world(get_viewport_ray(corner), u_ray_epsilon, MAX_T, true, hit_record) ? hit_record.uuid : -1;
+  }
+  return uuids[0] != uuids[1] || uuids[0] != uuids[2] || uuids[0] != uuids[3];
 }
 
 // accumulates color from each ray and averages them out
-vec3 get_pixel_color(in vec2 st) {
+vec3 get_pixel_color(in vec2 st, in float eye_offset) {
   // accumulate color per pixel
   vec3 color = vec3(0.);
+  int samples_per_pixel = is_in_roi() ? u_roi_samples_per_pixel : u_samples_per_pixel;
+  if (u_edge_aa && is_on_edge(st)) {
+    samples_per_pixel += EDGE_AA_SAMPLES;
+  }
 
-  for(int i = 0; i < u_samples_per_pixel; i++) {
+  for(int i = 0; i < samples_per_pixel; i++) {
     vec2 random = hash2(global_seed);
     vec2 random_within_pixel = random / vec2(u_width, u_height);
 
     // pixel coordinate +/- the value of 1 pixel
     vec2 randomized_st = st + random_within_pixel;
-    Ray r = get_ray_from_camera(randomized_st);
+    Ray r = get_ray_from_camera(randomized_st, eye_offset);
 
     color += ray_color(r);
   }
 
   // scale color by number of samples
-  float scale = (1. / float(u_samples_per_pixel));
+  float scale = (1. / float(samples_per_pixel));
   color *= scale;
 
   // gamma correction
-  color = sqrt(color);
+  if (!u_raw_output) {
+    color = sqrt(color);
+  }
 
   return color;
 }
 
+vec3 get_stereo_pixel_color(in vec2 st) {
+  if (u_stereo_mode == STEREO_SIDE_BY_SIDE) {
+    return get_pixel_color(side_by_side_st(st), side_by_side_eye_offset(st));
+  }
+  if (u_stereo_mode == STEREO_ANAGLYPH) {
+    vec3 left = get_pixel_color(st, -u_ipd / 2.);
+    vec3 right = get_pixel_color(st, u_ipd / 2.);
+    return vec3(left.r, right.gb);
+  }
+  return get_pixel_color(st, 0.);
+}
+
+// sampler arrays can only be indexed with constant expressions
+vec4 history_frame(in int i, in vec2 st) {
+  switch (i) {
+    case 0: return texture(u_history[0], st);
+    case 1: return texture(u_history[1], st);
+    case 2: return texture(u_history[2], st);
+    case 3: return texture(u_history[3], st);
+    case 4: return texture(u_history[4], st);
+    case 5: return texture(u_history[5], st);
+    case 6: return texture(u_history[6], st);
+  }
+  return vec4(0.);
+}
+
+// uniformly average this frame with the last few frames
+// (only those rendered since the last time the camera moved)
+vec3 average_history(in vec3 pixel_color, in vec2 st) {
+  vec3 color_sum = pixel_color;
+  float frame_count = 1.;
+  for (int i = 0; i < MAX_HISTORY_FRAMES; i++) {
+    if (i >= u_history_count || i >= u_render_count - 1) {
+      break;
+    }
+    vec4 frame = history_frame(i, st);
+    if (frame.a == 0.) {
+      break;
+    }
+    color_sum += frame.rgb;
+    frame_count += 1.;
+  }
+  return color_sum / frame_count;
+}
+
+// colors are already gamma corrected, so exposure is applied in linear space
+vec3 apply_exposure(in vec3 color) {
+  return sqrt(color * color * u_exposure);
+}
+
 // either do a plain render or average this frame with 
 // the previous one, depending on global settings
-void render(in vec3 pixel_color, in vec2 st) {
+void blend(in vec3 pixel_color, in vec2 st) {
+  if (u_should_average && u_history_count > 0) {
+    if (u_store_unexposed) {
+      o_color = vec4(pixel_color, 1.);
+    } else {
+      o_color = vec4(average_history(pixel_color, st), 1.);
+    }
+    return;
+  }
+
   vec4 prev_frame = texture(u_texture, st);
   float render_count = float(u_render_count);
   if (u_should_average) {
     if (prev_frame.a == 0. || u_render_count <= 1) {
       // not enough data to average, render it straight
       o_color = vec4(pixel_color, 1.);
+    } else if (u_true_average) {
+      // running mean of every frame so far (render count includes this frame)
+      vec3 merged_color = prev_frame.rgb + (pixel_color - prev_frame.rgb) / render_count;
+      o_color = vec4(merged_color, 1.);
     } else {
-      // average this frame with previous frames
+      // weighted average of this frame with previous frames
       float total_frames = render_count + u_last_frame_weight;
       vec3 merged_color = (prev_frame.rgb * render_count + pixel_color * u_last_frame_weight) / total_frames;
       o_color = vec4(merged_color, 1.);
@@ -403,11 +989,113 @@ void render(in vec3 pixel_color, in vec2 st) {
   }
 }
 
+#define GRID_COLOR vec3(0.8)
+// the grid fades out completely by this many grid cells away from the camera
+#define GRID_FADE_CELLS 50.
+
+// composites a reference grid on the y = 0 plane over the displayed image
+vec3 apply_grid(in vec3 color, in vec2 st) {
+  // a pinhole ray, since lens blur would make the line widths noisy
+  Ray r = Ray(u_camera_origin, u_lower_left_corner + st.s * u_horizontal + st.t * u_vertical - u_camera_origin);
+  float plane_t = -r.origin.y / r.direction.y;
+  vec2 grid_coordinate = ray_at(r, plane_t).xz / u_grid_spacing;
+  // derivatives must be taken before branching, to stay defined at every pixel
+  vec2 line_width = fwidth(grid_coordinate);
+  if (plane_t <= 0.) {
+    return color;
+  }
+  HitRecord hit_record;
+  if (hit_world(r, u_ray_epsilon, plane_t, true, hit_record)) {
+    // something is in front of the grid
+    return color;
+  }
+  // distance (in pixels) to the nearest line, for anti-aliasing
+  vec2 distance_to_line = abs(fract(grid_coordinate - 0.5) - 0.5) / line_width;
+  float coverage = 1. - min(min(distance_to_line.x, distance_to_line.y), 1.);
+  float cell_distance = length(ray_at(r, plane_t) - r.origin) / u_grid_spacing;
+  float fade = 1. - smoothstep(0., GRID_FADE_CELLS, cell_distance);
+  return mix(color, GRID_COLOR, coverage * fade);
+}
+
+void render(in vec3 pixel_color, in vec2 st) {
+  blend(pixel_color, st);
+  // only the displayed image is exposed, so accumulation is unaffected by exposure changes
+  if (!u_store_unexposed && !u_raw_output) {
+    if (u_transition < 1.) {
+      o_color.rgb = mix(texture(u_transition_texture, st).rgb, o_color.rgb, u_transition);
+    }
+    o_color.rgb = apply_exposure(o_color.rgb);
+  }
+}
+
+// writes the uuid (+ 1, so that 0 means nothing) of whatever the camera sees at this pixel
+void render_id(in vec2 st) {
+  HitRecord hit_record;
+  Ray r = u_stereo_mode == STEREO_SIDE_BY_SIDE
+    ? get_ray_from_camera(side_by_side_st(st), side_by_side_eye_offset(st))
+    : get_ray_from_camera(st, 0.);
+  if (hit_world(r, u_ray_epsilon, MAX_T, true, hit_record)) {
+    o_color = vec4(float(hit_record.uuid + 1) / 255., 0., 0., 1.);
+  } else {
+    o_color = vec4(0.);
+  }
+}
+
+// a ray through the center of the pixel, without depth of field
+Ray get_pinhole_ray(in vec2 st) {
+  vec2 view_st = u_stereo_mode == STEREO_SIDE_BY_SIDE ? side_by_side_st(st) : st;
+  return get_viewport_ray(view_st);
+}
+
+// normal and distance of the first thing seen through the center of the pixel
+// (without depth of field), or zeros for the sky
+void render_g_buffer(in vec2 st) {
+  HitRecord hit_record;
+  Ray r = get_pinhole_ray(st);
+  if (hit_world(r, u_ray_epsilon, MAX_T, false, hit_record)) {
+    o_normal = vec4(hit_record.normal, 1.);
+    o_depth = vec4(hit_record.hit_t * length(r.direction), 0., 0., 1.);
+  } else {
+    o_normal = vec4(0.);
+    o_depth = vec4(0.);
+  }
+}
+
+// distance to the first thing seen through the center of the pixel, from black at the camera
+// to white at `u_depth_far` (anything farther, including the sky, is also white)
+void render_depth(in vec2 st) {
+  HitRecord hit_record;
+  Ray r = get_pinhole_ray(st);
+  float depth = 1.;
+  if (hit_world(r, u_ray_epsilon, MAX_T, false, hit_record)) {
+    depth = clamp(hit_record.hit_t * length(r.direction) / u_depth_far, 0., 1.);
+  }
+  o_color = vec4(vec3(depth), 1.);
+}
+
 void main() {
   init_global_seed();
 
   // current position on viewport, mapped from -1->1 to 0->1
   vec2 st = (v_position + 1.) * 0.5;
-  vec3 pixel_color = get_pixel_color(st);
-  render(pixel_color, st);
+  if (u_output_id) {
+    render_id(st);
+    return;
+  }
+  if (u_output_depth) {
+    render_depth(st);
+    return;
+  }
+  vec3 pixel_color = get_stereo_pixel_color(st);
+  // previous frames are sampled at this pixel's position on the canvas,
+  // which differs from its position on the viewport when letterboxed
+  vec2 canvas_st = gl_FragCoord.xy / vec2(u_width, u_height);
+  render(pixel_color, canvas_st);
+  if (u_g_buffer && u_store_unexposed) {
+    render_g_buffer(st);
+  }
+  // drawn after exposure, so that the grid looks the same however the scene is exposed
+  if (u_show_grid && !u_store_unexposed && !u_raw_output) {
+    o_color.rgb = apply_grid(o_color.rgb, st);
+  }
 }
\ No newline at end of file
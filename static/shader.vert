@@ -5,6 +5,8 @@ in vec4 a_position;
 out vec2 v_position;
 
 void main() {
-  v_position = a_position.xy;
+  // the quad may be shrunk to letterbox the image, but its corners
+  // should still map to the corners of the viewport
+  v_position = sign(a_position.xy);
   gl_Position = a_position;
 }
\ No newline at end of file